@@ -0,0 +1,60 @@
+#![feature(test)]
+
+// These benchmarks can be run from the project root with:
+// > cargo bench --bench bulk
+//
+// Each transacts 100,000 datoms about new entities into a fresh store, through the usual
+// transactor path or through the bulk-load path.
+
+extern crate test;
+extern crate edn;
+extern crate mentat;
+extern crate mentat_tx;
+extern crate mentat_tx_parser;
+
+use test::Bencher;
+
+use mentat::Conn;
+use mentat_tx::entities::Entity;
+use mentat_tx_parser::Tx;
+
+const SCHEMA: &'static str = "[{:db/ident :bench/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                               {:db/ident :bench/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                               {:db/ident :bench/tag :db/valueType :db.type/keyword :db/cardinality :db.cardinality/many}]";
+
+/// 100,000 datoms: 25,000 entities with a name, an age, and two tags.
+fn entities() -> Vec<Entity> {
+    let mut input = String::from("[");
+    for i in 0..25_000 {
+        input.push_str(&format!("{{:bench/name \"person {}\" :bench/age {} :bench/tag [:bench/a :bench/b]}}\n", i, i % 100));
+    }
+    input.push_str("]");
+    let parsed_edn = edn::parse::value(&input).expect("to parse test input");
+    Tx::parse(&parsed_edn).expect("to parse entities")
+}
+
+fn bench_load(b: &mut Bencher, bulk: bool) {
+    let entities = entities();
+    b.iter(|| {
+        let (mut conn, mut sqlite) = Conn::in_memory().expect("to open a store");
+        conn.transact(&mut sqlite, SCHEMA).expect("to transact the schema");
+
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("to begin");
+        if bulk {
+            in_progress.transact_entities_bulk(entities.clone()).expect("to transact");
+        } else {
+            in_progress.transact_entities(entities.clone()).expect("to transact");
+        }
+        in_progress.commit().expect("to commit");
+    });
+}
+
+#[bench]
+fn bench_transact_100k(b: &mut Bencher) {
+    bench_load(b, false);
+}
+
+#[bench]
+fn bench_transact_bulk_100k(b: &mut Bencher) {
+    bench_load(b, true);
+}
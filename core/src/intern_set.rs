@@ -13,6 +13,7 @@
 use std::collections::HashSet;
 use std::hash::Hash;
 use std::rc::Rc;
+use std::sync::Arc;
 
 /// An `InternSet` allows to "intern" some potentially large values, maintaining a single value
 /// instance owned by the `InternSet` and leaving consumers with lightweight ref-counted handles to
@@ -61,4 +62,63 @@ impl<T> InternSet<T> where T: Eq + Hash {
             self.inner.get(&key).unwrap().clone()
         }
     }
-}
\ No newline at end of file
+}
+
+/// Like `InternSet`, but handing out `Arc` handles, so that interned values can be shared across
+/// threads, and bounded: once it holds `capacity` values, interning another forgets them all and
+/// starts afresh.  Handles already given out remain valid.
+///
+/// This suits a long-lived cache of values that recur, like the keywords in a stream of
+/// transactions, without letting a stream of values that don't recur grow it without limit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BoundedInternSet<T> where T: Eq + Hash {
+    inner: HashSet<Arc<T>>,
+    capacity: usize,
+}
+
+impl<T> BoundedInternSet<T> where T: Clone + Eq + Hash {
+    pub fn with_capacity(capacity: usize) -> BoundedInternSet<T> {
+        BoundedInternSet {
+            inner: HashSet::new(),
+            capacity: capacity,
+        }
+    }
+
+    /// Intern `value`, cloning it only if an equal value isn't already interned.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use mentat_core::intern_set::BoundedInternSet;
+    ///
+    /// let mut s = BoundedInternSet::with_capacity(2);
+    ///
+    /// let foo = s.intern_ref(&"foo".to_string());
+    /// assert!(Arc::ptr_eq(&foo, &s.intern_ref(&"foo".to_string())));
+    /// assert_eq!(1, s.len());
+    ///
+    /// // A third value clears the set.
+    /// s.intern_ref(&"bar".to_string());
+    /// s.intern_ref(&"baz".to_string());
+    /// assert_eq!(1, s.len());
+    /// assert!(!Arc::ptr_eq(&foo, &s.intern_ref(&"foo".to_string())));
+    /// ```
+    pub fn intern_ref(&mut self, value: &T) -> Arc<T> {
+        if let Some(interned) = self.inner.get(value) {
+            return interned.clone();
+        }
+        if self.inner.len() >= self.capacity {
+            self.inner.clear();
+        }
+        let interned = Arc::new(value.clone());
+        self.inner.insert(interned.clone());
+        interned
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
@@ -17,6 +17,12 @@ extern crate ordered_float;
 extern crate edn;
 extern crate uuid;
 
+#[cfg(feature = "serde_support")]
+extern crate serde;
+#[cfg(feature = "serde_support")]
+#[macro_use]
+extern crate serde_derive;
+
 pub mod values;
 
 use std::collections::{
@@ -24,8 +30,9 @@ use std::collections::{
     BTreeSet,
 };
 
+use std::error;
 use std::fmt;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use enum_set::EnumSet;
 
@@ -52,6 +59,25 @@ pub use edn::{
 /// use i64 rather than manually truncating u64 to u63 and casting to i64 throughout the codebase.
 pub type Entid = i64;
 
+/// An entid that is known to exist: one that was resolved from an ident, or to which a tempid
+/// resolved or was allocated.  Functions that resolve entids return a `KnownEntid`, so that APIs
+/// accepting one document that their caller has already done the resolution, rather than passing
+/// an arbitrary integer.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct KnownEntid(pub Entid);
+
+impl From<KnownEntid> for Entid {
+    fn from(k: KnownEntid) -> Entid {
+        k.0
+    }
+}
+
+impl From<KnownEntid> for TypedValue {
+    fn from(k: KnownEntid) -> TypedValue {
+        TypedValue::Ref(k.0)
+    }
+}
+
 /// The attribute of each Mentat assertion has a :db/valueType constraining the value to a
 /// particular set.  Mentat recognizes the following :db/valueType values.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
@@ -137,8 +163,8 @@ pub enum TypedValue {
     Double(OrderedFloat<f64>),
     Instant(DateTime<Utc>),
     // TODO: &str throughout?
-    String(Rc<String>),
-    Keyword(Rc<NamespacedKeyword>),
+    String(Arc<String>),
+    Keyword(Arc<NamespacedKeyword>),
     Uuid(Uuid),                        // It's only 128 bits, so this should be acceptable to clone.
 }
 
@@ -169,25 +195,134 @@ impl TypedValue {
     }
 
     /// Construct a new `TypedValue::Keyword` instance by cloning the provided
-    /// values and wrapping them in a new `Rc`. This is expensive, so this might
+    /// values and wrapping them in a new `Arc`. This is expensive, so this might
     /// be best limited to tests.
     pub fn typed_ns_keyword(ns: &str, name: &str) -> TypedValue {
-        TypedValue::Keyword(Rc::new(NamespacedKeyword::new(ns, name)))
+        TypedValue::Keyword(Arc::new(NamespacedKeyword::new(ns, name)))
     }
 
     /// Construct a new `TypedValue::String` instance by cloning the provided
-    /// value and wrapping it in a new `Rc`. This is expensive, so this might
+    /// value and wrapping it in a new `Arc`. This is expensive, so this might
     /// be best limited to tests.
     pub fn typed_string(s: &str) -> TypedValue {
-        TypedValue::String(Rc::new(s.to_string()))
+        TypedValue::String(Arc::new(s.to_string()))
     }
 
     pub fn current_instant() -> TypedValue {
         TypedValue::Instant(Utc::now())
     }
+
+    /// Coerce the EDN `value` into a `TypedValue` of the `expected` value type.
+    ///
+    /// Most value types accept only the corresponding EDN values, but an EDN integer coerces to a
+    /// `ValueType::Ref`.  Keywords do not coerce to refs here: that requires a schema to resolve
+    /// the keyword as an ident.
+    pub fn from_edn_value(value: &edn::Value, expected: ValueType) -> Result<TypedValue, ValueTypeMismatch> {
+        match (expected, value) {
+            (ValueType::Boolean, &edn::Value::Boolean(x)) => Ok(TypedValue::Boolean(x)),
+            (ValueType::Long, &edn::Value::Integer(x)) => Ok(TypedValue::Long(x)),
+            (ValueType::Double, &edn::Value::Float(x)) => Ok(TypedValue::Double(x)),
+            (ValueType::String, &edn::Value::Text(ref x)) => Ok(TypedValue::String(Arc::new(x.clone()))),
+            (ValueType::Uuid, &edn::Value::Uuid(x)) => Ok(TypedValue::Uuid(x)),
+            (ValueType::Instant, &edn::Value::Instant(x)) => Ok(TypedValue::Instant(x)),
+            (ValueType::Keyword, &edn::Value::NamespacedKeyword(ref x)) => Ok(TypedValue::Keyword(Arc::new(x.clone()))),
+            (ValueType::Ref, &edn::Value::Integer(x)) => Ok(TypedValue::Ref(x)),
+            _ => Err(ValueTypeMismatch {
+                expected: expected,
+                value: value.clone(),
+            }),
+        }
+    }
+
+    pub fn into_entid(self) -> Option<Entid> {
+        match self {
+            TypedValue::Ref(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_kw(self) -> Option<Arc<NamespacedKeyword>> {
+        match self {
+            TypedValue::Keyword(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_boolean(self) -> Option<bool> {
+        match self {
+            TypedValue::Boolean(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_long(self) -> Option<i64> {
+        match self {
+            TypedValue::Long(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_double(self) -> Option<f64> {
+        match self {
+            TypedValue::Double(v) => Some(v.into_inner()),
+            _ => None,
+        }
+    }
+
+    pub fn into_instant(self) -> Option<DateTime<Utc>> {
+        match self {
+            TypedValue::Instant(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_string(self) -> Option<Arc<String>> {
+        match self {
+            TypedValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_uuid(self) -> Option<Uuid> {
+        match self {
+            TypedValue::Uuid(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// An EDN value that couldn't be coerced to the expected Mentat value type.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValueTypeMismatch {
+    pub expected: ValueType,
+    pub value: edn::Value,
+}
+
+impl fmt::Display for ValueTypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EDN value '{}' is not the expected Mentat value type {:?}", self.value, self.expected)
+    }
+}
+
+impl error::Error for ValueTypeMismatch {
+    fn description(&self) -> &str {
+        "EDN value is not the expected Mentat value type"
+    }
 }
 
-// We don't do From<i64> or From<Entid> 'cos it's ambiguous.
+// `Entid` is an alias for `i64`, so `From<i64>` necessarily produces a `Long`: wrap entids in
+// `TypedValue::Ref` explicitly.
+impl From<i64> for TypedValue {
+    fn from(value: i64) -> TypedValue {
+        TypedValue::Long(value)
+    }
+}
+
+impl From<f64> for TypedValue {
+    fn from(value: f64) -> TypedValue {
+        TypedValue::Double(OrderedFloat(value))
+    }
+}
 
 impl From<bool> for TypedValue {
     fn from(value: bool) -> TypedValue {
@@ -209,13 +344,19 @@ impl From<Uuid> for TypedValue {
 
 impl From<String> for TypedValue {
     fn from(value: String) -> TypedValue {
-        TypedValue::String(Rc::new(value))
+        TypedValue::String(Arc::new(value))
+    }
+}
+
+impl<'a> From<&'a str> for TypedValue {
+    fn from(value: &'a str) -> TypedValue {
+        TypedValue::String(Arc::new(value.to_string()))
     }
 }
 
 impl From<NamespacedKeyword> for TypedValue {
     fn from(value: NamespacedKeyword) -> TypedValue {
-        TypedValue::Keyword(Rc::new(value))
+        TypedValue::Keyword(Arc::new(value))
     }
 }
 
@@ -524,6 +665,12 @@ pub struct Attribute {
     /// They are used to compose entities from component sub-entities: they are fetched recursively
     /// by pull expressions, and they are automatically recursively deleted where appropriate.
     pub component: bool,
+
+    /// `true` if this attribute doesn't retain history, i.e., it is `:db/noHistory true`.
+    ///
+    /// When a value of such an attribute is retracted or replaced, the superseded assertion and its
+    /// retraction are removed from the transaction log rather than being kept.
+    pub no_history: bool,
 }
 
 impl Attribute {
@@ -574,6 +721,10 @@ impl Attribute {
             attribute_map.insert(values::DB_IS_COMPONENT.clone(), edn::Value::Boolean(true));
         }
 
+        if self.no_history {
+            attribute_map.insert(values::DB_NO_HISTORY.clone(), edn::Value::Boolean(true));
+        }
+
         edn::Value::Map(attribute_map)
     }
 }
@@ -588,6 +739,7 @@ impl Default for Attribute {
             multival: false,
             unique: None,
             component: false,
+            no_history: false,
         }
     }
 }
@@ -677,6 +829,63 @@ mod test {
         schema.schema_map.insert(e, a);
     }
 
+    #[test]
+    fn test_typed_value_from_edn_value() {
+        assert_eq!(TypedValue::from_edn_value(&edn::Value::Integer(5), ValueType::Long),
+                   Ok(TypedValue::Long(5)));
+        assert_eq!(TypedValue::from_edn_value(&edn::Value::Integer(5), ValueType::Ref),
+                   Ok(TypedValue::Ref(5)));
+        assert_eq!(TypedValue::from_edn_value(&edn::Value::Text("foo".to_string()), ValueType::String),
+                   Ok(TypedValue::typed_string("foo")));
+        assert_eq!(TypedValue::from_edn_value(&edn::Value::NamespacedKeyword(NamespacedKeyword::new("foo", "bar")), ValueType::Keyword),
+                   Ok(TypedValue::typed_ns_keyword("foo", "bar")));
+
+        let mismatch = TypedValue::from_edn_value(&edn::Value::Text("5".to_string()), ValueType::Long).unwrap_err();
+        assert_eq!(mismatch.expected, ValueType::Long);
+        assert_eq!(mismatch.value, edn::Value::Text("5".to_string()));
+        assert_eq!(mismatch.to_string(), "EDN value '\"5\"' is not the expected Mentat value type Long");
+
+        // Coercing keywords to refs requires a schema.
+        assert!(TypedValue::from_edn_value(&edn::Value::NamespacedKeyword(NamespacedKeyword::new("db", "ident")), ValueType::Ref).is_err());
+    }
+
+    #[test]
+    fn test_typed_value_into() {
+        assert_eq!(TypedValue::Ref(5).into_entid(), Some(5));
+        assert_eq!(TypedValue::Long(5).into_entid(), None);
+        assert_eq!(TypedValue::Long(5).into_long(), Some(5));
+        assert_eq!(TypedValue::Ref(5).into_long(), None);
+        assert_eq!(TypedValue::Boolean(true).into_boolean(), Some(true));
+        assert_eq!(TypedValue::Double(OrderedFloat(1.5)).into_double(), Some(1.5));
+        assert_eq!(TypedValue::typed_string("foo").into_string(), Some(Arc::new("foo".to_string())));
+        assert_eq!(TypedValue::typed_string("foo").into_kw(), None);
+        assert_eq!(TypedValue::typed_ns_keyword("foo", "bar").into_kw(), Some(Arc::new(NamespacedKeyword::new("foo", "bar"))));
+        assert_eq!(TypedValue::typed_ns_keyword("foo", "bar").into_string(), None);
+
+        let now = Utc::now();
+        assert_eq!(TypedValue::Instant(now).into_instant(), Some(now));
+        assert_eq!(TypedValue::Long(5).into_instant(), None);
+
+        let uuid = Uuid::nil();
+        assert_eq!(TypedValue::Uuid(uuid).into_uuid(), Some(uuid));
+        assert_eq!(TypedValue::Boolean(false).into_uuid(), None);
+    }
+
+    #[test]
+    fn test_typed_value_from() {
+        assert_eq!(TypedValue::from(5i64), TypedValue::Long(5));
+        assert_eq!(TypedValue::from(5i32), TypedValue::Long(5));
+        assert_eq!(TypedValue::from(1.5f64), TypedValue::Double(OrderedFloat(1.5)));
+        assert_eq!(TypedValue::from(true), TypedValue::Boolean(true));
+        assert_eq!(TypedValue::from("foo"), TypedValue::typed_string("foo"));
+        assert_eq!(TypedValue::from("foo".to_string()), TypedValue::typed_string("foo"));
+        assert_eq!(TypedValue::from(NamespacedKeyword::new("foo", "bar")), TypedValue::typed_ns_keyword("foo", "bar"));
+
+        // Unlike a bare `Entid`, a `KnownEntid` converts to a ref.
+        assert_eq!(TypedValue::from(KnownEntid(5)), TypedValue::Ref(5));
+        assert_eq!(Entid::from(KnownEntid(5)), 5);
+    }
+
     #[test]
     fn test_attribute_flags() {
         let attr1 = Attribute {
@@ -686,6 +895,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         };
 
         assert!(attr1.flags() & AttributeBitFlags::IndexAVET as u8 != 0);
@@ -700,6 +910,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: false,
             component: false,
+            no_history: false,
         };
 
         assert!(attr2.flags() & AttributeBitFlags::IndexAVET as u8 == 0);
@@ -714,6 +925,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: false,
+            no_history: false,
         };
 
         assert!(attr3.flags() & AttributeBitFlags::IndexAVET as u8 == 0);
@@ -733,6 +945,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         };
         associate_ident(&mut schema, NamespacedKeyword::new("foo", "bar"), 97);
         add_attribute(&mut schema, 97, attr1);
@@ -744,6 +957,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: true,
             component: false,
+            no_history: false,
         };
         associate_ident(&mut schema, NamespacedKeyword::new("foo", "bas"), 98);
         add_attribute(&mut schema, 98, attr2);
@@ -755,6 +969,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: true,
+            no_history: false,
         };
 
         associate_ident(&mut schema, NamespacedKeyword::new("foo", "bat"), 99);
@@ -775,7 +990,7 @@ mod test {
     :db/valueType :db.type/boolean
     :db/cardinality :db.cardinality/one
     :db/unique :db.unique/identity
-    :db/component true }, ]"#;
+    :db/isComponent true }, ]"#;
         let expected_value = edn::parse::value(&expected_output).expect("to be able to parse").without_spans();
         assert_eq!(expected_value, value);
 
@@ -788,3 +1003,5 @@ mod test {
 pub mod intern_set;
 pub mod counter;
 pub mod util;
+#[cfg(feature = "serde_support")]
+pub mod serde_support;
@@ -0,0 +1,222 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! `serde` support for `TypedValue`, enabled by the `serde_support` feature.
+//!
+//! Each value is tagged with its value type, like `{"ref": 65536}` or `{"keyword": ":db/ident"}`,
+//! so that refs can't be confused with longs, nor keywords with strings.  Instants are RFC 3339
+//! strings with full precision and UUIDs are hyphenated strings, so every value round-trips
+//! exactly.  JSON has no numbers for the doubles NaN and the infinities, so they are the strings
+//! `"NaN"`, `"Infinity"`, and `"-Infinity"`.
+
+use std::f64;
+use std::sync::Arc;
+
+use serde::{
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+use serde::de;
+
+use ordered_float::OrderedFloat;
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use uuid::Uuid;
+
+use {
+    Entid,
+    TypedValue,
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Tagged {
+    Ref(Entid),
+    Boolean(bool),
+    Long(i64),
+    Double(Double),
+    Instant(String),
+    String(String),
+    Keyword(String),
+    Uuid(String),
+}
+
+/// A double that JSON can represent: a number if finite, and otherwise a string.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Double {
+    Finite(f64),
+    NonFinite(String),
+}
+
+impl From<f64> for Double {
+    fn from(d: f64) -> Double {
+        if d.is_finite() {
+            Double::Finite(d)
+        } else if d.is_nan() {
+            Double::NonFinite("NaN".to_string())
+        } else if d > 0.0 {
+            Double::NonFinite("Infinity".to_string())
+        } else {
+            Double::NonFinite("-Infinity".to_string())
+        }
+    }
+}
+
+impl Double {
+    fn into_f64(self) -> Option<f64> {
+        match self {
+            Double::Finite(d) => Some(d),
+            Double::NonFinite(s) => match s.as_str() {
+                "NaN" => Some(f64::NAN),
+                "Infinity" => Some(f64::INFINITY),
+                "-Infinity" => Some(f64::NEG_INFINITY),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn parse_instant(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s).ok().map(|instant| instant.with_timezone(&Utc))
+}
+
+/// Parse the `:namespace/name` form produced by `NamespacedKeyword`'s `Display`.
+fn parse_keyword(s: &str) -> Option<NamespacedKeyword> {
+    if !s.starts_with(':') {
+        return None;
+    }
+    let mut parts = s[1..].splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(namespace), Some(name)) if !namespace.is_empty() && !name.is_empty() => {
+            Some(NamespacedKeyword::new(namespace, name))
+        },
+        _ => None,
+    }
+}
+
+impl Serialize for TypedValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let tagged = match *self {
+            TypedValue::Ref(e) => Tagged::Ref(e),
+            TypedValue::Boolean(b) => Tagged::Boolean(b),
+            TypedValue::Long(l) => Tagged::Long(l),
+            TypedValue::Double(OrderedFloat(d)) => Tagged::Double(Double::from(d)),
+            TypedValue::Instant(ref t) => Tagged::Instant(t.to_rfc3339()),
+            TypedValue::String(ref s) => Tagged::String((**s).clone()),
+            TypedValue::Keyword(ref k) => Tagged::Keyword(k.to_string()),
+            TypedValue::Uuid(ref u) => Tagged::Uuid(u.hyphenated().to_string()),
+        };
+        tagged.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TypedValue {
+    fn deserialize<D>(deserializer: D) -> Result<TypedValue, D::Error> where D: Deserializer<'de> {
+        Ok(match Tagged::deserialize(deserializer)? {
+            Tagged::Ref(e) => TypedValue::Ref(e),
+            Tagged::Boolean(b) => TypedValue::Boolean(b),
+            Tagged::Long(l) => TypedValue::Long(l),
+            Tagged::Double(d) => TypedValue::Double(OrderedFloat(d.into_f64().ok_or_else(|| de::Error::custom("invalid double"))?)),
+            Tagged::Instant(t) => TypedValue::Instant(parse_instant(&t).ok_or_else(|| de::Error::custom(format!("invalid instant {}", t)))?),
+            Tagged::String(s) => TypedValue::String(Arc::new(s)),
+            Tagged::Keyword(k) => {
+                let keyword = parse_keyword(&k).ok_or_else(|| de::Error::custom(format!("invalid keyword {}", k)))?;
+                TypedValue::Keyword(Arc::new(keyword))
+            },
+            Tagged::Uuid(u) => TypedValue::Uuid(Uuid::parse_str(&u).map_err(|_| de::Error::custom(format!("invalid UUID {}", u)))?),
+        })
+    }
+}
+
+/// Serialize an optional attribute and value, like a tempid's upsert, as `[":ns/name", value]` or
+/// `null`.  Use with `#[serde(with = "mentat_core::serde_support::optional_keyword_and_value")]`.
+pub mod optional_keyword_and_value {
+    use serde::{
+        Deserialize,
+        Deserializer,
+        Serialize,
+        Serializer,
+    };
+    use serde::de;
+
+    use edn::NamespacedKeyword;
+
+    use TypedValue;
+
+    pub fn serialize<S>(pair: &Option<(NamespacedKeyword, TypedValue)>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        pair.as_ref().map(|&(ref keyword, ref value)| (keyword.to_string(), value)).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<(NamespacedKeyword, TypedValue)>, D::Error> where D: Deserializer<'de> {
+        match Option::<(String, TypedValue)>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some((k, value)) => {
+                let keyword = super::parse_keyword(&k).ok_or_else(|| de::Error::custom(format!("invalid keyword {}", k)))?;
+                Ok(Some((keyword, value)))
+            },
+        }
+    }
+}
+
+/// Serialize an instant as an RFC 3339 string, like `TypedValue::Instant`.  Use with
+/// `#[serde(with = "mentat_core::serde_support::instant")]`.
+pub mod instant {
+    use serde::{
+        Deserialize,
+        Deserializer,
+        Serializer,
+    };
+    use serde::de;
+
+    use edn::{
+        DateTime,
+        Utc,
+    };
+
+    pub fn serialize<S>(instant: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&instant.to_rfc3339())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error> where D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        super::parse_instant(&s).ok_or_else(|| de::Error::custom(format!("invalid instant {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_instant() {
+        let instant = parse_instant("2017-04-28T20:23:05.187123456Z").expect("parsed");
+        assert_eq!(instant.to_rfc3339(), "2017-04-28T20:23:05.187123456+00:00");
+        assert_eq!(parse_instant(&instant.to_rfc3339()), Some(instant));
+        assert_eq!(parse_instant("yesterday"), None);
+    }
+
+    #[test]
+    fn test_parse_keyword() {
+        assert_eq!(parse_keyword(":db/ident"), Some(NamespacedKeyword::new("db", "ident")));
+        assert_eq!(parse_keyword(":db.type/ref"), Some(NamespacedKeyword::new("db.type", "ref")));
+        assert_eq!(parse_keyword("db/ident"), None);
+        assert_eq!(parse_keyword(":ident"), None);
+        assert_eq!(parse_keyword(":/ident"), None);
+        assert_eq!(parse_keyword(":db/"), None);
+    }
+}
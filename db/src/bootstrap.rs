@@ -181,7 +181,7 @@ fn symbolic_schema_to_triples(ident_map: &IdentMap, symbolic_schema: &Value) ->
                             // TODO: remove this limitation, perhaps by including a type tag in the
                             // bootstrap symbolic schema, or by representing the initial bootstrap
                             // schema directly as Rust data.
-                            let typed_value = match TypedValue::from_edn_value(value) {
+                            let typed_value = match TypedValue::from_untyped_edn_value(value) {
                                 Some(TypedValue::Keyword(ref k)) => {
                                     ident_map.get(k)
                                         .map(|entid| TypedValue::Ref(*entid))
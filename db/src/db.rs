@@ -11,12 +11,15 @@
 #![allow(dead_code)]
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{
+    BTreeSet,
+    HashMap,
+};
 use std::fmt::Display;
 use std::iter::{once, repeat};
 use std::ops::Range;
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use itertools;
 use itertools::Itertools;
@@ -24,8 +27,10 @@ use rusqlite;
 use rusqlite::TransactionBehavior;
 use rusqlite::limits::Limit;
 use rusqlite::types::{ToSql, ToSqlOutput};
+use sha2::{Digest, Sha256};
 
 use ::{repeat_values, to_namespaced_keyword};
+use verify::attribute_list;
 use bootstrap;
 
 use edn::{
@@ -60,15 +65,23 @@ use types::{
     DB,
     Partition,
     PartitionMap,
+    partition_end,
 };
 use tx::transact;
 
-pub fn new_connection<T>(uri: T) -> rusqlite::Result<rusqlite::Connection> where T: AsRef<Path> {
-    let conn = match uri.as_ref().to_string_lossy().len() {
+fn make_connection(uri: &Path, maybe_encryption_key: Option<&str>) -> rusqlite::Result<rusqlite::Connection> {
+    let conn = match uri.to_string_lossy().len() {
         0 => rusqlite::Connection::open_in_memory()?,
         _ => rusqlite::Connection::open(uri)?,
     };
 
+    // The key must be set before anything else touches the database.
+    if let Some(key) = maybe_encryption_key {
+        assert!(cfg!(feature = "sqlcipher"),
+                "This function shouldn't be called with a key unless we have SQLCipher support");
+        conn.execute_batch(&format!("PRAGMA key = '{}';", escape_string_for_pragma(key)))?;
+    }
+
     /// See https://github.com/mozilla/mentat/issues/505 for details on temp_store
     /// pragma and how it might interact together with consumers such as Firefox.
     /// temp_store=2 is currently present to force SQLite to store temp files in memory.
@@ -87,6 +100,226 @@ pub fn new_connection<T>(uri: T) -> rusqlite::Result<rusqlite::Connection> where
     Ok(conn)
 }
 
+/// Escape a string for use as a quoted literal in a `PRAGMA`, which can't take bound parameters.
+fn escape_string_for_pragma(s: &str) -> String {
+    s.replace("'", "''")
+}
+
+pub fn new_connection<T>(uri: T) -> rusqlite::Result<rusqlite::Connection> where T: AsRef<Path> {
+    make_connection(uri.as_ref(), None)
+}
+
+/// A SQLite journal mode.  See https://www.sqlite.org/pragma.html#pragma_journal_mode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<JournalMode> {
+        match s.to_lowercase().as_str() {
+            "delete" => Some(JournalMode::Delete),
+            "truncate" => Some(JournalMode::Truncate),
+            "persist" => Some(JournalMode::Persist),
+            "memory" => Some(JournalMode::Memory),
+            "wal" => Some(JournalMode::Wal),
+            "off" => Some(JournalMode::Off),
+            _ => None,
+        }
+    }
+}
+
+/// A SQLite `synchronous` level.  See https://www.sqlite.org/pragma.html#pragma_synchronous.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn to_i64(&self) -> i64 {
+        match *self {
+            Synchronous::Off => 0,
+            Synchronous::Normal => 1,
+            Synchronous::Full => 2,
+            Synchronous::Extra => 3,
+        }
+    }
+
+    fn from_i64(x: i64) -> Option<Synchronous> {
+        match x {
+            0 => Some(Synchronous::Off),
+            1 => Some(Synchronous::Normal),
+            2 => Some(Synchronous::Full),
+            3 => Some(Synchronous::Extra),
+            _ => None,
+        }
+    }
+}
+
+/// SQLite settings to apply when opening a connection with `new_connection_with_options`.  Unset
+/// options keep the defaults chosen by `new_connection`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionOptions {
+    pub journal_mode: Option<JournalMode>,
+    pub synchronous: Option<Synchronous>,
+
+    /// As for `PRAGMA cache_size`: a positive number of pages, or, if negative, a number of KiB.
+    pub cache_size: Option<i64>,
+
+    /// As for `PRAGMA mmap_size`: the maximum number of bytes of the store to memory-map.
+    pub mmap_size: Option<i64>,
+}
+
+impl ConnectionOptions {
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> ConnectionOptions {
+        self.journal_mode = Some(journal_mode);
+        self
+    }
+
+    pub fn synchronous(mut self, synchronous: Synchronous) -> ConnectionOptions {
+        self.synchronous = Some(synchronous);
+        self
+    }
+
+    pub fn cache_size(mut self, cache_size: i64) -> ConnectionOptions {
+        self.cache_size = Some(cache_size);
+        self
+    }
+
+    pub fn mmap_size(mut self, mmap_size: i64) -> ConnectionOptions {
+        self.mmap_size = Some(mmap_size);
+        self
+    }
+}
+
+/// The settings SQLite reports after `new_connection_with_options` applied the requested
+/// `ConnectionOptions`.  Only requested settings are read back; the rest are `None`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AppliedConnectionOptions {
+    pub requested: ConnectionOptions,
+
+    /// `None` if not requested, or if SQLite reported a mode we don't recognize.
+    pub journal_mode: Option<JournalMode>,
+    pub synchronous: Option<Synchronous>,
+    pub cache_size: Option<i64>,
+    pub mmap_size: Option<i64>,
+}
+
+impl AppliedConnectionOptions {
+    /// True if every requested setting took effect.  This can be false even though SQLite
+    /// reported no error: for example, WAL is unavailable for in-memory stores and on some
+    /// filesystems, and `mmap_size` is capped at compile time.
+    pub fn took_effect(&self) -> bool {
+        self.requested.journal_mode == self.journal_mode &&
+        self.requested.synchronous == self.synchronous &&
+        self.requested.cache_size == self.cache_size &&
+        self.requested.mmap_size == self.mmap_size
+    }
+}
+
+/// Like `new_connection`, but also applying `options`, and reporting which of them took effect.
+pub fn new_connection_with_options<T>(uri: T, options: &ConnectionOptions) -> rusqlite::Result<(rusqlite::Connection, AppliedConnectionOptions)> where T: AsRef<Path> {
+    let conn = make_connection(uri.as_ref(), None)?;
+
+    let mut applied = AppliedConnectionOptions {
+        requested: options.clone(),
+        ..Default::default()
+    };
+
+    // These pragmas can't take bound parameters, but every value interpolated here is either a
+    // fixed string or a number.
+    if let Some(journal_mode) = options.journal_mode {
+        // Setting the journal mode returns the resulting mode.
+        let mode: String = conn.query_row(&format!("PRAGMA journal_mode={}", journal_mode.as_str()), &[], |row| row.get(0))?;
+        applied.journal_mode = JournalMode::from_str(&mode);
+    }
+    if let Some(synchronous) = options.synchronous {
+        conn.execute_batch(&format!("PRAGMA synchronous={}", synchronous.to_i64()))?;
+        let level: i64 = conn.query_row("PRAGMA synchronous", &[], |row| row.get(0))?;
+        applied.synchronous = Synchronous::from_i64(level);
+    }
+    if let Some(cache_size) = options.cache_size {
+        conn.execute_batch(&format!("PRAGMA cache_size={}", cache_size))?;
+        applied.cache_size = Some(conn.query_row("PRAGMA cache_size", &[], |row| row.get(0))?);
+    }
+    if let Some(mmap_size) = options.mmap_size {
+        // Setting the mmap size returns the resulting size, unless memory-mapping was compiled out.
+        applied.mmap_size = match conn.query_row(&format!("PRAGMA mmap_size={}", mmap_size), &[], |row| row.get(0)) {
+            Ok(size) => Some(size),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e),
+        };
+    }
+
+    Ok((conn, applied))
+}
+
+/// Open the existing SQLite store at `uri` strictly read-only.  Unlike `new_connection`, this
+/// doesn't change the journal mode or anything else persistent about the store.
+pub fn new_read_only_connection<T>(uri: T) -> rusqlite::Result<rusqlite::Connection> where T: AsRef<Path> {
+    let conn = rusqlite::Connection::open_with_flags(uri, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.execute_batch("
+        PRAGMA foreign_keys=ON;
+        PRAGMA temp_store=2;
+    ")?;
+    Ok(conn)
+}
+
+/// SQLCipher reports a wrong key, or an unencrypted store, as `SQLITE_NOTADB`.
+#[cfg(feature = "sqlcipher")]
+fn bad_key_error(e: rusqlite::Error) -> ::errors::Error {
+    match e {
+        rusqlite::Error::SqliteFailure(ref f, _) if f.code == rusqlite::ErrorCode::NotADatabase => {
+            ErrorKind::BadEncryptionKey.into()
+        },
+        e => e.into(),
+    }
+}
+
+/// Like `new_connection`, but for a store encrypted at rest with SQLCipher, using `encryption_key`.
+///
+/// The returned connection can be used with `Conn::connect` as usual.  If the key is wrong, or the
+/// store at `uri` exists but isn't encrypted, this fails with `ErrorKind::BadEncryptionKey`.
+#[cfg(feature = "sqlcipher")]
+pub fn new_connection_with_key<P, S>(uri: P, encryption_key: S) -> Result<rusqlite::Connection>
+where P: AsRef<Path>, S: AsRef<str> {
+    let conn = make_connection(uri.as_ref(), Some(encryption_key.as_ref())).map_err(bad_key_error)?;
+
+    // SQLCipher only notices a bad key once it reads the database.  Do so now, rather than
+    // failing confusingly later.
+    conn.query_row("SELECT count(*) FROM sqlite_master", &[], |_| ()).map_err(bad_key_error)?;
+
+    Ok(conn)
+}
+
+/// Re-encrypt the SQLCipher store behind `conn` with `new_encryption_key`.  Subsequent
+/// connections must use the new key.
+#[cfg(feature = "sqlcipher")]
+pub fn change_encryption_key<S>(conn: &rusqlite::Connection, new_encryption_key: S) -> Result<()>
+where S: AsRef<str> {
+    conn.execute_batch(&format!("PRAGMA rekey = '{}';", escape_string_for_pragma(new_encryption_key.as_ref())))?;
+    Ok(())
+}
+
 /// Version history:
 ///
 /// 1: initial Rust Mentat schema.
@@ -351,10 +584,34 @@ pub fn ensure_current_version(conn: &mut rusqlite::Connection) -> Result<DB> {
     }
 }
 
+/// Like `ensure_current_version`, but never writes: a store that would need to be created or
+/// migrated is rejected with `ErrorKind::CannotMigrateReadOnlyStore`.
+pub fn ensure_current_version_read_only(conn: &rusqlite::Connection) -> Result<DB> {
+    if rusqlite::version_number() < MIN_SQLITE_VERSION {
+        panic!("Mentat requires at least sqlite {}", MIN_SQLITE_VERSION);
+    }
+
+    match get_user_version(conn)? {
+        CURRENT_VERSION => read_db(conn),
+        v => bail!(ErrorKind::CannotMigrateReadOnlyStore(v, CURRENT_VERSION)),
+    }
+}
+
+/// The SQL expression for the value of a row of `datoms` or `transactions`, in the form
+/// `TypedValue::from_sql_value_pair` expects.  Fulltext values are stored as references into
+/// `fulltext_values`, so for a fulltext attribute this looks up the string.
+pub fn datom_value_sql(fulltext: bool) -> &'static str {
+    if fulltext {
+        "(SELECT text FROM fulltext_values WHERE rowid = v)"
+    } else {
+        "v"
+    }
+}
+
 pub trait TypedSQLValue {
     fn from_sql_value_pair(value: rusqlite::types::Value, value_type_tag: i32) -> Result<TypedValue>;
     fn to_sql_value_pair<'a>(&'a self) -> (ToSqlOutput<'a>, i32);
-    fn from_edn_value(value: &Value) -> Option<TypedValue>;
+    fn from_untyped_edn_value(value: &Value) -> Option<TypedValue>;
     fn to_edn_value_pair(&self) -> (Value, ValueType);
 }
 
@@ -372,7 +629,7 @@ impl TypedSQLValue for TypedValue {
             // share a tag.
             (5, rusqlite::types::Value::Integer(x)) => Ok(TypedValue::Long(x)),
             (5, rusqlite::types::Value::Real(x)) => Ok(TypedValue::Double(x.into())),
-            (10, rusqlite::types::Value::Text(x)) => Ok(TypedValue::String(Rc::new(x))),
+            (10, rusqlite::types::Value::Text(x)) => Ok(TypedValue::String(Arc::new(x))),
             (11, rusqlite::types::Value::Blob(x)) => {
                 let u = Uuid::from_bytes(x.as_slice());
                 if u.is_err() {
@@ -383,7 +640,7 @@ impl TypedSQLValue for TypedValue {
                 Ok(TypedValue::Uuid(u.unwrap()))
             },
             (13, rusqlite::types::Value::Text(x)) => {
-                to_namespaced_keyword(&x).map(|k| TypedValue::Keyword(Rc::new(k)))
+                to_namespaced_keyword(&x).map(|k| TypedValue::Keyword(Arc::new(k)))
             },
             (_, value) => bail!(ErrorKind::BadSQLValuePair(value, value_type_tag)),
         }
@@ -392,19 +649,20 @@ impl TypedSQLValue for TypedValue {
     /// Given an EDN `value`, return a corresponding Mentat `TypedValue`.
     ///
     /// An EDN `Value` does not encode a unique Mentat `ValueType`, so the composition
-    /// `from_edn_value(first(to_edn_value_pair(...)))` loses information.  Additionally, there are
-    /// EDN values which are not Mentat typed values.
+    /// `from_untyped_edn_value(first(to_edn_value_pair(...)))` loses information.  Additionally,
+    /// there are EDN values which are not Mentat typed values.  Prefer
+    /// `TypedValue::from_edn_value` when the expected value type is known.
     ///
     /// This function is deterministic.
-    fn from_edn_value(value: &Value) -> Option<TypedValue> {
+    fn from_untyped_edn_value(value: &Value) -> Option<TypedValue> {
         match value {
             &Value::Boolean(x) => Some(TypedValue::Boolean(x)),
             &Value::Instant(x) => Some(TypedValue::Instant(x)),
             &Value::Integer(x) => Some(TypedValue::Long(x)),
             &Value::Uuid(x) => Some(TypedValue::Uuid(x)),
             &Value::Float(ref x) => Some(TypedValue::Double(x.clone())),
-            &Value::Text(ref x) => Some(TypedValue::String(Rc::new(x.clone()))),
-            &Value::NamespacedKeyword(ref x) => Some(TypedValue::Keyword(Rc::new(x.clone()))),
+            &Value::Text(ref x) => Some(TypedValue::String(Arc::new(x.clone()))),
+            &Value::NamespacedKeyword(ref x) => Some(TypedValue::Keyword(Arc::new(x.clone()))),
             _ => None
         }
     }
@@ -496,6 +754,127 @@ pub fn read_db(conn: &rusqlite::Connection) -> Result<DB> {
     Ok(DB::new(partition_map, schema))
 }
 
+/// Feed a length-prefixed byte string into `hasher`, so that adjacent fields can't run together.
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hash_i64(hasher, bytes.len() as i64);
+    hasher.input(bytes);
+}
+
+fn hash_i64(hasher: &mut Sha256, x: i64) {
+    let mut buf = [0u8; 8];
+    for i in 0..8 {
+        buf[i] = (x >> (56 - 8 * i)) as u8;
+    }
+    hasher.input(&buf);
+}
+
+/// Compute a SHA-256 hash over the datoms in the given SQL store.
+///
+/// Datoms are visited in (e, a, value_type_tag, v, tx) order, just like `debug::datoms_after`, and
+/// datoms of the form [... :db/txInstant ...] are skipped: they record wall-clock time, so they
+/// would make equal stores hash differently.  Fulltext values are hashed by their text rather than
+/// by their `fulltext_values` rowid, which depends on insertion order.
+///
+/// Two stores with the same datoms produce the same hash, no matter the order in which the datoms
+/// were inserted.
+pub fn state_hash(conn: &rusqlite::Connection) -> Result<[u8; 32]> {
+    let mut stmt: rusqlite::Statement = conn.prepare(
+        "SELECT d.e, d.a, d.value_type_tag, CASE WHEN d.index_fulltext THEN f.text ELSE d.v END AS value, d.tx \
+         FROM datoms AS d LEFT JOIN fulltext_values AS f ON d.index_fulltext AND f.rowid = d.v \
+         WHERE d.a != ? \
+         ORDER BY d.e ASC, d.a ASC, d.value_type_tag ASC, value ASC, d.tx ASC")?;
+
+    let mut hasher = Sha256::default();
+    let mut rows = stmt.query(&[&entids::DB_TX_INSTANT])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let e: i64 = row.get_checked(0)?;
+        let a: i64 = row.get_checked(1)?;
+        let value_type_tag: i64 = row.get_checked(2)?;
+        let v: rusqlite::types::Value = row.get_checked(3)?;
+        let tx: i64 = row.get_checked(4)?;
+
+        hash_i64(&mut hasher, e);
+        hash_i64(&mut hasher, a);
+        hash_i64(&mut hasher, value_type_tag);
+        match v {
+            rusqlite::types::Value::Null => hasher.input(&[0u8]),
+            rusqlite::types::Value::Integer(x) => {
+                hasher.input(&[1u8]);
+                hash_i64(&mut hasher, x);
+            },
+            rusqlite::types::Value::Real(x) => {
+                hasher.input(&[2u8]);
+                hash_i64(&mut hasher, x.to_bits() as i64);
+            },
+            rusqlite::types::Value::Text(x) => {
+                hasher.input(&[3u8]);
+                hash_bytes(&mut hasher, x.as_bytes());
+            },
+            rusqlite::types::Value::Blob(x) => {
+                hasher.input(&[4u8]);
+                hash_bytes(&mut hasher, &x[..]);
+            },
+        }
+        hash_i64(&mut hasher, tx);
+    }
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.result()[..]);
+    Ok(hash)
+}
+
+/// Remove retracted datoms from the `transactions` log, together with the assertions they
+/// retracted, when the retraction was made in a transaction before `before_tx`.  Returns the number
+/// of rows removed.
+///
+/// Only history is forgotten: the `datoms` table, and hence the current state of the store, is
+/// untouched.  `transactions_after` no longer reports the removed datoms, so a compacted log can't
+/// be used to replay the store's full history.
+///
+/// This doesn't reclaim disk space; follow with `VACUUM` for that.  Fulltext strings that are no
+/// longer referenced are left behind for `collect_fulltext_garbage`.
+pub fn compact(conn: &rusqlite::Connection, before_tx: Entid) -> Result<usize> {
+    // An assertion is forgotten if the same datom was later retracted before `before_tx`.  An
+    // assertion that was retracted and then re-asserted keeps its most recent assertion.  Remove
+    // assertions first, since they're identified by the retractions that follow them.
+    let assertions = conn.execute(r#"
+      DELETE FROM transactions
+      WHERE added IS NOT 0 AND
+            EXISTS (SELECT 1 FROM transactions AS retracted
+                    WHERE retracted.added IS 0 AND
+                          retracted.tx > transactions.tx AND
+                          retracted.tx < ? AND
+                          retracted.e = transactions.e AND
+                          retracted.a = transactions.a AND
+                          retracted.v = transactions.v AND
+                          retracted.value_type_tag = transactions.value_type_tag)"#,
+                                  &[&before_tx])
+        .chain_err(|| "Could not remove retracted assertions")?;
+
+    let retractions = conn.execute("DELETE FROM transactions WHERE added IS 0 AND tx < ?", &[&before_tx])
+        .chain_err(|| "Could not remove retractions")?;
+
+    Ok(assertions + retractions)
+}
+
+/// Delete rows of `fulltext_values` that are referenced by neither a datom nor the transaction
+/// log, such as strings that were retracted and whose history was then forgotten by `compact`.
+/// Returns the number of rows deleted.
+pub fn collect_fulltext_garbage(conn: &rusqlite::Connection, schema: &Schema) -> Result<usize> {
+    // The `transactions` table doesn't record which datoms are fulltext indexed, so we use the
+    // schema to find historical references.
+    let fulltext_attributes = attribute_list(schema, |attribute| attribute.fulltext);
+
+    let s = format!(r#"
+      DELETE FROM fulltext_values
+      WHERE rowid NOT IN (SELECT v FROM datoms WHERE index_fulltext IS NOT 0) AND
+            rowid NOT IN (SELECT v FROM transactions WHERE a IN ({}))"#, fulltext_attributes);
+
+    conn.execute(s.as_str(), &[])
+        .chain_err(|| "Could not collect fulltext garbage")
+}
+
 /// Internal representation of an [e a v added] datom, ready to be transacted against the store.
 pub type ReducedEntity<'a> = (Entid, Entid, &'a Attribute, TypedValue, bool);
 
@@ -532,6 +911,13 @@ pub trait MentatStoring {
     fn insert_non_fts_searches<'a>(&self, entities: &'a [ReducedEntity], search_type: SearchType) -> Result<()>;
     fn insert_fts_searches<'a>(&self, entities: &'a [ReducedEntity], search_type: SearchType) -> Result<()>;
 
+    /// Write assertions about entities allocated by the transaction `tx_id` directly to the
+    /// transaction log and the `datoms` table, bypassing the temporary search tables.
+    ///
+    /// Such entities can't have any datoms yet, so there's nothing to search for: every assertion
+    /// is new, and every retraction is a no-op.  The caller must not pass :db.fulltext assertions.
+    fn insert_new_entity_datoms<'a>(&self, entities: &'a [ReducedEntity], tx_id: Entid) -> Result<()>;
+
     /// Finalize the underlying storage layer after a Mentat transaction.
     ///
     /// Use this to finalize temporary tables, complete indices, revert pragmas, etc, after the
@@ -540,6 +926,11 @@ pub trait MentatStoring {
 
     /// Extract metadata-related [e a typed_value added] datoms committed in the given transaction.
     fn committed_metadata_assertions(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>>;
+
+    /// Forget the history of the given `:db/noHistory` attributes as changed by the given
+    /// transaction: each value retracted (or replaced) by the transaction is removed from the
+    /// transaction log, together with the earlier assertions of it.
+    fn remove_superseded_history(&self, tx_id: Entid, attributes: &BTreeSet<Entid>) -> Result<()>;
 }
 
 /// Take search rows and complete `temp.search_results`.
@@ -837,6 +1228,71 @@ impl MentatStoring for rusqlite::Connection {
         results.map(|_| ())
     }
 
+    /// Insert the datoms of new entities directly, as many rows per statement as SQLite allows.
+    /// The indices on `datoms`, including the unique indices that enforce :db/unique and forbid
+    /// transacting the same [e a v] twice, are maintained as usual.
+    fn insert_new_entity_datoms<'a>(&self, entities: &'a [ReducedEntity<'a>], tx_id: Entid) -> Result<()> {
+        let bindings_per_transaction = 6;
+        let bindings_per_datom = 9;
+
+        // Rows of `datoms` need the most bindings, so they determine the chunk size.
+        let max_vars = self.limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER) as usize;
+        // There's nothing to retract.
+        let added = entities.into_iter().filter(|&&(_, _, _, _, added)| added);
+        let chunks: itertools::IntoChunks<_> = added.chunks(max_vars / bindings_per_datom);
+        let tx_id = &tx_id;
+
+        let results: Result<Vec<()>> = chunks.into_iter().map(|chunk| -> Result<()> {
+            // (e, a, v, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value)
+            let block: Vec<_> = chunk.map(|&(e, a, ref attribute, ref typed_value, _)| {
+                let (value, value_type_tag): (ToSqlOutput, i32) = typed_value.to_sql_value_pair();
+                let flags = attribute.flags();
+                (e, a, value, value_type_tag,
+                 flags & AttributeBitFlags::IndexAVET as u8 != 0,
+                 flags & AttributeBitFlags::IndexVAET as u8 != 0,
+                 flags & AttributeBitFlags::IndexFulltext as u8 != 0,
+                 flags & AttributeBitFlags::UniqueValue as u8 != 0)
+            }).collect();
+            let count = block.len();
+
+            // `params` reference computed values in `block`.
+            let params: Vec<&ToSql> = block.iter().flat_map(|&(ref e, ref a, ref value, ref value_type_tag, _, _, _, _)| {
+                once(e as &ToSql)
+                    .chain(once(a as &ToSql)
+                           .chain(once(value as &ToSql)
+                                  .chain(once(tx_id as &ToSql)
+                                         .chain(once(to_bool_ref(true) as &ToSql)
+                                                .chain(once(value_type_tag as &ToSql))))))
+            }).collect();
+            let s = format!("INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES {}",
+                            repeat_values(bindings_per_transaction, count));
+            let mut stmt = self.prepare_cached(s.as_str())?;
+            stmt.execute(&params)
+                .map(|_c| ())
+                .chain_err(|| "Could not insert transaction: failed to add datoms of new entities")?;
+
+            let params: Vec<&ToSql> = block.iter().flat_map(|&(ref e, ref a, ref value, ref value_type_tag, ref index_avet, ref index_vaet, ref index_fulltext, ref unique_value)| {
+                once(e as &ToSql)
+                    .chain(once(a as &ToSql)
+                           .chain(once(value as &ToSql)
+                                  .chain(once(tx_id as &ToSql)
+                                         .chain(once(value_type_tag as &ToSql)
+                                                .chain(once(index_avet as &ToSql)
+                                                       .chain(once(index_vaet as &ToSql)
+                                                              .chain(once(index_fulltext as &ToSql)
+                                                                     .chain(once(unique_value as &ToSql)))))))))
+            }).collect();
+            let s = format!("INSERT INTO datoms (e, a, v, tx, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value) VALUES {}",
+                            repeat_values(bindings_per_datom, count));
+            let mut stmt = self.prepare_cached(s.as_str())?;
+            stmt.execute(&params)
+                .map(|_c| ())
+                .chain_err(|| "Could not update datoms: failed to add datoms of new entities")
+        }).collect();
+
+        results.map(|_| ())
+    }
+
     /// Insert search rows into temporary search tables.
     ///
     /// Eventually, the details of this approach will be captured in
@@ -942,6 +1398,25 @@ impl MentatStoring for rusqlite::Connection {
         Ok(())
     }
 
+    fn remove_superseded_history(&self, tx_id: Entid, attributes: &BTreeSet<Entid>) -> Result<()> {
+        // Attributes are entids, so it's safe to interpolate them directly.
+        let s = format!(r#"
+          DELETE FROM transactions
+          WHERE a IN ({}) AND (added IS 0 OR tx < ?) AND
+                EXISTS (SELECT 1 FROM transactions AS retracted
+                        WHERE retracted.tx = ? AND retracted.added IS 0 AND
+                              retracted.e = transactions.e AND
+                              retracted.a = transactions.a AND
+                              retracted.v = transactions.v AND
+                              retracted.value_type_tag = transactions.value_type_tag)"#,
+                        attributes.iter().join(", "));
+
+        let mut stmt = self.prepare_cached(s.as_str())?;
+        stmt.execute(&[&tx_id, &tx_id])
+            .map(|_c| ())
+            .chain_err(|| "Could not remove superseded history")
+    }
+
     fn committed_metadata_assertions(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>> {
         // TODO: use concat! to avoid creating String instances.
         let mut stmt = self.prepare_cached(format!("SELECT e, a, v, value_type_tag, added FROM transactions WHERE tx = ? AND a IN {} ORDER BY e, a, v, value_type_tag, added", entids::METADATA_SQL_LIST.as_str()).as_str())?;
@@ -1070,33 +1545,50 @@ SELECT EXISTS
 }
 
 pub trait PartitionMapping {
-    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> i64 where String: Borrow<S>;
-    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Range<i64> where String: Borrow<S>;
+    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> Result<i64> where String: Borrow<S>;
+    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Result<Range<i64>> where String: Borrow<S>;
     fn contains_entid(&self, entid: Entid) -> bool;
+    fn within_partition_ranges(&self, entid: Entid) -> bool;
 }
 
 impl PartitionMapping for PartitionMap {
     /// Allocate a single fresh entid in the given `partition`.
-    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> i64 where String: Borrow<S> {
-        self.allocate_entids(partition, 1).start
+    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> Result<i64> where String: Borrow<S> {
+        Ok(self.allocate_entids(partition, 1)?.start)
     }
 
-    /// Allocate `n` fresh entids in the given `partition`.
-    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Range<i64> where String: Borrow<S> {
-        match self.get_mut(partition) {
-            Some(partition) => {
-                let idx = partition.index;
-                partition.index += n as i64;
-                idx..partition.index
-            },
+    /// Allocate `n` fresh entids in the given `partition`, failing with
+    /// `ErrorKind::PartitionExhausted` if they would reach the start of the next partition.
+    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Result<Range<i64>> where String: Borrow<S> {
+        let end = match self.get(partition) {
+            Some(p) => partition_end(self, p.start),
             // This is a programming error.
             None => panic!("Cannot allocate entid from unknown partition: {}", partition),
+        };
+        let p = self.get_mut(partition).expect("partition exists");
+        if end - p.index < n as i64 {
+            bail!(ErrorKind::PartitionExhausted(partition.to_string()));
         }
+        let idx = p.index;
+        p.index += n as i64;
+        Ok(idx..p.index)
     }
 
     fn contains_entid(&self, entid: Entid) -> bool {
         self.values().any(|partition| partition.contains_entid(entid))
     }
+
+    /// Whether `entid` is at or above the start of the lowest partition, and below the next
+    /// entid to be allocated in the highest.  An entid outside this span can't belong to any
+    /// partition, allocated or not.
+    fn within_partition_ranges(&self, entid: Entid) -> bool {
+        let lowest = self.values().map(|partition| partition.start).min();
+        let highest = self.values().map(|partition| partition.index).max();
+        match (lowest, highest) {
+            (Some(lowest), Some(highest)) => lowest <= entid && entid < highest,
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1107,13 +1599,17 @@ mod tests {
     use edn;
     use mentat_core::{
         attribute,
+        KnownEntid,
     };
     use mentat_tx_parser;
     use rusqlite;
     use std::collections::{
         BTreeMap,
     };
-    use types::TxReport;
+    use types::{
+        PartitionName,
+        TxReport,
+    };
 
     // Macro to parse a `Borrow<str>` to an `edn::Value` and assert the given `edn::Value` `matches`
     // against it.
@@ -1137,7 +1633,9 @@ mod tests {
     // This unwraps safely and makes asserting errors pleasant.
     macro_rules! assert_transact {
         ( $conn: expr, $input: expr, $expected: expr ) => {{
-            let result = $conn.transact($input).map_err(|e| e.to_string());
+            // Compare the root cause: an entity that can't be transacted fails with
+            // `InvalidEntity`, chained to what was wrong with it.
+            let result = $conn.transact($input).map_err(|e| e.iter().last().unwrap().to_string());
             assert_eq!(result, $expected.map_err(|e| e.to_string()));
         }};
         ( $conn: expr, $input: expr ) => {{
@@ -1192,7 +1690,7 @@ mod tests {
         }
 
         fn last_tx_id(&self) -> Entid {
-            self.partition_map.get(&":db.part/tx".to_string()).unwrap().index - 1
+            self.partition_map[PartitionName::Tx.as_str()].index - 1
         }
 
         fn last_transaction(&self) -> edn::Value {
@@ -1252,6 +1750,27 @@ mod tests {
         edn::Value::Map(map)
     }
 
+    #[test]
+    fn test_datoms_to_pretty_string() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :name/Ivan]
+                                 [:db/add 101 :db/ident :name/Petr]
+                                 [:db/add 100 :db/doc \"the first\"]]");
+
+        let datoms = debug::datoms_after(&conn.sqlite, &conn.schema, bootstrap::TX0).expect("datoms");
+        assert_eq!(datoms.to_pretty_string(),
+                   "[[100  :db/ident  :name/Ivan]\n \
+                     [100  :db/doc    \"the first\"]\n \
+                     [101  :db/ident  :name/Petr]]\n");
+
+        // It's the same EDN, just laid out differently.
+        assert_eq!(edn::parse::value(&datoms.to_pretty_string()).expect("EDN").without_spans(), datoms.into_edn());
+
+        let none = debug::datoms_after(&conn.sqlite, &conn.schema, conn.last_tx_id()).expect("datoms");
+        assert_eq!(none.to_pretty_string(), "[]");
+    }
+
     #[test]
     fn test_add() {
         let mut conn = TestConn::default();
@@ -1478,6 +1997,31 @@ mod tests {
         //                   [?tx :db/txInstant ?ms ?tx true]]");
     }
 
+    #[test]
+    fn test_tx_report_resolved() {
+        use mentat_tx::entities::TempId;
+
+        let mut conn = TestConn::default();
+
+        let alice = TempId::external("alice");
+        let bob = TempId::external("bob");
+        let report = assert_transact!(conn, "[[:db/add \"alice\" :db/ident :name/Alice]
+                                              [:db/add \"bob\" :db/ident :name/Bob]]");
+
+        assert_eq!(report.resolved(&alice), report.tempids.get("alice").cloned().map(KnownEntid));
+        assert_eq!(report.resolved(&bob), report.tempids.get("bob").cloned().map(KnownEntid));
+        assert!(report.resolved(&alice).is_some());
+        assert!(report.resolved(&alice) != report.resolved(&bob));
+
+        // Tempids that weren't mentioned, and internal tempids, don't resolve.
+        assert_eq!(report.resolved(&TempId::external("carol")), None);
+        assert_eq!(report.resolved(&TempId::Internal(0)), None);
+
+        // Upserted tempids resolve to the existing entid.
+        let upserted = assert_transact!(conn, "[[:db/add \"a\" :db/ident :name/Alice]]");
+        assert_eq!(upserted.resolved(&TempId::external("a")), report.resolved(&alice));
+    }
+
     #[test]
     fn test_sqlite_limit() {
         let conn = new_connection("").expect("Couldn't open in-memory db");
@@ -2269,4 +2813,218 @@ mod tests {
                          "[{:test/_dangling 1.23}]",
                          Err("EDN value \'1.23\' is not the expected Mentat value type Ref"));
     }
+
+    #[test]
+    fn test_no_history() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/cursor]
+                                 [:db/add 100 :db/valueType :db.type/long]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]
+                                 [:db/add 100 :db/noHistory true]
+                                 [:db/add 101 :db/ident :test/normal]
+                                 [:db/add 101 :db/valueType :db.type/long]
+                                 [:db/add 101 :db/cardinality :db.cardinality/one]]");
+        assert!(conn.schema.attribute_for_entid(100).unwrap().no_history);
+        assert!(!conn.schema.attribute_for_entid(101).unwrap().no_history);
+        let schema_tx = conn.last_tx_id();
+
+        assert_transact!(conn, "[[:db/add 200 :test/cursor 1] [:db/add 200 :test/normal 1]]");
+        assert_transact!(conn, "[[:db/add 200 :test/cursor 2] [:db/add 200 :test/normal 2]]");
+        assert_transact!(conn, "[[:db/add 200 :test/cursor 3] [:db/add 200 :test/normal 3]]");
+
+        // Only the latest value of :test/cursor remains in the log; :test/normal keeps its history.
+        let transactions = debug::transactions_after(&conn.sqlite, &conn.schema, schema_tx).expect("transactions");
+        assert_matches!(transactions.into_edn(),
+                        "[[[200 :test/normal 1 ?tx1 true]
+                           [?tx1 :db/txInstant ?ms1 ?tx1 true]]
+                          [[200 :test/normal 1 ?tx2 false]
+                           [200 :test/normal 2 ?tx2 true]
+                           [?tx2 :db/txInstant ?ms2 ?tx2 true]]
+                          [[200 :test/cursor 3 ?tx3 true]
+                           [200 :test/normal 2 ?tx3 false]
+                           [200 :test/normal 3 ?tx3 true]
+                           [?tx3 :db/txInstant ?ms3 ?tx3 true]]]");
+        assert_matches!(conn.datoms(),
+                        "[[200 :test/cursor 3]
+                          [200 :test/normal 3]]");
+
+        // An explicit retraction leaves no trace either.
+        assert_transact!(conn, "[[:db/retract 200 :test/cursor 3]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[?tx :db/txInstant ?ms ?tx true]]");
+        let transactions = debug::transactions_after(&conn.sqlite, &conn.schema, schema_tx).expect("transactions");
+        assert_matches!(transactions.into_edn(),
+                        "[[[200 :test/normal 1 ?tx1 true]
+                           [?tx1 :db/txInstant ?ms1 ?tx1 true]]
+                          [[200 :test/normal 1 ?tx2 false]
+                           [200 :test/normal 2 ?tx2 true]
+                           [?tx2 :db/txInstant ?ms2 ?tx2 true]]
+                          [[200 :test/normal 2 ?tx3 false]
+                           [200 :test/normal 3 ?tx3 true]
+                           [?tx3 :db/txInstant ?ms3 ?tx3 true]]
+                          [[?tx4 :db/txInstant ?ms4 ?tx4 true]]]");
+    }
+
+    #[test]
+    fn test_datoms_for_entity() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/name]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]
+                                 [:db/add 101 :db/ident :test/tag]
+                                 [:db/add 101 :db/valueType :db.type/ref]
+                                 [:db/add 101 :db/cardinality :db.cardinality/many]
+                                 [:db/add 102 :db/ident :test/red]]");
+        assert_transact!(conn, "[[:db/add 200 :test/name \"x\"]
+                                 [:db/add 200 :test/tag 300]
+                                 [:db/add 200 :test/tag 102]
+                                 [:db/add 201 :test/name \"y\"]]");
+
+        // Ordered by attribute, with refs to idents rendered as keywords.
+        let datoms = debug::datoms_for_entity(&conn.sqlite, &conn.schema, 200).expect("datoms");
+        assert_eq!(datoms.len(), 3);
+        assert_matches!(datoms.into_edn(),
+                        "[[200 :test/name \"x\"]
+                          [200 :test/tag :test/red]
+                          [200 :test/tag 300]]");
+        assert_eq!(debug::entity_to_edn(&conn.schema, &datoms).expect("rendered"),
+                   edn::parse::value("{:test/name \"x\" :test/tag [:test/red 300]}").expect("to parse EDN").without_spans());
+
+        // An entity we know nothing about has no datoms.
+        let datoms = debug::datoms_for_entity(&conn.sqlite, &conn.schema, 999).expect("datoms");
+        assert!(datoms.is_empty());
+        assert_eq!(debug::entity_to_edn(&conn.schema, &datoms).expect("rendered"),
+                   edn::Value::Map(Default::default()));
+    }
+
+    #[test]
+    fn test_datoms_matching() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/name]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]
+                                 [:db/add 101 :db/ident :test/age]
+                                 [:db/add 101 :db/valueType :db.type/long]
+                                 [:db/add 101 :db/cardinality :db.cardinality/one]]");
+        let schema_tx = conn.last_tx_id();
+        assert_transact!(conn, "[[:db/add 200 :test/name \"x\"]
+                                 [:db/add 200 :test/age 10]
+                                 [:db/add 201 :test/name \"y\"]]");
+        let tx = conn.last_tx_id();
+
+        let matching = |filter: debug::DatomFilter| {
+            debug::datoms_matching(&conn.sqlite, &conn.schema, filter).expect("datoms")
+        };
+
+        // The default filter is the same as `datoms`.
+        assert_eq!(matching(debug::DatomFilter::default()).into_edn(),
+                   debug::datoms(&conn.sqlite, &conn.schema).expect("datoms").into_edn());
+
+        // By transaction.
+        assert_matches!(matching(debug::DatomFilter { after_tx: schema_tx, ..Default::default() }).into_edn(),
+                        "[[200 :test/name \"x\"]
+                          [200 :test/age 10]
+                          [201 :test/name \"y\"]]");
+
+        // Opting in to :db/txInstant.
+        assert_matches!(matching(debug::DatomFilter { after_tx: schema_tx, include_tx_instant: true, ..Default::default() }).into_edn(),
+                        "[[200 :test/name \"x\"]
+                          [200 :test/age 10]
+                          [201 :test/name \"y\"]
+                          [?tx :db/txInstant ?ms]]");
+
+        // By attribute.
+        assert_matches!(matching(debug::DatomFilter { attribute: Some(100), ..Default::default() }).into_edn(),
+                        "[[200 :test/name \"x\"]
+                          [201 :test/name \"y\"]]");
+
+        // By entity.
+        assert_matches!(matching(debug::DatomFilter { entity: Some(200), ..Default::default() }).into_edn(),
+                        "[[200 :test/name \"x\"]
+                          [200 :test/age 10]]");
+
+        // Combined.
+        assert_matches!(matching(debug::DatomFilter { attribute: Some(101), entity: Some(200), ..Default::default() }).into_edn(),
+                        "[[200 :test/age 10]]");
+        assert_matches!(matching(debug::DatomFilter { after_tx: schema_tx, include_tx_instant: true, attribute: Some(entids::DB_TX_INSTANT), entity: Some(tx) }).into_edn(),
+                        "[[?tx :db/txInstant ?ms]]");
+        assert!(matching(debug::DatomFilter { after_tx: tx, ..Default::default() }).is_empty());
+        assert!(matching(debug::DatomFilter { attribute: Some(101), entity: Some(201), ..Default::default() }).is_empty());
+    }
+
+    #[test]
+    fn test_value_type_mismatch() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 111 :db/ident :test/long]
+                                 [:db/add 111 :db/valueType :db.type/long]
+                                 [:db/add 111 :db/cardinality :db.cardinality/one]]");
+
+        // The failure names the offending entity by index, and is caused by the mismatch.
+        let err = conn.transact("[[:db/add 200 :test/long :test/keyword]]").unwrap_err();
+        match err {
+            ::errors::Error(::errors::ErrorKind::InvalidEntity(0), _) => {},
+            ref x => panic!("expected InvalidEntity, got {:?}", x),
+        }
+        match err.1.next_error.as_ref().and_then(|e| e.downcast_ref::<::errors::Error>()) {
+            Some(&::errors::Error(::errors::ErrorKind::ValueTypeMismatch(ref attribute, ref expected, ref got), _)) => {
+                assert_eq!(attribute, ":test/long");
+                assert_eq!(expected, &ValueType::Long);
+                assert_eq!(got, &edn::Value::NamespacedKeyword(edn::NamespacedKeyword::new("test", "keyword")));
+            },
+            x => panic!("expected ValueTypeMismatch, got {:?}", x),
+        }
+
+        assert_transact!(conn,
+                         "[[:db/add 200 :test/long \"a string\"]]",
+                         Err("value '\"a string\"' for attribute :test/long is not the expected Mentat value type Long"));
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_sqlcipher_keys() {
+        let path = debug::TempStorePath::new("sqlcipher");
+
+        let is_bad_key = |result: Result<rusqlite::Connection>| match result {
+            Err(::errors::Error(::errors::ErrorKind::BadEncryptionKey, _)) => true,
+            _ => false,
+        };
+
+        {
+            let mut conn = new_connection_with_key(&path, "secret").expect("created");
+            ensure_current_version(&mut conn).expect("bootstrapped");
+        }
+
+        // The right key loads the schema.
+        {
+            let conn = new_connection_with_key(&path, "secret").expect("opened");
+            let db = read_db(&conn).expect("read");
+            assert!(db.schema.get_entid(&to_namespaced_keyword(":db/ident").unwrap()).is_some());
+
+            change_encryption_key(&conn, "it's a new secret").expect("rekeyed");
+        }
+
+        // The old key, a wrong key, and no key at all are refused.
+        assert!(is_bad_key(new_connection_with_key(&path, "secret")));
+        assert!(is_bad_key(new_connection_with_key(&path, "wrong")));
+        assert!(new_connection(&path).and_then(|conn| conn.query_row("SELECT count(*) FROM sqlite_master", &[], |_| ())).is_err());
+
+        // The new key works.
+        {
+            let conn = new_connection_with_key(&path, "it's a new secret").expect("opened");
+            read_db(&conn).expect("read");
+        }
+
+        path.remove();
+
+        // A key doesn't unlock an unencrypted store.
+        {
+            let mut conn = new_connection(&path).expect("created");
+            ensure_current_version(&mut conn).expect("bootstrapped");
+        }
+        assert!(is_bad_key(new_connection_with_key(&path, "secret")));
+    }
 }
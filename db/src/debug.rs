@@ -12,241 +12,290 @@
 
 /// Low-level functions for testing.
 
-use std::borrow::Borrow;
+use std::env;
+use std::fs;
 use std::io::{Write};
-use std::rc::Rc;
+use std::ops::Deref;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process;
 
-use itertools::Itertools;
 use rusqlite;
-use rusqlite::types::{ToSql};
+use rusqlite::types::{
+    ToSql,
+    Value,
+};
 use tabwriter::TabWriter;
 
-use bootstrap;
-use db::TypedSQLValue;
-use edn;
-use entids;
 use errors::Result;
-use mentat_core::{
-    SQLValueType,
-    TypedValue,
-    ValueType,
-};
-use mentat_tx::entities::{Entid};
-use schema::{
-    SchemaBuilding,
+
+pub use dumps::{
+    Datom,
+    DatomFilter,
+    Datoms,
+    FulltextValues,
+    Transactions,
+    datoms,
+    datoms_after,
+    datoms_for_entity,
+    datoms_matching,
+    entity_to_edn,
+    fulltext_values,
+    transactions_after,
 };
-use types::Schema;
-
-/// Represents a *datom* (assertion) in the store.
-#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
-pub struct Datom {
-    // TODO: generalize this.
-    e: Entid,
-    a: Entid,
-    v: edn::Value,
-    tx: i64,
-    added: Option<bool>,
-}
 
-/// Represents a set of datoms (assertions) in the store.
+/// The path of a store file in the temporary directory, for tests that need a store on disk.
 ///
-/// To make comparision easier, we deterministically order.  The ordering is the ascending tuple
-/// ordering determined by `(e, a, (value_type_tag, v), tx)`, where `value_type_tag` is an internal
-/// value that is not exposed but is deterministic.
-pub struct Datoms(pub Vec<Datom>);
+/// The store, and its `-wal` and `-shm` files, are removed when the path is made, in case an
+/// earlier run left them behind, and again when it is dropped, even if the test panics.  Declare it
+/// before any connection to the store, so that it is dropped after them.
+pub struct TempStorePath(PathBuf);
+
+impl TempStorePath {
+    /// A path distinct to `name` and to this process.
+    pub fn new(name: &str) -> TempStorePath {
+        let path = TempStorePath(env::temp_dir().join(format!("mentat-test-{}-{}.db", name, process::id())));
+        path.remove();
+        path
+    }
 
-/// Represents an ordered sequence of transactions in the store.
-///
-/// To make comparision easier, we deterministically order.  The ordering is the ascending tuple
-/// ordering determined by `(e, a, (value_type_tag, v), tx, added)`, where `value_type_tag` is an
-/// internal value that is not exposed but is deterministic, and `added` is ordered such that
-/// retracted assertions appear before added assertions.
-pub struct Transactions(pub Vec<Datoms>);
-
-/// Represents the fulltext values in the store.
-pub struct FulltextValues(pub Vec<(i64, String)>);
-
-impl Datom {
-    pub fn into_edn(&self) -> edn::Value {
-        let f = |entid: &Entid| -> edn::Value {
-            match *entid {
-                Entid::Entid(ref y) => edn::Value::Integer(y.clone()),
-                Entid::Ident(ref y) => edn::Value::NamespacedKeyword(y.clone()),
-            }
-        };
-
-        let mut v = vec![f(&self.e), f(&self.a), self.v.clone()];
-        if let Some(added) = self.added {
-            v.push(edn::Value::Integer(self.tx));
-            v.push(edn::Value::Boolean(added));
+    /// Remove the store and its `-wal` and `-shm` files, if they exist.
+    pub fn remove(&self) {
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", self.0.display(), suffix));
         }
-
-        edn::Value::Vector(v)
     }
 }
 
-impl Datoms {
-    pub fn into_edn(&self) -> edn::Value {
-        edn::Value::Vector((&self.0).into_iter().map(|x| x.into_edn()).collect())
-    }
-}
+impl Deref for TempStorePath {
+    type Target = Path;
 
-impl Transactions {
-    pub fn into_edn(&self) -> edn::Value {
-        edn::Value::Vector((&self.0).into_iter().map(|x| x.into_edn()).collect())
+    fn deref(&self) -> &Path {
+        &self.0
     }
 }
 
-impl FulltextValues {
-    pub fn into_edn(&self) -> edn::Value {
-        edn::Value::Vector((&self.0).into_iter().map(|&(x, ref y)| edn::Value::Vector(vec![edn::Value::Integer(x), edn::Value::Text(y.clone())])).collect())
+impl AsRef<Path> for TempStorePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
     }
 }
 
-/// Turn TypedValue::Ref into TypedValue::Keyword when it is possible.
-trait ToIdent {
-  fn map_ident(self, schema: &Schema) -> Self;
+impl Drop for TempStorePath {
+    fn drop(&mut self) {
+        self.remove();
+    }
 }
 
-impl ToIdent for TypedValue {
-    fn map_ident(self, schema: &Schema) -> Self {
-        if let TypedValue::Ref(e) = self {
-            schema.get_ident(e).cloned().map(|i| TypedValue::Keyword(Rc::new(i))).unwrap_or(TypedValue::Ref(e))
-        } else {
-            self
-        }
+/// Render a single SQLite value for display, without the `Debug` wrapper: text as-is, numbers as
+/// written by Rust, blobs as an SQL hex literal, and `NULL` as the empty string.
+fn format_value(value: &Value) -> String {
+    match *value {
+        Value::Null => "".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(ref s) => s.clone(),
+        Value::Blob(ref bytes) => {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("X'{}'", hex.concat())
+        },
     }
 }
 
-/// Convert a numeric entid to an ident `Entid` if possible, otherwise a numeric `Entid`.
-fn to_entid(schema: &Schema, entid: i64) -> Entid {
-    schema.get_ident(entid).map_or(Entid::Entid(entid), |ident| Entid::Ident(ident.clone()))
+/// Quote a CSV field as required by RFC 4180: fields containing commas, double quotes, or line
+/// breaks are wrapped in double quotes, with embedded double quotes doubled.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
 }
 
-/// Return the set of datoms in the store, ordered by (e, a, v, tx), but not including any datoms of
-/// the form [... :db/txInstant ...].
-pub fn datoms<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S) -> Result<Datoms> {
-    datoms_after(conn, schema, bootstrap::TX0 - 1)
+/// Collect the values of each of `rows`.
+fn collect_rows(mut rows: rusqlite::Rows) -> Result<Vec<Vec<Value>>> {
+    let mut values = vec![];
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let mut row_values = Vec::with_capacity(row.column_count() as usize);
+        for i in 0..row.column_count() {
+            row_values.push(row.get_checked(i)?);
+        }
+        values.push(row_values);
+    }
+    Ok(values)
 }
 
-/// Return the set of datoms in the store with transaction ID strictly greater than the given `tx`,
-/// ordered by (e, a, v, tx).
-///
-/// The datom set returned does not include any datoms of the form [... :db/txInstant ...].
-pub fn datoms_after<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S, tx: i64) -> Result<Datoms> {
-    let borrowed_schema = schema.borrow();
-
-    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag, tx FROM datoms WHERE tx > ? ORDER BY e ASC, a ASC, value_type_tag ASC, v ASC, tx ASC")?;
-
-    let r: Result<Vec<_>> = stmt.query_and_then(&[&tx], |row| {
-        let e: i64 = row.get_checked(0)?;
-        let a: i64 = row.get_checked(1)?;
+/// Format the query `sql`, the names of its `columns`, and the `rows` it returned as a
+/// tab-and-newline formatted string, stopping after `max_rows` rows (if given) and noting if more
+/// rows were left out.  Rows are formatted as they're read, and at most one more is read to tell
+/// whether any were left out, so a huge result costs no more than `max_rows` of it.
+fn format_rows(sql: &str, columns: &[String], mut rows: rusqlite::Rows, max_rows: Option<usize>) -> Result<String> {
+    let mut tw = TabWriter::new(Vec::new()).padding(2);
+    write!(&mut tw, "{}\n", sql)?;
 
-        if a == entids::DB_TX_INSTANT {
-            return Ok(None);
+    for column_name in columns {
+        write!(&mut tw, "{}\t", column_name)?;
+    }
+    write!(&mut tw, "\n")?;
+
+    let mut shown = 0;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        if max_rows.map_or(false, |max| shown >= max) {
+            write!(&mut tw, "… more rows omitted\n")?;
+            break;
         }
+        for i in 0..row.column_count() {
+            let value: Value = row.get_checked(i)?;
+            write!(&mut tw, "{}\t", format_value(&value))?;
+        }
+        write!(&mut tw, "\n")?;
+        shown += 1;
+    }
 
-        let v: rusqlite::types::Value = row.get_checked(2)?;
-        let value_type_tag: i32 = row.get_checked(3)?;
-
-        let attribute = borrowed_schema.require_attribute_for_entid(a)?;
-        let value_type_tag = if !attribute.fulltext { value_type_tag } else { ValueType::Long.value_type_tag() };
+    let dump = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+    Ok(dump)
+}
 
-        let typed_value = TypedValue::from_sql_value_pair(v, value_type_tag)?.map_ident(borrowed_schema);
-        let (value, _) = typed_value.to_edn_value_pair();
+/// Execute the given `sql` query with the given `params`, returning the names of the columns and
+/// the raw values of each row, for tooling that wants to process the results rather than print
+/// them.
+pub fn query_sql_rows(conn: &rusqlite::Connection, sql: &str, params: &[&ToSql]) -> Result<(Vec<String>, Vec<Vec<Value>>)> {
+    let mut stmt: rusqlite::Statement = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(|name| name.to_string()).collect();
+    let rows = collect_rows(stmt.query(params)?)?;
+    Ok((columns, rows))
+}
 
-        let tx: i64 = row.get_checked(4)?;
+/// Like `query_sql_rows`, but binding named `params`, as produced by the query translator.
+pub fn query_sql_rows_named(conn: &rusqlite::Connection, sql: &str, params: &[(&str, &ToSql)]) -> Result<(Vec<String>, Vec<Vec<Value>>)> {
+    let mut stmt: rusqlite::Statement = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(|name| name.to_string()).collect();
+    let rows = collect_rows(stmt.query_named(params)?)?;
+    Ok((columns, rows))
+}
 
-        Ok(Some(Datom {
-            e: Entid::Entid(e),
-            a: to_entid(borrowed_schema, a),
-            v: value,
-            tx: tx,
-            added: None,
-        }))
-    })?.collect();
+/// Execute the given `sql` query with the given `params` and format the results as a
+/// tab-and-newline formatted string suitable for debug printing.
+///
+/// The query is printed followed by a newline, then the returned columns followed by a newline, and
+/// then the data rows and columns.  All columns are aligned.  If `max_rows` is given, at most that
+/// many rows are formatted, followed by a line noting that more were left out, if any were.
+pub fn dump_sql_query(conn: &rusqlite::Connection, sql: &str, params: &[&ToSql], max_rows: Option<usize>) -> Result<String> {
+    let mut stmt: rusqlite::Statement = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(|name| name.to_string()).collect();
+    let dump = format_rows(sql, &columns, stmt.query(params)?, max_rows)?;
+    Ok(dump)
+}
 
-    Ok(Datoms(r?.into_iter().filter_map(|x| x).collect()))
+/// Like `dump_sql_query`, but binding named `params`, as produced by the query translator.
+pub fn dump_sql_query_named(conn: &rusqlite::Connection, sql: &str, params: &[(&str, &ToSql)], max_rows: Option<usize>) -> Result<String> {
+    let mut stmt: rusqlite::Statement = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(|name| name.to_string()).collect();
+    let dump = format_rows(sql, &columns, stmt.query_named(params)?, max_rows)?;
+    Ok(dump)
 }
 
-/// Return the sequence of transactions in the store with transaction ID strictly greater than the
-/// given `tx`, ordered by (tx, e, a, v).
+/// Execute the given `sql` query with the given `params` and write the results to `w` as RFC 4180
+/// CSV, suitable for loading into a spreadsheet.
 ///
-/// Each transaction returned includes the [:db/tx :db/txInstant ...] datom.
-pub fn transactions_after<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S, tx: i64) -> Result<Transactions> {
-    let borrowed_schema = schema.borrow();
-
-    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag, tx, added FROM transactions WHERE tx > ? ORDER BY tx ASC, e ASC, a ASC, value_type_tag ASC, v ASC, added ASC")?;
+/// The first record names the returned columns; each following record is a row.  Records are
+/// terminated by CRLF, and `NULL` is written as an empty field.
+pub fn dump_sql_query_csv(conn: &rusqlite::Connection, sql: &str, params: &[&ToSql], w: &mut Write) -> Result<()> {
+    let mut stmt: rusqlite::Statement = conn.prepare(sql)?;
 
-    let r: Result<Vec<_>> = stmt.query_and_then(&[&tx], |row| {
-        let e: i64 = row.get_checked(0)?;
-        let a: i64 = row.get_checked(1)?;
+    let header: Vec<String> = stmt.column_names().into_iter().map(csv_field).collect();
+    write!(w, "{}\r\n", header.join(","))?;
 
-        let v: rusqlite::types::Value = row.get_checked(2)?;
-        let value_type_tag: i32 = row.get_checked(3)?;
+    let mut rows = stmt.query(params)?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let mut fields: Vec<String> = Vec::with_capacity(row.column_count() as usize);
+        for i in 0..row.column_count() {
+            let value: Value = row.get_checked(i)?;
+            fields.push(csv_field(&format_value(&value)));
+        }
+        write!(w, "{}\r\n", fields.join(","))?;
+    }
+    Ok(())
+}
 
-        let attribute = borrowed_schema.require_attribute_for_entid(a)?;
-        let value_type_tag = if !attribute.fulltext { value_type_tag } else { ValueType::Long.value_type_tag() };
+#[cfg(test)]
+mod tests {
+    extern crate csv;
 
-        let typed_value = TypedValue::from_sql_value_pair(v, value_type_tag)?.map_ident(borrowed_schema);
-        let (value, _) = typed_value.to_edn_value_pair();
+    use super::*;
 
-        let tx: i64 = row.get_checked(4)?;
-        let added: bool = row.get_checked(5)?;
+    #[test]
+    fn test_dump_sql_query_max_rows() {
+        let conn = rusqlite::Connection::open_in_memory().expect("connection");
+        conn.execute_batch("CREATE TABLE t (x INTEGER, y TEXT);
+                            INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, NULL), (4, 'd');").expect("table");
 
-        Ok(Datom {
-            e: Entid::Entid(e),
-            a: to_entid(borrowed_schema, a),
-            v: value,
-            tx: tx,
-            added: Some(added),
-        })
-    })?.collect();
+        let sql = "SELECT x, y FROM t ORDER BY x";
 
-    // Group by tx.
-    let r: Vec<Datoms> = r?.into_iter().group_by(|x| x.tx).into_iter().map(|(_key, group)| Datoms(group.collect())).collect();
-    Ok(Transactions(r))
-}
+        // Values are rendered without their `Debug` wrapper.
+        let dump = dump_sql_query(&conn, sql, &[], None).expect("dump");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<_>>(), vec!["1", "a"]);
+        assert!(!dump.contains("more rows"));
 
-/// Return the set of fulltext values in the store, ordered by rowid.
-pub fn fulltext_values(conn: &rusqlite::Connection) -> Result<FulltextValues> {
-    let mut stmt: rusqlite::Statement = conn.prepare("SELECT rowid, text FROM fulltext_values ORDER BY rowid")?;
+        // Exactly at the cap, nothing is left out.
+        let dump = dump_sql_query(&conn, sql, &[], Some(4)).expect("dump");
+        assert!(!dump.contains("more rows"));
 
-    let r: Result<Vec<_>> = stmt.query_and_then(&[], |row| {
-        let rowid: i64 = row.get_checked(0)?;
-        let text: String = row.get_checked(1)?;
-        Ok((rowid, text))
-    })?.collect();
+        let dump = dump_sql_query(&conn, sql, &[], Some(2)).expect("dump");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3].split_whitespace().collect::<Vec<_>>(), vec!["2", "b"]);
+        assert_eq!(lines[4], "… more rows omitted");
+    }
 
-    r.map(FulltextValues)
-}
+    #[test]
+    fn test_query_sql_rows() {
+        let conn = rusqlite::Connection::open_in_memory().expect("connection");
+        conn.execute_batch("CREATE TABLE t (x INTEGER, y TEXT);
+                            INSERT INTO t VALUES (1, 'a'), (2, NULL);").expect("table");
+
+        let (columns, rows) = query_sql_rows(&conn, "SELECT x, y AS why FROM t WHERE x >= ? ORDER BY x", &[&1]).expect("rows");
+        assert_eq!(columns, vec!["x".to_string(), "why".to_string()]);
+        assert_eq!(rows, vec![vec![Value::Integer(1), Value::Text("a".to_string())],
+                              vec![Value::Integer(2), Value::Null]]);
+
+        let (columns, rows) = query_sql_rows_named(&conn, "SELECT y FROM t WHERE x = :x", &[(":x", &2)]).expect("rows");
+        assert_eq!(columns, vec!["y".to_string()]);
+        assert_eq!(rows, vec![vec![Value::Null]]);
+
+        // No rows, but still the columns.
+        let (columns, rows) = query_sql_rows(&conn, "SELECT x FROM t WHERE x > 2", &[]).expect("rows");
+        assert_eq!(columns, vec!["x".to_string()]);
+        assert!(rows.is_empty());
+    }
 
-/// Execute the given `sql` query with the given `params` and format the results as a
-/// tab-and-newline formatted string suitable for debug printing.
-///
-/// The query is printed followed by a newline, then the returned columns followed by a newline, and
-/// then the data rows and columns.  All columns are aligned.
-pub fn dump_sql_query(conn: &rusqlite::Connection, sql: &str, params: &[&ToSql]) -> Result<String> {
-    let mut stmt: rusqlite::Statement = conn.prepare(sql)?;
+    #[test]
+    fn test_dump_sql_query_csv() {
+        let conn = rusqlite::Connection::open_in_memory().expect("connection");
+        conn.execute_batch("CREATE TABLE t (x INTEGER, y TEXT);").expect("table");
 
-    let mut tw = TabWriter::new(Vec::new()).padding(2);
-    write!(&mut tw, "{}\n", sql).unwrap();
+        let tricky = "quoted \"a,b\",\nand more";
+        conn.execute("INSERT INTO t VALUES (?, ?)", &[&1, &tricky]).expect("inserted");
+        conn.execute("INSERT INTO t VALUES (?, NULL)", &[&2]).expect("inserted");
 
-    for column_name in stmt.column_names() {
-        write!(&mut tw, "{}\t", column_name).unwrap();
-    }
-    write!(&mut tw, "\n").unwrap();
+        let mut out: Vec<u8> = vec![];
+        dump_sql_query_csv(&conn, "SELECT x, y AS \"the, y\" FROM t ORDER BY x", &[], &mut out).expect("dumped");
 
-    let r: Result<Vec<_>> = stmt.query_and_then(params, |row| {
-        for i in 0..row.column_count() {
-            let value: rusqlite::types::Value = row.get_checked(i)?;
-            write!(&mut tw, "{:?}\t", value).unwrap();
-        }
-        write!(&mut tw, "\n").unwrap();
-        Ok(())
-    })?.collect();
-    r?;
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let headers: Vec<String> = reader.headers().expect("headers").iter().map(|s| s.to_string()).collect();
+        assert_eq!(headers, vec!["x".to_string(), "the, y".to_string()]);
 
-    let dump = String::from_utf8(tw.into_inner().unwrap()).unwrap();
-    Ok(dump)
+        let records: Vec<Vec<String>> = reader.records()
+                                              .map(|r| r.expect("record").iter().map(|s| s.to_string()).collect())
+                                              .collect();
+        assert_eq!(records, vec![vec!["1".to_string(), tricky.to_string()],
+                                 vec!["2".to_string(), "".to_string()]]);
+    }
 }
@@ -0,0 +1,443 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Dumps of the datoms, transactions, and fulltext values in a store, in a deterministic order
+//! that makes them easy to compare.  These are used throughout our tests, and are just as useful
+//! for tooling: replication, diffing, and inspecting a store by hand.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::slice;
+use std::sync::Arc;
+use std::vec;
+
+use itertools::Itertools;
+use rusqlite;
+use tabwriter::TabWriter;
+
+use bootstrap;
+use db::TypedSQLValue;
+use edn;
+use entids;
+use errors::Result;
+use mentat_core::{
+    SQLValueType,
+    TypedValue,
+    ValueType,
+};
+use mentat_tx::entities::{Entid};
+use schema::{
+    SchemaBuilding,
+};
+use types::Schema;
+
+/// Represents a *datom* (assertion) in the store.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct Datom {
+    // TODO: generalize this.
+    e: Entid,
+    a: Entid,
+    v: edn::Value,
+    tx: i64,
+    added: Option<bool>,
+}
+
+/// Represents a set of datoms (assertions) in the store.
+///
+/// To make comparision easier, we deterministically order.  The ordering is the ascending tuple
+/// ordering determined by `(e, a, (value_type_tag, v), tx)`, where `value_type_tag` is an internal
+/// value that is not exposed but is deterministic.
+pub struct Datoms(pub Vec<Datom>);
+
+/// Represents an ordered sequence of transactions in the store.
+///
+/// To make comparision easier, we deterministically order.  The ordering is the ascending tuple
+/// ordering determined by `(e, a, (value_type_tag, v), tx, added)`, where `value_type_tag` is an
+/// internal value that is not exposed but is deterministic, and `added` is ordered such that
+/// retracted assertions appear before added assertions.
+pub struct Transactions(pub Vec<Datoms>);
+
+/// Represents the fulltext values in the store.
+pub struct FulltextValues(pub Vec<(i64, String)>);
+
+impl Datom {
+    /// The entity, always numeric.
+    pub fn e(&self) -> &Entid {
+        &self.e
+    }
+
+    /// The attribute, named by its ident if it has one.
+    pub fn a(&self) -> &Entid {
+        &self.a
+    }
+
+    /// The value, with refs to entities that have idents rendered as keywords.
+    pub fn value(&self) -> &edn::Value {
+        &self.v
+    }
+
+    pub fn tx(&self) -> i64 {
+        self.tx
+    }
+
+    /// Whether this datom was asserted or retracted, for datoms drawn from the transaction log;
+    /// `None` for datoms drawn from the current state of the store.
+    pub fn added(&self) -> Option<bool> {
+        self.added
+    }
+
+    pub fn into_edn(&self) -> edn::Value {
+        let f = |entid: &Entid| -> edn::Value {
+            match *entid {
+                Entid::Entid(ref y) => edn::Value::Integer(y.clone()),
+                Entid::Ident(ref y) => edn::Value::NamespacedKeyword(y.clone()),
+            }
+        };
+
+        let mut v = vec![f(&self.e), f(&self.a), self.v.clone()];
+        if let Some(added) = self.added {
+            v.push(edn::Value::Integer(self.tx));
+            v.push(edn::Value::Boolean(added));
+        }
+
+        edn::Value::Vector(v)
+    }
+}
+
+impl Datoms {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<Datom> {
+        self.0.iter()
+    }
+
+    pub fn into_edn(&self) -> edn::Value {
+        edn::Value::Vector((&self.0).into_iter().map(|x| x.into_edn()).collect())
+    }
+
+    /// Render these datoms as EDN that reads the same as `into_edn`, but with one datom per line
+    /// and the columns aligned, for test snapshots and debugging.
+    pub fn to_pretty_string(&self) -> String {
+        if self.0.is_empty() {
+            return "[]".to_string();
+        }
+
+        let mut tw = TabWriter::new(Vec::new()).padding(2);
+        let last = self.0.len() - 1;
+        for (i, datom) in self.0.iter().enumerate() {
+            let columns: Vec<String> = match datom.into_edn() {
+                edn::Value::Vector(values) => values.iter().map(|v| v.to_string()).collect(),
+                _ => unreachable!(),
+            };
+            // Writing to a `Vec` can't fail.
+            write!(&mut tw, "{}[{}]{}\n",
+                   if i == 0 { "[" } else { " " },
+                   columns.join("\t"),
+                   if i == last { "]" } else { "" }).unwrap();
+        }
+        String::from_utf8(tw.into_inner().unwrap()).unwrap()
+    }
+}
+
+impl IntoIterator for Datoms {
+    type Item = Datom;
+    type IntoIter = vec::IntoIter<Datom>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Datoms {
+    type Item = &'a Datom;
+    type IntoIter = slice::Iter<'a, Datom>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl Transactions {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<Datoms> {
+        self.0.iter()
+    }
+
+    pub fn into_edn(&self) -> edn::Value {
+        edn::Value::Vector((&self.0).into_iter().map(|x| x.into_edn()).collect())
+    }
+
+    /// Render these transactions for reading: each is introduced by a comment giving its ID and
+    /// `:db/txInstant`, and followed by its datoms as by `Datoms::to_pretty_string`, each with
+    /// its transaction and whether it was added or retracted.  Transactions are separated by blank
+    /// lines.
+    pub fn to_pretty_string(&self) -> String {
+        let tx_instant = Entid::Ident(edn::NamespacedKeyword::new("db", "txInstant"));
+        let blocks: Vec<String> = self.0.iter().map(|datoms| {
+            let header = match datoms.0.iter().find(|datom| datom.a == tx_instant) {
+                Some(datom) => format!(";; Transaction {} at {}\n", datom.tx, datom.v),
+                None => match datoms.0.first() {
+                    Some(datom) => format!(";; Transaction {}\n", datom.tx),
+                    None => "".to_string(),
+                },
+            };
+            format!("{}{}", header, datoms.to_pretty_string())
+        }).collect();
+        blocks.join("\n")
+    }
+}
+
+impl FulltextValues {
+    pub fn into_edn(&self) -> edn::Value {
+        edn::Value::Vector((&self.0).into_iter().map(|&(x, ref y)| edn::Value::Vector(vec![edn::Value::Integer(x), edn::Value::Text(y.clone())])).collect())
+    }
+}
+
+impl fmt::Display for Datom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.into_edn())
+    }
+}
+
+impl fmt::Display for Datoms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.into_edn())
+    }
+}
+
+impl fmt::Display for Transactions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.into_edn())
+    }
+}
+
+impl fmt::Display for FulltextValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.into_edn())
+    }
+}
+
+/// Turn TypedValue::Ref into TypedValue::Keyword when it is possible.
+trait ToIdent {
+  fn map_ident(self, schema: &Schema) -> Self;
+}
+
+impl ToIdent for TypedValue {
+    fn map_ident(self, schema: &Schema) -> Self {
+        if let TypedValue::Ref(e) = self {
+            schema.get_ident(e).cloned().map(|i| TypedValue::Keyword(Arc::new(i))).unwrap_or(TypedValue::Ref(e))
+        } else {
+            self
+        }
+    }
+}
+
+/// Convert a numeric entid to an ident `Entid` if possible, otherwise a numeric `Entid`.
+fn to_entid(schema: &Schema, entid: i64) -> Entid {
+    schema.get_ident(entid).map_or(Entid::Entid(entid), |ident| Entid::Ident(ident.clone()))
+}
+
+/// Which datoms `datoms_matching` should return.
+///
+/// Every filter is applied in SQL, so narrowing by entity or attribute is cheap even on a large
+/// store.  The default matches what `datoms` returns: everything but `:db/txInstant` datoms.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct DatomFilter {
+    /// Only include datoms with transaction ID strictly greater than this.
+    pub after_tx: i64,
+
+    /// Whether to include datoms of the form [... :db/txInstant ...].
+    pub include_tx_instant: bool,
+
+    /// Only include datoms with this attribute.
+    pub attribute: Option<i64>,
+
+    /// Only include datoms about this entity.
+    pub entity: Option<i64>,
+}
+
+impl Default for DatomFilter {
+    fn default() -> DatomFilter {
+        DatomFilter {
+            after_tx: bootstrap::TX0 - 1,
+            include_tx_instant: false,
+            attribute: None,
+            entity: None,
+        }
+    }
+}
+
+/// Return the set of datoms in the store, ordered by (e, a, v, tx), but not including any datoms of
+/// the form [... :db/txInstant ...].
+pub fn datoms<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S) -> Result<Datoms> {
+    datoms_matching(conn, schema, DatomFilter::default())
+}
+
+/// Return the set of datoms in the store with transaction ID strictly greater than the given `tx`,
+/// ordered by (e, a, v, tx).
+///
+/// The datom set returned does not include any datoms of the form [... :db/txInstant ...].
+pub fn datoms_after<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S, tx: i64) -> Result<Datoms> {
+    datoms_matching(conn, schema, DatomFilter { after_tx: tx, ..DatomFilter::default() })
+}
+
+/// Return the datoms in the store about the entity `e`, ordered by (a, v, tx).
+///
+/// As with `datoms_after`, refs to entities with idents are rendered as keywords, and fulltext
+/// values as the rowid of their text.  Unlike `datoms_after`, a transaction's `:db/txInstant` is
+/// included.  An entity about which nothing is known has no datoms.
+pub fn datoms_for_entity<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S, e: i64) -> Result<Datoms> {
+    datoms_matching(conn, schema, DatomFilter { include_tx_instant: true, entity: Some(e), ..DatomFilter::default() })
+}
+
+/// Return the set of datoms in the store that pass the given `filter`, ordered by (e, a, v, tx).
+pub fn datoms_matching<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S, filter: DatomFilter) -> Result<Datoms> {
+    let borrowed_schema = schema.borrow();
+
+    let mut constraints: Vec<&'static str> = vec!["tx > ?"];
+    let mut args: Vec<&rusqlite::types::ToSql> = vec![&filter.after_tx];
+    if !filter.include_tx_instant {
+        constraints.push("a <> ?");
+        args.push(&entids::DB_TX_INSTANT);
+    }
+    if let Some(ref a) = filter.attribute {
+        constraints.push("a = ?");
+        args.push(a);
+    }
+    if let Some(ref e) = filter.entity {
+        constraints.push("e = ?");
+        args.push(e);
+    }
+
+    let sql = format!("SELECT e, a, v, value_type_tag, tx FROM datoms WHERE {} ORDER BY e ASC, a ASC, value_type_tag ASC, v ASC, tx ASC",
+                      constraints.join(" AND "));
+    let mut stmt: rusqlite::Statement = conn.prepare(sql.as_str())?;
+
+    let r: Result<Vec<_>> = stmt.query_and_then(&args, |row| {
+        let e: i64 = row.get_checked(0)?;
+        let a: i64 = row.get_checked(1)?;
+
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+
+        let attribute = borrowed_schema.require_attribute_for_entid(a)?;
+        let value_type_tag = if !attribute.fulltext { value_type_tag } else { ValueType::Long.value_type_tag() };
+
+        let typed_value = TypedValue::from_sql_value_pair(v, value_type_tag)?.map_ident(borrowed_schema);
+        let (value, _) = typed_value.to_edn_value_pair();
+
+        let tx: i64 = row.get_checked(4)?;
+
+        Ok(Datom {
+            e: Entid::Entid(e),
+            a: to_entid(borrowed_schema, a),
+            v: value,
+            tx: tx,
+            added: None,
+        })
+    })?.collect();
+
+    r.map(Datoms)
+}
+
+/// Render the datoms about a single entity, as returned by `datoms_for_entity`, as an EDN map
+/// like `{:person/name "Alice" :person/friend [:a/b 65540]}`.
+///
+/// Attributes are named by ident where possible.  The values of a cardinality-many attribute are
+/// collected into a vector, even if there's only one.
+pub fn entity_to_edn<S: Borrow<Schema>>(schema: &S, datoms: &Datoms) -> Result<edn::Value> {
+    let borrowed_schema = schema.borrow();
+
+    let mut m: BTreeMap<edn::Value, edn::Value> = BTreeMap::default();
+    for datom in datoms {
+        let (key, entid) = match datom.a {
+            Entid::Entid(entid) => (edn::Value::Integer(entid), entid),
+            Entid::Ident(ref ident) => (edn::Value::NamespacedKeyword(ident.clone()), borrowed_schema.require_entid(ident)?),
+        };
+
+        if borrowed_schema.require_attribute_for_entid(entid)?.multival {
+            match m.entry(key).or_insert_with(|| edn::Value::Vector(vec![])) {
+                &mut edn::Value::Vector(ref mut values) => values.push(datom.v.clone()),
+                _ => unreachable!(),
+            }
+        } else {
+            m.insert(key, datom.v.clone());
+        }
+    }
+
+    Ok(edn::Value::Map(m))
+}
+
+/// Return the sequence of transactions in the store with transaction ID strictly greater than the
+/// given `tx`, ordered by (tx, e, a, v).
+///
+/// Each transaction returned includes the [:db/tx :db/txInstant ...] datom.
+pub fn transactions_after<S: Borrow<Schema>>(conn: &rusqlite::Connection, schema: &S, tx: i64) -> Result<Transactions> {
+    let borrowed_schema = schema.borrow();
+
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag, tx, added FROM transactions WHERE tx > ? ORDER BY tx ASC, e ASC, a ASC, value_type_tag ASC, v ASC, added ASC")?;
+
+    let r: Result<Vec<_>> = stmt.query_and_then(&[&tx], |row| {
+        let e: i64 = row.get_checked(0)?;
+        let a: i64 = row.get_checked(1)?;
+
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+
+        let attribute = borrowed_schema.require_attribute_for_entid(a)?;
+        let value_type_tag = if !attribute.fulltext { value_type_tag } else { ValueType::Long.value_type_tag() };
+
+        let typed_value = TypedValue::from_sql_value_pair(v, value_type_tag)?.map_ident(borrowed_schema);
+        let (value, _) = typed_value.to_edn_value_pair();
+
+        let tx: i64 = row.get_checked(4)?;
+        let added: bool = row.get_checked(5)?;
+
+        Ok(Datom {
+            e: Entid::Entid(e),
+            a: to_entid(borrowed_schema, a),
+            v: value,
+            tx: tx,
+            added: Some(added),
+        })
+    })?.collect();
+
+    // Group by tx.
+    let r: Vec<Datoms> = r?.into_iter().group_by(|x| x.tx).into_iter().map(|(_key, group)| Datoms(group.collect())).collect();
+    Ok(Transactions(r))
+}
+
+/// Return the set of fulltext values in the store, ordered by rowid.
+pub fn fulltext_values(conn: &rusqlite::Connection) -> Result<FulltextValues> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT rowid, text FROM fulltext_values ORDER BY rowid")?;
+
+    let r: Result<Vec<_>> = stmt.query_and_then(&[], |row| {
+        let rowid: i64 = row.get_checked(0)?;
+        let text: String = row.get_checked(1)?;
+        Ok((rowid, text))
+    })?.collect();
+
+    r.map(FulltextValues)
+}
@@ -23,6 +23,7 @@ error_chain! {
 
     foreign_links {
         Rusqlite(rusqlite::Error);
+        Io(::std::io::Error);
     }
 
     links {
@@ -30,6 +31,20 @@ error_chain! {
     }
 
     errors {
+        /// A store opened read-only has a different version than this Mentat expects -- version 0
+        /// means it was never initialized -- and so would need writes to create or migrate it.
+        CannotMigrateReadOnlyStore(found: i32, expected: i32) {
+            description("read-only store needs to be created or migrated")
+            display("read-only store has version {}, but version {} is required", found, expected)
+        }
+
+        /// An encrypted store couldn't be opened: either the key is wrong, or the store isn't
+        /// encrypted at all.
+        BadEncryptionKey {
+            description("bad encryption key")
+            display("unable to open the store: the encryption key is wrong, or the store is not encrypted")
+        }
+
         /// We're just not done yet.  Message that the feature is recognized but not yet
         /// implemented.
         NotYetImplemented(t: String) {
@@ -43,6 +58,13 @@ error_chain! {
             display("EDN value '{}' is not the expected Mentat value type {:?}", value, value_type)
         }
 
+        /// We've been asked to assert a value for an attribute that doesn't match the attribute's
+        /// value type.  The attribute is named by its ident, or by its entid if it has no ident.
+        ValueTypeMismatch(attribute: String, expected: ValueType, got: edn::types::Value) {
+            description("value is not of the attribute's value type")
+            display("value '{}' for attribute {} is not the expected Mentat value type {:?}", got, attribute, expected)
+        }
+
         /// We've got corrupt data in the SQL store: a value and value_type_tag don't line up.
         BadSQLValuePair(value: rusqlite::types::Value, value_type_tag: i32) {
             description("bad SQL (value_type_tag, value) pair")
@@ -82,5 +104,35 @@ error_chain! {
             description("unrecognized or no ident found for entid")
             display("unrecognized or no ident found for entid: {}", entid)
         }
+
+        /// An entid was transacted that lies outside every known partition: below the start of
+        /// the lowest, or beyond anything yet allocated in the highest.  An entid inside a
+        /// partition that simply hasn't been allocated is an `UnrecognizedEntid`.
+        EntidOutOfPartitionRange(entid: Entid) {
+            description("entid outside all known partition ranges")
+            display("entid outside all known partition ranges: {}", entid)
+        }
+
+        /// A transaction was cancelled through `TxOptions::cancel` before it finished.
+        TransactInterrupted {
+            description("transaction interrupted")
+            display("transaction interrupted")
+        }
+
+        /// A partition has no entids left: allocating another would reach the start of the next
+        /// partition.
+        PartitionExhausted(partition: String) {
+            description("no entids left in partition")
+            display("no entids left in partition {}", partition)
+        }
+
+        /// A transaction entity couldn't be turned into datoms.  The entity is identified by its
+        /// index in the transaction, counting from 0; the next error in the chain says what was
+        /// wrong with it.  Failures found while writing, such as conflicting unique values, are
+        /// not attributed to an entity.
+        InvalidEntity(entity_index: usize) {
+            description("invalid transaction entity")
+            display("entity {} is invalid", entity_index)
+        }
     }
 }
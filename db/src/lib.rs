@@ -15,6 +15,12 @@ extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
 extern crate rusqlite;
+#[cfg(feature = "serde_support")]
+extern crate serde;
+#[cfg(feature = "serde_support")]
+#[macro_use]
+extern crate serde_derive;
+extern crate sha2;
 extern crate tabwriter;
 extern crate time;
 
@@ -38,6 +44,7 @@ pub use errors::{Error, ErrorKind, ResultExt, Result};
 pub mod db;
 mod bootstrap;
 pub mod debug;
+pub mod dumps;
 mod add_retract_alter_set;
 mod entids;
 pub mod errors;
@@ -47,6 +54,7 @@ pub mod types;
 mod internal_types;
 mod upsert_resolution;
 mod tx;
+pub mod verify;
 
 // Export these for reference from tests. cfg(test) should work, but doesn't.
 // #[cfg(test)]
@@ -56,15 +64,48 @@ pub use bootstrap::{
 };
 
 pub use db::{
+    AppliedConnectionOptions,
+    ConnectionOptions,
+    JournalMode,
+    Synchronous,
     TypedSQLValue,
+    collect_fulltext_garbage,
+    compact,
     new_connection,
+    new_connection_with_options,
+    new_read_only_connection,
+    state_hash,
+};
+
+#[cfg(feature = "sqlcipher")]
+pub use db::{
+    change_encryption_key,
+    new_connection_with_key,
 };
 
-pub use tx::transact;
+pub use tx::{
+    TxOptions,
+    transact,
+    transact_as,
+    transact_bulk,
+    transact_with_options,
+};
+pub use verify::{
+    IntegrityIssue,
+    VerificationProblem,
+    VerificationReport,
+    verify,
+};
 pub use types::{
     DB,
+    Partition,
+    PartitionInfo,
     PartitionMap,
+    PartitionName,
+    TempIdResolution,
     TxReport,
+    partition_end,
+    partition_infos,
 };
 
 use edn::symbols;
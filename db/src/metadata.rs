@@ -169,6 +169,13 @@ pub fn update_schema_map_from_entid_triples<U>(schema_map: &mut SchemaMap, asser
                 }
             },
 
+            entids::DB_NO_HISTORY => {
+                match *value {
+                    TypedValue::Boolean(x) => { builder.no_history(x); },
+                    _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/noHistory true|false] but got [... :db/noHistory {:?}]", value)))
+                }
+            },
+
             _ => {
                 bail!(ErrorKind::BadSchemaAssertion(format!("Do not recognize attribute {} for entid {}", attr, entid)))
             }
@@ -10,7 +10,6 @@
 
 #![allow(dead_code)]
 
-use db::TypedSQLValue;
 use edn;
 use errors::{ErrorKind, Result};
 use edn::symbols;
@@ -65,6 +64,7 @@ pub struct AttributeBuilder {
     index: Option<bool>,
     fulltext: Option<bool>,
     component: Option<bool>,
+    no_history: Option<bool>,
 }
 
 impl AttributeBuilder {
@@ -98,6 +98,11 @@ impl AttributeBuilder {
         self
     }
 
+    pub fn no_history<'a>(&'a mut self, no_history: bool) -> &'a mut Self {
+        self.no_history = Some(no_history);
+        self
+    }
+
     pub fn validate_install_attribute(&self) -> Result<()> {
         if self.value_type.is_none() {
             bail!(ErrorKind::BadSchemaAssertion("Schema attribute for new attribute does not set :db/valueType".into()));
@@ -135,6 +140,9 @@ impl AttributeBuilder {
         if let Some(component) = self.component {
             attribute.component = component;
         }
+        if let Some(no_history) = self.no_history {
+            attribute.no_history = no_history;
+        }
 
         attribute
     }
@@ -165,6 +173,12 @@ impl AttributeBuilder {
                 mutations.push(AttributeAlteration::IsComponent);
             }
         }
+        if let Some(no_history) = self.no_history {
+            if no_history != attribute.no_history {
+                attribute.no_history = no_history;
+                mutations.push(AttributeAlteration::NoHistory);
+            }
+        }
 
         mutations
     }
@@ -233,36 +247,12 @@ impl SchemaTypeChecking for Schema {
         // TODO: encapsulate entid-ident-attribute for better error messages, perhaps by including
         // the attribute (rather than just the attribute's value type) into this function or a
         // wrapper function.
-        match TypedValue::from_edn_value(value) {
-            // We don't recognize this EDN at all.  Get out!
-            None => bail!(ErrorKind::BadEDNValuePair(value.clone(), value_type)),
-            Some(typed_value) => match (value_type, typed_value) {
-                // Most types don't coerce at all.
-                (ValueType::Boolean, tv @ TypedValue::Boolean(_)) => Ok(tv),
-                (ValueType::Long, tv @ TypedValue::Long(_)) => Ok(tv),
-                (ValueType::Double, tv @ TypedValue::Double(_)) => Ok(tv),
-                (ValueType::String, tv @ TypedValue::String(_)) => Ok(tv),
-                (ValueType::Uuid, tv @ TypedValue::Uuid(_)) => Ok(tv),
-                (ValueType::Instant, tv @ TypedValue::Instant(_)) => Ok(tv),
-                (ValueType::Keyword, tv @ TypedValue::Keyword(_)) => Ok(tv),
-                // Ref coerces a little: we interpret some things depending on the schema as a Ref.
-                (ValueType::Ref, TypedValue::Long(x)) => Ok(TypedValue::Ref(x)),
-                (ValueType::Ref, TypedValue::Keyword(ref x)) => self.require_entid(&x).map(|entid| TypedValue::Ref(entid)),
-
-                // Otherwise, we have a type mismatch.
-                // Enumerate all of the types here to allow the compiler to help us.
-                // We don't enumerate all `TypedValue` cases, though: that would multiply this
-                // collection by 8!
-                (vt @ ValueType::Boolean, _) |
-                (vt @ ValueType::Long, _) |
-                (vt @ ValueType::Double, _) |
-                (vt @ ValueType::String, _) |
-                (vt @ ValueType::Uuid, _) |
-                (vt @ ValueType::Instant, _) |
-                (vt @ ValueType::Keyword, _) |
-                (vt @ ValueType::Ref, _)
-                => bail!(ErrorKind::BadEDNValuePair(value.clone(), vt)),
-            }
+        match (value_type, value) {
+            // Ref coerces a little more than `TypedValue::from_edn_value` allows: we interpret
+            // keywords as idents, depending on the schema.
+            (ValueType::Ref, &edn::Value::NamespacedKeyword(ref x)) => self.require_entid(&x).map(|entid| TypedValue::Ref(entid)),
+            _ => TypedValue::from_edn_value(value, value_type)
+                     .map_err(|e| ErrorKind::BadEDNValuePair(e.value, e.expected).into()),
         }
     }
 }
@@ -297,6 +287,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
         // attribute is unique by value and an index
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "baz"), 98, Attribute {
@@ -306,6 +297,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: false,
             component: false,
+            no_history: false,
         });
         // attribue is unique by identity and an index
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "bat"), 99, Attribute {
@@ -315,6 +307,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: false,
+            no_history: false,
         });
         // attribute is a components and a `Ref`
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "bak"), 100, Attribute {
@@ -324,6 +317,7 @@ mod test {
             unique: None,
             multival: false,
             component: true,
+            no_history: false,
         });
         // fulltext attribute is a string and an index
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "bap"), 101, Attribute {
@@ -333,6 +327,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
 
         assert!(validate_schema_map(&schema.entid_map, &schema.schema_map).is_ok());
@@ -350,6 +345,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -372,6 +368,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -394,6 +391,7 @@ mod test {
             unique: None,
             multival: false,
             component: true,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -416,6 +414,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -437,6 +436,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
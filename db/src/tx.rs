@@ -52,6 +52,11 @@ use std::collections::{
     VecDeque,
 };
 use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
 
 use db;
 use db::{
@@ -62,7 +67,7 @@ use edn::{
     NamespacedKeyword,
 };
 use entids;
-use errors::{ErrorKind, Result};
+use errors::{Error, ErrorKind, Result, ResultExt};
 use internal_types::{
     KnownEntid,
     KnownEntidOr,
@@ -87,6 +92,7 @@ use mentat_core::{
     attribute,
     intern_set,
 };
+use mentat_core::intern_set::BoundedInternSet;
 use mentat_tx::entities as entmod;
 use mentat_tx::entities::{
     Entity,
@@ -106,6 +112,8 @@ use types::{
     AVMap,
     Entid,
     PartitionMap,
+    PartitionName,
+    TempIdResolution,
     TypedValue,
     TxReport,
     ValueType,
@@ -140,6 +148,34 @@ pub struct Tx<'conn, 'a> {
 
     /// The timestamp when the transaction began to be committed.
     tx_instant: DateTime<Utc>,
+
+    /// Whether to write assertions about entities allocated by this transaction straight to the
+    /// store, rather than searching for existing datoms first.  See `transact_bulk`.
+    bulk: bool,
+
+    /// Keyword values seen by earlier transactions, to be shared rather than allocated afresh.
+    /// See `TxOptions::keywords`.
+    keywords: Option<&'a Mutex<BoundedInternSet<NamespacedKeyword>>>,
+
+    /// Checked as the transaction proceeds.  See `TxOptions::cancel`.
+    cancel: Option<&'a AtomicBool>,
+}
+
+/// How `transact_with_options` applies a transaction.  The default is what `transact` does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TxOptions<'a> {
+    /// Use the bulk-load path; see `transact_bulk`.
+    pub bulk: bool,
+
+    /// Intern the keyword values of the transaction in this set, so that a keyword that recurs
+    /// across many transactions, like an enumeration value, is allocated once rather than once per
+    /// datom.  Long-lived callers, like a `Conn`, keep one set for all their transactions.
+    pub keywords: Option<&'a Mutex<BoundedInternSet<NamespacedKeyword>>>,
+
+    /// Checked as each entity is processed, and before the transaction is written.  If it's set,
+    /// the transaction stops with `ErrorKind::TransactInterrupted`.  As with any failed
+    /// transaction, the caller must roll back whatever was written.
+    pub cancel: Option<&'a AtomicBool>,
 }
 
 impl<'conn, 'a> Tx<'conn, 'a> {
@@ -157,15 +193,28 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             schema: schema,
             tx_id: tx_id,
             tx_instant: tx_instant,
+            bulk: false,
+            keywords: None,
+            cancel: None,
+        }
+    }
+
+    /// Fail with `ErrorKind::TransactInterrupted` if the transaction has been cancelled.
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel {
+            Some(cancel) if cancel.load(Ordering::SeqCst) => bail!(ErrorKind::TransactInterrupted),
+            _ => Ok(()),
         }
     }
 
     /// Given a collection of tempids and the [a v] pairs that they might upsert to, resolve exactly
     /// which [a v] pairs do upsert to entids, and map each tempid that upserts to the upserted
     /// entid.  The keys of the resulting map are exactly those tempids that upserted.
-    pub fn resolve_temp_id_avs<'b>(&self, temp_id_avs: &'b [(TempIdHandle, AVPair)]) -> Result<TempIdMap> {
+    ///
+    /// Also map each tempid that upserts to the first [a v] pair it upserted by.
+    pub fn resolve_temp_id_avs<'b>(&self, temp_id_avs: &'b [(TempIdHandle, AVPair)]) -> Result<(TempIdMap, BTreeMap<TempIdHandle, AVPair>)> {
         if temp_id_avs.is_empty() {
-            return Ok(TempIdMap::default());
+            return Ok((TempIdMap::default(), BTreeMap::default()));
         }
 
         // Map [a v]->entid.
@@ -179,6 +228,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
 
         // Map id->entid.
         let mut temp_id_map: TempIdMap = TempIdMap::default();
+        let mut temp_id_av_pairs: BTreeMap<TempIdHandle, AVPair> = BTreeMap::default();
         for &(ref temp_id, ref av_pair) in temp_id_avs {
             if let Some(n) = av_map.get(&av_pair) {
                 if let Some(&KnownEntid(previous_n)) = temp_id_map.get(&*temp_id) {
@@ -188,10 +238,11 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                     }
                 }
                 temp_id_map.insert(temp_id.clone(), KnownEntid(*n));
+                temp_id_av_pairs.entry(temp_id.clone()).or_insert_with(|| av_pair.clone());
             }
         }
 
-        Ok((temp_id_map))
+        Ok((temp_id_map, temp_id_av_pairs))
     }
 
     /// Pipeline stage 1: convert `Entity` instances into `Term` instances, ready for term
@@ -222,6 +273,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             fn ensure_entid_exists(&self, e: Entid) -> Result<KnownEntid> {
                 if self.partition_map.contains_entid(e) {
                     Ok(KnownEntid(e))
+                } else if !self.partition_map.within_partition_ranges(e) {
+                    bail!(ErrorKind::EntidOutOfPartitionRange(e))
                 } else {
                     bail!(ErrorKind::UnrecognizedEntid(e))
                 }
@@ -322,6 +375,9 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                             entmod::AtomOrLookupRefOrVectorOrMapNotation::LookupRef(ref lookup_ref) =>
                                 Ok(Either::Right(LookupRefOrTempId::LookupRef(self.intern_lookup_ref(lookup_ref)?))),
 
+                            entmod::AtomOrLookupRefOrVectorOrMapNotation::TempId(tempid) =>
+                                Ok(Either::Right(LookupRefOrTempId::TempId(self.intern_temp_id(tempid)))),
+
                             entmod::AtomOrLookupRefOrVectorOrMapNotation::Vector(_) =>
                                 bail!(ErrorKind::NotYetImplemented(format!("Cannot explode vector value in :attr/_reversed notation for attribute {}", forward_a))),
 
@@ -331,160 +387,204 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                     },
                 }
             }
-        }
-
-        let mut in_process = InProcess::with_schema_and_partition_map(&self.schema, &self.partition_map);
 
-        // We want to handle entities in the order they're given to us, while also "exploding" some
-        // entities into many.  We therefore push the initial entities onto the back of the deque,
-        // take from the front of the deque, and explode onto the front as well.
-        let mut deque: VecDeque<Entity> = VecDeque::default();
-        deque.extend(entities);
-
-        let mut terms: Vec<TermWithTempIdsAndLookupRefs> = Vec::with_capacity(deque.len());
-
-        while let Some(entity) = deque.pop_front() {
-            match entity {
-                Entity::MapNotation(mut map_notation) => {
-                    // :db/id is optional; if it's not given, we generate a special internal tempid
-                    // to use for upserting.  This tempid will not be reported in the TxReport.
-                    let db_id: entmod::EntidOrLookupRefOrTempId = mentat_tx_parser::remove_db_id(&mut map_notation)?.unwrap_or_else(|| in_process.allocate_mentat_id());
-
-                    // We're not nested, so :db/isComponent is not relevant.  We just explode the
-                    // map notation.
-                    for (a, v) in map_notation {
-                        deque.push_front(Entity::AddOrRetract {
-                            op: OpType::Add,
-                            e: db_id.clone(),
-                            a: a,
-                            v: v,
-                        });
-                    }
-                },
+            /// Explode `entity` into terms, pushing them onto `terms`, and interning keyword values
+            /// in `keywords` if it's given.
+            fn explode_entity(&mut self, keywords: Option<&Mutex<BoundedInternSet<NamespacedKeyword>>>, entity: Entity, terms: &mut Vec<TermWithTempIdsAndLookupRefs>) -> Result<()> {
+                // We want to handle entities in the order they're given to us, while also
+                // "exploding" some entities into many.  We therefore push each entity onto the
+                // back of the deque, take from the front of the deque, and explode onto the front
+                // as well.
+                let mut deque: VecDeque<Entity> = VecDeque::default();
+                deque.push_back(entity);
+
+                let schema = self.schema;
+
+                while let Some(entity) = deque.pop_front() {
+                    match entity {
+                        Entity::MapNotation(mut map_notation) => {
+                            // :db/id is optional; if it's not given, we generate a special internal tempid
+                            // to use for upserting.  This tempid will not be reported in the TxReport.
+                            let db_id: entmod::EntidOrLookupRefOrTempId = mentat_tx_parser::remove_db_id(&mut map_notation)?.unwrap_or_else(|| self.allocate_mentat_id());
+
+                            // We're not nested, so :db/isComponent is not relevant.  We just explode the
+                            // map notation.
+                            for (a, v) in map_notation {
+                                deque.push_front(Entity::AddOrRetract {
+                                    op: OpType::Add,
+                                    e: db_id.clone(),
+                                    a: a,
+                                    v: v,
+                                });
+                            }
+                        },
+
+                        Entity::AddOrRetract { op, e, a, v } => {
+                            if let Some(reversed_a) = a.unreversed() {
+                                let reversed_e = self.entity_v_into_term_e(v, &a)?;
+                                let reversed_a = self.entity_a_into_term_a(reversed_a)?;
+                                let reversed_v = self.entity_e_into_term_v(e)?;
+                                terms.push(Term::AddOrRetract(OpType::Add, reversed_e, reversed_a, reversed_v));
+                            } else {
+                                let a = self.entity_a_into_term_a(a)?;
+                                let attribute = schema.require_attribute_for_entid(a)?;
+
+                                let v = match v {
+                                    entmod::AtomOrLookupRefOrVectorOrMapNotation::Atom(v) => {
+                                        if attribute.value_type == ValueType::Ref && v.inner.is_text() {
+                                            Either::Right(LookupRefOrTempId::TempId(self.intern_temp_id(v.inner.as_text().cloned().map(TempId::External).unwrap())))
+                                        } else {
+                                            let interned = match (attribute.value_type, keywords) {
+                                                (ValueType::Keyword, Some(keywords)) => {
+                                                    match (v.inner.as_namespaced_keyword(), keywords.lock()) {
+                                                        (Some(keyword), Ok(mut keywords)) => Some(keywords.intern_ref(keyword)),
+                                                        _ => None,
+                                                    }
+                                                },
+                                                _ => None,
+                                            };
+
+                                            // Here is where we do schema-aware typechecking: we either assert that
+                                            // the given value is in the attribute's value set, or (in limited
+                                            // cases) coerce the value into the attribute's value set.
+                                            let typed_value: TypedValue = match interned {
+                                                Some(keyword) => TypedValue::Keyword(keyword),
+                                                None => schema.to_typed_value(&v.without_spans(), attribute.value_type)
+                                                    .map_err(|e| match e {
+                                                        Error(ErrorKind::BadEDNValuePair(value, expected), _) => {
+                                                            let attribute = schema.get_ident(a).map_or_else(|| a.to_string(), |ident| ident.to_string());
+                                                            Error::from(ErrorKind::ValueTypeMismatch(attribute, expected, value))
+                                                        },
+                                                        e => e,
+                                                    })?,
+                                            };
+                                            Either::Left(typed_value)
+                                        }
+                                    },
 
-                Entity::AddOrRetract { op, e, a, v } => {
-                    if let Some(reversed_a) = a.unreversed() {
-                        let reversed_e = in_process.entity_v_into_term_e(v, &a)?;
-                        let reversed_a = in_process.entity_a_into_term_a(reversed_a)?;
-                        let reversed_v = in_process.entity_e_into_term_v(e)?;
-                        terms.push(Term::AddOrRetract(OpType::Add, reversed_e, reversed_a, reversed_v));
-                    } else {
-                        let a = in_process.entity_a_into_term_a(a)?;
-                        let attribute = self.schema.require_attribute_for_entid(a)?;
-
-                        let v = match v {
-                            entmod::AtomOrLookupRefOrVectorOrMapNotation::Atom(v) => {
-                                if attribute.value_type == ValueType::Ref && v.inner.is_text() {
-                                    Either::Right(LookupRefOrTempId::TempId(in_process.intern_temp_id(v.inner.as_text().cloned().map(TempId::External).unwrap())))
-                                } else {
-                                    // Here is where we do schema-aware typechecking: we either assert that
-                                    // the given value is in the attribute's value set, or (in limited
-                                    // cases) coerce the value into the attribute's value set.
-                                    let typed_value: TypedValue = self.schema.to_typed_value(&v.without_spans(), attribute.value_type)?;
-                                    Either::Left(typed_value)
-                                }
-                            },
+                                    entmod::AtomOrLookupRefOrVectorOrMapNotation::LookupRef(ref lookup_ref) => {
+                                        if attribute.value_type != ValueType::Ref {
+                                            bail!(ErrorKind::NotYetImplemented(format!("Cannot resolve value lookup ref for attribute {} that is not :db/valueType :db.type/ref", a)))
+                                        }
 
-                            entmod::AtomOrLookupRefOrVectorOrMapNotation::LookupRef(ref lookup_ref) => {
-                                if attribute.value_type != ValueType::Ref {
-                                    bail!(ErrorKind::NotYetImplemented(format!("Cannot resolve value lookup ref for attribute {} that is not :db/valueType :db.type/ref", a)))
-                                }
+                                        Either::Right(LookupRefOrTempId::LookupRef(self.intern_lookup_ref(lookup_ref)?))
+                                    },
 
-                                Either::Right(LookupRefOrTempId::LookupRef(in_process.intern_lookup_ref(lookup_ref)?))
-                            },
+                                    entmod::AtomOrLookupRefOrVectorOrMapNotation::TempId(tempid) => {
+                                        if attribute.value_type != ValueType::Ref {
+                                            bail!(ErrorKind::NotYetImplemented(format!("Cannot use tempid as value for attribute {} that is not :db/valueType :db.type/ref", a)))
+                                        }
 
-                            entmod::AtomOrLookupRefOrVectorOrMapNotation::Vector(vs) => {
-                                if !attribute.multival {
-                                    bail!(ErrorKind::NotYetImplemented(format!("Cannot explode vector value for attribute {} that is not :db.cardinality :db.cardinality/many", a)));
-                                }
+                                        Either::Right(LookupRefOrTempId::TempId(self.intern_temp_id(tempid)))
+                                    },
 
-                                for vv in vs {
-                                    deque.push_front(Entity::AddOrRetract {
-                                        op: op.clone(),
-                                        e: e.clone(),
-                                        a: entmod::Entid::Entid(a),
-                                        v: vv,
-                                    });
-                                }
-                                continue
-                            },
-
-                            entmod::AtomOrLookupRefOrVectorOrMapNotation::MapNotation(mut map_notation) => {
-                                // TODO: consider handling this at the tx-parser level.  That would be
-                                // more strict and expressive, but it would lead to splitting
-                                // AddOrRetract, which proliferates types and code, or only handling
-                                // nested maps rather than map values, like Datomic does.
-                                if op != OpType::Add {
-                                    bail!(ErrorKind::NotYetImplemented(format!("Cannot explode nested map value in :db/retract for attribute {}", a)));
-                                }
+                                    entmod::AtomOrLookupRefOrVectorOrMapNotation::Vector(vs) => {
+                                        if !attribute.multival {
+                                            bail!(ErrorKind::NotYetImplemented(format!("Cannot explode vector value for attribute {} that is not :db.cardinality :db.cardinality/many", a)));
+                                        }
 
-                                if attribute.value_type != ValueType::Ref {
-                                    bail!(ErrorKind::NotYetImplemented(format!("Cannot explode nested map value for attribute {} that is not :db/valueType :db.type/ref", a)))
-                                }
+                                        for vv in vs {
+                                            deque.push_front(Entity::AddOrRetract {
+                                                op: op.clone(),
+                                                e: e.clone(),
+                                                a: entmod::Entid::Entid(a),
+                                                v: vv,
+                                            });
+                                        }
+                                        continue
+                                    },
+
+                                    entmod::AtomOrLookupRefOrVectorOrMapNotation::MapNotation(mut map_notation) => {
+                                        // TODO: consider handling this at the tx-parser level.  That would be
+                                        // more strict and expressive, but it would lead to splitting
+                                        // AddOrRetract, which proliferates types and code, or only handling
+                                        // nested maps rather than map values, like Datomic does.
+                                        if op != OpType::Add {
+                                            bail!(ErrorKind::NotYetImplemented(format!("Cannot explode nested map value in :db/retract for attribute {}", a)));
+                                        }
 
-                                // :db/id is optional; if it's not given, we generate a special internal tempid
-                                // to use for upserting.  This tempid will not be reported in the TxReport.
-                                let db_id: Option<entmod::EntidOrLookupRefOrTempId> = mentat_tx_parser::remove_db_id(&mut map_notation)?;
-                                let mut dangling = db_id.is_none();
-                                let db_id: entmod::EntidOrLookupRefOrTempId = db_id.unwrap_or_else(|| in_process.allocate_mentat_id());
-
-                                // We're nested, so we want to ensure we're not creating "dangling"
-                                // entities that can't be reached.  If we're :db/isComponent, then this
-                                // is not dangling.  Otherwise, the resulting map needs to have a
-                                // :db/unique :db.unique/identity [a v] pair, so that it's reachable.
-                                // Per http://docs.datomic.com/transactions.html: "Either the reference
-                                // to the nested map must be a component attribute, or the nested map
-                                // must include a unique attribute. This constraint prevents the
-                                // accidental creation of easily-orphaned entities that have no identity
-                                // or relation to other entities."
-                                if attribute.component {
-                                    dangling = false;
-                                }
+                                        if attribute.value_type != ValueType::Ref {
+                                            bail!(ErrorKind::NotYetImplemented(format!("Cannot explode nested map value for attribute {} that is not :db/valueType :db.type/ref", a)))
+                                        }
 
-                                for (inner_a, inner_v) in map_notation {
-                                    if let Some(reversed_a) = inner_a.unreversed() {
-                                        // We definitely have a reference.  The reference might be
-                                        // dangling (a bare entid, for example), but we don't yet
-                                        // support nested maps and reverse notation simultaneously
-                                        // (i.e., we don't accept {:reverse/_attribute {:nested map}})
-                                        // so we don't need to check that the nested map reference isn't
-                                        // dangling.
-                                        dangling = false;
-
-                                        let reversed_e = in_process.entity_v_into_term_e(inner_v, &inner_a)?;
-                                        let reversed_a = in_process.entity_a_into_term_a(reversed_a)?;
-                                        let reversed_v = in_process.entity_e_into_term_v(db_id.clone())?;
-                                        terms.push(Term::AddOrRetract(OpType::Add, reversed_e, reversed_a, reversed_v));
-                                    } else {
-                                        let inner_a = in_process.entity_a_into_term_a(inner_a)?;
-                                        let inner_attribute = self.schema.require_attribute_for_entid(inner_a)?;
-                                        if inner_attribute.unique == Some(attribute::Unique::Identity) {
+                                        // :db/id is optional; if it's not given, we generate a special internal tempid
+                                        // to use for upserting.  This tempid will not be reported in the TxReport.
+                                        let db_id: Option<entmod::EntidOrLookupRefOrTempId> = mentat_tx_parser::remove_db_id(&mut map_notation)?;
+                                        let mut dangling = db_id.is_none();
+                                        let db_id: entmod::EntidOrLookupRefOrTempId = db_id.unwrap_or_else(|| self.allocate_mentat_id());
+
+                                        // We're nested, so we want to ensure we're not creating "dangling"
+                                        // entities that can't be reached.  If we're :db/isComponent, then this
+                                        // is not dangling.  Otherwise, the resulting map needs to have a
+                                        // :db/unique :db.unique/identity [a v] pair, so that it's reachable.
+                                        // Per http://docs.datomic.com/transactions.html: "Either the reference
+                                        // to the nested map must be a component attribute, or the nested map
+                                        // must include a unique attribute. This constraint prevents the
+                                        // accidental creation of easily-orphaned entities that have no identity
+                                        // or relation to other entities."
+                                        if attribute.component {
                                             dangling = false;
                                         }
 
-                                        deque.push_front(Entity::AddOrRetract {
-                                            op: OpType::Add,
-                                            e: db_id.clone(),
-                                            a: entmod::Entid::Entid(inner_a),
-                                            v: inner_v,
-                                        });
-                                    }
-                                }
+                                        for (inner_a, inner_v) in map_notation {
+                                            if let Some(reversed_a) = inner_a.unreversed() {
+                                                // We definitely have a reference.  The reference might be
+                                                // dangling (a bare entid, for example), but we don't yet
+                                                // support nested maps and reverse notation simultaneously
+                                                // (i.e., we don't accept {:reverse/_attribute {:nested map}})
+                                                // so we don't need to check that the nested map reference isn't
+                                                // dangling.
+                                                dangling = false;
+
+                                                let reversed_e = self.entity_v_into_term_e(inner_v, &inner_a)?;
+                                                let reversed_a = self.entity_a_into_term_a(reversed_a)?;
+                                                let reversed_v = self.entity_e_into_term_v(db_id.clone())?;
+                                                terms.push(Term::AddOrRetract(OpType::Add, reversed_e, reversed_a, reversed_v));
+                                            } else {
+                                                let inner_a = self.entity_a_into_term_a(inner_a)?;
+                                                let inner_attribute = schema.require_attribute_for_entid(inner_a)?;
+                                                if inner_attribute.unique == Some(attribute::Unique::Identity) {
+                                                    dangling = false;
+                                                }
+
+                                                deque.push_front(Entity::AddOrRetract {
+                                                    op: OpType::Add,
+                                                    e: db_id.clone(),
+                                                    a: entmod::Entid::Entid(inner_a),
+                                                    v: inner_v,
+                                                });
+                                            }
+                                        }
 
-                                if dangling {
-                                    bail!(ErrorKind::NotYetImplemented(format!("Cannot explode nested map value that would lead to dangling entity for attribute {}", a)));
-                                }
+                                        if dangling {
+                                            bail!(ErrorKind::NotYetImplemented(format!("Cannot explode nested map value that would lead to dangling entity for attribute {}", a)));
+                                        }
 
-                                in_process.entity_e_into_term_v(db_id)?
-                            },
-                        };
+                                        self.entity_e_into_term_v(db_id)?
+                                    },
+                                };
 
-                        let e = in_process.entity_e_into_term_e(e)?;
-                        terms.push(Term::AddOrRetract(op, e, a, v));
+                                let e = self.entity_e_into_term_e(e)?;
+                                terms.push(Term::AddOrRetract(op, e, a, v));
+                            }
+                        },
                     }
-                },
+                }
+                Ok(())
             }
-        };
+        }
+
+        let mut in_process = InProcess::with_schema_and_partition_map(&self.schema, &self.partition_map);
+
+        let mut terms: Vec<TermWithTempIdsAndLookupRefs> = vec![];
+
+        for (index, entity) in entities.into_iter().enumerate() {
+            self.check_cancelled()?;
+
+            in_process.explode_entity(self.keywords, entity, &mut terms)
+                      .chain_err(|| ErrorKind::InvalidEntity(index))?;
+        }
+
         Ok((terms, in_process.temp_ids, in_process.lookup_refs))
     }
 
@@ -512,9 +612,14 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         // TODO: push these into an internal transaction report?
         let mut tempids: BTreeMap<TempId, KnownEntid> = BTreeMap::default();
 
+        // The [a v] pair by which each tempid that upserted did so.
+        let mut upserted_via: BTreeMap<TempId, AVPair> = BTreeMap::default();
+
         // Pipeline stage 1: entities -> terms with tempids and lookup refs.
         let (terms_with_temp_ids_and_lookup_refs, tempid_set, lookup_ref_set) = self.entities_into_terms_with_temp_ids_and_lookup_refs(entities)?;
 
+        self.check_cancelled()?;
+
         // Pipeline stage 2: resolve lookup refs -> terms with tempids.
         let lookup_ref_avs: Vec<&(i64, TypedValue)> = lookup_ref_set.inner.iter().map(|rc| &**rc).collect();
         let lookup_ref_map: AVMap = self.store.resolve_avs(&lookup_ref_avs[..])?;
@@ -528,9 +633,13 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         // And evolve them forward.
         while generation.can_evolve() {
             // Evolve further.
-            let temp_id_map: TempIdMap = self.resolve_temp_id_avs(&generation.temp_id_avs()[..])?;
+            let (temp_id_map, temp_id_av_pairs) = self.resolve_temp_id_avs(&generation.temp_id_avs()[..])?;
             generation = generation.evolve_one_step(&temp_id_map);
 
+            for (tempid, av_pair) in temp_id_av_pairs {
+                upserted_via.insert((*tempid).clone(), av_pair);
+            }
+
             // Report each tempid that resolves via upsert.
             for (tempid, entid) in temp_id_map {
                 // Every tempid should be resolved at most once.  Prima facie, we might expect a
@@ -552,7 +661,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         let unresolved_temp_ids: BTreeSet<TempIdHandle> = generation.temp_ids_in_allocations();
 
         // TODO: track partitions for temporary IDs.
-        let entids = self.partition_map.allocate_entids(":db.part/user", unresolved_temp_ids.len());
+        let entids = self.partition_map.allocate_entids(PartitionName::User.as_str(), unresolved_temp_ids.len())?;
+        let allocated = entids.clone();
 
         let temp_id_allocations: TempIdMap = unresolved_temp_ids.into_iter()
                                                                 .zip(entids.map(|e| KnownEntid(e)))
@@ -573,8 +683,27 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             assert!(tempids.contains_key(&**tempid));
         }
 
-        // Any internal tempid has been allocated by the system and is a private implementation
-        // detail; it shouldn't be exposed in the final transaction report.
+        // Describe how each external tempid was resolved: by upsert, or by allocating a new entid.
+        let mut tempid_resolutions: BTreeMap<String, TempIdResolution> = BTreeMap::default();
+        for (tempid, &KnownEntid(e)) in &tempids {
+            if let TempId::External(ref name) = *tempid {
+                let via = upserted_via.get(tempid);
+                tempid_resolutions.insert(name.clone(), TempIdResolution {
+                    entid: e,
+                    newly_allocated: via.is_none(),
+                    upserted_via: via.and_then(|&(a, ref v)| self.schema.get_ident(a).map(|ident| (ident.clone(), v.clone()))),
+                });
+            }
+        }
+
+        // Negative internal tempids were minted by the caller, which wants to know what they became.
+        let minted_tempids: BTreeMap<i64, Entid> = tempids.iter().filter_map(|(tempid, e)| match *tempid {
+            TempId::Internal(x) if x < 0 => Some((x, e.0)),
+            _ => None,
+        }).collect();
+
+        // Any other internal tempid has been allocated by the system and is a private
+        // implementation detail; it shouldn't be exposed in the final transaction report.
         let tempids = tempids.into_iter().filter_map(|(tempid, e)| tempid.into_external().map(|s| (s, e.0))).collect();
 
         // A transaction might try to add or retract :db/ident assertions or other metadata mutating
@@ -584,6 +713,11 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         // store.
         let mut tx_might_update_metadata = false;
 
+        // Attributes that don't retain history and that this transaction touches.  Superseded
+        // assertions for these are removed from the transaction log after the transaction is
+        // written.
+        let mut no_history_attributes: BTreeSet<Entid> = BTreeSet::default();
+
         let final_terms: Vec<TermWithoutTempIds> = [final_populations.resolved,
                                                     final_populations.allocated,
                                                     inert_terms.into_iter().map(|term| term.unwrap()).collect()].concat();
@@ -615,6 +749,9 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                     if entids::might_update_metadata(a) {
                         tx_might_update_metadata = true;
                     }
+                    if attribute.no_history {
+                        no_history_attributes.insert(a);
+                    }
 
                     let added = op == OpType::Add;
                     let reduced = (e.0, a, attribute, v, added);
@@ -636,6 +773,23 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                           TypedValue::Instant(self.tx_instant),
                           true));
 
+        // This is the last chance to stop before writing.
+        self.check_cancelled()?;
+
+        if self.bulk {
+            // Entities allocated by this transaction, including the transaction itself, have no
+            // datoms to search for.
+            let tx_id = self.tx_id;
+            let is_new = |e: Entid| e == tx_id || (allocated.start <= e && e < allocated.end);
+
+            let (new_one, old_one): (Vec<db::ReducedEntity>, Vec<db::ReducedEntity>) = non_fts_one.into_iter().partition(|r| is_new(r.0));
+            let (new_many, old_many): (Vec<db::ReducedEntity>, Vec<db::ReducedEntity>) = non_fts_many.into_iter().partition(|r| is_new(r.0));
+            non_fts_one = old_one;
+            non_fts_many = old_many;
+
+            self.store.insert_new_entity_datoms(&[new_one, new_many].concat()[..], self.tx_id)?;
+        }
+
         if !non_fts_one.is_empty() {
             self.store.insert_non_fts_searches(&non_fts_one[..], db::SearchType::Inexact)?;
         }
@@ -653,6 +807,10 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         }
 
         self.store.commit_transaction(self.tx_id)?;
+
+        if !no_history_attributes.is_empty() {
+            self.store.remove_superseded_history(self.tx_id, &no_history_attributes)?;
+        }
         }
 
         db::update_partition_map(self.store, &self.partition_map)?;
@@ -680,6 +838,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             tx_id: self.tx_id,
             tx_instant: self.tx_instant,
             tempids: tempids,
+            tempid_resolutions: tempid_resolutions,
+            minted_tempids: minted_tempids,
         })
     }
 }
@@ -690,19 +850,81 @@ impl<'conn, 'a> Tx<'conn, 'a> {
 // TODO: move this to the transactor layer.
 pub fn transact<'conn, 'a, I>(
     conn: &'conn rusqlite::Connection,
-    mut partition_map: PartitionMap,
+    partition_map: PartitionMap,
     schema_for_mutation: &'a Schema,
     schema: &'a Schema,
     entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
     // Eventually, this function will be responsible for managing a SQLite transaction.  For
     // now, it's just about the tx details.
 
+    transact_with_options(conn, partition_map, schema_for_mutation, schema, TxOptions::default(), entities)
+}
+
+/// Transact the given `entities` as the transaction `tx_id`, labelled with `tx_instant`, rather
+/// than allocating a fresh transaction ID and using the current time.
+///
+/// This is for replaying transactions recorded elsewhere, such as in an export of another store.
+/// The caller is responsible for ensuring that `tx_id` is unused and already allocated from the
+/// `:db.part/tx` partition in `partition_map`.
+pub fn transact_as<'conn, 'a, I>(
+    conn: &'conn rusqlite::Connection,
+    partition_map: PartitionMap,
+    schema_for_mutation: &'a Schema,
+    schema: &'a Schema,
+    tx_id: Entid,
+    tx_instant: DateTime<Utc>,
+    entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
+    let tx = Tx::new(conn, partition_map, schema_for_mutation, schema, tx_id, tx_instant);
+    transact_tx(tx, TxOptions::default(), entities)
+}
+
+/// Like `transact`, but tuned for loading many new entities at once.
+///
+/// Assertions about entities that the transaction allocates -- those named by tempids that don't
+/// upsert -- are written straight to the transaction log and the `datoms` table, in multi-row
+/// `INSERT ... VALUES (...), (...)` statements, rather than being staged in the temporary search
+/// tables and joined against the existing datoms: a new entity has no datoms to find.  Each
+/// statement writes as many rows as SQLite's limit on bound variables allows for a row of
+/// `datoms`, which needs the most.  Assertions about
+/// existing entities, and :db.fulltext assertions, are transacted as usual.
+///
+/// The resulting datoms are exactly those `transact` would produce.  Uniqueness is enforced by the
+/// indices on `datoms`, so a transaction that violates :db/unique, or asserts the same datom twice,
+/// fails; the error is less descriptive than `transact`'s.
+pub fn transact_bulk<'conn, 'a, I>(
+    conn: &'conn rusqlite::Connection,
+    partition_map: PartitionMap,
+    schema_for_mutation: &'a Schema,
+    schema: &'a Schema,
+    entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
+    let options = TxOptions {
+        bulk: true,
+        ..TxOptions::default()
+    };
+    transact_with_options(conn, partition_map, schema_for_mutation, schema, options, entities)
+}
+
+/// Like `transact`, but applying the transaction as `options` says.
+pub fn transact_with_options<'conn, 'a, I>(
+    conn: &'conn rusqlite::Connection,
+    mut partition_map: PartitionMap,
+    schema_for_mutation: &'a Schema,
+    schema: &'a Schema,
+    options: TxOptions<'a>,
+    entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
     let tx_instant = ::now(); // Label the transaction with the timestamp when we first see it: leading edge.
-    let tx_id = partition_map.allocate_entid(":db.part/tx");
+    let tx_id = partition_map.allocate_entid(PartitionName::Tx.as_str())?;
+
+    let tx = Tx::new(conn, partition_map, schema_for_mutation, schema, tx_id, tx_instant);
+    transact_tx(tx, options, entities)
+}
 
-    conn.begin_tx_application()?;
+fn transact_tx<'conn, 'a, I>(mut tx: Tx<'conn, 'a>, options: TxOptions<'a>, entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
+    tx.store.begin_tx_application()?;
 
-    let mut tx = Tx::new(conn, partition_map, schema_for_mutation, schema, tx_id, tx_instant);
+    tx.bulk = options.bulk;
+    tx.keywords = options.keywords;
+    tx.cancel = options.cancel;
 
     let report = tx.transact_entities(entities)?;
 
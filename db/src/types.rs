@@ -12,12 +12,18 @@
 
 use std::collections::HashMap;
 use std::collections::BTreeMap;
+use std::i64;
 
 extern crate mentat_core;
 
+use edn;
+
+use mentat_tx::entities::TempId;
+
 pub use self::mentat_core::{
     DateTime,
     Entid,
+    KnownEntid,
     ValueType,
     TypedValue,
     Attribute,
@@ -49,6 +55,90 @@ impl Partition {
 /// Map partition names to `Partition` instances.
 pub type PartitionMap = BTreeMap<String, Partition>;
 
+/// The name of a partition of the entid space: one of the bootstrapped partitions, or a partition
+/// defined by an application.  Use `as_str` to get the key in a `PartitionMap`.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum PartitionName {
+    /// `:db.part/db`, which holds idents and attributes.
+    Db,
+    /// `:db.part/user`, from which tempids are allocated.
+    User,
+    /// `:db.part/tx`, which holds transactions.
+    Tx,
+    /// Any other partition, named like `:app.part/things`.
+    Custom(String),
+}
+
+impl PartitionName {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            PartitionName::Db => ":db.part/db",
+            PartitionName::User => ":db.part/user",
+            PartitionName::Tx => ":db.part/tx",
+            PartitionName::Custom(ref name) => name.as_str(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for PartitionName {
+    /// Recognize the bootstrapped partitions by name; any other name is `Custom`.
+    fn from(name: &'a str) -> PartitionName {
+        match name {
+            ":db.part/db" => PartitionName::Db,
+            ":db.part/user" => PartitionName::User,
+            ":db.part/tx" => PartitionName::Tx,
+            _ => PartitionName::Custom(name.to_string()),
+        }
+    }
+}
+
+impl ::std::fmt::Display for PartitionName {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A description of one partition, and how much of it has been allocated.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct PartitionInfo {
+    pub name: PartitionName,
+    /// The first entid in the partition.
+    pub start: Entid,
+    /// The next entid to be allocated in the partition.
+    pub index: Entid,
+    /// The first entid beyond the partition: the start of the next partition.
+    pub end: Entid,
+}
+
+impl PartitionInfo {
+    /// The number of entids allocated from this partition so far.
+    pub fn allocated(&self) -> i64 {
+        self.index - self.start
+    }
+}
+
+/// Describe each partition in `partition_map`, in name order.
+pub fn partition_infos(partition_map: &PartitionMap) -> Vec<PartitionInfo> {
+    partition_map.iter()
+                 .map(|(name, partition)| PartitionInfo {
+                     name: PartitionName::from(name.as_str()),
+                     start: partition.start,
+                     index: partition.index,
+                     end: partition_end(partition_map, partition.start),
+                 })
+                 .collect()
+}
+
+/// The first entid beyond the partition of `partition_map` that starts at `start`.  Each partition
+/// extends to the start of the next, and the last without limit.
+pub fn partition_end(partition_map: &PartitionMap, start: Entid) -> Entid {
+    partition_map.values()
+                 .map(|partition| partition.start)
+                 .filter(|&next| next > start)
+                 .min()
+                 .unwrap_or(i64::MAX)
+}
+
 /// Represents the metadata required to query from, or apply transactions to, a Mentat store.
 ///
 /// See https://github.com/mozilla/mentat/wiki/Thoughts:-modeling-db-conn-in-Rust.
@@ -82,14 +172,31 @@ pub type AVPair = (Entid, TypedValue);
 /// Used to resolve lookup-refs and upserts.
 pub type AVMap<'a> = HashMap<&'a AVPair, Entid>;
 
+/// How a string literal tempid was resolved by a transaction.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct TempIdResolution {
+    /// The entid the tempid resolved to or was allocated.
+    pub entid: Entid,
+
+    /// True if `entid` was allocated by the transaction, and false if it already existed.
+    pub newly_allocated: bool,
+
+    /// The `:db.unique/identity` attribute and value by which the tempid upserted to an existing
+    /// entity, if it did.  If several pairs upserted to the same entity, one of them.
+    #[cfg_attr(feature = "serde_support", serde(with = "::mentat_core::serde_support::optional_keyword_and_value"))]
+    pub upserted_via: Option<(edn::NamespacedKeyword, TypedValue)>,
+}
+
 /// A transaction report summarizes an applied transaction.
-// TODO: include map of resolved tempids.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
 pub struct TxReport {
     /// The transaction ID of the transaction.
     pub tx_id: Entid,
 
     /// The timestamp when the transaction began to be committed.
+    #[cfg_attr(feature = "serde_support", serde(with = "::mentat_core::serde_support::instant"))]
     pub tx_instant: DateTime<Utc>,
 
     /// A map from string literal tempid to resolved or allocated entid.
@@ -98,4 +205,48 @@ pub struct TxReport {
     /// existing entid, or is allocated a new entid.  (It is possible for multiple distinct string
     /// literal tempids to all unify to a single freshly allocated entid.)
     pub tempids: BTreeMap<String, Entid>,
+
+    /// How each string literal tempid in `tempids` was resolved: whether its entity is new, and
+    /// if not, by which attribute and value it upserted.  This tells "created" from "updated".
+    pub tempid_resolutions: BTreeMap<String, TempIdResolution>,
+
+    /// The entid each internal tempid minted by the caller resolved to or was allocated, keyed by
+    /// its number.  See `TempId::Internal`.
+    pub minted_tempids: BTreeMap<i64, Entid>,
+}
+
+impl TxReport {
+    /// Return the entid that `tempid` resolved to or was allocated in this transaction, or `None`
+    /// if the transaction didn't mention `tempid`.
+    ///
+    /// This is the typed counterpart of looking up a string literal tempid in `tempids`, or an
+    /// internal tempid minted by the caller in `minted_tempids`.  Internal tempids allocated by the
+    /// transactor are never reported, so always yield `None`.
+    pub fn resolved(&self, tempid: &TempId) -> Option<KnownEntid> {
+        match *tempid {
+            TempId::External(ref name) => self.tempids.get(name).cloned().map(KnownEntid),
+            TempId::Internal(x) => self.minted_tempids.get(&x).cloned().map(KnownEntid),
+        }
+    }
+
+    /// Return a stable EDN representation of this report, suitable for transmitting to a peer:
+    ///
+    /// `{:tx-id 268435457 :tx-instant #inst "2017-06-16T00:56:41.257Z" :tempids {"a" 65536}}`.
+    ///
+    /// This is a summary, not the transaction itself.  A report doesn't hold the transaction's
+    /// datoms, so they aren't included: ship those from `Conn::transactions_since`.  Nor are
+    /// `tempid_resolutions`, or `minted_tempids`, whose keys mean nothing outside this process.
+    pub fn to_edn(&self) -> edn::Value {
+        let kw = |name: &str| edn::Value::Keyword(edn::Keyword::new(name));
+        let tempids = self.tempids
+                          .iter()
+                          .map(|(tempid, &e)| (edn::Value::Text(tempid.clone()), edn::Value::Integer(e)))
+                          .collect();
+
+        let mut m = BTreeMap::new();
+        m.insert(kw("tx-id"), edn::Value::Integer(self.tx_id));
+        m.insert(kw("tx-instant"), edn::Value::Instant(self.tx_instant));
+        m.insert(kw("tempids"), edn::Value::Map(tempids));
+        edn::Value::Map(m)
+    }
 }
@@ -0,0 +1,394 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Check that a store is internally consistent, for example after a crash, before trusting the
+//! results of queries against it.
+
+use std::i64;
+
+use itertools::Itertools;
+use rusqlite;
+
+use mentat_core::{
+    SQLValueType,
+    ValueType,
+};
+
+use errors::Result;
+use types::{
+    Attribute,
+    Entid,
+    Schema,
+};
+
+/// A single inconsistency found by `verify`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum VerificationProblem {
+    /// A line reported by SQLite's `PRAGMA integrity_check`.
+    IntegrityCheck(String),
+
+    /// A datom's stored value type doesn't match its attribute's `:db/valueType`.
+    ValueTypeMismatch {
+        e: Entid,
+        a: Entid,
+        expected: ValueType,
+        value_type_tag: i32,
+    },
+
+    /// A datom's attribute is neither an installed attribute nor named by a `:db/ident`.
+    UnknownAttribute {
+        e: Entid,
+        a: Entid,
+    },
+
+    /// An entity has more than one value for a cardinality-one attribute.
+    CardinalityOneViolation {
+        e: Entid,
+        a: Entid,
+        count: i64,
+    },
+
+    /// More than one entity has the same value for a unique attribute.
+    UniqueViolation {
+        a: Entid,
+        entities: Vec<Entid>,
+    },
+
+    /// An entid in the partition's range is already in use, but the partition would allocate it
+    /// (or an entid before it) again.
+    PartitionIndexTooLow {
+        part: String,
+        index: i64,
+        max_entid: Entid,
+    },
+
+    /// A row of `fulltext_values` that no datom, current or historical, refers to.
+    OrphanedFulltextValue {
+        rowid: i64,
+        text: String,
+    },
+}
+
+/// `Conn::check_integrity` reports the same problems as `verify`.
+pub type IntegrityIssue = VerificationProblem;
+
+/// The problems found by `verify`, in the order they were checked.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerificationReport {
+    pub problems: Vec<VerificationProblem>,
+}
+
+impl VerificationReport {
+    /// True if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+fn check_integrity(conn: &rusqlite::Connection, problems: &mut Vec<VerificationProblem>) -> Result<()> {
+    let mut stmt: rusqlite::Statement = conn.prepare("PRAGMA integrity_check")?;
+    let lines: Result<Vec<String>> = stmt.query_and_then(&[], |row| -> Result<String> {
+        Ok(row.get_checked(0)?)
+    })?.collect();
+
+    // A healthy database reports exactly one line, "ok".
+    problems.extend(lines?.into_iter()
+                          .filter(|line| line != "ok")
+                          .map(VerificationProblem::IntegrityCheck));
+    Ok(())
+}
+
+fn check_attributes(conn: &rusqlite::Connection, schema: &Schema, problems: &mut Vec<VerificationProblem>) -> Result<()> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, value_type_tag FROM datoms ORDER BY e, a, value_type_tag")?;
+    let rows: Result<Vec<(Entid, Entid, i32)>> = stmt.query_and_then(&[], |row| -> Result<(Entid, Entid, i32)> {
+        Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?))
+    })?.collect();
+
+    for (e, a, value_type_tag) in rows? {
+        match schema.attribute_for_entid(a) {
+            Some(attribute) => {
+                if attribute.value_type.value_type_tag() != value_type_tag {
+                    problems.push(VerificationProblem::ValueTypeMismatch {
+                        e: e,
+                        a: a,
+                        expected: attribute.value_type,
+                        value_type_tag: value_type_tag,
+                    });
+                }
+            },
+            None => {
+                if schema.get_ident(a).is_none() {
+                    problems.push(VerificationProblem::UnknownAttribute { e: e, a: a });
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// The entids of the attributes in `schema` that satisfy `predicate`, separated by commas, for an
+/// SQL `IN` list.  Attributes are entids, so it's safe to interpolate them directly into SQL.
+pub fn attribute_list<P>(schema: &Schema, predicate: P) -> String where P: Fn(&Attribute) -> bool {
+    schema.schema_map.iter()
+                     .filter(|&(_, attribute)| predicate(attribute))
+                     .map(|(entid, _)| *entid)
+                     .join(", ")
+}
+
+fn check_cardinality(conn: &rusqlite::Connection, schema: &Schema, problems: &mut Vec<VerificationProblem>) -> Result<()> {
+    let s = format!(r#"
+      SELECT e, a, count(*) FROM datoms
+      WHERE a IN ({})
+      GROUP BY e, a
+      HAVING count(*) > 1
+      ORDER BY e, a"#, attribute_list(schema, |attribute| !attribute.multival));
+
+    let mut stmt: rusqlite::Statement = conn.prepare(s.as_str())?;
+    let violations: Result<Vec<VerificationProblem>> = stmt.query_and_then(&[], |row| -> Result<VerificationProblem> {
+        Ok(VerificationProblem::CardinalityOneViolation {
+            e: row.get_checked(0)?,
+            a: row.get_checked(1)?,
+            count: row.get_checked(2)?,
+        })
+    })?.collect();
+
+    problems.extend(violations?);
+    Ok(())
+}
+
+fn check_uniqueness(conn: &rusqlite::Connection, schema: &Schema, problems: &mut Vec<VerificationProblem>) -> Result<()> {
+    // Don't trust the `unique_value` column or its index: either might be what's corrupt.
+    let s = format!(r#"
+      SELECT d.a, d.value_type_tag, d.v, d.e FROM datoms AS d
+      WHERE d.a IN ({}) AND
+            EXISTS (SELECT 1 FROM datoms AS other
+                    WHERE other.a = d.a AND
+                          other.value_type_tag = d.value_type_tag AND
+                          other.v = d.v AND
+                          other.e != d.e)
+      ORDER BY d.a, d.value_type_tag, d.v, d.e"#, attribute_list(schema, |attribute| attribute.unique.is_some()));
+
+    let mut stmt: rusqlite::Statement = conn.prepare(s.as_str())?;
+    let rows: Result<Vec<((Entid, i32, rusqlite::types::Value), Entid)>> = stmt.query_and_then(&[], |row| -> Result<((Entid, i32, rusqlite::types::Value), Entid)> {
+        Ok(((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?), row.get_checked(3)?))
+    })?.collect();
+
+    for (key, group) in &rows?.into_iter().group_by(|&(ref key, _)| key.clone()) {
+        problems.push(VerificationProblem::UniqueViolation {
+            a: key.0,
+            entities: group.map(|(_, e)| e).collect(),
+        });
+    }
+    Ok(())
+}
+
+fn check_partitions(conn: &rusqlite::Connection, problems: &mut Vec<VerificationProblem>) -> Result<()> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT part, start, idx FROM parts ORDER BY start, part")?;
+    let parts: Result<Vec<(String, i64, i64)>> = stmt.query_and_then(&[], |row| -> Result<(String, i64, i64)> {
+        Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?))
+    })?.collect();
+    let parts = parts?;
+
+    // Every entid the store has allocated: entities, transactions, and the targets of refs.
+    let mut max_stmt: rusqlite::Statement = conn.prepare(r#"
+      SELECT max(x) FROM (SELECT e AS x FROM datoms
+                          UNION ALL SELECT tx FROM datoms
+                          UNION ALL SELECT v FROM datoms WHERE value_type_tag = 0
+                          UNION ALL SELECT e FROM transactions
+                          UNION ALL SELECT tx FROM transactions)
+      WHERE x >= ? AND x < ?"#)?;
+
+    // Each partition extends to the start of the next.
+    for (i, &(ref part, start, index)) in parts.iter().enumerate() {
+        let end = parts.get(i + 1).map(|&(_, next_start, _)| next_start).unwrap_or(i64::MAX);
+        let max_entid: Option<Entid> = max_stmt.query_row(&[&start, &end], |row| row.get(0))?;
+        if let Some(max_entid) = max_entid {
+            if index <= max_entid {
+                problems.push(VerificationProblem::PartitionIndexTooLow {
+                    part: part.clone(),
+                    index: index,
+                    max_entid: max_entid,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_fulltext_values(conn: &rusqlite::Connection, schema: &Schema, problems: &mut Vec<VerificationProblem>) -> Result<()> {
+    // The `transactions` table doesn't record which datoms are fulltext indexed, so we use the
+    // schema to find historical references.
+    let fulltext_attributes = attribute_list(schema, |attribute| attribute.fulltext);
+
+    let s = format!(r#"
+      SELECT rowid, text FROM fulltext_values
+      WHERE rowid NOT IN (SELECT v FROM datoms WHERE index_fulltext IS NOT 0) AND
+            rowid NOT IN (SELECT v FROM transactions WHERE a IN ({}))
+      ORDER BY rowid"#, fulltext_attributes);
+
+    let mut stmt: rusqlite::Statement = conn.prepare(s.as_str())?;
+    let orphans: Result<Vec<VerificationProblem>> = stmt.query_and_then(&[], |row| -> Result<VerificationProblem> {
+        Ok(VerificationProblem::OrphanedFulltextValue {
+            rowid: row.get_checked(0)?,
+            text: row.get_checked(1)?,
+        })
+    })?.collect();
+
+    problems.extend(orphans?);
+    Ok(())
+}
+
+/// Check the store for corruption, collecting every problem found rather than stopping at the
+/// first.  `schema` should be the schema read from the store.
+///
+/// This checks that:
+///
+/// - SQLite's `PRAGMA integrity_check` passes;
+/// - every datom's value type matches its attribute's `:db/valueType`;
+/// - every datom's attribute is installed, or at least has a `:db/ident`;
+/// - no entity has more than one value for a cardinality-one attribute;
+/// - no two entities share a value for a unique attribute, whatever the SQL indexes say;
+/// - each partition's next index is beyond every entid already used in the partition's range;
+/// - every row of `fulltext_values` is referenced by some datom.
+///
+/// An `Err` means the checks themselves couldn't be run; problems with the store are reported in
+/// the returned `VerificationReport`.
+pub fn verify(conn: &rusqlite::Connection, schema: &Schema) -> Result<VerificationReport> {
+    let mut problems = vec![];
+    check_integrity(conn, &mut problems)?;
+    check_attributes(conn, schema, &mut problems)?;
+    check_cardinality(conn, schema, &mut problems)?;
+    check_uniqueness(conn, schema, &mut problems)?;
+    check_partitions(conn, &mut problems)?;
+    check_fulltext_values(conn, schema, &mut problems)?;
+    Ok(VerificationReport { problems: problems })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bootstrap;
+    use db::{
+        ensure_current_version,
+        new_connection,
+    };
+    use entids;
+    use types::DB;
+
+    fn fresh() -> (rusqlite::Connection, DB) {
+        let mut conn = new_connection("").expect("Couldn't open in-memory db");
+        let db = ensure_current_version(&mut conn).expect("bootstrapped");
+        (conn, db)
+    }
+
+    #[test]
+    fn test_verify_fresh_store() {
+        let (conn, db) = fresh();
+        assert_eq!(verify(&conn, &db.schema).expect("verified"), VerificationReport::default());
+    }
+
+    #[test]
+    fn test_verify_value_type_mismatch() {
+        let (conn, db) = fresh();
+
+        // :db/doc is a string, but this claims to be a long.
+        conn.execute("INSERT INTO datoms (e, a, v, tx, value_type_tag) VALUES (?, ?, 1, ?, 5)",
+                     &[&entids::DB_IDENT, &entids::DB_DOC, &bootstrap::TX0]).expect("inserted");
+
+        let report = verify(&conn, &db.schema).expect("verified");
+        assert_eq!(report.problems, vec![VerificationProblem::ValueTypeMismatch {
+            e: entids::DB_IDENT,
+            a: entids::DB_DOC,
+            expected: ValueType::String,
+            value_type_tag: 5,
+        }]);
+    }
+
+    #[test]
+    fn test_verify_unknown_attribute() {
+        let (conn, db) = fresh();
+
+        conn.execute("INSERT INTO datoms (e, a, v, tx, value_type_tag) VALUES (?, 9999, 1, ?, 5)",
+                     &[&entids::DB_IDENT, &bootstrap::TX0]).expect("inserted");
+
+        let report = verify(&conn, &db.schema).expect("verified");
+        assert_eq!(report.problems, vec![VerificationProblem::UnknownAttribute {
+            e: entids::DB_IDENT,
+            a: 9999,
+        }]);
+    }
+
+    #[test]
+    fn test_verify_cardinality() {
+        let (conn, db) = fresh();
+
+        // :db/doc is cardinality one.
+        conn.execute("INSERT INTO datoms (e, a, v, tx, value_type_tag) VALUES (?, ?, 'one', ?, 10), (?, ?, 'two', ?, 10)",
+                     &[&entids::DB_IDENT, &entids::DB_DOC, &bootstrap::TX0,
+                       &entids::DB_IDENT, &entids::DB_DOC, &bootstrap::TX0]).expect("inserted");
+
+        let report = verify(&conn, &db.schema).expect("verified");
+        assert_eq!(report.problems, vec![VerificationProblem::CardinalityOneViolation {
+            e: entids::DB_IDENT,
+            a: entids::DB_DOC,
+            count: 2,
+        }]);
+    }
+
+    #[test]
+    fn test_verify_uniqueness() {
+        let (conn, db) = fresh();
+
+        // Give :db/doc the same ident as :db/ident, dodging the unique index on the way.
+        conn.execute("UPDATE datoms SET unique_value = 0, v = (SELECT v FROM datoms WHERE e = ? AND a = ?) WHERE e = ? AND a = ?",
+                     &[&entids::DB_IDENT, &entids::DB_IDENT, &entids::DB_DOC, &entids::DB_IDENT]).expect("updated");
+
+        let report = verify(&conn, &db.schema).expect("verified");
+        assert_eq!(report.problems, vec![VerificationProblem::UniqueViolation {
+            a: entids::DB_IDENT,
+            entities: vec![entids::DB_IDENT, entids::DB_DOC],
+        }]);
+    }
+
+    #[test]
+    fn test_verify_partition_index() {
+        let (conn, db) = fresh();
+        let db_part = db.partition_map[":db.part/db"].clone();
+        let start = db_part.start;
+
+        conn.execute("UPDATE parts SET idx = start WHERE part = ':db.part/db'", &[]).expect("updated");
+
+        let report = verify(&conn, &db.schema).expect("verified");
+        assert_eq!(report.problems.len(), 1);
+        match report.problems[0] {
+            VerificationProblem::PartitionIndexTooLow { ref part, index, max_entid } => {
+                assert_eq!(part, ":db.part/db");
+                assert_eq!(index, start);
+                assert!(max_entid >= start && max_entid < db_part.index);
+            },
+            ref x => panic!("expected a partition problem, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_verify_orphaned_fulltext_value() {
+        let (conn, db) = fresh();
+
+        conn.execute("INSERT INTO fulltext_values (text) VALUES ('orphan')", &[]).expect("inserted");
+        let rowid = conn.last_insert_rowid();
+
+        let report = verify(&conn, &db.schema).expect("verified");
+        assert_eq!(report.problems, vec![VerificationProblem::OrphanedFulltextValue {
+            rowid: rowid,
+            text: "orphan".to_string(),
+        }]);
+    }
+}
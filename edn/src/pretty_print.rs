@@ -15,6 +15,7 @@ use std::io;
 use std::borrow::Cow;
 
 use types::Value;
+use utils::escape_text;
 
 impl Value {
     /// Return a pretty string representation of this `Value`.
@@ -24,6 +25,12 @@ impl Value {
         Ok(String::from_utf8_lossy(&out).into_owned())
     }
 
+    /// Return a pretty string representation of this `Value`, 120 columns wide.  Unlike
+    /// `to_pretty`, this can't fail.
+    pub fn to_pretty_string(&self) -> String {
+        self.to_pretty(120).expect("writing to memory to succeed")
+    }
+
     /// Write a pretty representation of this `Value` to the given writer.
     pub fn write_pretty<W>(&self, width: usize, out: &mut W) -> Result<(), io::Error> where W: io::Write {
         self.as_doc(&pretty::BoxAllocator).1.render(width, out)
@@ -68,7 +75,7 @@ impl Value {
             Value::PlainSymbol(ref v) => pp.text(v.0.as_ref()),
             Value::NamespacedKeyword(ref v) => pp.text(":").append(v.namespace.as_ref()).append("/").append(v.name.as_ref()),
             Value::Keyword(ref v) => pp.text(":").append(v.0.as_ref()),
-            Value::Text(ref v) => pp.text("\"").append(v.as_ref()).append("\""),
+            Value::Text(ref v) => pp.text("\"").append(escape_text(v).into_owned()).append("\""),
             Value::Uuid(ref u) => pp.text("#uuid \"").append(u.hyphenated().to_string()).append("\""),
             _ => pp.text(self.to_string())
         }
@@ -95,6 +102,16 @@ mod test {
         assert_eq!(data.to_pretty(40).unwrap(), "[[] () #{} {} \"\"]");
     }
 
+    #[test]
+    fn test_to_pretty_string() {
+        let string = "[:a/b #inst \"2017-04-28T20:23:05.187123456Z\" #uuid \"550e8400-e29b-41d4-a716-446655440000\" \"c\" 1.5]";
+        let data = parse::value(string).unwrap().without_spans();
+
+        let pretty = data.to_pretty_string();
+        assert_eq!(pretty, "[:a/b #inst \"2017-04-28T20:23:05.187123456+00:00\" #uuid \"550e8400-e29b-41d4-a716-446655440000\" \"c\" 1.5]");
+        assert_eq!(parse::value(&pretty).unwrap().without_spans(), data);
+    }
+
     #[test]
     fn test_vector() {
         let string = "[1 2 3 4 5 6]";
@@ -25,6 +25,7 @@ use ordered_float::OrderedFloat;
 use uuid::Uuid;
 
 use symbols;
+use utils::escape_text;
 
 /// Value represents one of the allowed values in an EDN string.
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -84,6 +85,18 @@ impl Span {
     pub fn new(start: usize, end: usize) -> Span {
         Span(start as u32, end as u32)
     }
+
+    /// Return the 1-based line and column at which this span starts in `source`, which should be
+    /// the string that was parsed to produce the spanned value.  Columns count characters, not
+    /// bytes.
+    pub fn start_line_and_column(&self, source: &str) -> (usize, usize) {
+        let offset = ::std::cmp::min(self.0 as usize, source.len());
+        let before = &source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = before[line_start..].chars().count() + 1;
+        (line, column)
+    }
 }
 
 /// A wrapper type around `SpannedValue` and `Span`, representing some EDN value
@@ -163,6 +176,36 @@ impl From<SpannedValue> for Value {
     }
 }
 
+impl From<Value> for SpannedValue {
+    fn from(src: Value) -> SpannedValue {
+        match src {
+            Value::Nil => SpannedValue::Nil,
+            Value::Boolean(v) => SpannedValue::Boolean(v),
+            Value::Integer(v) => SpannedValue::Integer(v),
+            Value::Instant(v) => SpannedValue::Instant(v),
+            Value::BigInteger(v) => SpannedValue::BigInteger(v),
+            Value::Float(v) => SpannedValue::Float(v),
+            Value::Text(v) => SpannedValue::Text(v),
+            Value::Uuid(v) => SpannedValue::Uuid(v),
+            Value::PlainSymbol(v) => SpannedValue::PlainSymbol(v),
+            Value::NamespacedSymbol(v) => SpannedValue::NamespacedSymbol(v),
+            Value::Keyword(v) => SpannedValue::Keyword(v),
+            Value::NamespacedKeyword(v) => SpannedValue::NamespacedKeyword(v),
+            Value::Vector(v) => SpannedValue::Vector(v.into_iter().map(ValueAndSpan::from).collect()),
+            Value::List(v) => SpannedValue::List(v.into_iter().map(ValueAndSpan::from).collect()),
+            Value::Set(v) => SpannedValue::Set(v.into_iter().map(ValueAndSpan::from).collect()),
+            Value::Map(v) => SpannedValue::Map(v.into_iter().map(|(x, y)| (ValueAndSpan::from(x), ValueAndSpan::from(y))).collect()),
+        }
+    }
+}
+
+/// Values that weren't produced by parsing have no meaningful span: every span is empty.
+impl From<Value> for ValueAndSpan {
+    fn from(src: Value) -> ValueAndSpan {
+        ValueAndSpan::new(SpannedValue::from(src), None)
+    }
+}
+
 /// Creates `from_$TYPE` helper functions for Value and SpannedValue,
 /// like `from_float()` or `from_ordered_float()`.
 macro_rules! def_from {
@@ -406,6 +449,28 @@ macro_rules! def_common_value_methods {
             !self.is_collection()
         }
 
+        /// A short human-readable name for the kind of this value, suitable for error messages.
+        pub fn type_name(&self) -> &'static str {
+            match *self {
+                $t::Nil => "nil",
+                $t::Boolean(_) => "boolean",
+                $t::Integer(_) => "integer",
+                $t::Instant(_) => "instant",
+                $t::BigInteger(_) => "big integer",
+                $t::Float(_) => "float",
+                $t::Text(_) => "text",
+                $t::Uuid(_) => "uuid",
+                $t::PlainSymbol(_) => "symbol",
+                $t::NamespacedSymbol(_) => "namespaced symbol",
+                $t::Keyword(_) => "keyword",
+                $t::NamespacedKeyword(_) => "namespaced keyword",
+                $t::Vector(_) => "vector",
+                $t::List(_) => "list",
+                $t::Set(_) => "set",
+                $t::Map(_) => "map",
+            }
+        }
+
         pub fn into_atom(self) -> Option<$t> {
             if self.is_atom() {
                 Some(self)
@@ -451,7 +516,7 @@ macro_rules! def_common_value_display {
             $t::Nil => write!($f, "nil"),
             $t::Boolean(v) => write!($f, "{}", v),
             $t::Integer(v) => write!($f, "{}", v),
-            $t::Instant(v) => write!($f, "{}", v),
+            $t::Instant(v) => write!($f, "#inst \"{}\"", v.to_rfc3339()),
             $t::BigInteger(ref v) => write!($f, "{}N", v),
             // TODO: make sure float syntax is correct.
             $t::Float(ref v) => {
@@ -465,8 +530,7 @@ macro_rules! def_common_value_display {
                     write!($f, "{}", v)
                 }
             }
-            // TODO: EDN escaping.
-            $t::Text(ref v) => write!($f, "\"{}\"", v),
+            $t::Text(ref v) => write!($f, "\"{}\"", escape_text(v)),
             $t::Uuid(ref u) => write!($f, "#uuid \"{}\"", u.hyphenated().to_string()),
             $t::PlainSymbol(ref v) => v.fmt($f),
             $t::NamespacedSymbol(ref v) => v.fmt($f),
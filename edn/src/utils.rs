@@ -10,6 +10,8 @@
 
 #![allow(dead_code)]
 
+use std::borrow::Cow;
+
 use types::Value;
 
 /// Merge the EDN `Value::Map` instance `right` into `left`.  Returns `None` if either `left` or
@@ -29,3 +31,124 @@ pub fn merge(left: &Value, right: &Value) -> Option<Value> {
         _ => None
     }
 }
+
+/// Escape `s` for writing between the quotes of an EDN string: each backslash and double quote is
+/// preceded by a backslash.  `unescape_text` undoes this.
+pub fn escape_text(s: &str) -> Cow<str> {
+    if !s.contains(|c: char| c == '"' || c == '\\') {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Cow::Owned(escaped)
+}
+
+/// Undo the escapes in `s`, the text between the quotes of an EDN string: `\"`, `\\`, `\n`, `\t`,
+/// and `\r`.  A backslash before any other character is kept as written.
+pub fn unescape_text(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            },
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ScanState {
+    Form,
+    Text,
+    TextEscape,
+    Comment,
+    CharEscape,
+}
+
+/// Tracks how deeply nested EDN text is, one byte at a time, without parsing it: for finding where
+/// a form ends in a stream, or whether more input is needed.
+///
+/// Brackets, braces, and parentheses inside strings (which may contain backslash escapes),
+/// comments, and character literals don't count.  Every delimiter is ASCII, so UTF-8 text can be
+/// scanned byte by byte.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FormScanner {
+    depth: isize,
+    state: ScanState,
+}
+
+impl Default for FormScanner {
+    fn default() -> FormScanner {
+        FormScanner {
+            depth: 0,
+            state: ScanState::Form,
+        }
+    }
+}
+
+impl FormScanner {
+    pub fn new() -> FormScanner {
+        FormScanner::default()
+    }
+
+    /// Scan the next byte.
+    pub fn push(&mut self, b: u8) {
+        self.state = match (self.state, b) {
+            (ScanState::Form, b'[') | (ScanState::Form, b'{') | (ScanState::Form, b'(') => {
+                self.depth += 1;
+                ScanState::Form
+            },
+            (ScanState::Form, b']') | (ScanState::Form, b'}') | (ScanState::Form, b')') => {
+                self.depth -= 1;
+                ScanState::Form
+            },
+            (ScanState::Form, b'"') => ScanState::Text,
+            (ScanState::Form, b';') => ScanState::Comment,
+            (ScanState::Form, b'\\') => ScanState::CharEscape,
+            (ScanState::Form, _) => ScanState::Form,
+            (ScanState::Text, b'"') => ScanState::Form,
+            (ScanState::Text, b'\\') => ScanState::TextEscape,
+            (ScanState::Text, _) => ScanState::Text,
+            (ScanState::TextEscape, _) => ScanState::Text,
+            (ScanState::Comment, b'\n') => ScanState::Form,
+            (ScanState::Comment, _) => ScanState::Comment,
+            (ScanState::CharEscape, _) => ScanState::Form,
+        };
+    }
+
+    /// Scan each byte of `s`.
+    pub fn push_str(&mut self, s: &str) {
+        for &b in s.as_bytes() {
+            self.push(b);
+        }
+    }
+
+    /// The number of forms opened and not yet closed.  Negative if more have been closed than
+    /// opened.
+    pub fn depth(&self) -> isize {
+        self.depth
+    }
+
+    /// True if every form opened has been closed, and the text doesn't end inside a string.
+    pub fn is_balanced(&self) -> bool {
+        self.depth <= 0 && self.state != ScanState::Text && self.state != ScanState::TextEscape
+    }
+}
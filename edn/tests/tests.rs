@@ -325,8 +325,18 @@ fn test_text() {
 
     assert_eq!(text("\"hello world\"").unwrap(), Text("hello world".to_string()));
     assert_eq!(text("\"\"").unwrap(), Text("".to_string()));
+    assert_eq!(text(r#""say \"hi\"""#).unwrap(), Text("say \"hi\"".to_string()));
+    assert_eq!(text(r#""C:\\""#).unwrap(), Text("C:\\".to_string()));
+    assert_eq!(text(r#""a\tb\nc""#).unwrap(), Text("a\tb\nc".to_string()));
+    assert_eq!(text(r#""\d+""#).unwrap(), Text("\\d+".to_string()));
+
+    // Escapes are written back out.
+    let escaped = Text("say \"hi\" C:\\".to_string());
+    assert_eq!(escaped.to_string(), r#""say \"hi\" C:\\""#);
+    assert_eq!(text(escaped.to_string().as_str()).unwrap(), escaped);
 
     assert!(text("\"").is_err());
+    assert!(text(r#""\""#).is_err());
     assert!(text("nil").is_err());
 }
 
@@ -1491,3 +1501,37 @@ List(LinkedList::from_iter(vec![
 Set(BTreeSet::from_iter(vec![
 ])),
 */
+
+#[test]
+fn test_span_start_line_and_column() {
+    let source = "[:a\n  [:b :c]\n  \"ü\" :d]";
+    let parsed = parse::value(source).expect("to parse EDN");
+    assert_eq!(parsed.span.start_line_and_column(source), (1, 1));
+
+    let children = parsed.inner.as_vector().expect("a vector");
+    assert_eq!(children[1].span.start_line_and_column(source), (2, 3));
+    assert_eq!(children[3].span.start_line_and_column(source), (3, 7));
+}
+
+#[test]
+fn test_form_scanner() {
+    let scan = |s: &str| {
+        let mut scanner = utils::FormScanner::new();
+        scanner.push_str(s);
+        (scanner.depth(), scanner.is_balanced())
+    };
+
+    assert_eq!(scan("[:a {:b (1 2)}]"), (0, true));
+    assert_eq!(scan("[:a {:b"), (2, false));
+    assert_eq!(scan("[:a]]"), (-1, true));
+
+    // Delimiters in strings, including after escaped quotes and backslashes, don't count.
+    assert_eq!(scan(r#"["[" "\"[" "\\" "]"]"#), (0, true));
+    assert_eq!(scan(r#"["\\"]"#), (0, true));
+    assert_eq!(scan(r#"["\"]"#), (1, false));
+    assert_eq!(scan(r#"["abc"#), (1, false));
+
+    // Nor do those in comments or character literals.
+    assert_eq!(scan("[; ]]\n \\[]"), (0, true));
+    assert_eq!(scan("[; ]]\n"), (1, false));
+}
@@ -0,0 +1,68 @@
+/* Copyright 2016 Mozilla
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+
+/* The C API to Mentat.  See ffi/src/lib.rs for ownership and error-reporting rules. */
+
+#ifndef MENTAT_H
+#define MENTAT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+struct Store;
+struct TxReport;
+struct QueryResults;
+struct TypedValue;
+
+/* `message` is null if the call succeeded; otherwise release it with `mentat_destroy_string`. */
+struct ExternError {
+    char *message;
+};
+
+/* The values returned by `mentat_typed_value_get_type`. */
+enum ValueType {
+    ValueTypeRef = 0,
+    ValueTypeBoolean = 1,
+    ValueTypeInstant = 2,
+    ValueTypeLong = 3,
+    ValueTypeDouble = 4,
+    ValueTypeString = 5,
+    ValueTypeKeyword = 6,
+    ValueTypeUuid = 7,
+};
+
+struct Store *mentat_store_open(const char *path, struct ExternError *error);
+void mentat_store_destroy(struct Store *store);
+
+struct TxReport *mentat_transact(struct Store *store, const char *transaction, struct ExternError *error);
+int64_t mentat_tx_report_get_tx_id(const struct TxReport *report);
+int64_t mentat_tx_report_get_tx_instant(const struct TxReport *report);
+int mentat_tx_report_get_entid_for_tempid(const struct TxReport *report, const char *tempid, int64_t *entid);
+void mentat_tx_report_destroy(struct TxReport *report);
+
+struct QueryResults *mentat_q_once(struct Store *store, const char *query, struct ExternError *error);
+size_t mentat_query_results_row_count(const struct QueryResults *results);
+size_t mentat_query_results_column_count(const struct QueryResults *results);
+const struct TypedValue *mentat_query_results_get(const struct QueryResults *results, size_t row, size_t column);
+void mentat_query_results_destroy(struct QueryResults *results);
+
+int mentat_typed_value_get_type(const struct TypedValue *value);
+int64_t mentat_typed_value_as_entid(const struct TypedValue *value);
+int64_t mentat_typed_value_as_long(const struct TypedValue *value);
+int mentat_typed_value_as_boolean(const struct TypedValue *value);
+double mentat_typed_value_as_double(const struct TypedValue *value);
+int64_t mentat_typed_value_as_timestamp(const struct TypedValue *value);
+char *mentat_typed_value_as_string(const struct TypedValue *value);
+char *mentat_typed_value_as_keyword(const struct TypedValue *value);
+int mentat_typed_value_as_uuid(const struct TypedValue *value, uint8_t bytes[16]);
+
+void mentat_destroy_string(char *s);
+
+#endif /* MENTAT_H */
@@ -0,0 +1,422 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A C API for opening a Mentat store, transacting against it, and querying it, for use from
+//! Swift, Kotlin, and anything else that can call C.  See `mentat.h` for the declarations.
+//!
+//! Every pointer returned by a `mentat_*` function belongs to the caller, and must be released
+//! with the matching `*_destroy` function; strings are released with `mentat_destroy_string`.  The
+//! one exception is `mentat_query_results_get`, which borrows a value from its results.
+//!
+//! Functions that can fail take an `ExternError` out-parameter.  On failure its `message` is set
+//! to a description of the error, which the caller must release, and the function returns null;
+//! on success `message` is set to null.  Panics never cross the boundary: a panic is reported as
+//! an error, or, by functions that can't fail, as a null or zero result.
+
+extern crate mentat;
+extern crate rusqlite;
+
+use std::any::Any;
+use std::ffi::{
+    CStr,
+    CString,
+};
+use std::os::raw::{
+    c_char,
+    c_int,
+};
+use std::panic;
+use std::ptr;
+
+use mentat::{
+    Conn,
+    QueryResults,
+    TxReport,
+    TypedValue,
+};
+
+/// An open store: a `Conn` together with the SQLite connection that backs it.
+pub struct Store {
+    conn: Conn,
+    sqlite: rusqlite::Connection,
+}
+
+/// Reports the failure of a call.  `message` is null if the call succeeded.
+#[repr(C)]
+pub struct ExternError {
+    pub message: *mut c_char,
+}
+
+fn string_to_c(s: String) -> *mut c_char {
+    // C strings can't contain NUL, so escape any rather than failing.
+    CString::new(s.replace('\0', "\\0")).expect("NULs to be escaped").into_raw()
+}
+
+unsafe fn c_to_str<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err("unexpected null string".to_string());
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| format!("invalid UTF-8: {}", e))
+}
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(s), _) => format!("panic: {}", s),
+        (_, Some(s)) => format!("panic: {}", s),
+        _ => "panic".to_string(),
+    }
+}
+
+fn set_error(error: *mut ExternError, message: Option<String>) {
+    if error.is_null() {
+        return;
+    }
+    unsafe {
+        (*error).message = message.map_or(ptr::null_mut(), string_to_c);
+    }
+}
+
+/// Run `f`, returning its result, or reporting its error or panic through `error` and returning
+/// `default`.
+fn call_with_error<R, F>(error: *mut ExternError, default: R, f: F) -> R where F: FnOnce() -> Result<R, String> {
+    let result = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => Err(panic_message(payload)),
+    };
+    match result {
+        Ok(r) => {
+            set_error(error, None);
+            r
+        },
+        Err(message) => {
+            set_error(error, Some(message));
+            default
+        },
+    }
+}
+
+/// Run `f`, which can't fail, returning `default` if it panics.
+fn call<R, F>(default: R, f: F) -> R where F: FnOnce() -> R {
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or(default)
+}
+
+/// Open the store at `path`, creating it if necessary.  An empty path opens an in-memory store.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_open(path: *const c_char, error: *mut ExternError) -> *mut Store {
+    call_with_error(error, ptr::null_mut(), || {
+        let path = c_to_str(path)?;
+        let (conn, sqlite) = Conn::open(path).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(Store {
+            conn: conn,
+            sqlite: sqlite,
+        })))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_destroy(store: *mut Store) {
+    if !store.is_null() {
+        call((), || drop(Box::from_raw(store)));
+    }
+}
+
+/// Transact the EDN `transaction` against `store`, returning a report of what was transacted.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_transact(store: *mut Store, transaction: *const c_char, error: *mut ExternError) -> *mut TxReport {
+    call_with_error(error, ptr::null_mut(), || {
+        let store = store.as_mut().ok_or_else(|| "unexpected null store".to_string())?;
+        let transaction = c_to_str(transaction)?;
+        let report = store.conn.transact(&mut store.sqlite, transaction).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(report)))
+    })
+}
+
+/// Run the EDN `query` against `store`, returning its results.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_q_once(store: *mut Store, query: *const c_char, error: *mut ExternError) -> *mut QueryResults {
+    call_with_error(error, ptr::null_mut(), || {
+        let store = store.as_ref().ok_or_else(|| "unexpected null store".to_string())?;
+        let query = c_to_str(query)?;
+        let output = store.conn.q_once(&store.sqlite, query, None).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(output.results)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_get_tx_id(report: *const TxReport) -> i64 {
+    call(0, || report.as_ref().map_or(0, |report| report.tx_id))
+}
+
+/// The instant at which the transaction was committed, in microseconds since the epoch.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_get_tx_instant(report: *const TxReport) -> i64 {
+    call(0, || report.as_ref().map_or(0, |report| {
+        report.tx_instant.timestamp() * 1_000_000 + report.tx_instant.timestamp_subsec_micros() as i64
+    }))
+}
+
+/// Store the entid that `tempid` resolved to in `entid`, returning 1, or return 0 if the
+/// transaction didn't mention `tempid`.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_get_entid_for_tempid(report: *const TxReport, tempid: *const c_char, entid: *mut i64) -> c_int {
+    call(0, || {
+        let e = match (report.as_ref(), c_to_str(tempid)) {
+            (Some(report), Ok(tempid)) => report.tempids.get(tempid).cloned(),
+            _ => None,
+        };
+        match (e, entid.as_mut()) {
+            (Some(e), Some(entid)) => {
+                *entid = e;
+                1
+            },
+            _ => 0,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_destroy(report: *mut TxReport) {
+    if !report.is_null() {
+        call((), || drop(Box::from_raw(report)));
+    }
+}
+
+fn column_count(results: &QueryResults) -> usize {
+    match *results {
+        QueryResults::Scalar(_) | QueryResults::Coll(_) => 1,
+        QueryResults::Tuple(ref t) => t.as_ref().map_or(0, |t| t.len()),
+        QueryResults::Rel(ref r) => r.first().map_or(0, |row| row.len()),
+    }
+}
+
+fn cell(results: &QueryResults, row: usize, column: usize) -> Option<&TypedValue> {
+    match *results {
+        QueryResults::Scalar(ref v) if row == 0 && column == 0 => v.as_ref(),
+        QueryResults::Tuple(Some(ref t)) if row == 0 => t.get(column),
+        QueryResults::Coll(ref c) if column == 0 => c.get(row),
+        QueryResults::Rel(ref r) => r.get(row).and_then(|row| row.get(column)),
+        _ => None,
+    }
+}
+
+/// The number of rows in `results`.  A scalar or tuple has at most one row, and a collection has a
+/// row per value.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_row_count(results: *const QueryResults) -> usize {
+    call(0, || results.as_ref().map_or(0, |results| results.len()))
+}
+
+/// The number of columns in `results`.  A scalar or collection has one column; an empty tuple or
+/// relation has none.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_column_count(results: *const QueryResults) -> usize {
+    call(0, || results.as_ref().map_or(0, column_count))
+}
+
+/// Return the value at `row` and `column` of `results`, or null if there is none.  The value is
+/// borrowed: it is valid until `results` is destroyed, and must not be destroyed itself.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_get(results: *const QueryResults, row: usize, column: usize) -> *const TypedValue {
+    call(ptr::null(), || {
+        results.as_ref()
+               .and_then(|results| cell(results, row, column))
+               .map_or(ptr::null(), |value| value as *const TypedValue)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_destroy(results: *mut QueryResults) {
+    if !results.is_null() {
+        call((), || drop(Box::from_raw(results)));
+    }
+}
+
+/// The `ValueType` of `value`, as numbered in `mentat.h`, or -1 if `value` is null.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_get_type(value: *const TypedValue) -> c_int {
+    call(-1, || value.as_ref().map_or(-1, |value| value.value_type() as c_int))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_entid(value: *const TypedValue) -> i64 {
+    call(0, || match value.as_ref() {
+        Some(&TypedValue::Ref(e)) => e,
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_long(value: *const TypedValue) -> i64 {
+    call(0, || match value.as_ref() {
+        Some(&TypedValue::Long(l)) => l,
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_boolean(value: *const TypedValue) -> c_int {
+    call(0, || match value.as_ref() {
+        Some(&TypedValue::Boolean(b)) => b as c_int,
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_double(value: *const TypedValue) -> f64 {
+    call(0.0, || match value.as_ref() {
+        Some(&TypedValue::Double(d)) => d.into_inner(),
+        _ => 0.0,
+    })
+}
+
+/// An instant, in microseconds since the epoch.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_timestamp(value: *const TypedValue) -> i64 {
+    call(0, || match value.as_ref() {
+        Some(&TypedValue::Instant(ref t)) => t.timestamp() * 1_000_000 + t.timestamp_subsec_micros() as i64,
+        _ => 0,
+    })
+}
+
+/// A copy of a string value, to be released with `mentat_destroy_string`, or null if `value` isn't
+/// a string.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_string(value: *const TypedValue) -> *mut c_char {
+    call(ptr::null_mut(), || match value.as_ref() {
+        Some(&TypedValue::String(ref s)) => string_to_c((**s).clone()),
+        _ => ptr::null_mut(),
+    })
+}
+
+/// A keyword value, like `:db/ident`, to be released with `mentat_destroy_string`, or null if
+/// `value` isn't a keyword.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_keyword(value: *const TypedValue) -> *mut c_char {
+    call(ptr::null_mut(), || match value.as_ref() {
+        Some(&TypedValue::Keyword(ref k)) => string_to_c(k.to_string()),
+        _ => ptr::null_mut(),
+    })
+}
+
+/// Copy the 16 bytes of a UUID value to `bytes`, returning 1, or return 0 if `value` isn't a UUID.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_as_uuid(value: *const TypedValue, bytes: *mut u8) -> c_int {
+    call(0, || match value.as_ref() {
+        Some(&TypedValue::Uuid(ref u)) if !bytes.is_null() => {
+            ptr::copy_nonoverlapping(u.as_bytes().as_ptr(), bytes, 16);
+            1
+        },
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_destroy_string(s: *mut c_char) {
+    if !s.is_null() {
+        call((), || drop(CString::from_raw(s)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat::ValueType;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn no_error() -> ExternError {
+        ExternError { message: ptr::null_mut() }
+    }
+
+    /// Take ownership of a string returned by the C API.
+    unsafe fn take(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let owned = CStr::from_ptr(s).to_str().unwrap().to_string();
+        mentat_destroy_string(s);
+        owned
+    }
+
+    #[test]
+    fn test_ffi() {
+        unsafe {
+            let mut error = no_error();
+            let store = mentat_store_open(c("").as_ptr(), &mut error);
+            assert!(error.message.is_null());
+            assert!(!store.is_null());
+
+            let schema = c("[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                             {:db/ident :test/tag :db/valueType :db.type/keyword :db/cardinality :db.cardinality/one}]");
+            mentat_tx_report_destroy(mentat_transact(store, schema.as_ptr(), &mut error));
+            assert!(error.message.is_null());
+
+            let report = mentat_transact(store, c(r#"[{:db/id "a" :test/name "Alice" :test/tag :test/x}]"#).as_ptr(), &mut error);
+            assert!(error.message.is_null());
+            assert!(mentat_tx_report_get_tx_id(report) > 0);
+            assert!(mentat_tx_report_get_tx_instant(report) > 0);
+            let mut alice = 0;
+            assert_eq!(mentat_tx_report_get_entid_for_tempid(report, c("a").as_ptr(), &mut alice), 1);
+            assert_eq!(mentat_tx_report_get_entid_for_tempid(report, c("b").as_ptr(), &mut alice), 0);
+            mentat_tx_report_destroy(report);
+
+            let results = mentat_q_once(store, c("[:find ?e ?name ?tag :where [?e :test/name ?name] [?e :test/tag ?tag]]").as_ptr(), &mut error);
+            assert!(error.message.is_null());
+            assert_eq!(mentat_query_results_row_count(results), 1);
+            assert_eq!(mentat_query_results_column_count(results), 3);
+
+            let e = mentat_query_results_get(results, 0, 0);
+            assert_eq!(mentat_typed_value_get_type(e), ValueType::Ref as c_int);
+            assert_eq!(mentat_typed_value_as_entid(e), alice);
+            assert_eq!(mentat_typed_value_as_long(e), 0);
+
+            let name = mentat_query_results_get(results, 0, 1);
+            assert_eq!(mentat_typed_value_get_type(name), ValueType::String as c_int);
+            assert_eq!(take(mentat_typed_value_as_string(name)), "Alice");
+            assert!(mentat_typed_value_as_keyword(name).is_null());
+
+            let tag = mentat_query_results_get(results, 0, 2);
+            assert_eq!(take(mentat_typed_value_as_keyword(tag)), ":test/x");
+
+            assert!(mentat_query_results_get(results, 1, 0).is_null());
+            assert!(mentat_query_results_get(results, 0, 3).is_null());
+            assert_eq!(mentat_typed_value_get_type(ptr::null()), -1);
+            mentat_query_results_destroy(results);
+
+            // A scalar result has one column.
+            let results = mentat_q_once(store, c("[:find ?name . :where [_ :test/name ?name]]").as_ptr(), &mut error);
+            assert_eq!(mentat_query_results_row_count(results), 1);
+            assert_eq!(mentat_query_results_column_count(results), 1);
+            mentat_query_results_destroy(results);
+
+            // Errors are reported through the out-parameter.
+            let results = mentat_q_once(store, c("[:find ?x :where]").as_ptr(), &mut error);
+            assert!(results.is_null());
+            assert!(!take(error.message).is_empty());
+
+            let report = mentat_transact(store, ptr::null(), &mut error);
+            assert!(report.is_null());
+            assert_eq!(take(error.message), "unexpected null string");
+
+            mentat_store_destroy(store);
+        }
+    }
+
+    #[test]
+    fn test_panics_are_caught() {
+        let mut error = no_error();
+        let result = call_with_error(&mut error, 0, || -> Result<i32, String> { panic!("boom") });
+        assert_eq!(result, 0);
+        assert_eq!(unsafe { take(error.message) }, "panic: boom");
+
+        assert_eq!(call(7, || -> i32 { panic!("boom") }), 7);
+    }
+}
@@ -21,6 +21,8 @@ extern crate itertools;
 #[derive(PartialEq)]
 pub struct ValueParseError {
     pub position: edn::Span,
+    /// The 1-based line and column of `position`, if the source text is known.  See `with_source`.
+    pub line_and_column: Option<(usize, usize)>,
     // Think of this as `Vec<Error<edn::Value, DisplayVec<edn::Value>>>`; see above.
     pub errors: Vec<combine::primitives::Error<edn::ValueAndSpan, edn::ValueAndSpan>>,
 }
@@ -43,18 +45,31 @@ pub use log::{
     LogParsing,
 };
 
+impl ValueParseError {
+    /// Locate this error's position in `source`, the EDN text that was parsed to produce the values
+    /// that failed to parse, so that the error can report a line and column.
+    pub fn with_source(mut self, source: &str) -> ValueParseError {
+        self.line_and_column = Some(self.position.start_line_and_column(source));
+        self
+    }
+}
+
 impl std::fmt::Debug for ValueParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f,
-               "ParseError {{ position: {:?}, errors: {:?} }}",
+               "ParseError {{ position: {:?}, line_and_column: {:?}, errors: {:?} }}",
                self.position,
+               self.line_and_column,
                self.errors)
     }
 }
 
 impl std::fmt::Display for ValueParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        try!(writeln!(f, "Parse error at {:?}", self.position));
+        match self.line_and_column {
+            Some((line, column)) => try!(writeln!(f, "Parse error at line {}, column {} ({:?})", line, column, self.position)),
+            None => try!(writeln!(f, "Parse error at {:?}", self.position)),
+        }
         combine::primitives::Error::fmt_errors(&self.errors, f)
     }
 }
@@ -69,6 +84,7 @@ impl<'a> From<combine::primitives::ParseError<Stream<'a>>> for ValueParseError {
     fn from(e: combine::primitives::ParseError<Stream<'a>>) -> ValueParseError {
         ValueParseError {
             position: e.position.0,
+            line_and_column: None,
             errors: e.errors.into_iter()
                 .map(|e| e.map_token(|t| t.clone()).map_range(|r| r.clone()))
                 .collect(),
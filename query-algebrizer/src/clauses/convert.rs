@@ -8,7 +8,7 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use mentat_core::{
     Schema,
@@ -165,7 +165,7 @@ impl ConjoiningClauses {
                     (true, true) => {
                         // Ambiguous: this could be a keyword or an ident.
                         // Default to keyword.
-                        Ok(Val(TypedValue::Keyword(Rc::new(x))))
+                        Ok(Val(TypedValue::Keyword(Arc::new(x))))
                     },
                     (true, false) => {
                         // This can only be an ident. Look it up!
@@ -175,7 +175,7 @@ impl ConjoiningClauses {
                         }
                     },
                     (false, true) => {
-                        Ok(Val(TypedValue::Keyword(Rc::new(x))))
+                        Ok(Val(TypedValue::Keyword(Arc::new(x))))
                     },
                     (false, false) => {
                         Ok(Impossible(EmptyBecause::TypeMismatch {
@@ -128,7 +128,6 @@ impl ConjoiningClauses {
 
         if !attribute.fulltext {
             // We can never get results from a non-fulltext attribute!
-            println!("Can't run fulltext on non-fulltext attribute {}.", a);
             self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(a));
             return Ok(());
         }
@@ -253,7 +252,7 @@ impl ConjoiningClauses {
 mod testing {
     use super::*;
 
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     use mentat_core::{
         Attribute,
@@ -299,7 +298,7 @@ mod testing {
             args: vec![
                 FnArg::SrcVar(SrcVar::DefaultSrc),
                 FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "fts")),
-                FnArg::Constant(NonIntegerConstant::Text(Rc::new("needle".into()))),
+                FnArg::Constant(NonIntegerConstant::Text(Arc::new("needle".into()))),
             ],
             binding: Binding::BindRel(vec![VariableOrPlaceholder::Variable(Variable::from_valid_name("?entity")),
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?value")),
@@ -321,7 +320,7 @@ mod testing {
         assert_eq!(clauses.0[1], ColumnConstraint::Equals(QualifiedAlias("datoms01".to_string(), Column::Fixed(DatomsColumn::Value)),
                                                           QueryValue::Column(QualifiedAlias("fulltext_values00".to_string(), Column::Fulltext(FulltextColumn::Rowid)))).into());
         assert_eq!(clauses.0[2], ColumnConstraint::Matches(QualifiedAlias("fulltext_values00".to_string(), Column::Fulltext(FulltextColumn::Text)),
-                                                           QueryValue::TypedValue(TypedValue::String(Rc::new("needle".into())))).into());
+                                                           QueryValue::TypedValue(TypedValue::String(Arc::new("needle".into())))).into());
 
         let bindings = cc.column_bindings;
         assert_eq!(bindings.len(), 3);
@@ -357,7 +356,7 @@ mod testing {
             args: vec![
                 FnArg::SrcVar(SrcVar::DefaultSrc),
                 FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")),
-                FnArg::Constant(NonIntegerConstant::Text(Rc::new("needle".into()))),
+                FnArg::Constant(NonIntegerConstant::Text(Arc::new("needle".into()))),
             ],
             binding: Binding::BindRel(vec![VariableOrPlaceholder::Variable(Variable::from_valid_name("?entity")),
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?value")),
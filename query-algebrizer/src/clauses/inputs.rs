@@ -72,4 +72,51 @@ impl QueryInputs {
         }
         Ok(QueryInputs { types: types, values: values })
     }
+
+    /// Start building a `QueryInputs` one binding at a time:
+    ///
+    /// ```
+    /// # use mentat_query_algebrizer::QueryInputs;
+    /// let inputs = QueryInputs::builder()
+    ///     .bind("?name", "Alice")
+    ///     .bind("?age", 30)
+    ///     .build()
+    ///     .expect("valid inputs");
+    /// assert_eq!(inputs.values.len(), 2);
+    /// ```
+    pub fn builder() -> QueryInputsBuilder {
+        QueryInputsBuilder::default()
+    }
+}
+
+/// Accumulates bindings for a `QueryInputs`.  See `QueryInputs::builder`.
+///
+/// Variable names are checked when the inputs are built, rather than when the query is run:
+/// `build` fails if any name isn't a valid variable name or if any variable is bound twice.
+#[derive(Clone, Debug, Default)]
+pub struct QueryInputsBuilder {
+    bindings: Vec<(String, TypedValue)>,
+}
+
+impl QueryInputsBuilder {
+    /// Bind the variable `name`, which should include the leading `?`, to `value`.
+    pub fn bind<T>(mut self, name: &str, value: T) -> QueryInputsBuilder where T: Into<TypedValue> {
+        self.bindings.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn build(self) -> Result<QueryInputs> {
+        let mut values: BTreeMap<Variable, TypedValue> = BTreeMap::default();
+        for (name, value) in self.bindings {
+            let var = match Variable::from_name(name.as_str()) {
+                Some(var) => var,
+                None => bail!(ErrorKind::InvalidInputVariableName(name)),
+            };
+            if values.contains_key(&var) {
+                bail!(ErrorKind::DuplicateInputBinding(var.name()));
+            }
+            values.insert(var, value);
+        }
+        Ok(QueryInputs::with_values(values))
+    }
 }
@@ -79,14 +79,17 @@ use validate::{
     validate_or_join,
 };
 
-pub use self::inputs::QueryInputs;
+pub use self::inputs::{
+    QueryInputs,
+    QueryInputsBuilder,
+};
 
 // We do this a lot for errors.
 trait RcCloned<T> {
     fn cloned(&self) -> T;
 }
 
-impl<T: Clone> RcCloned<T> for ::std::rc::Rc<T> {
+impl<T: Clone> RcCloned<T> for ::std::sync::Arc<T> {
     fn cloned(&self) -> T {
         self.as_ref().clone()
     }
@@ -922,7 +925,7 @@ fn add_attribute(schema: &mut Schema, e: Entid, a: Attribute) {
 
 #[cfg(test)]
 pub fn ident(ns: &str, name: &str) -> PatternNonValuePlace {
-    PatternNonValuePlace::Ident(::std::rc::Rc::new(NamespacedKeyword::new(ns, name)))
+    PatternNonValuePlace::Ident(::std::sync::Arc::new(NamespacedKeyword::new(ns, name)))
 }
 
 #[cfg(test)]
@@ -72,7 +72,7 @@ impl ConjoiningClauses {
 mod testing {
     extern crate mentat_query_parser;
 
-    use std::rc::Rc;
+    use std::sync::Arc;
     use std::collections::BTreeSet;
 
     use super::*;
@@ -450,7 +450,7 @@ mod testing {
              :where [?x :foo/knows "Bill"]
                     (not [?x :foo/knows ?y])]"#;
 
-        let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?y"),TypedValue::String(Rc::new("John".to_string())))]);
+        let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?y"),TypedValue::String(Arc::new("John".to_string())))]);
         let cc = alg_with_inputs(&schema, query, inputs);
 
         let vx = Variable::from_valid_name("?x");
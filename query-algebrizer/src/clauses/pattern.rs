@@ -28,6 +28,7 @@ use clauses::ConjoiningClauses;
 use types::{
     ColumnConstraint,
     DatomsColumn,
+    DatomsTable,
     EmptyBecause,
     SourceAlias,
 };
@@ -241,6 +242,25 @@ impl ConjoiningClauses {
             },
         }
 
+        // Looking up an attribute with `:db/index true` by value can use the value index, so
+        // long as the query says so.  The index is ordered by type tag before value, so
+        // constrain the tag too; a keyword literal's tag is already constrained above.
+        let (value_is_constant, tag_constrained) = match pattern.value {
+            PatternValuePlace::Placeholder | PatternValuePlace::Variable(_) => (false, false),
+            PatternValuePlace::IdentOrKeyword(_) => (true, value_type != Some(ValueType::Ref)),
+            _ => (true, false),
+        };
+        if value_is_constant && alias.0 == DatomsTable::Datoms {
+            if let Some(attribute) = self.get_attribute(schema, pattern) {
+                if attribute.index && !attribute.fulltext {
+                    self.wheres.add_intersection(ColumnConstraint::ValueIndexed(col.clone()));
+                    if !tag_constrained {
+                        self.wheres.add_intersection(ColumnConstraint::HasType(col.clone(), attribute.value_type));
+                    }
+                }
+            }
+        }
+
         match pattern.tx {
             PatternNonValuePlace::Placeholder => (),
             PatternNonValuePlace::Variable(ref v) => {
@@ -289,7 +309,7 @@ mod testing {
 
     use std::collections::BTreeMap;
     use std::collections::BTreeSet;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     use mentat_core::attribute::Unique;
     use mentat_core::{
@@ -464,7 +484,7 @@ mod testing {
         let v = Variable::from_valid_name("?v");
 
         cc.input_variables.insert(a.clone());
-        cc.value_bindings.insert(a.clone(), TypedValue::Keyword(Rc::new(NamespacedKeyword::new("foo", "bar"))));
+        cc.value_bindings.insert(a.clone(), TypedValue::Keyword(Arc::new(NamespacedKeyword::new("foo", "bar"))));
         cc.apply_pattern(&schema, Pattern {
             source: None,
             entity: PatternNonValuePlace::Variable(x.clone()),
@@ -564,7 +584,7 @@ mod testing {
             source: None,
             entity: PatternNonValuePlace::Variable(x.clone()),
             attribute: PatternNonValuePlace::Placeholder,
-            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new("hello".to_string()))),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new("hello".to_string()))),
             tx: PatternNonValuePlace::Placeholder,
         });
 
@@ -587,7 +607,7 @@ mod testing {
         // - datoms0.value_type_tag = string
         // TODO: implement expand_type_tags.
         assert_eq!(cc.wheres, vec![
-                   ColumnConstraint::Equals(d0_v, QueryValue::TypedValue(TypedValue::String(Rc::new("hello".to_string())))),
+                   ColumnConstraint::Equals(d0_v, QueryValue::TypedValue(TypedValue::String(Arc::new("hello".to_string())))),
                    ColumnConstraint::HasType("all_datoms00".to_string(), ValueType::String),
         ].into());
     }
@@ -614,7 +634,7 @@ mod testing {
             source: None,
             entity: PatternNonValuePlace::Variable(x.clone()),
             attribute: ident("foo", "roz"),
-            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new("idgoeshere".to_string()))),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new("idgoeshere".to_string()))),
             tx: PatternNonValuePlace::Placeholder,
         });
         cc.apply_pattern(&schema, Pattern {
@@ -90,10 +90,9 @@ error_chain! {
             display("invalid limit {} of type {}: expected natural number.", val, kind)
         }
 
-        NonMatchingVariablesInOrClause {
-            // TODO: flesh out.
+        NonMatchingVariablesInOrClause(expected: String, found: String) {
             description("non-matching variables in 'or' clause")
-            display("non-matching variables in 'or' clause")
+            display("non-matching variables in 'or' clause: expected {}, found {}", expected, found)
         }
 
         NonMatchingVariablesInNotClause {
@@ -101,6 +100,16 @@ error_chain! {
             description("non-matching variables in 'not' clause")
             display("non-matching variables in 'not' clause")
         }
+
+        InvalidInputVariableName(name: String) {
+            description("invalid input variable name")
+            display("invalid input variable name: '{}'", name)
+        }
+
+        DuplicateInputBinding(var: PlainSymbol) {
+            description("input variable bound more than once")
+            display("input variable {} bound more than once", var)
+        }
     }
 }
 
@@ -52,6 +52,7 @@ pub use errors::{
 
 pub use clauses::{
     QueryInputs,
+    QueryInputsBuilder,
 };
 
 pub use types::{
@@ -62,7 +63,7 @@ pub use types::{
 pub struct AlgebraicQuery {
     default_source: SrcVar,
     pub find_spec: FindSpec,
-    has_aggregates: bool,
+    pub has_aggregates: bool,
     pub with: BTreeSet<Variable>,
     pub order: Option<Vec<OrderBy>>,
     pub limit: Limit,
@@ -191,10 +192,11 @@ pub fn algebrize_with_inputs(schema: &Schema,
 
     // This might leave us with an unused `:in` variable.
     let limit = if parsed.find_spec.is_unit_limited() { Limit::Fixed(1) } else { parsed.limit };
+    let has_aggregates = parsed.find_spec.has_aggregates();
     let q = AlgebraicQuery {
         default_source: parsed.default_source,
         find_spec: parsed.find_spec,
-        has_aggregates: false,           // TODO: we don't parse them yet.
+        has_aggregates: has_aggregates,
         with: with,
         order: order,
         limit: limit,
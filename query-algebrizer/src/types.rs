@@ -79,6 +79,7 @@ pub enum DatomsColumn {
     Value,
     Tx,
     ValueTypeTag,
+    IndexAVET,
 }
 
 /// One of the named columns of our fulltext values table.
@@ -122,6 +123,7 @@ impl DatomsColumn {
             Value => "v",
             Tx => "tx",
             ValueTypeTag => "value_type_tag",
+            IndexAVET => "index_avet",
         }
     }
 }
@@ -335,6 +337,9 @@ pub enum ColumnConstraint {
         right: QueryValue,
     },
     HasType(TableAlias, ValueType),
+    /// The datoms are of an attribute with `:db/index true`, so they're in `idx_datoms_avet`.
+    /// SQLite only uses that partial index for a query that repeats its `WHERE` clause.
+    ValueIndexed(TableAlias),
     NotExists(ComputedTable),
     Matches(QualifiedAlias, QueryValue),
 }
@@ -454,6 +459,9 @@ impl Debug for ColumnConstraint {
             &HasType(ref qa, value_type) => {
                 write!(f, "{:?}.value_type_tag = {:?}", qa, value_type)
             },
+            &ValueIndexed(ref qa) => {
+                write!(f, "{:?}.index_avet IS NOT 0", qa)
+            },
             &NotExists(ref ct) => {
                 write!(f, "NOT EXISTS {:?}", ct)
             },
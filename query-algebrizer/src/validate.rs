@@ -23,6 +23,12 @@ use errors::{
     Result,
 };
 
+/// Render a set of variables like `[?x ?y]` for use in error messages.
+fn format_vars(vars: &BTreeSet<Variable>) -> String {
+    let names: Vec<&str> = vars.iter().map(|v| v.as_str()).collect();
+    format!("[{}]", names.join(" "))
+}
+
 /// In an `or` expression, every mentioned var is considered 'free'.
 /// In an `or-join` expression, every var in the var list is 'required'.
 ///
@@ -55,8 +61,10 @@ pub fn validate_or_join(or_join: &OrJoin) -> Result<()> {
                 let mut clauses = or_join.clauses.iter();
                 let template = clauses.next().unwrap().collect_mentioned_variables();
                 for clause in clauses {
-                    if template != clause.collect_mentioned_variables() {
-                        bail!(ErrorKind::NonMatchingVariablesInOrClause);
+                    let mentioned = clause.collect_mentioned_variables();
+                    if template != mentioned {
+                        bail!(ErrorKind::NonMatchingVariablesInOrClause(format_vars(&template),
+                                                                        format_vars(&mentioned)));
                     }
                 }
                 Ok(())
@@ -66,8 +74,10 @@ pub fn validate_or_join(or_join: &OrJoin) -> Result<()> {
             // Each leg must use the joined vars.
             let var_set: BTreeSet<Variable> = vars.iter().cloned().collect();
             for clause in &or_join.clauses {
-                if !var_set.is_subset(&clause.collect_mentioned_variables()) {
-                    bail!(ErrorKind::NonMatchingVariablesInOrClause);
+                let mentioned = clause.collect_mentioned_variables();
+                if !var_set.is_subset(&mentioned) {
+                    bail!(ErrorKind::NonMatchingVariablesInOrClause(format_vars(&var_set),
+                                                                    format_vars(&mentioned)));
                 }
             }
             Ok(())
@@ -120,7 +130,7 @@ mod tests {
     };
 
     fn value_ident(ns: &str, name: &str) -> PatternValuePlace {
-        PatternValuePlace::IdentOrKeyword(::std::rc::Rc::new(NamespacedKeyword::new(ns, name)))
+        PatternValuePlace::IdentOrKeyword(::std::sync::Arc::new(NamespacedKeyword::new(ns, name)))
     }
 
     /// Tests that the top-level form is a valid `or`, returning the clauses.
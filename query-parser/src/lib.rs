@@ -31,5 +31,6 @@ pub use parse::{
     ErrorKind,
     Result,
     ResultExt,
+    parse_find,
     parse_find_string,
 };
@@ -38,6 +38,7 @@ use self::mentat_parser_utils::value_and_span::{
 };
 
 use self::mentat_query::{
+    Aggregate,
     Binding,
     Direction,
     Element,
@@ -374,8 +375,18 @@ def_matches_plain_symbol!(Find, ellipsis, "...");
 
 def_matches_plain_symbol!(Find, placeholder, "_");
 
+/// An aggregate over a variable, like `(count ?x)`.
+def_parser!(Find, aggregate, Aggregate, {
+    list().of_exactly((Query::query_function(), Query::variable()))
+          .map(|(func, var)| Aggregate {
+              func: func,
+              var: var,
+          })
+});
+
 def_parser!(Find, elem, Element, {
     Query::variable().map(Element::Variable)
+        .or(Find::aggregate().map(Element::Aggregate))
 });
 
 def_parser!(Find, find_scalar, FindSpec, {
@@ -404,8 +415,8 @@ def_parser!(Find, find_tuple, FindSpec, {
 /// Parse a stream of values into one of four find specs.
 ///
 /// `:find` must be an array of plain var symbols (?foo), pull expressions, and aggregates.  For now
-/// we only support variables and the annotations necessary to declare which flavor of :find we
-/// want:
+/// we only support variables, aggregates over a single variable (`(count ?foo)`), and the
+/// annotations necessary to declare which flavor of :find we want:
 ///
 ///
 ///     `?x ?y ?z  `     = FindRel
@@ -521,7 +532,11 @@ def_parser!(Bind, binding, Binding, {
 });
 
 pub fn parse_find_string(string: &str) -> Result<FindQuery> {
-    let expr = edn::parse::value(string)?;
+    parse_find(&edn::parse::value(string)?)
+}
+
+/// Parse a query that has already been parsed as EDN.
+pub fn parse_find(expr: &edn::ValueAndSpan) -> Result<FindQuery> {
     Find::query()
         .parse(expr.atom_stream())
         .map(|x| x.0)
@@ -534,7 +549,7 @@ mod test {
     extern crate edn;
     extern crate mentat_query;
 
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     use self::combine::Parser;
     use self::edn::OrderedFloat;
@@ -554,11 +569,11 @@ mod test {
     use super::*;
 
     fn variable(x: edn::PlainSymbol) -> Variable {
-        Variable(Rc::new(x))
+        Variable(Arc::new(x))
     }
 
     fn ident_kw(kw: edn::NamespacedKeyword) -> PatternNonValuePlace {
-        PatternNonValuePlace::Ident(Rc::new(kw))
+        PatternNonValuePlace::Ident(Arc::new(kw))
     }
 
     fn ident(ns: &str, name: &str) -> PatternNonValuePlace {
@@ -16,7 +16,7 @@ extern crate mentat_core;
 extern crate mentat_query;
 extern crate mentat_query_parser;
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use edn::{
     NamespacedKeyword,
@@ -24,6 +24,7 @@ use edn::{
 };
 
 use mentat_query::{
+    Aggregate,
     Direction,
     Element,
     FindSpec,
@@ -37,6 +38,7 @@ use mentat_query::{
     PatternNonValuePlace,
     PatternValuePlace,
     Predicate,
+    QueryFunction,
     UnifyVars,
     Variable,
     WhereClause,
@@ -164,7 +166,7 @@ fn can_parse_simple_or_join() {
 
 #[cfg(test)]
 fn ident(ns: &str, name: &str) -> PatternNonValuePlace {
-    PatternNonValuePlace::Ident(::std::rc::Rc::new(NamespacedKeyword::new(ns, name)))
+    PatternNonValuePlace::Ident(::std::sync::Arc::new(NamespacedKeyword::new(ns, name)))
 }
 
 #[test]
@@ -283,8 +285,35 @@ fn can_parse_uuid() {
                WhereClause::Pattern(
                    Pattern::new(None,
                                 PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
-                                PatternNonValuePlace::Ident(Rc::new(NamespacedKeyword::new("foo", "baz"))),
+                                PatternNonValuePlace::Ident(Arc::new(NamespacedKeyword::new("foo", "baz"))),
                                 PatternValuePlace::Constant(NonIntegerConstant::Uuid(expected)),
                                 PatternNonValuePlace::Placeholder)
                        .expect("valid pattern")));
 }
+
+#[test]
+fn can_parse_aggregates_with() {
+    let s = "[:find ?x (sum ?y) :with ?z :where [?x :foo/bar ?y] [?z :foo/baz ?x]]";
+    let p = parse_find_string(s).expect("parsed");
+    assert_eq!(p.find_spec,
+               FindSpec::FindRel(vec![
+                   Element::Variable(Variable::from_valid_name("?x")),
+                   Element::Aggregate(Aggregate {
+                       func: QueryFunction(PlainSymbol::new("sum")),
+                       var: Variable::from_valid_name("?y"),
+                   }),
+               ]));
+    assert!(p.find_spec.has_aggregates());
+    assert_eq!(p.with.into_iter().collect::<Vec<Variable>>(), vec![Variable::from_valid_name("?z")]);
+
+    let scalar = "[:find (count ?x) . :where [?x :foo/bar _]]";
+    assert_eq!(parse_find_string(scalar).expect("parsed").find_spec,
+               FindSpec::FindScalar(Element::Aggregate(Aggregate {
+                   func: QueryFunction(PlainSymbol::new("count")),
+                   var: Variable::from_valid_name("?x"),
+               })));
+
+    // Aggregates only take a single variable.
+    assert!(parse_find_string("[:find (count 5) :where [?x :foo/bar _]]").is_err());
+    assert!(parse_find_string("[:find (count ?x ?y) :where [?x :foo/bar ?y]]").is_err());
+}
@@ -11,7 +11,14 @@
 #[macro_use]
 extern crate error_chain;
 extern crate rusqlite;
+#[cfg(feature = "serde_support")]
+extern crate serde;
+#[cfg(feature = "serde_support")]
+#[macro_use]
+extern crate serde_derive;
+extern crate tabwriter;
 
+extern crate edn;
 extern crate mentat_core;
 extern crate mentat_db;                 // For value conversion.
 extern crate mentat_query;
@@ -19,6 +26,9 @@ extern crate mentat_query_algebrizer;
 extern crate mentat_query_sql;
 extern crate mentat_sql;
 
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
 use std::iter;
 use rusqlite::{
     Row,
@@ -26,6 +36,7 @@ use rusqlite::{
 };
 
 use mentat_core::{
+    Schema,
     SQLValueType,
     TypedValue,
     ValueType,
@@ -37,9 +48,11 @@ use mentat_db::{
 };
 
 use mentat_query::{
+    Aggregate,
     Element,
     FindSpec,
     Limit,
+    QueryFunction,
     Variable,
 };
 
@@ -47,16 +60,28 @@ use mentat_query_algebrizer::{
     AlgebraicQuery,
     ColumnName,
     ConjoiningClauses,
+    OrderBy,
     VariableColumn,
 };
 
 use mentat_query_sql::{
     ColumnOrExpression,
+    Expression,
+    GroupBy,
     Name,
     Projection,
     ProjectedColumn,
 };
 
+use tabwriter::TabWriter;
+
+mod rows;
+
+pub use rows::{
+    FromTuple,
+    FromValue,
+};
+
 error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
@@ -75,10 +100,29 @@ error_chain! {
             description("unexpected query results type")
             display("expected {}, got {}", expected, actual)
         }
+
+        InvalidProjection(t: String) {
+            description("invalid projection")
+            display("invalid projection: {}", t)
+        }
+
+        UnexpectedRowLength(expected: usize, actual: usize) {
+            description("unexpected number of values in a query result row")
+            display("expected a row of {} values, got {}", expected, actual)
+        }
+
+        UnexpectedValueType(expected: ValueType, actual: ValueType) {
+            description("unexpected query result value type")
+            display("expected a value of type {}, got {}", expected, actual)
+        }
     }
 }
 
+/// With the `serde_support` feature, results serialize tagged with their shape, like
+/// `{"type": "rel", "values": [[...], ...]}`.
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde_support", serde(tag = "type", content = "values", rename_all = "lowercase"))]
 pub enum QueryResults {
     Scalar(Option<TypedValue>),
     Tuple(Option<Vec<TypedValue>>),
@@ -162,6 +206,239 @@ impl QueryResults {
             QueryResults::Rel(r) => Ok(r),
         }
     }
+
+    /// Convert each row of a relation into a `T`, by position: `into_rows::<(Entid, String)>()`
+    /// checks that each row has two values, a ref and a string.  See `FromTuple`.
+    pub fn into_rows<T>(self) -> Result<Vec<T>> where T: FromTuple {
+        self.into_rel()?.into_iter().map(T::from_tuple).collect()
+    }
+
+    /// Return these results as EDN, for consumers that read EDN rather than Rust values: a scalar
+    /// is its bare value, a tuple or collection a vector, and a relation a vector of vectors.  No
+    /// result is `nil`.  Refs are entids, not idents.
+    pub fn to_edn(&self) -> edn::Value {
+        fn value(v: &TypedValue) -> edn::Value {
+            v.to_edn_value_pair().0
+        }
+
+        fn vector(vs: &[TypedValue]) -> edn::Value {
+            edn::Value::Vector(vs.iter().map(value).collect())
+        }
+
+        match self {
+            &QueryResults::Scalar(None) |
+            &QueryResults::Tuple(None) => edn::Value::Nil,
+            &QueryResults::Scalar(Some(ref v)) => value(v),
+            &QueryResults::Tuple(Some(ref vs)) => vector(vs),
+            &QueryResults::Coll(ref vs) => vector(vs),
+            &QueryResults::Rel(ref rows) => edn::Value::Vector(rows.iter().map(|row| vector(row)).collect()),
+        }
+    }
+
+    /// Render the results for reading at a terminal.  See `pretty_with_columns`; this version
+    /// doesn't label the columns of a relation.
+    pub fn pretty(&self) -> String {
+        self.pretty_with_columns(&[])
+    }
+
+    /// Render the results for reading at a terminal, in the style of
+    /// `mentat_db::debug::dump_sql_query`.
+    ///
+    /// Values are printed as EDN, so that keywords keep their leading colon and strings are
+    /// quoted.  A scalar is printed alone, and a tuple or collection as a vector; no result is
+    /// `nil`.  A relation is an aligned table with a row per result, headed by `columns` unless
+    /// `columns` is empty.
+    pub fn pretty_with_columns(&self, columns: &[Variable]) -> String {
+        fn value(v: &TypedValue) -> String {
+            v.to_edn_value_pair().0.to_string()
+        }
+
+        fn vector(vs: &[TypedValue]) -> String {
+            format!("[{}]", vs.iter().map(value).collect::<Vec<String>>().join(" "))
+        }
+
+        match self {
+            &QueryResults::Scalar(None) |
+            &QueryResults::Tuple(None) => "nil\n".to_string(),
+            &QueryResults::Scalar(Some(ref v)) => format!("{}\n", value(v)),
+            &QueryResults::Tuple(Some(ref vs)) => format!("{}\n", vector(vs)),
+            &QueryResults::Coll(ref vs) => format!("{}\n", vector(vs)),
+            &QueryResults::Rel(ref rows) => {
+                let header: Vec<String> = columns.iter().map(|column| column.as_str().to_string()).collect();
+                aligned_table(&header, rows, value)
+            },
+        }
+    }
+
+    /// Render the results as an aligned table for debugging.  See `dump_with_columns`; this
+    /// version heads each column with its position.
+    pub fn dump(&self, schema: &Schema) -> String {
+        self.dump_with_columns(schema, &[])
+    }
+
+    /// Render the results as an aligned table for debugging, in the style of
+    /// `mentat_db::debug::dump_sql_query`.
+    ///
+    /// Unlike `pretty_with_columns`, every shape is a table: a scalar is a single cell, a tuple a
+    /// single row, and a collection a single column.  The table is headed by `columns`, or by
+    /// column positions if `columns` is empty.  Values are printed as EDN, except that refs to
+    /// entities with idents in `schema` are printed as those idents.
+    pub fn dump_with_columns(&self, schema: &Schema, columns: &[Variable]) -> String {
+        let value = |v: &TypedValue| -> String {
+            match v {
+                &TypedValue::Ref(e) => schema.get_ident(e).map_or_else(|| e.to_string(), |ident| ident.to_string()),
+                v => v.to_edn_value_pair().0.to_string(),
+            }
+        };
+
+        let rows: Vec<Vec<TypedValue>> = match self {
+            &QueryResults::Scalar(ref o) => o.iter().map(|v| vec![v.clone()]).collect(),
+            &QueryResults::Tuple(ref o) => o.iter().cloned().collect(),
+            &QueryResults::Coll(ref vs) => vs.iter().map(|v| vec![v.clone()]).collect(),
+            &QueryResults::Rel(ref rows) => rows.clone(),
+        };
+
+        let width = match self {
+            &QueryResults::Scalar(_) |
+            &QueryResults::Coll(_) => 1,
+            &QueryResults::Tuple(_) |
+            &QueryResults::Rel(_) => rows.first().map_or(columns.len(), |row| row.len()),
+        };
+
+        let header: Vec<String> = if columns.is_empty() {
+            (0..width).map(|i| i.to_string()).collect()
+        } else {
+            columns.iter().map(|column| column.as_str().to_string()).collect()
+        };
+        aligned_table(&header, &rows, value)
+    }
+}
+
+/// Render `rows` as a table aligned with tabs, headed by `header` unless it's empty, with each
+/// value rendered by `value`.  This is shared by `QueryResults::pretty_with_columns` and
+/// `QueryResults::dump_with_columns`.
+fn aligned_table<F>(header: &[String], rows: &[Vec<TypedValue>], value: F) -> String where F: Fn(&TypedValue) -> String {
+    let mut tw = TabWriter::new(Vec::new()).padding(2);
+
+    if !header.is_empty() {
+        for column in header {
+            write!(&mut tw, "{}\t", column).unwrap();
+        }
+        write!(&mut tw, "\n").unwrap();
+    }
+
+    for row in rows {
+        for v in row {
+            write!(&mut tw, "{}\t", value(v)).unwrap();
+        }
+        write!(&mut tw, "\n").unwrap();
+    }
+
+    String::from_utf8(tw.into_inner().unwrap()).unwrap()
+}
+
+/// Return the index of the only one of `columns` named `name`, or `None` if there are none or
+/// several.
+pub fn column_index(columns: &[Variable], name: &str) -> Option<usize> {
+    let mut matching = columns.iter().enumerate().filter(|&(_, v)| v.as_str() == name).map(|(i, _)| i);
+    match (matching.next(), matching.next()) {
+        (Some(index), None) => Some(index),
+        _ => None,
+    }
+}
+
+/// The results of a query, together with the find spec that produced them and the variables
+/// that name each column, in order.
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueryOutput {
+    pub spec: FindSpec,
+    pub columns: Vec<Variable>,
+    pub results: QueryResults,
+}
+
+impl fmt::Display for QueryOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pretty())
+    }
+}
+
+impl QueryOutput {
+    pub fn new(spec: FindSpec, results: QueryResults) -> QueryOutput {
+        QueryOutput {
+            columns: spec.variables(),
+            spec: spec,
+            results: results,
+        }
+    }
+
+    pub fn empty(spec: FindSpec) -> QueryOutput {
+        let results = QueryResults::empty(&spec);
+        QueryOutput::new(spec, results)
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Render the results for reading at a terminal, labelling the columns of a relation with the
+    /// variables of the `:find` spec.  See `QueryResults::pretty_with_columns`.
+    pub fn pretty(&self) -> String {
+        self.results.pretty_with_columns(&self.columns)
+    }
+
+    /// Render the results as an aligned table for debugging, headed by the variables of the
+    /// `:find` spec.  See `QueryResults::dump_with_columns`.
+    pub fn dump(&self, schema: &Schema) -> String {
+        self.results.dump_with_columns(schema, &self.columns)
+    }
+
+    /// Return the index of the column bound to the variable named `name`, which should include
+    /// the leading `?`, or `None` if the query doesn't project that variable.
+    ///
+    /// Also `None` if more than one column is named `name`, as when several aggregates aggregate
+    /// the same variable: there's no telling which was meant.
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        column_index(&self.columns, name)
+    }
+
+    /// Return the value bound to the variable named `var` in result row `row`, so that callers
+    /// needn't depend on the order of the `:find` clause.  `var` should include the leading `?`.
+    ///
+    /// A scalar or tuple result has at most one row, and a collection has one column.  Returns
+    /// `None` if there's no such row, or if the query doesn't project `var` exactly once.
+    pub fn get(&self, row: usize, var: &str) -> Option<&TypedValue> {
+        let column = self.column_index(var)?;
+        match self.results {
+            QueryResults::Scalar(ref o) => if row == 0 { o.as_ref() } else { None },
+            QueryResults::Tuple(ref o) => if row == 0 { o.as_ref().and_then(|t| t.get(column)) } else { None },
+            QueryResults::Coll(ref c) => c.get(row),
+            QueryResults::Rel(ref r) => r.get(row).and_then(|values| values.get(column)),
+        }
+    }
+
+    pub fn into_scalar(self) -> Result<Option<TypedValue>> {
+        self.results.into_scalar()
+    }
+
+    pub fn into_coll(self) -> Result<Vec<TypedValue>> {
+        self.results.into_coll()
+    }
+
+    pub fn into_tuple(self) -> Result<Option<Vec<TypedValue>>> {
+        self.results.into_tuple()
+    }
+
+    pub fn into_rel(self) -> Result<Vec<Vec<TypedValue>>> {
+        self.results.into_rel()
+    }
+
+    pub fn into_rows<T>(self) -> Result<Vec<T>> where T: FromTuple {
+        self.results.into_rows()
+    }
 }
 
 type Index = i32;            // See rusqlite::RowIndex.
@@ -239,9 +516,129 @@ pub fn projected_column_for_var(var: &Variable, cc: &ConjoiningClauses) -> (Proj
     }
 }
 
+/// The SQL projection and Datalog templates that together project the elements of a find spec.
+struct ProjectedElements {
+    sql_projection: Projection,
+
+    /// If the find spec aggregates, `sql_projection` is computed over a `SELECT DISTINCT`
+    /// subquery that projects these columns.
+    pre_aggregate_projection: Option<Projection>,
+
+    templates: Vec<TypedIndex>,
+    group_by: Vec<GroupBy>,
+}
+
+impl ProjectedElements {
+    /// The number of columns in each row of the SQL results.
+    fn column_count(&self) -> usize {
+        match self.sql_projection {
+            Projection::Columns(ref cols) => cols.len(),
+            Projection::Star | Projection::One => 0,
+        }
+    }
+}
+
+/// The aggregate functions we know how to compute in SQL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SimpleAggregationOp {
+    Avg,
+    Count,
+    Max,
+    Min,
+    Sum,
+}
+
+impl SimpleAggregationOp {
+    fn for_function(function: &QueryFunction) -> Option<SimpleAggregationOp> {
+        use self::SimpleAggregationOp::*;
+        match (function.0).0.as_str() {
+            "avg" => Some(Avg),
+            "count" => Some(Count),
+            "max" => Some(Max),
+            "min" => Some(Min),
+            "sum" => Some(Sum),
+            _ => None,
+        }
+    }
+
+    fn to_sql(&self) -> &'static str {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Avg => "avg",
+            &Count => "count",
+            &Max => "max",
+            &Min => "min",
+            &Sum => "sum",
+        }
+    }
+
+    /// Return the type of the result of applying this operation to values of type `input`, or
+    /// `None` if that makes no sense.
+    ///
+    /// Only counting works for values of an unknown type: SQLite would happily sum a column of
+    /// entids and doubles, but the result would be meaningless.
+    fn result_type(&self, input: Option<ValueType>) -> Option<ValueType> {
+        use self::SimpleAggregationOp::*;
+        match (*self, input) {
+            (Count, _) => Some(ValueType::Long),
+            (_, None) => None,
+            (Avg, Some(ValueType::Long)) |
+            (Avg, Some(ValueType::Double)) => Some(ValueType::Double),
+            (Sum, Some(ValueType::Long)) => Some(ValueType::Long),
+            (Sum, Some(ValueType::Double)) => Some(ValueType::Double),
+
+            // Instants are stored as microseconds since the epoch, so they order correctly.
+            (Max, Some(t)) | (Min, Some(t)) => match t {
+                ValueType::Double | ValueType::Instant | ValueType::Long => Some(t),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Return an outer projection column that applies `aggregate` to the column of the same name
+/// projected by the subquery, together with the type of the result.
+fn aggregate_column(aggregate: &Aggregate, input_type: Option<ValueType>) -> Result<(ProjectedColumn, ValueType)> {
+    let op = SimpleAggregationOp::for_function(&aggregate.func)
+        .ok_or_else(|| ErrorKind::InvalidProjection(format!("unknown aggregate function {}", aggregate.func.0)))?;
+
+    let result_type = op.result_type(input_type)
+        .ok_or_else(|| {
+            let input = input_type.map_or("values of unknown type".to_string(), |t| format!("values of type {}", t));
+            ErrorKind::InvalidProjection(format!("cannot apply {} to {}: {}", op.to_sql(), aggregate.var.as_str(), input))
+        })?;
+
+    let name = format!("({} {})", aggregate.func.0, aggregate.var.as_str());
+    let arg = ColumnOrExpression::ExistingColumn(VariableColumn::Variable(aggregate.var.clone()).column_name());
+    let expression = Expression::Unary {
+        sql_op: op.to_sql(),
+        arg: arg,
+    };
+    Ok((ProjectedColumn(ColumnOrExpression::Expression(Box::new(expression)), name), result_type))
+}
+
+/// Push the projected column for `var` into `cols`, followed by its type tag if its type isn't
+/// known, unless we've already done so; `projected` records the variables we've seen.
+/// Returns the type of `var`, if known.
+fn project_var_once(var: &Variable,
+                    cc: &ConjoiningClauses,
+                    projected: &mut BTreeSet<Variable>,
+                    cols: &mut Vec<ProjectedColumn>) -> Option<ValueType> {
+    let (projected_column, maybe_type) = projected_column_for_var(var, cc);
+    if projected.insert(var.clone()) {
+        cols.push(projected_column);
+        if maybe_type.is_none() {
+            let (type_column, type_name) = candidate_type_column(cc, var);
+            cols.push(ProjectedColumn(type_column, type_name));
+        }
+    }
+    maybe_type
+}
+
 /// Walk an iterator of `Element`s, collecting projector templates and columns.
 ///
-/// Returns a pair: the SQL projection (which should always be a `Projection::Columns`)
+/// Returns the SQL projection (which should always be a `Projection::Columns`)
 /// and a `Vec` of `TypedIndex` 'keys' to use when looking up values.
 ///
 /// Callers must ensure that every `Element` is distinct -- a query like
@@ -254,7 +651,11 @@ pub fn projected_column_for_var(var: &Variable, cc: &ConjoiningClauses) -> (Proj
 fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
     count: usize,
     elements: I,
-    query: &AlgebraicQuery) -> Result<(Projection, Vec<TypedIndex>)> {
+    query: &AlgebraicQuery) -> Result<ProjectedElements> {
+
+    if query.has_aggregates {
+        return project_aggregate_elements(count, elements, query);
+    }
 
     let mut cols = Vec::with_capacity(count);
     let mut i: i32 = 0;
@@ -284,7 +685,10 @@ fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
                     let (type_column, type_name) = candidate_type_column(&query.cc, &var);
                     cols.push(ProjectedColumn(type_column, type_name));
                 }
-            }
+            },
+            &Element::Aggregate(_) => {
+                unreachable!("aggregates are handled by project_aggregate_elements");
+            },
         }
     }
 
@@ -300,11 +704,108 @@ fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
         }
     }
 
-    Ok((Projection::Columns(cols), templates))
+    Ok(ProjectedElements {
+        sql_projection: Projection::Columns(cols),
+        pre_aggregate_projection: None,
+        templates: templates,
+        group_by: vec![],
+    })
+}
+
+/// Like `project_elements`, but for a find spec that includes aggregates, like
+///
+/// ```edn
+/// [:find ?x (sum ?y) :with ?z :where …]
+/// ```
+///
+/// A `SELECT DISTINCT` subquery projects every variable in the find spec, together with the
+/// `:with` variables; as in Datomic, the `:with` variables decide which otherwise identical
+/// bindings survive to be aggregated.  The outer query applies the aggregates to the columns of
+/// the subquery, grouping by the variables that aren't aggregated.
+fn project_aggregate_elements<'a, I: IntoIterator<Item = &'a Element>>(
+    count: usize,
+    elements: I,
+    query: &AlgebraicQuery) -> Result<ProjectedElements> {
+
+    let mut inner_cols = Vec::with_capacity(count + query.with.len());
+    let mut inner_vars: BTreeSet<Variable> = BTreeSet::new();
+    let mut outer_cols = Vec::with_capacity(count);
+    let mut group_by = vec![];
+    let mut grouping_vars: BTreeSet<Variable> = BTreeSet::new();
+    let mut i: i32 = 0;
+    let mut templates = vec![];
+
+    for e in elements {
+        match e {
+            &Element::Variable(ref var) => {
+                // Pass the variable through from the subquery, grouping by it.
+                let maybe_type = project_var_once(var, &query.cc, &mut inner_vars, &mut inner_cols);
+                grouping_vars.insert(var.clone());
+
+                let name = VariableColumn::Variable(var.clone()).column_name();
+                outer_cols.push(ProjectedColumn(ColumnOrExpression::ExistingColumn(name.clone()), name.clone()));
+                group_by.push(GroupBy::ProjectedColumn(name));
+
+                if let Some(ty) = maybe_type {
+                    templates.push(TypedIndex::Known(i, ty.value_type_tag()));
+                    i += 1;
+                } else {
+                    templates.push(TypedIndex::Unknown(i, i + 1));
+                    i += 2;
+
+                    let type_name = VariableColumn::VariableTypeTag(var.clone()).column_name();
+                    outer_cols.push(ProjectedColumn(ColumnOrExpression::ExistingColumn(type_name.clone()), type_name.clone()));
+                    group_by.push(GroupBy::ProjectedColumn(type_name));
+                }
+            },
+            &Element::Aggregate(ref aggregate) => {
+                let maybe_type = project_var_once(&aggregate.var, &query.cc, &mut inner_vars, &mut inner_cols);
+                let (column, result_type) = aggregate_column(aggregate, maybe_type)?;
+                outer_cols.push(column);
+                templates.push(TypedIndex::Known(i, result_type.value_type_tag()));
+                i += 1;
+            },
+        }
+    }
+
+    // These distinguish bindings in the subquery, but don't appear in the results.
+    for var in query.with.iter() {
+        project_var_once(var, &query.cc, &mut inner_vars, &mut inner_cols);
+    }
+
+    // The outer query can only be ordered by the columns it projects.  Ordering by anything else
+    // would be meaningless -- it's been aggregated away -- and the algebrizer would have added
+    // it to `:with`, changing the results.
+    if let Some(ref order) = query.order {
+        for &OrderBy(_, ref column) in order.iter() {
+            let var = match column {
+                &VariableColumn::Variable(ref var) |
+                &VariableColumn::VariableTypeTag(ref var) => var,
+            };
+            if !grouping_vars.contains(var) {
+                bail!(ErrorKind::InvalidProjection(format!("cannot order by {}: when aggregating, only non-aggregated :find variables can be ordered", var.as_str())));
+            }
+        }
+    }
+
+    if group_by.is_empty() {
+        group_by.push(GroupBy::Everything);
+    }
+
+    Ok(ProjectedElements {
+        sql_projection: Projection::Columns(outer_cols),
+        pre_aggregate_projection: Some(Projection::Columns(inner_cols)),
+        templates: templates,
+        group_by: group_by,
+    })
 }
 
 pub trait Projector {
     fn project<'stmt>(&self, rows: Rows<'stmt>) -> Result<QueryResults>;
+
+    /// Project a single row into the values it binds, one per projected element, without
+    /// accumulating into a `QueryResults`.
+    fn project_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>>;
 }
 
 /// A projector that produces a `QueryResult` containing fixed data.
@@ -323,6 +824,10 @@ impl Projector for ConstantProjector {
     fn project<'stmt>(&self, _: Rows<'stmt>) -> Result<QueryResults> {
         Ok((self.results_factory)())
     }
+
+    fn project_row<'a, 'stmt>(&self, _: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+        Ok(vec![])
+    }
 }
 
 struct ScalarProjector {
@@ -336,12 +841,14 @@ impl ScalarProjector {
         }
     }
 
-    fn combine(sql: Projection, mut templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let template = templates.pop().expect("Expected a single template");
+    fn combine(mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let template = elements.templates.pop().expect("Expected a single template");
         Ok(CombinedProjection {
-            sql_projection: sql,
+            sql_projection: elements.sql_projection,
+            pre_aggregate_projection: elements.pre_aggregate_projection,
             datalog_projector: Box::new(ScalarProjector::with_template(template)),
             distinct: false,
+            group_by: elements.group_by,
         })
     }
 }
@@ -356,6 +863,10 @@ impl Projector for ScalarProjector {
             Ok(QueryResults::Scalar(None))
         }
     }
+
+    fn project_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+        Ok(vec![self.template.lookup(row)?])
+    }
 }
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
@@ -373,20 +884,22 @@ impl TupleProjector {
     }
 
     // This is exactly the same as for rel.
-    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    fn collect_bindings<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
         assert_eq!(row.column_count(), self.len as i32);
         self.templates
             .iter()
-            .map(|ti| ti.lookup(&row))
+            .map(|ti| ti.lookup(row))
             .collect::<Result<Vec<TypedValue>>>()
     }
 
-    fn combine(column_count: usize, sql: Projection, templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let p = TupleProjector::with_templates(column_count, templates);
+    fn combine(elements: ProjectedElements) -> Result<CombinedProjection> {
+        let p = TupleProjector::with_templates(elements.column_count(), elements.templates);
         Ok(CombinedProjection {
-            sql_projection: sql,
+            sql_projection: elements.sql_projection,
+            pre_aggregate_projection: elements.pre_aggregate_projection,
             datalog_projector: Box::new(p),
             distinct: false,
+            group_by: elements.group_by,
         })
     }
 }
@@ -395,12 +908,16 @@ impl Projector for TupleProjector {
     fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryResults> {
         if let Some(r) = rows.next() {
             let row = r?;
-            let bindings = self.collect_bindings(row)?;
+            let bindings = self.collect_bindings(&row)?;
             Ok(QueryResults::Tuple(Some(bindings)))
         } else {
             Ok(QueryResults::Tuple(None))
         }
     }
+
+    fn project_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+        self.collect_bindings(row)
+    }
 }
 
 /// A rel projector produces a vector of vectors.
@@ -421,20 +938,25 @@ impl RelProjector {
         }
     }
 
-    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    fn collect_bindings<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
         assert_eq!(row.column_count(), self.len as i32);
         self.templates
             .iter()
-            .map(|ti| ti.lookup(&row))
+            .map(|ti| ti.lookup(row))
             .collect::<Result<Vec<TypedValue>>>()
     }
 
-    fn combine(column_count: usize, sql: Projection, templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let p = RelProjector::with_templates(column_count, templates);
+    fn combine(elements: ProjectedElements) -> Result<CombinedProjection> {
+        let p = RelProjector::with_templates(elements.column_count(), elements.templates);
+
+        // Grouping already makes each row distinct.
+        let distinct = elements.pre_aggregate_projection.is_none();
         Ok(CombinedProjection {
-            sql_projection: sql,
+            sql_projection: elements.sql_projection,
+            pre_aggregate_projection: elements.pre_aggregate_projection,
             datalog_projector: Box::new(p),
-            distinct: true,
+            distinct: distinct,
+            group_by: elements.group_by,
         })
     }
 }
@@ -444,11 +966,15 @@ impl Projector for RelProjector {
         let mut out: Vec<Vec<TypedValue>> = vec![];
         while let Some(r) = rows.next() {
             let row = r?;
-            let bindings = self.collect_bindings(row)?;
+            let bindings = self.collect_bindings(&row)?;
             out.push(bindings);
         }
         Ok(QueryResults::Rel(out))
     }
+
+    fn project_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+        self.collect_bindings(row)
+    }
 }
 
 /// A coll projector produces a vector of values.
@@ -464,12 +990,17 @@ impl CollProjector {
         }
     }
 
-    fn combine(sql: Projection, mut templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let template = templates.pop().expect("Expected a single template");
+    fn combine(mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let template = elements.templates.pop().expect("Expected a single template");
+
+        // Grouping already makes each row distinct.
+        let distinct = elements.pre_aggregate_projection.is_none();
         Ok(CombinedProjection {
-            sql_projection: sql,
+            sql_projection: elements.sql_projection,
+            pre_aggregate_projection: elements.pre_aggregate_projection,
             datalog_projector: Box::new(CollProjector::with_template(template)),
-            distinct: true,
+            distinct: distinct,
+            group_by: elements.group_by,
         })
     }
 }
@@ -484,6 +1015,10 @@ impl Projector for CollProjector {
         }
         Ok(QueryResults::Coll(out))
     }
+
+    fn project_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+        Ok(vec![self.template.lookup(row)?])
+    }
 }
 
 /// Combines the two things you need to turn a query into SQL and turn its results into
@@ -493,12 +1028,19 @@ pub struct CombinedProjection {
     /// output.
     pub sql_projection: Projection,
 
+    /// If the query aggregates, a SQL projection for a `SELECT DISTINCT` subquery over the body
+    /// of the query.  `sql_projection` then maps the columns of that subquery to the output.
+    pub pre_aggregate_projection: Option<Projection>,
+
     /// A Datalog projection. This consumes rows of the appropriate shape (as defined by
     /// the SQL projection) to yield one of the four kinds of Datalog query result.
     pub datalog_projector: Box<Projector>,
 
     /// True if this query requires the SQL query to include DISTINCT.
     pub distinct: bool,
+
+    /// How to group rows when aggregating.
+    pub group_by: Vec<GroupBy>,
 }
 
 impl CombinedProjection {
@@ -513,8 +1055,8 @@ impl CombinedProjection {
 /// Compute a suitable SQL projection for an algebrized query.
 /// This takes into account a number of things:
 /// - The variable list in the find spec.
-/// - The presence of any aggregate operations in the find spec. TODO: for now we only handle
-///   simple variables
+/// - The presence of any aggregate operations in the find spec, and the `:with` variables that
+///   control which bindings they aggregate.
 /// - The bindings established by the topmost CC.
 /// - The types known at algebrizing time.
 /// - The types extracted from the store for unknown attributes.
@@ -527,32 +1069,191 @@ pub fn query_projection(query: &AlgebraicQuery) -> Result<CombinedProjection> {
         let constant_projector = ConstantProjector::new(empty);
         Ok(CombinedProjection {
             sql_projection: Projection::One,
+            pre_aggregate_projection: None,
             datalog_projector: Box::new(constant_projector),
             distinct: false,
+            group_by: vec![],
         })
     } else {
         match query.find_spec {
             FindColl(ref element) => {
-                let (cols, templates) = project_elements(1, iter::once(element), query)?;
-                CollProjector::combine(cols, templates).map(|p| p.flip_distinct_for_limit(&query.limit))
+                let elements = project_elements(1, iter::once(element), query)?;
+                CollProjector::combine(elements).map(|p| p.flip_distinct_for_limit(&query.limit))
             },
 
             FindScalar(ref element) => {
-                let (cols, templates) = project_elements(1, iter::once(element), query)?;
-                ScalarProjector::combine(cols, templates)
+                let elements = project_elements(1, iter::once(element), query)?;
+                ScalarProjector::combine(elements)
             },
 
             FindRel(ref elements) => {
                 let column_count = query.find_spec.expected_column_count();
-                let (cols, templates) = project_elements(column_count, elements, query)?;
-                RelProjector::combine(column_count, cols, templates).map(|p| p.flip_distinct_for_limit(&query.limit))
+                let elements = project_elements(column_count, elements, query)?;
+                RelProjector::combine(elements).map(|p| p.flip_distinct_for_limit(&query.limit))
             },
 
             FindTuple(ref elements) => {
                 let column_count = query.find_spec.expected_column_count();
-                let (cols, templates) = project_elements(column_count, elements, query)?;
-                TupleProjector::combine(column_count, cols, templates)
+                let elements = project_elements(column_count, elements, query)?;
+                TupleProjector::combine(elements)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fmt::Debug;
+    use std::sync::Arc;
+
+    use mentat_query::NamespacedKeyword;
+
+    fn expect_mismatch<T: Debug>(result: Result<T>, actual: &'static str, expected: &'static str) {
+        match result {
+            Err(Error(ErrorKind::UnexpectedResultsType(a, e), _)) => {
+                assert_eq!(a, actual);
+                assert_eq!(e, expected);
             },
+            x => panic!("expected UnexpectedResultsType({}, {}), got {:?}", actual, expected, x),
+        }
+    }
+
+    #[test]
+    fn test_into_results() {
+        assert_eq!(QueryResults::Scalar(Some(TypedValue::Long(1))).into_scalar().unwrap(), Some(TypedValue::Long(1)));
+        assert_eq!(QueryResults::Coll(vec![TypedValue::Long(1)]).into_coll().unwrap(), vec![TypedValue::Long(1)]);
+        assert_eq!(QueryResults::Tuple(None).into_tuple().unwrap(), None);
+        assert_eq!(QueryResults::Rel(vec![]).into_rel().unwrap(), Vec::<Vec<TypedValue>>::new());
+    }
+
+    #[test]
+    fn test_into_rows() {
+        let rel = || QueryResults::Rel(vec![
+            vec![TypedValue::Ref(65536), TypedValue::typed_string("Alice"), TypedValue::Long(30)],
+            vec![TypedValue::Ref(65537), TypedValue::typed_string("Bob"), TypedValue::Long(25)],
+        ]);
+
+        let rows: Vec<(i64, String, i64)> = rel().into_rows().expect("rows");
+        assert_eq!(rows, vec![(65536, "Alice".to_string(), 30), (65537, "Bob".to_string(), 25)]);
+
+        let rows: Vec<(TypedValue, Arc<String>, TypedValue)> = rel().into_rows().expect("rows");
+        assert_eq!(rows[1].1, Arc::new("Bob".to_string()));
+
+        match rel().into_rows::<(i64, String)>() {
+            Err(Error(ErrorKind::UnexpectedRowLength(2, 3), _)) => {},
+            x => panic!("expected UnexpectedRowLength, got {:?}", x),
+        }
+        match rel().into_rows::<(i64, bool, i64)>() {
+            Err(Error(ErrorKind::UnexpectedValueType(ValueType::Boolean, ValueType::String), _)) => {},
+            x => panic!("expected UnexpectedValueType, got {:?}", x),
         }
+        expect_mismatch(QueryResults::Coll(vec![]).into_rows::<(i64,)>(), "coll", "rel");
+    }
+
+    #[test]
+    fn test_pretty() {
+        let kw = TypedValue::typed_ns_keyword;
+
+        assert_eq!(QueryResults::Scalar(None).pretty(), "nil\n");
+        assert_eq!(QueryResults::Scalar(Some(kw("foo", "bar"))).pretty(), ":foo/bar\n");
+        assert_eq!(QueryResults::Tuple(Some(vec![TypedValue::Long(1), TypedValue::typed_string("x")])).pretty(),
+                   "[1 \"x\"]\n");
+        assert_eq!(QueryResults::Coll(vec![TypedValue::Boolean(true), TypedValue::Ref(65536)]).pretty(),
+                   "[true 65536]\n");
+
+        let rel = QueryResults::Rel(vec![vec![TypedValue::Ref(65536), kw("foo", "bar")],
+                                         vec![TypedValue::Ref(65537), kw("foo", "longer")]]);
+        let columns = vec![Variable::from_valid_name("?entity"), Variable::from_valid_name("?x")];
+        assert_eq!(rel.pretty_with_columns(&columns),
+                   "?entity  ?x           \n\
+                    65536    :foo/bar     \n\
+                    65537    :foo/longer  \n");
+        assert_eq!(rel.pretty(),
+                   "65536  :foo/bar     \n\
+                    65537  :foo/longer  \n");
+    }
+
+    #[test]
+    fn test_to_edn() {
+        let instant = edn::parse::value("#inst \"2017-04-28T20:23:05.187123Z\"").unwrap().without_spans();
+        let instant = TypedValue::Instant(instant.into_instant().unwrap());
+
+        let results = vec![
+            (QueryResults::Scalar(None), "nil"),
+            (QueryResults::Scalar(Some(TypedValue::Ref(65536))), "65536"),
+            (QueryResults::Tuple(None), "nil"),
+            (QueryResults::Tuple(Some(vec![TypedValue::typed_ns_keyword("foo", "bar"), instant.clone()])),
+             "[:foo/bar #inst \"2017-04-28T20:23:05.187123+00:00\"]"),
+            (QueryResults::Coll(vec![TypedValue::Long(1), TypedValue::typed_string("x")]), "[1 \"x\"]"),
+            (QueryResults::Rel(vec![vec![TypedValue::Ref(65536), TypedValue::Boolean(true)],
+                                    vec![TypedValue::Ref(65537), TypedValue::from(1.5f64)]]),
+             "[[65536 true] [65537 1.5]]"),
+            (QueryResults::Rel(vec![]), "[]"),
+        ];
+
+        for (results, expected) in results {
+            let edn = results.to_edn();
+            let pretty = edn.to_pretty_string();
+            assert_eq!(pretty, expected);
+            assert_eq!(edn::parse::value(&pretty).expect("to parse EDN").without_spans(), edn);
+        }
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut schema = Schema::default();
+        schema.entid_map.insert(65536, NamespacedKeyword::new("foo", "bar"));
+        schema.ident_map.insert(NamespacedKeyword::new("foo", "bar"), 65536);
+
+        let kw = TypedValue::typed_ns_keyword;
+
+        let rel = QueryResults::Rel(vec![vec![TypedValue::Ref(65536), TypedValue::typed_string("x"), kw("foo", "baz")],
+                                         vec![TypedValue::Ref(65537), TypedValue::Long(10), kw("foo", "longer")]]);
+        assert_eq!(rel.dump(&schema),
+                   "0         1    2            \n\
+                    :foo/bar  \"x\"  :foo/baz     \n\
+                    65537     10   :foo/longer  \n");
+
+        let columns = vec![Variable::from_valid_name("?e"), Variable::from_valid_name("?v"), Variable::from_valid_name("?k")];
+        assert_eq!(rel.dump_with_columns(&schema, &columns),
+                   "?e        ?v   ?k           \n\
+                    :foo/bar  \"x\"  :foo/baz     \n\
+                    65537     10   :foo/longer  \n");
+
+        assert_eq!(QueryResults::Scalar(Some(TypedValue::Ref(65536))).dump(&schema),
+                   "0         \n\
+                    :foo/bar  \n");
+        assert_eq!(QueryResults::Scalar(None).dump(&schema),
+                   "0  \n");
+        assert_eq!(QueryResults::Coll(vec![TypedValue::Long(1), TypedValue::Long(22)]).dump(&schema),
+                   "0   \n\
+                    1   \n\
+                    22  \n");
+    }
+
+    #[test]
+    fn test_into_results_mismatch() {
+        let scalar = || QueryResults::Scalar(None);
+        let coll = || QueryResults::Coll(vec![]);
+        let tuple = || QueryResults::Tuple(None);
+        let rel = || QueryResults::Rel(vec![]);
+
+        expect_mismatch(coll().into_scalar(), "coll", "scalar");
+        expect_mismatch(tuple().into_scalar(), "tuple", "scalar");
+        expect_mismatch(rel().into_scalar(), "rel", "scalar");
+
+        expect_mismatch(scalar().into_coll(), "scalar", "coll");
+        expect_mismatch(tuple().into_coll(), "tuple", "coll");
+        expect_mismatch(rel().into_coll(), "rel", "coll");
+
+        expect_mismatch(scalar().into_tuple(), "scalar", "tuple");
+        expect_mismatch(coll().into_tuple(), "coll", "tuple");
+        expect_mismatch(rel().into_tuple(), "rel", "tuple");
+
+        expect_mismatch(scalar().into_rel(), "scalar", "rel");
+        expect_mismatch(coll().into_rel(), "coll", "rel");
+        expect_mismatch(tuple().into_rel(), "tuple", "rel");
     }
 }
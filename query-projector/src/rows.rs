@@ -0,0 +1,140 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Convert the rows of a relation into Rust values, checking the type of each value as it goes.
+//! See `QueryResults::into_rows`.
+//!
+//! `FromTuple` is implemented for tuples of up to eight `FromValue` types, so that
+//! `into_rows::<(Entid, String, i64)>()` yields typed rows.  To fill a struct by position,
+//! implement `FromTuple` for it in terms of a tuple:
+//!
+//! ```ignore
+//! impl FromTuple for Person {
+//!     fn from_tuple(values: Vec<TypedValue>) -> Result<Person> {
+//!         let (id, name) = <(Entid, String)>::from_tuple(values)?;
+//!         Ok(Person { id: id, name: name })
+//!     }
+//! }
+//! ```
+
+use std::sync::Arc;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    DateTime,
+    KnownEntid,
+    TypedValue,
+    Utc,
+    Uuid,
+    ValueType,
+};
+
+use {
+    ErrorKind,
+    Result,
+};
+
+/// A Rust value that a single query result value can be converted into.
+pub trait FromValue: Sized {
+    /// Convert `value`, failing with `ErrorKind::UnexpectedValueType` if it's of the wrong type.
+    fn from_value(value: TypedValue) -> Result<Self>;
+}
+
+/// A Rust value that a row of query results can be converted into, by position.
+pub trait FromTuple: Sized {
+    /// Convert `values`, failing with `ErrorKind::UnexpectedRowLength` if there are too few or too
+    /// many, or with `ErrorKind::UnexpectedValueType` if any is of the wrong type.
+    fn from_tuple(values: Vec<TypedValue>) -> Result<Self>;
+}
+
+impl FromValue for TypedValue {
+    fn from_value(value: TypedValue) -> Result<TypedValue> {
+        Ok(value)
+    }
+}
+
+/// An `Entid` is an `i64`, so this accepts a ref as well as a long.
+impl FromValue for i64 {
+    fn from_value(value: TypedValue) -> Result<i64> {
+        match value {
+            TypedValue::Ref(x) | TypedValue::Long(x) => Ok(x),
+            other => bail!(ErrorKind::UnexpectedValueType(ValueType::Long, other.value_type())),
+        }
+    }
+}
+
+impl FromValue for KnownEntid {
+    fn from_value(value: TypedValue) -> Result<KnownEntid> {
+        match value {
+            TypedValue::Ref(x) => Ok(KnownEntid(x)),
+            other => bail!(ErrorKind::UnexpectedValueType(ValueType::Ref, other.value_type())),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: TypedValue) -> Result<String> {
+        match value {
+            TypedValue::String(x) => Ok(Arc::try_unwrap(x).unwrap_or_else(|x| (*x).clone())),
+            other => bail!(ErrorKind::UnexpectedValueType(ValueType::String, other.value_type())),
+        }
+    }
+}
+
+impl FromValue for NamespacedKeyword {
+    fn from_value(value: TypedValue) -> Result<NamespacedKeyword> {
+        match value {
+            TypedValue::Keyword(x) => Ok(Arc::try_unwrap(x).unwrap_or_else(|x| (*x).clone())),
+            other => bail!(ErrorKind::UnexpectedValueType(ValueType::Keyword, other.value_type())),
+        }
+    }
+}
+
+macro_rules! from_value {
+    ($t:ty, $value_type:expr, $into:ident) => {
+        impl FromValue for $t {
+            fn from_value(value: TypedValue) -> Result<$t> {
+                let actual = value.value_type();
+                value.$into().ok_or_else(|| ErrorKind::UnexpectedValueType($value_type, actual).into())
+            }
+        }
+    }
+}
+
+from_value!(bool, ValueType::Boolean, into_boolean);
+from_value!(f64, ValueType::Double, into_double);
+from_value!(DateTime<Utc>, ValueType::Instant, into_instant);
+from_value!(Uuid, ValueType::Uuid, into_uuid);
+from_value!(Arc<String>, ValueType::String, into_string);
+from_value!(Arc<NamespacedKeyword>, ValueType::Keyword, into_kw);
+
+macro_rules! from_tuple {
+    ($len:expr; $($t:ident),+) => {
+        impl<$($t: FromValue),+> FromTuple for ($($t,)+) {
+            fn from_tuple(values: Vec<TypedValue>) -> Result<($($t,)+)> {
+                if values.len() != $len {
+                    bail!(ErrorKind::UnexpectedRowLength($len, values.len()));
+                }
+                let mut values = values.into_iter();
+                Ok(($(<$t as FromValue>::from_value(values.next().expect("length checked"))?,)+))
+            }
+        }
+    }
+}
+
+from_tuple!(1; A);
+from_tuple!(2; A, B);
+from_tuple!(3; A, B, C);
+from_tuple!(4; A, B, C, D);
+from_tuple!(5; A, B, C, D, E);
+from_tuple!(6; A, B, C, D, E, F);
+from_tuple!(7; A, B, C, D, E, F, G);
+from_tuple!(8; A, B, C, D, E, F, G, H);
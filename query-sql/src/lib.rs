@@ -60,6 +60,18 @@ pub enum ColumnOrExpression {
     Integer(i32),       // We use these for type codes etc.
     Long(i64),
     Value(TypedValue),
+    /// A column projected by the subquery we're selecting from, referred to by its name.
+    ExistingColumn(Name),
+    Expression(Box<Expression>),
+}
+
+/// An SQL expression more complicated than a column or a value.
+pub enum Expression {
+    /// A function of one argument, like `sum(x)`.
+    Unary {
+        sql_op: &'static str,
+        arg: ColumnOrExpression,
+    },
 }
 
 /// `QueryValue` and `ColumnOrExpression` are almost identical… merge somehow?
@@ -84,6 +96,15 @@ pub enum Projection {
     One,
 }
 
+pub enum GroupBy {
+    /// Group by a column of the projection, referred to by its name.
+    ProjectedColumn(Name),
+
+    /// Put every row in one group.  This differs from having no `GROUP BY` at all: aggregating
+    /// no rows at all produces no groups, rather than a single row of `NULL`s and zeroes.
+    Everything,
+}
+
 #[derive(Copy, Clone)]
 pub struct Op(pub &'static str);      // TODO: we can do better than this!
 
@@ -177,6 +198,7 @@ pub enum Values {
 pub enum FromClause {
     TableList(TableList),      // Short-hand for a pile of inner joins.
     Join(Join),
+    Subquery(Box<SelectQuery>),
     Nothing,
 }
 
@@ -185,6 +207,7 @@ pub struct SelectQuery {
     pub projection: Projection,
     pub from: FromClause,
     pub constraints: Vec<Constraint>,
+    pub group_by: Vec<GroupBy>,
     pub order: Vec<OrderBy>,
     pub limit: Limit,
 }
@@ -277,6 +300,26 @@ impl QueryFragment for ColumnOrExpression {
             &Value(ref v) => {
                 out.push_typed_value(v)
             },
+            &ExistingColumn(ref alias) => {
+                out.push_identifier(alias.as_str())
+            },
+            &Expression(ref e) => {
+                e.push_sql(out)
+            },
+        }
+    }
+}
+
+impl QueryFragment for Expression {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        match self {
+            &Expression::Unary { ref sql_op, ref arg } => {
+                out.push_sql(sql_op);
+                out.push_sql("(");
+                arg.push_sql(out)?;
+                out.push_sql(")");
+                Ok(())
+            },
         }
     }
 }
@@ -492,6 +535,12 @@ impl QueryFragment for FromClause {
                 out.push_sql(" FROM ");
                 join.push_sql(out)
             },
+            &Subquery(ref subquery) => {
+                out.push_sql(" FROM (");
+                subquery.push_sql(out)?;
+                out.push_sql(")");
+                Ok(())
+            },
             &Nothing => Ok(()),
         }
     }
@@ -527,6 +576,19 @@ impl QueryFragment for SelectQuery {
                        { out.push_sql(" AND ") });
         }
 
+        if !self.group_by.is_empty() {
+            out.push_sql(" GROUP BY ");
+            interpose!(group, self.group_by,
+                       { match group {
+                             &GroupBy::ProjectedColumn(ref name) => { out.push_identifier(name.as_str())?; },
+                             // SQLite treats a constant integer as the index of a result column,
+                             // but any other constant is a single group.
+                             &GroupBy::Everything => { out.push_sql("NULL"); },
+                         }
+                       },
+                       { out.push_sql(", ") });
+        }
+
         if !self.order.is_empty() {
             out.push_sql(" ORDER BY ");
             interpose!(&OrderBy(ref dir, ref var), self.order,
@@ -568,6 +630,7 @@ impl SelectQuery {
 mod tests {
     use super::*;
     use std::rc::Rc;
+    use std::sync::Arc;
 
     use mentat_query_algebrizer::{
         Column,
@@ -676,7 +739,7 @@ mod tests {
         let c = Constraint::Infix {
             op: Op("MATCHES"),
             left: ColumnOrExpression::Column(QualifiedAlias("fulltext01".to_string(), Column::Fulltext(FulltextColumn::Text))),
-            right: ColumnOrExpression::Value(TypedValue::String(Rc::new("needle".to_string()))),
+            right: ColumnOrExpression::Value(TypedValue::String(Arc::new("needle".to_string()))),
         };
         let q = build_query(&c);
         assert_eq!("`fulltext01`.text MATCHES $v0", q.sql);
@@ -727,6 +790,7 @@ mod tests {
                     right: ColumnOrExpression::Entid(65536),
                 },
             ],
+            group_by: vec![],
             order: vec![],
             limit: Limit::None,
         };
@@ -744,4 +808,64 @@ mod tests {
         assert!(args.is_empty());
 
     }
+
+    #[test]
+    fn test_aggregate_over_subquery() {
+        // [:find ?x (sum ?v) :with ?y :where [?x 65537 ?v] [?y 65536 ?x]]
+        let datoms00 = "datoms00".to_string();
+        let datoms01 = "datoms01".to_string();
+        let column = |alias: &String, column: DatomsColumn| {
+            ColumnOrExpression::Column(QualifiedAlias::new(alias.clone(), column))
+        };
+
+        let inner = SelectQuery {
+            distinct: true,
+            projection: Projection::Columns(vec![
+                ProjectedColumn(column(&datoms00, DatomsColumn::Entity), "?x".to_string()),
+                ProjectedColumn(column(&datoms00, DatomsColumn::Value), "?v".to_string()),
+                ProjectedColumn(column(&datoms01, DatomsColumn::Entity), "?y".to_string()),
+            ]),
+            from: FromClause::TableList(TableList(vec![
+                TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms00.clone())),
+                TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms01.clone())),
+            ])),
+            constraints: vec![
+                Constraint::equal(column(&datoms00, DatomsColumn::Attribute), ColumnOrExpression::Entid(65537)),
+                Constraint::equal(column(&datoms01, DatomsColumn::Attribute), ColumnOrExpression::Entid(65536)),
+                Constraint::equal(column(&datoms01, DatomsColumn::Value), column(&datoms00, DatomsColumn::Entity)),
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+        };
+
+        let mut outer = SelectQuery {
+            distinct: false,
+            projection: Projection::Columns(vec![
+                ProjectedColumn(ColumnOrExpression::ExistingColumn("?x".to_string()), "?x".to_string()),
+                ProjectedColumn(ColumnOrExpression::Expression(Box::new(Expression::Unary {
+                                    sql_op: "sum",
+                                    arg: ColumnOrExpression::ExistingColumn("?v".to_string()),
+                                })),
+                                "(sum ?v)".to_string()),
+            ]),
+            from: FromClause::Subquery(Box::new(inner)),
+            constraints: vec![],
+            group_by: vec![GroupBy::ProjectedColumn("?x".to_string())],
+            order: vec![],
+            limit: Limit::None,
+        };
+
+        let SQLQuery { sql, args } = outer.to_sql_query().unwrap();
+        assert_eq!("SELECT `?x` AS `?x`, sum(`?v`) AS `(sum ?v)` FROM \
+                    (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?v`, `datoms01`.e AS `?y` \
+                     FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 65537 AND `datoms01`.a = 65536 AND `datoms01`.v = `datoms00`.e) \
+                    GROUP BY `?x`", sql);
+        assert!(args.is_empty());
+
+        outer.group_by = vec![GroupBy::Everything];
+        let SQLQuery { sql, .. } = outer.to_sql_query().unwrap();
+        assert!(sql.ends_with(" GROUP BY NULL"));
+    }
 }
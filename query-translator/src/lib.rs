@@ -26,6 +26,7 @@ pub use mentat_query_sql::{
 pub use translate::{
     cc_to_exists,
     query_to_select,
+    ProjectedSelect,
 };
 
 error_chain! {
@@ -163,6 +163,15 @@ impl ToConstraint for ColumnConstraint {
                 Constraint::equal(column, ColumnOrExpression::Integer(value_type.value_type_tag()))
             },
 
+            ValueIndexed(table) => {
+                // This must match the `WHERE` clause of `idx_datoms_avet` exactly.
+                Constraint::Infix {
+                    op: Op("IS NOT"),
+                    left: ColumnOrExpression::Column(QualifiedAlias::new(table, DatomsColumn::IndexAVET)),
+                    right: ColumnOrExpression::Integer(0),
+                }
+            },
+
             NotExists(computed_table) => {
                 let subquery = table_for_computed(computed_table, TableAlias::new());
                 Constraint::NotExists {
@@ -306,6 +315,7 @@ fn cc_to_select_query(projection: Projection,
                        .into_iter()
                        .map(|c| c.to_constraint())
                        .collect(),
+        group_by: vec![],
         order: order,
         limit: limit,
     }
@@ -321,6 +331,7 @@ pub fn cc_to_exists(cc: ConjoiningClauses) -> SelectQuery {
             projection: Projection::One,
             from: FromClause::Nothing,
             constraints: vec![],
+            group_by: vec![],
             order: vec![],
             limit: Limit::None,
         }
@@ -332,11 +343,34 @@ pub fn cc_to_exists(cc: ConjoiningClauses) -> SelectQuery {
 /// Consume a provided `AlgebraicQuery` to yield a new
 /// `ProjectedSelect`.
 pub fn query_to_select(query: AlgebraicQuery) -> Result<ProjectedSelect> {
-    // TODO: we can't pass `query.limit` here if we aggregate during projection.
-    // SQL-based aggregation -- `SELECT SUM(datoms00.e)` -- is fine.
-    let CombinedProjection { sql_projection, datalog_projector, distinct } = query_projection(&query)?;
+    let CombinedProjection {
+        sql_projection,
+        pre_aggregate_projection,
+        datalog_projector,
+        distinct,
+        group_by,
+    } = query_projection(&query)?;
+
+    let select = match pre_aggregate_projection {
+        None => cc_to_select_query(sql_projection, query.cc, distinct, query.order, query.limit),
+        Some(pre_aggregate_projection) => {
+            // Collect the distinct bindings in a subquery, and aggregate over them: the
+            // subquery's order doesn't matter, and the limit applies to the aggregated rows.
+            let inner = cc_to_select_query(pre_aggregate_projection, query.cc, true, None, Limit::None);
+            SelectQuery {
+                distinct: distinct,
+                projection: sql_projection,
+                from: FromClause::Subquery(Box::new(inner)),
+                constraints: vec![],
+                group_by: group_by,
+                order: query.order.unwrap_or(vec![]),
+                limit: query.limit,
+            }
+        },
+    };
+
     Ok(ProjectedSelect {
-        query: cc_to_select_query(sql_projection, query.cc, distinct, query.order, query.limit),
+        query: select,
         projector: datalog_projector,
     })
 }
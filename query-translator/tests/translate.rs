@@ -18,6 +18,7 @@ extern crate mentat_sql;
 use std::collections::BTreeMap;
 
 use std::rc::Rc;
+use std::sync::Arc;
 
 use mentat_query::{
     NamespacedKeyword,
@@ -129,6 +130,31 @@ fn test_rel() {
     assert_eq!(args, vec![make_arg("$v0", "yyy")]);
 }
 
+#[test]
+fn test_indexed_value() {
+    let mut schema = prepopulated_schema();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "indexed"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::String,
+        index: true,
+        ..Default::default()
+    });
+
+    // Looking an indexed attribute up by value repeats the value index's `WHERE` clause, and
+    // constrains the type tag that precedes the value in that index.
+    let query = r#"[:find ?x :where [?x :foo/indexed "yyy"]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 101 AND `datoms00`.v = $v0 \
+                     AND `datoms00`.index_avet IS NOT 0 AND `datoms00`.value_type_tag = 10");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+
+    // A variable value can't use the index.
+    let query = r#"[:find ?x ?y :where [?x :foo/indexed ?y]]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 101");
+}
+
 #[test]
 fn test_limit() {
     let schema = prepopulated_schema();
@@ -622,7 +648,7 @@ fn test_ground_scalar() {
 
     // Verify that we accept bound input constants.
     let query = r#"[:find ?x . :in ?v :where [(ground ?v) ?x]]"#;
-    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?v"), TypedValue::String(Rc::new("aaa".into())))]);
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?v"), TypedValue::String(Arc::new("aaa".into())))]);
     let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
     assert_eq!(sql, "SELECT $v0 AS `?x` LIMIT 1");
     assert_eq!(args, vec![make_arg("$v0", "aaa"),]);
@@ -641,7 +667,7 @@ fn test_ground_tuple() {
     // Verify that we accept bound input constants.
     let query = r#"[:find [?x ?y] :in ?u ?v :where [(ground [?u ?v]) [?x ?y]]]"#;
     let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?u"), TypedValue::Long(2)),
-                                                       (Variable::from_valid_name("?v"), TypedValue::String(Rc::new("aaa".into()))),]);
+                                                       (Variable::from_valid_name("?v"), TypedValue::String(Arc::new("aaa".into()))),]);
     let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
     // TODO: treat 2 as an input variable that could be bound late, rather than eagerly binding it.
     assert_eq!(sql, "SELECT 2 AS `?x`, $v0 AS `?y` LIMIT 1");
@@ -955,3 +981,34 @@ fn test_instant_range() {
                        AND `datoms00`.v > 1497574601257000");
     assert_eq!(args, vec![]);
 }
+
+#[test]
+fn test_aggregates() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x (count ?y) :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT `?x` AS `?x`, count(`?y`) AS `(count ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99) \
+                     GROUP BY `?x`");
+    assert_eq!(args, vec![]);
+
+    // Without grouping variables, the `:with` variables still make it into the subquery.
+    let query = r#"[:find (sum ?y) :with ?x :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT sum(`?y`) AS `(sum ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.v AS `?y`, `datoms00`.e AS `?x` \
+                           FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99) \
+                     GROUP BY NULL");
+    assert_eq!(args, vec![]);
+
+    // We can't sum strings, nor run aggregates we don't know.
+    let schema = prepopulated_schema();
+    for query in &[r#"[:find (sum ?y) :where [?x :foo/bar ?y]]"#,
+                   r#"[:find (frobnicate ?y) :where [?x :foo/bar ?y]]"#] {
+        let parsed = parse_find_string(query).expect("parse to succeed");
+        let algebrized = algebrize(&schema, parsed).expect("algebrize to succeed");
+        assert!(query_to_select(algebrized).is_err());
+    }
+}
@@ -39,7 +39,8 @@ use std::collections::{
 };
 
 use std::fmt;
-use std::rc::Rc;
+use std::iter;
+use std::sync::Arc;
 
 use edn::{
     BigInt,
@@ -61,7 +62,7 @@ use mentat_core::{
 pub type SrcVarName = String;          // Do not include the required syntactic '$'.
 
 #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct Variable(pub Rc<PlainSymbol>);
+pub struct Variable(pub Arc<PlainSymbol>);
 
 impl Variable {
     pub fn as_str(&self) -> &str {
@@ -80,7 +81,19 @@ impl Variable {
     pub fn from_valid_name(name: &str) -> Variable {
         let s = PlainSymbol::new(name);
         assert!(s.is_var_symbol());
-        Variable(Rc::new(s))
+        Variable(Arc::new(s))
+    }
+
+    /// Return a new `Variable` if the provided string is exactly a valid variable name: a single
+    /// EDN symbol beginning with `?`, such as `?foo`.  Otherwise, return `None`.
+    pub fn from_name(name: &str) -> Option<Variable> {
+        if name.len() < 2 {
+            return None;
+        }
+        edn::parse::value(name)
+            .ok()
+            .and_then(|v| Variable::from_value(&v))
+            .and_then(|var| if var.as_str() == name { Some(var) } else { None })
     }
 }
 
@@ -102,7 +115,7 @@ impl FromValue<Variable> for Variable {
 }
 
 impl Variable {
-    pub fn from_rc(sym: Rc<PlainSymbol>) -> Option<Variable> {
+    pub fn from_arc(sym: Arc<PlainSymbol>) -> Option<Variable> {
         if sym.is_var_symbol() {
             Some(Variable(sym.clone()))
         } else {
@@ -113,7 +126,7 @@ impl Variable {
     /// TODO: intern strings. #398.
     pub fn from_symbol(sym: &PlainSymbol) -> Option<Variable> {
         if sym.is_var_symbol() {
-            Some(Variable(Rc::new(sym.clone())))
+            Some(Variable(Arc::new(sym.clone())))
         } else {
             None
         }
@@ -192,7 +205,7 @@ pub enum NonIntegerConstant {
     Boolean(bool),
     BigInteger(BigInt),
     Float(OrderedFloat<f64>),
-    Text(Rc<String>),
+    Text(Arc<String>),
     Instant(DateTime<Utc>),
     Uuid(Uuid),
 }
@@ -247,7 +260,7 @@ impl FromValue<FnArg> for FnArg {
                 Some(FnArg::Constant(NonIntegerConstant::BigInteger(x.clone()))),
             Text(ref x) =>
                 // TODO: intern strings. #398.
-                Some(FnArg::Constant(NonIntegerConstant::Text(Rc::new(x.clone())))),
+                Some(FnArg::Constant(NonIntegerConstant::Text(Arc::new(x.clone())))),
             Nil |
             NamespacedSymbol(_) |
             Keyword(_) |
@@ -279,7 +292,7 @@ pub enum PatternNonValuePlace {
     Placeholder,
     Variable(Variable),
     Entid(i64),                       // Will always be +ve. See #190.
-    Ident(Rc<NamespacedKeyword>),
+    Ident(Arc<NamespacedKeyword>),
 }
 
 impl PatternNonValuePlace {
@@ -322,7 +335,7 @@ impl FromValue<PatternNonValuePlace> for PatternNonValuePlace {
                 }
             },
             edn::SpannedValue::NamespacedKeyword(ref x) =>
-                Some(PatternNonValuePlace::Ident(Rc::new(x.clone()))),
+                Some(PatternNonValuePlace::Ident(Arc::new(x.clone()))),
             _ => None,
         }
     }
@@ -330,7 +343,7 @@ impl FromValue<PatternNonValuePlace> for PatternNonValuePlace {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum IdentOrEntid {
-    Ident(Rc<NamespacedKeyword>),
+    Ident(Arc<NamespacedKeyword>),
     Entid(i64),
 }
 
@@ -342,7 +355,7 @@ pub enum PatternValuePlace {
     Placeholder,
     Variable(Variable),
     EntidOrInteger(i64),
-    IdentOrKeyword(Rc<NamespacedKeyword>),
+    IdentOrKeyword(Arc<NamespacedKeyword>),
     Constant(NonIntegerConstant),
 }
 
@@ -356,7 +369,7 @@ impl FromValue<PatternValuePlace> for PatternValuePlace {
             edn::SpannedValue::PlainSymbol(ref x) =>
                 Variable::from_symbol(x).map(PatternValuePlace::Variable),
             edn::SpannedValue::NamespacedKeyword(ref x) =>
-                Some(PatternValuePlace::IdentOrKeyword(Rc::new(x.clone()))),
+                Some(PatternValuePlace::IdentOrKeyword(Arc::new(x.clone()))),
             edn::SpannedValue::Boolean(x) =>
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Boolean(x))),
             edn::SpannedValue::Float(x) =>
@@ -367,7 +380,7 @@ impl FromValue<PatternValuePlace> for PatternValuePlace {
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Instant(x))),
             edn::SpannedValue::Text(ref x) =>
                 // TODO: intern strings. #398.
-                Some(PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new(x.clone())))),
+                Some(PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new(x.clone())))),
             edn::SpannedValue::Uuid(ref u) =>
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Uuid(u.clone()))),
 
@@ -428,20 +441,31 @@ pub struct Pull {
 }
 */
 
-/*
+/// An aggregate over a single variable, like `(count ?x)`.
+///
+/// Which functions are supported is up to the projector.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Aggregate {
-    pub fn_name: String,
-    pub args: Vec<FnArg>,
+    pub func: QueryFunction,
+    pub var: Variable,
 }
-*/
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Element {
     Variable(Variable),
-    // Aggregate(Aggregate),   // TODO
+    Aggregate(Aggregate),
     // Pull(Pull),             // TODO
 }
 
+impl Element {
+    pub fn is_aggregate(&self) -> bool {
+        match self {
+            &Element::Variable(_) => false,
+            &Element::Aggregate(_) => true,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Limit {
     None,
@@ -480,7 +504,7 @@ pub enum Limit {
 /// # }
 /// ```
 ///
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum FindSpec {
     /// Returns an array of arrays.
     FindRel(Vec<Element>),
@@ -511,6 +535,33 @@ impl FindSpec {
         }
     }
 
+    /// Return the elements projected by this find spec, in order.
+    pub fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
+        use FindSpec::*;
+        match self {
+            &FindScalar(ref e) => Box::new(iter::once(e)),
+            &FindColl(ref e)   => Box::new(iter::once(e)),
+            &FindTuple(ref v)  => Box::new(v.iter()),
+            &FindRel(ref v)    => Box::new(v.iter()),
+        }
+    }
+
+    /// Return the variables projected by this find spec, in order.  An aggregate is named by the
+    /// variable it aggregates, so in `[:find (min ?x) (max ?x)]` two columns are named `?x`.
+    pub fn variables(&self) -> Vec<Variable> {
+        self.columns()
+            .map(|e| match e {
+                &Element::Variable(ref var) => var.clone(),
+                &Element::Aggregate(ref aggregate) => aggregate.var.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns true if any of the elements of this find spec is an aggregate.
+    pub fn has_aggregates(&self) -> bool {
+        self.columns().any(|e| e.is_aggregate())
+    }
+
     pub fn expected_column_count(&self) -> usize {
         use FindSpec::*;
         match self {
@@ -668,7 +719,7 @@ impl Pattern {
                     return Some(Pattern {
                         source: src,
                         entity: v_e,
-                        attribute: PatternNonValuePlace::Ident(Rc::new(k.to_reversed())),
+                        attribute: PatternNonValuePlace::Ident(Arc::new(k.to_reversed())),
                         value: e_v,
                         tx: tx,
                     });
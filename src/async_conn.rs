@@ -0,0 +1,200 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Transact on a background thread, so that writers needn't wait for SQLite.  See
+//! `Conn::into_async`.
+//!
+//! An `AsyncConn` owns a writer thread, which owns the SQLite connection it writes through, and a
+//! queue of work for it.  Transactions are run one at a time, in the order they were submitted,
+//! each committed before the next begins.  The `Conn` is shared, so that other threads can query
+//! it, through their own SQLite connections, and see each transaction as soon as it commits.
+
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+
+use rusqlite;
+
+use mentat_db::TxReport;
+
+use conn::Conn;
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+enum Job {
+    Transact(String, mpsc::Sender<Result<TxReport>>),
+    Flush(mpsc::Sender<()>),
+}
+
+/// The eventual result of a transaction submitted to an `AsyncConn`.
+pub struct TxFuture {
+    receiver: mpsc::Receiver<Result<TxReport>>,
+}
+
+impl TxFuture {
+    /// Block until the transaction has been committed or has failed, and return its report or
+    /// its error.
+    pub fn wait(self) -> Result<TxReport> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            Err(_) => bail!(ErrorKind::TransactorStopped),
+        }
+    }
+
+    /// Return the transaction's result if it's finished, or `None` if it's still waiting to run.
+    pub fn try_wait(&self) -> Option<Result<TxReport>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(ErrorKind::TransactorStopped.into())),
+        }
+    }
+}
+
+/// A `Conn` that transacts on a background thread.  Dropping it waits for the submitted
+/// transactions to finish, and then stops the thread.
+pub struct AsyncConn {
+    conn: Arc<Conn>,
+    sender: Option<mpsc::Sender<Job>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+/// Transact `transaction` through `conn` and `sqlite`, as `Conn::transact` does.
+fn transact(conn: &Conn, sqlite: &mut rusqlite::Connection, transaction: &str) -> Result<TxReport> {
+    let mut in_progress = conn.begin_transaction(sqlite)?;
+    in_progress.transact(transaction)?;
+    Ok(in_progress.commit()?.expect("we always get a report"))
+}
+
+impl AsyncConn {
+    /// Start a writer thread transacting through `conn` and `sqlite`.  See `Conn::into_async`.
+    pub fn new(conn: Conn, sqlite: rusqlite::Connection) -> AsyncConn {
+        let conn = Arc::new(conn);
+        let (sender, receiver) = mpsc::channel();
+
+        let writer_conn = conn.clone();
+        let writer = thread::spawn(move || {
+            let mut sqlite = sqlite;
+            for job in receiver {
+                // Nobody need be waiting for a reply.
+                match job {
+                    Job::Transact(transaction, reply) => {
+                        let _ = reply.send(transact(&writer_conn, &mut sqlite, &transaction));
+                    },
+                    Job::Flush(reply) => {
+                        let _ = reply.send(());
+                    },
+                }
+            }
+        });
+
+        AsyncConn {
+            conn: conn,
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+
+    /// The `Conn` transacted through, for querying from any thread with another SQLite connection
+    /// to the same store.  It reflects each transaction as soon as it commits.
+    pub fn conn(&self) -> &Arc<Conn> {
+        &self.conn
+    }
+
+    fn submit(&self, job: Job) -> Result<()> {
+        match self.sender.as_ref().expect("sender until dropped").send(job) {
+            Ok(()) => Ok(()),
+            Err(_) => bail!(ErrorKind::TransactorStopped),
+        }
+    }
+
+    /// Queue `transaction`, in EDN, to be transacted after everything submitted before it.  A
+    /// transaction that fails reports its error through its `TxFuture`, and doesn't affect those
+    /// that follow.
+    pub fn transact(&self, transaction: String) -> TxFuture {
+        let (reply, receiver) = mpsc::channel();
+        // If the writer has stopped, the reply sender is dropped, and `wait` reports it.
+        let _ = self.submit(Job::Transact(transaction, reply));
+        TxFuture {
+            receiver: receiver,
+        }
+    }
+
+    /// Block until everything submitted so far has been transacted.
+    pub fn flush(&self) -> Result<()> {
+        let (reply, receiver) = mpsc::channel();
+        self.submit(Job::Flush(reply))?;
+        match receiver.recv() {
+            Ok(()) => Ok(()),
+            Err(_) => bail!(ErrorKind::TransactorStopped),
+        }
+    }
+}
+
+impl Drop for AsyncConn {
+    fn drop(&mut self) {
+        // Closing the queue lets the writer finish what's queued, and then stop.
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_core::TypedValue;
+
+    use mentat_db::debug::TempStorePath;
+
+    #[test]
+    fn test_async_conn() {
+        let path = TempStorePath::new("async");
+
+        let (mut conn, mut sqlite) = Conn::open(&path).expect("opened");
+        conn.transact(&mut sqlite, "[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transacted schema");
+        let async_conn = conn.into_async(sqlite);
+
+        // Three transactions, the second of which fails.
+        let first = async_conn.transact(r#"[{:db/id "a" :test/name "Alice"}]"#.to_string());
+        let second = async_conn.transact(r#"[{:db/id "b" :test/name 2}]"#.to_string());
+        let third = async_conn.transact(r#"[{:db/id "c" :test/name "Carol"}]"#.to_string());
+
+        let first = first.wait().expect("first transacted");
+        assert!(second.wait().is_err());
+        let third = third.wait().expect("third transacted");
+
+        // Transacted in order, and the failure didn't get in the way.
+        assert!(first.tx_id < third.tx_id);
+        assert!(first.tempids["a"] < third.tempids["c"]);
+
+        // Another thread, with its own SQLite connection, sees the committed changes.
+        async_conn.flush().expect("flushed");
+        let conn = async_conn.conn().clone();
+        let reader_path = path.to_path_buf();
+        let names = thread::spawn(move || {
+            let reader = ::mentat_db::db::new_connection(&reader_path).expect("opened reader");
+            conn.q_once(&reader, "[:find [?name ...] :where [_ :test/name ?name]]", None)
+                .expect("queried")
+                .into_coll()
+                .expect("coll")
+        }).join().expect("reader");
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&TypedValue::typed_string("Carol")));
+        assert_eq!(async_conn.conn().stats().transactions_committed, 3);
+
+        drop(async_conn);
+    }
+}
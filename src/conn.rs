@@ -10,7 +10,29 @@
 
 #![allow(dead_code)]
 
-use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::io::{
+    Read,
+    Write,
+};
+use std::mem;
+use std::path::Path;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc;
+use std::sync::atomic::{
+    AtomicBool,
+    AtomicUsize,
+    Ordering,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
 
 use rusqlite;
 use rusqlite::{
@@ -20,28 +42,87 @@ use rusqlite::{
 use edn;
 
 use mentat_core::{
+    Attribute,
     Entid,
+    KnownEntid,
     Schema,
     TypedValue,
+    ValueType,
 };
+use mentat_core::attribute::Unique as UniqueKind;
+use mentat_core::intern_set::BoundedInternSet;
 
 use mentat_db::db;
+use mentat_db::db::{
+    TypedSQLValue,
+    datom_value_sql,
+};
+use mentat_db::dumps;
 use mentat_db::{
-    transact,
+    partition_end,
+    partition_infos,
+    transact_as,
+    transact_with_options,
+    IntegrityIssue,
+    TX0,
+    Partition,
+    PartitionInfo,
     PartitionMap,
+    PartitionName,
+    TxOptions,
     TxReport,
+    VerificationReport,
+    verify,
 };
 
 use mentat_tx;
+use mentat_tx::entities::{
+    AtomOrLookupRefOrVectorOrMapNotation,
+    Entity,
+    EntidOrLookupRefOrTempId,
+    MapNotation,
+    OpType,
+    TempId,
+};
 
 use mentat_tx_parser;
 
 use errors::*;
+use async_conn::AsyncConn;
+use entity_builder::{
+    TermBuilder,
+    mint_tempid,
+};
+use constraints::{
+    Constraints,
+    Validator,
+};
+use entity_stream::EntityReader;
+use export;
+use export::ExportedTransaction;
+use pull;
+use pull::Pulled;
+use schema_diff::vocabulary_to_edn;
+use spans;
+use watch;
+use watch::{
+    TxChange,
+    WatchHandle,
+    Watches,
+};
 use query::{
     lookup_value_for_attribute,
+    q_explain,
     q_once,
+    q_once_each,
+    q_once_parsed,
+    q_once_with_options,
+    ControlFlow,
+    QueryExplanation,
+    QueryOptions,
+    QueryRow,
     QueryInputs,
-    QueryResults,
+    QueryOutput,
 };
 
 
@@ -55,6 +136,10 @@ pub struct Metadata {
     pub generation: u64,
     pub partition_map: PartitionMap,
     pub schema: Arc<Schema>,
+
+    /// True while a commit brings the metadata up to date with what it committed to SQLite.  If a
+    /// panic interrupts that, the metadata no longer matches the store.  See `try_lock_metadata`.
+    committing: bool,
 }
 
 impl Metadata {
@@ -64,8 +149,171 @@ impl Metadata {
             generation: generation,
             partition_map: partition_map,
             schema: schema,
+            committing: false,
+        }
+    }
+}
+
+/// Take the metadata mutex.  A panic while it was held poisons it; rather than propagate the panic
+/// to every thread that touches the `Conn`, adopt the metadata as the panic left it.
+///
+/// The metadata is only changed by a commit, so it can only be out of step with the store if a
+/// commit was interrupted.  That's fine for reading -- at worst, a query sees the schema as it
+/// was -- but not for writing, which must use `try_lock_metadata`.
+fn lock_metadata(mutex: &Mutex<Metadata>) -> MutexGuard<Metadata> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Take the metadata mutex as `lock_metadata` does, but fail with `ErrorKind::MetadataPoisoned`
+/// if a panic interrupted a commit, leaving the metadata out of step with the store.
+fn try_lock_metadata(mutex: &Mutex<Metadata>) -> Result<MutexGuard<Metadata>> {
+    let metadata = lock_metadata(mutex);
+    if metadata.committing {
+        bail!(ErrorKind::MetadataPoisoned);
+    }
+    Ok(metadata)
+}
+
+/// Counters of the work done through a `Conn`.
+///
+/// These are atomics so that they can be read without taking the metadata mutex.
+#[derive(Debug, Default)]
+struct Counters {
+    transactions_committed: AtomicUsize,
+    transactions_rolled_back: AtomicUsize,
+    queries: AtomicUsize,
+    datoms_asserted: AtomicUsize,
+    datoms_retracted: AtomicUsize,
+    watch_changes_dropped: AtomicUsize,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ConnStats {
+        ConnStats {
+            transactions_committed: self.transactions_committed.load(Ordering::Relaxed),
+            transactions_rolled_back: self.transactions_rolled_back.load(Ordering::Relaxed),
+            queries: self.queries.load(Ordering::Relaxed),
+            datoms_asserted: self.datoms_asserted.load(Ordering::Relaxed),
+            datoms_retracted: self.datoms_retracted.load(Ordering::Relaxed),
+            watch_changes_dropped: self.watch_changes_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn increment_queries(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the counters maintained by a `Conn`.  See `Conn::stats`.
+///
+/// Only explicit rollbacks are counted: an `InProgress` that is dropped without being committed
+/// discards its changes without being recorded here.  Datom counts don't include the
+/// `:db/txInstant` datom that accompanies every transaction.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ConnStats {
+    pub transactions_committed: usize,
+    pub transactions_rolled_back: usize,
+    pub queries: usize,
+    pub datoms_asserted: usize,
+    pub datoms_retracted: usize,
+
+    /// Changes not sent to a watcher because its channel was full or closed.  See `Conn::watch`.
+    pub watch_changes_dropped: usize,
+}
+
+/// How far a long transaction has got, as reported to `TransactOptions::progress`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct TransactProgress {
+    /// The number of entities read so far.
+    pub entities: usize,
+
+    /// The number of datoms asserted or retracted so far.  Every entity of a Mentat transaction
+    /// is read before any of it is written, so this only advances as each transaction is written.
+    pub datoms: usize,
+}
+
+/// Options for long transactions: see `InProgress::transact_entities_with_options` and
+/// `Conn::transact_from_read`.
+#[derive(Default)]
+pub struct TransactOptions<'a> {
+    /// Called with the progress so far each time another `report_every` entities have been read,
+    /// and once more when everything has been transacted.
+    pub progress: Option<Box<FnMut(TransactProgress) + 'a>>,
+
+    /// How often, in entities, to report progress and check `cancel` while reading.  If 0,
+    /// progress is only reported at the end.
+    pub report_every: usize,
+
+    /// Checked just after progress is reported, once every entity has been read, and as the
+    /// transactor processes each entity.  If it's set, reading stops, nothing more is written,
+    /// and the transaction fails with `ErrorKind::TransactInterrupted`, giving the progress made.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl<'a> TransactOptions<'a> {
+    fn report(&mut self, progress: TransactProgress) {
+        if let Some(ref mut f) = self.progress {
+            f(progress);
         }
     }
+
+    fn check_cancelled(&self, progress: TransactProgress) -> Result<()> {
+        match self.cancel {
+            Some(ref cancel) if cancel.load(Ordering::SeqCst) => bail!(ErrorKind::TransactInterrupted(progress.entities, progress.datoms)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How `Conn::transact_from_read` commits the chunks of entities it reads.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ChunkCommit {
+    /// Transact every chunk in a single SQL transaction: nothing is committed unless everything is.
+    Once,
+
+    /// Commit each chunk in its own SQL transaction, so that a failure leaves the chunks before it
+    /// in place and the rest of the input unread.
+    EachChunk,
+}
+
+/// The outcome of `Conn::compact`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct CompactionReport {
+    /// Rows removed from the transaction log: retractions, and the assertions they retracted.
+    pub datoms_removed: usize,
+
+    /// The number of bytes by which `VACUUM` shrank the database.
+    pub bytes_reclaimed: u64,
+}
+
+/// How `Conn::gc` should return free space to the filesystem.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum VacuumMode {
+    /// Leave free pages in the database for reuse.
+    None,
+
+    /// Rebuild the whole database with `VACUUM`.  This is thorough but slow.
+    Full,
+
+    /// Release free pages with `PRAGMA incremental_vacuum`.  This only does anything for stores
+    /// created with `PRAGMA auto_vacuum = INCREMENTAL`.
+    Incremental,
+}
+
+/// The outcome of `Conn::gc`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct GcReport {
+    /// Rows removed from `fulltext_values`.
+    pub fulltext_values_removed: usize,
+
+    /// The number of bytes by which the database shrank.
+    pub bytes_reclaimed: u64,
+}
+
+fn database_size(sqlite: &rusqlite::Connection) -> Result<u64> {
+    let page_count: i64 = sqlite.query_row("PRAGMA page_count", &[], |row| row.get(0))?;
+    let page_size: i64 = sqlite.query_row("PRAGMA page_size", &[], |row| row.get(0))?;
+    Ok((page_count * page_size) as u64)
 }
 
 /// A mutable, safe reference to the current Mentat store.
@@ -80,12 +328,45 @@ pub struct Conn {
     /// map and schema -- forward.
     metadata: Mutex<Metadata>,
 
-    // TODO: maintain set of change listeners or handles to transaction report queues. #298.
+    /// Paired with `metadata`, and notified each time a committed transaction advances its
+    /// generation.  See `wait_for_change`.
+    changed: Condvar,
+
+    /// Counters that are cheap to maintain and read without taking the metadata mutex.
+    counters: Counters,
+
+    /// How long `begin_transaction` waits for another connection to release its write lock, and
+    /// `transact` keeps retrying, if configured.  See `set_busy_timeout`.
+    busy_timeout: Option<Duration>,
+
+    /// If true, every attempt to write fails with `ErrorKind::ReadOnlyConnection`.  See
+    /// `connect_read_only`.
+    read_only: bool,
+
+    /// Checks on what each transaction writes.  See `register_validator`,
+    /// `require_attributes_for`, and `set_schema_locked`.
+    constraints: Constraints,
+
+    /// Called, in order, after each committed transaction.  A `Mutex` only to keep `Conn` `Sync`:
+    /// it's never held while the metadata mutex is.  See `on_commit`.
+    commit_callbacks: Mutex<Vec<CommitCallback>>,
+
+    /// Sent the changes each committed transaction makes to the attributes they watch.  Shared
+    /// with the `WatchHandle`s that unregister them.  See `watch`.
+    watches: Arc<Mutex<Watches>>,
+
+    /// Keyword values seen by earlier transactions, shared by later ones rather than allocated
+    /// afresh for each datom.  See `mentat_db::TxOptions::keywords`.
+    keywords: Mutex<BoundedInternSet<edn::NamespacedKeyword>>,
 
     // TODO: maintain cache of query plans that could be shared across threads and invalidated when
     // the schema changes. #315.
 }
 
+/// The number of distinct keyword values a `Conn` remembers before forgetting them all and
+/// starting afresh.
+const KEYWORD_CACHE_CAPACITY: usize = 4096;
+
 /// Represents an in-progress, not yet committed, set of changes to the store.
 /// Call `commit` to commit your changes, or `rollback` to discard them.
 /// A transaction is held open until you do so.
@@ -93,382 +374,4195 @@ pub struct Conn {
 pub struct InProgress<'a, 'c> {
     transaction: rusqlite::Transaction<'c>,
     mutex: &'a Mutex<Metadata>,
+    changed: &'a Condvar,
+    counters: &'a Counters,
     generation: u64,
     partition_map: PartitionMap,
     schema: Schema,
     last_report: Option<TxReport>,   // For now we track only the last, but we could accumulate all.
+    datoms_asserted: usize,
+    datoms_retracted: usize,
+    savepoints: Vec<Savepoint>,
+    constraints: &'a Constraints,
+    precommit_hook: Option<PrecommitHook>,
+    commit_callbacks: &'a Mutex<Vec<CommitCallback>>,
+    watches: &'a Mutex<Watches>,
+    keywords: &'a Mutex<BoundedInternSet<edn::NamespacedKeyword>>,
+
+    /// The ID the first transaction in this `InProgress` was, or will be, given.
+    first_tx: Entid,
+
+    /// The entid each string tempid resolved to in the transactions so far, so that later
+    /// transactions can refer to the same entities by the same tempids.
+    tempids: BTreeMap<String, Entid>,
+
+    /// Likewise for internal tempids minted by the caller, such as a `TermBuilder`'s, keyed by
+    /// number.
+    minted_tempids: BTreeMap<i64, Entid>,
 }
 
-impl<'a, 'c> InProgress<'a, 'c> {
-    pub fn transact_entities<I>(mut self, entities: I) -> Result<InProgress<'a, 'c>> where I: IntoIterator<Item=mentat_tx::entities::Entity> {
-        let (report, next_partition_map, next_schema) = transact(&self.transaction, self.partition_map, &self.schema, &self.schema, entities)?;
-        self.partition_map = next_partition_map;
-        if let Some(schema) = next_schema {
-            self.schema = schema;
-        }
-        self.last_report = Some(report);
-        Ok(self)
-    }
+/// A callback run after each committed transaction.  See `Conn::on_commit`.
+pub type CommitCallback = Box<Fn(&TxReport) + Send>;
 
-    /// Query the Mentat store, using the given connection and the current metadata.
-    pub fn q_once<T>(&self,
-                     query: &str,
-                     inputs: T) -> Result<QueryResults>
-        where T: Into<Option<QueryInputs>>
-        {
+/// A read-only view of an `InProgress`, as given to its pre-commit hook: it can be queried, and
+/// shows the work done so far.  See `InProgress::set_precommit_hook`.
+pub struct InProgressView<'v> {
+    transaction: &'v rusqlite::Connection,
+    counters: &'v Counters,
+    schema: &'v Schema,
+    last_report: Option<&'v TxReport>,
+}
 
-        q_once(&*(self.transaction),
-               &self.schema,
-               query,
-               inputs)
-    }
+/// A check run by `InProgress::commit` before committing.  Failing aborts the commit.
+pub type PrecommitHook = Box<Fn(&InProgressView) -> Result<()>>;
 
-    pub fn lookup_value_for_attribute(&self,
-                                      entity: Entid,
-                                      attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>> {
-        lookup_value_for_attribute(&*(self.transaction), &self.schema, entity, attribute)
-    }
+/// The in-memory state of an `InProgress` at the time a SQLite savepoint was taken, so that it can
+/// be restored alongside the SQL state when rolling back to that savepoint.
+struct Savepoint {
+    name: String,
+    partition_map: PartitionMap,
+    schema: Schema,
+    last_report: Option<TxReport>,
+    datoms_asserted: usize,
+    datoms_retracted: usize,
+    tempids: BTreeMap<String, Entid>,
+    minted_tempids: BTreeMap<i64, Entid>,
+}
 
-    pub fn transact(self, transaction: &str) -> Result<InProgress<'a, 'c>> {
-        let assertion_vector = edn::parse::value(transaction)?;
-        let entities = mentat_tx_parser::Tx::parse(&assertion_vector)?;
-        self.transact_entities(entities)
-    }
+/// The result of transacting: a report, and the partition map and schema (if changed) after it.
+type Transacted = (TxReport, PartitionMap, Option<Schema>);
 
-    pub fn last_report(&self) -> Option<&TxReport> {
-        self.last_report.as_ref()
-    }
+/// Quote `name` as a SQLite identifier.
+fn quote_savepoint_name(name: &str) -> String {
+    format!("\"{}\"", name.replace("\"", "\"\""))
+}
 
-    pub fn rollback(mut self) -> Result<()> {
-        self.last_report = None;
-        self.transaction.rollback().map_err(|e| e.into())
-    }
+/// A consistent, read-only view of the store, as of the moment it was taken.  Call `close` to
+/// release it; until then, the SQLite read transaction it holds remains open.  (Dropping a
+/// `Snapshot` also releases it.)
+///
+/// This is the read analog of `InProgress`: queries see neither the changes made by transactions
+/// committed after the snapshot was taken, nor any schema changes they made.
+pub struct Snapshot<'a, 'c> {
+    transaction: rusqlite::Transaction<'c>,
+    counters: &'a Counters,
+    schema: Arc<Schema>,
+}
 
-    pub fn commit(self) -> Result<Option<TxReport>> {
-        // The mutex is taken during this entire method.
-        let mut metadata = self.mutex.lock().unwrap();
+/// A read-only, not yet finished, scope of queries against the store.  See `Conn::begin_read`.
+pub type InProgressRead<'a, 'c> = Snapshot<'a, 'c>;
 
-        if self.generation != metadata.generation {
-            // Somebody else wrote!
-            // Retrying is tracked by https://github.com/mozilla/mentat/issues/357.
-            // This should not occur -- an attempt to take a competing IMMEDIATE transaction
-            // will fail with `SQLITE_BUSY`, causing this function to abort.
-            bail!("Lost the transact() race!");
-        }
+/// Return the number of datoms asserted and retracted by the transaction `tx_id`, not counting its
+/// `:db/txInstant` datom.
+fn count_transacted_datoms(sqlite: &rusqlite::Connection, schema: &Schema, tx_id: Entid) -> Result<(usize, usize)> {
+    let tx_instant = schema.get_entid(&edn::NamespacedKeyword::new("db", "txInstant"))
+                           .expect(":db/txInstant is always present");
+    let (asserted, retracted): (i64, i64) =
+        sqlite.query_row("SELECT COALESCE(SUM(added), 0), COALESCE(SUM(1 - added), 0) FROM transactions WHERE tx = ? AND a != ?",
+                         &[&tx_id, &tx_instant],
+                         |row| (row.get(0), row.get(1)))?;
+    Ok((asserted as usize, retracted as usize))
+}
 
-        // Commit the SQLite transaction while we hold the mutex.
-        self.transaction.commit()?;
+/// Return the attribute named `ident` in `schema`, and its entid, if `ident` names an attribute.
+fn attribute_for_ident(schema: &Schema, ident: &edn::NamespacedKeyword) -> Option<(Attribute, KnownEntid)> {
+    schema.get_entid(ident)
+          .and_then(|e| schema.attribute_for_entid(e).map(|attribute| (attribute.clone(), KnownEntid(e))))
+}
 
-        metadata.generation += 1;
-        metadata.partition_map = self.partition_map;
-        if self.schema != *(metadata.schema) {
-            metadata.schema = Arc::new(self.schema);
-        }
+/// Like `attribute_for_ident`, but failing with `ErrorKind::UnknownAttribute` if `ident` doesn't
+/// name an attribute.
+fn require_attribute_for_ident(schema: &Schema, ident: &edn::NamespacedKeyword) -> Result<(Attribute, KnownEntid)> {
+    attribute_for_ident(schema, ident).ok_or_else(|| ErrorKind::UnknownAttribute(ident.clone()).into())
+}
 
-        Ok(self.last_report)
+/// Resolve `ident` to the entid of a `:db/unique` attribute, failing with
+/// `ErrorKind::UnknownAttribute` or `ErrorKind::NotUniqueAttribute` if it doesn't name one.
+fn unique_attribute(schema: &Schema, ident: &edn::NamespacedKeyword) -> Result<Entid> {
+    let a = schema.get_entid(ident)
+                  .ok_or_else(|| Error::from(ErrorKind::UnknownAttribute(ident.clone())))?;
+    match schema.attribute_for_entid(a) {
+        Some(attribute) if attribute.unique.is_some() => Ok(a),
+        _ => bail!(ErrorKind::NotUniqueAttribute(ident.clone())),
     }
 }
 
-impl Conn {
-    // Intentionally not public.
-    fn new(partition_map: PartitionMap, schema: Schema) -> Conn {
-        Conn {
-            metadata: Mutex::new(Metadata::new(0, partition_map, Arc::new(schema)))
+/// Find the entity with the given `value` for the unique attribute `attribute`, if any.
+fn lookup_unique(sqlite: &rusqlite::Connection, attribute: Entid, value: &TypedValue) -> Result<Option<Entid>> {
+    let (value, value_type_tag) = value.to_sql_value_pair();
+    let mut stmt = sqlite.prepare("SELECT e FROM datoms WHERE a = ? AND v = ? AND value_type_tag = ? LIMIT 1")?;
+    let mut rows = stmt.query(&[&attribute, &value, &value_type_tag])?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?.get_checked(0)?)),
+        None => Ok(None),
+    }
+}
+
+/// Run `f`, which transacts within `transaction`, and check what it wrote against
+/// `constraints`.  If `f` fails or a check does, everything `f` wrote is undone, so that the
+/// caller can carry on with, or commit, the rest of `transaction`.
+fn transact_checked<F>(transaction: &rusqlite::Transaction,
+                       schema: &Schema,
+                       constraints: &Constraints,
+                       f: F) -> Result<Transacted>
+    where F: FnOnce() -> Result<Transacted> {
+    // The transactor writes in several statements, and can fail after some have succeeded.
+    transaction.execute_batch("SAVEPOINT mentat_transact")?;
+    let result = f().and_then(|(report, next_partition_map, next_schema)| {
+        if !constraints.is_empty() {
+            constraints.check(transaction, next_schema.as_ref().unwrap_or(schema), report.tx_id, next_schema.is_some())?;
         }
+        Ok((report, next_partition_map, next_schema))
+    });
+    match result {
+        Ok(_) => transaction.execute_batch("RELEASE mentat_transact")?,
+        Err(_) => transaction.execute_batch("ROLLBACK TO mentat_transact; RELEASE mentat_transact")?,
     }
+    result
+}
 
-    pub fn connect(sqlite: &mut rusqlite::Connection) -> Result<Conn> {
-        let db = db::ensure_current_version(sqlite)
-            .chain_err(|| "Unable to initialize Mentat store")?;
-        Ok(Conn::new(db.partition_map, db.schema))
+/// True if a string in the value position of `a` names a tempid: that is, if `a` is a reverse
+/// attribute, or a ref attribute.  An unknown attribute will fail in the transactor regardless.
+fn is_ref_position(schema: &Schema, a: &mentat_tx::entities::Entid) -> bool {
+    let e = match *a {
+        mentat_tx::entities::Entid::Entid(e) => Some(e),
+        mentat_tx::entities::Entid::Ident(ref ident) => {
+            if ident.is_backward() {
+                return true;
+            }
+            schema.get_entid(ident)
+        },
+    };
+    e.and_then(|e| schema.attribute_for_entid(e))
+     .map_or(false, |attribute| attribute.value_type == ValueType::Ref)
+}
+
+/// Replace each tempid in `entity` that names an entity in `tempids`, or in `minted` for internal
+/// tempids minted by the caller, with that entity's entid, so that tempids resolved by earlier
+/// transactions in an `InProgress` resolve the same way in later ones.  Other tempids are left
+/// for the transactor.
+fn substitute_tempids(schema: &Schema, tempids: &BTreeMap<String, Entid>, minted: &BTreeMap<i64, Entid>, entity: Entity) -> Entity {
+    if tempids.is_empty() && minted.is_empty() {
+        return entity;
+    }
+    match entity {
+        Entity::AddOrRetract { op, e, a, v } => {
+            let e = match e {
+                EntidOrLookupRefOrTempId::TempId(tempid) => {
+                    match resolved_tempid(tempids, minted, &tempid) {
+                        Some(entid) => EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(entid)),
+                        None => EntidOrLookupRefOrTempId::TempId(tempid),
+                    }
+                },
+                e => e,
+            };
+            let v = if is_ref_position(schema, &a) { substitute_tempids_in_value(schema, tempids, minted, v) } else { v };
+            Entity::AddOrRetract { op: op, e: e, a: a, v: v }
+        },
+        Entity::MapNotation(map) => Entity::MapNotation(substitute_tempids_in_map(schema, tempids, minted, map)),
     }
+}
 
-    /// Yield the current `Schema` instance.
-    pub fn current_schema(&self) -> Arc<Schema> {
-        // We always unwrap the mutex lock: if it's poisoned, this will propogate panics to all
-        // accessing threads.  This is perhaps not reasonable; we expect the mutex to be held for
-        // very short intervals, but a panic during a critical update section is possible, since the
-        // lock encapsulates committing a SQL transaction.
-        //
-        // That being said, in the future we will provide an interface to take the mutex, providing
-        // maximum flexibility for Mentat consumers.
-        //
-        // This approach might need to change when we support interrupting query threads (#297), and
-        // will definitely need to change if we support interrupting transactor threads.
-        //
-        // Improving this is tracked by https://github.com/mozilla/mentat/issues/356.
-        self.metadata.lock().unwrap().schema.clone()
+/// The entid `tempid` resolved to in an earlier transaction; see `substitute_tempids`.
+fn resolved_tempid(tempids: &BTreeMap<String, Entid>, minted: &BTreeMap<i64, Entid>, tempid: &TempId) -> Option<Entid> {
+    match *tempid {
+        TempId::External(ref name) => tempids.get(name).cloned(),
+        TempId::Internal(x) => minted.get(&x).cloned(),
     }
+}
 
-    /// Query the Mentat store, using the given connection and the current metadata.
-    pub fn q_once<T>(&self,
-                     sqlite: &rusqlite::Connection,
-                     query: &str,
-                     inputs: T) -> Result<QueryResults>
-        where T: Into<Option<QueryInputs>>
-        {
+/// Substitute tempids in `v`, the value of a ref attribute; see `substitute_tempids`.
+fn substitute_tempids_in_value(schema: &Schema, tempids: &BTreeMap<String, Entid>, minted: &BTreeMap<i64, Entid>, v: AtomOrLookupRefOrVectorOrMapNotation) -> AtomOrLookupRefOrVectorOrMapNotation {
+    match v {
+        AtomOrLookupRefOrVectorOrMapNotation::Atom(atom) => {
+            let entid = atom.inner.as_text().and_then(|name| tempids.get(name)).cloned();
+            match entid {
+                Some(entid) => AtomOrLookupRefOrVectorOrMapNotation::Atom(edn::Value::Integer(entid).with_spans()),
+                None => AtomOrLookupRefOrVectorOrMapNotation::Atom(atom),
+            }
+        },
+        AtomOrLookupRefOrVectorOrMapNotation::TempId(tempid) => {
+            match resolved_tempid(tempids, minted, &tempid) {
+                Some(entid) => AtomOrLookupRefOrVectorOrMapNotation::Atom(edn::Value::Integer(entid).with_spans()),
+                None => AtomOrLookupRefOrVectorOrMapNotation::TempId(tempid),
+            }
+        },
+        AtomOrLookupRefOrVectorOrMapNotation::Vector(vs) => {
+            AtomOrLookupRefOrVectorOrMapNotation::Vector(vs.into_iter().map(|v| substitute_tempids_in_value(schema, tempids, minted, v)).collect())
+        },
+        AtomOrLookupRefOrVectorOrMapNotation::MapNotation(map) => {
+            AtomOrLookupRefOrVectorOrMapNotation::MapNotation(substitute_tempids_in_map(schema, tempids, minted, map))
+        },
+        v @ AtomOrLookupRefOrVectorOrMapNotation::LookupRef(_) => v,
+    }
+}
 
-        q_once(sqlite,
-               &*self.current_schema(),
-               query,
-               inputs)
+/// Substitute tempids in `map`, including its `:db/id`; see `substitute_tempids`.
+fn substitute_tempids_in_map(schema: &Schema, tempids: &BTreeMap<String, Entid>, minted: &BTreeMap<i64, Entid>, map: MapNotation) -> MapNotation {
+    let db_id = mentat_tx::entities::Entid::Ident(edn::NamespacedKeyword::new("db", "id"));
+    map.into_iter().map(|(a, v)| {
+        let v = if a == db_id || is_ref_position(schema, &a) { substitute_tempids_in_value(schema, tempids, minted, v) } else { v };
+        (a, v)
+    }).collect()
+}
+
+/// Parse `transaction` as EDN and then as a sequence of entities to transact.
+///
+/// A transaction must be an EDN vector; anything else is rejected with
+/// `ErrorKind::TransactionNotAVector` before the entity parser gets a chance to produce a less
+/// helpful error.
+fn parse_transaction(transaction: &str) -> Result<Vec<mentat_tx::entities::Entity>> {
+    let assertion_vector = edn::parse::value(transaction)?;
+    if !assertion_vector.inner.is_vector() {
+        bail!(ErrorKind::TransactionNotAVector(assertion_vector.inner.type_name()));
     }
+    let entities = mentat_tx_parser::Tx::parse_with_source(&assertion_vector, transaction)?;
+    Ok(entities)
+}
 
-    pub fn lookup_value_for_attribute(&self,
-                                      sqlite: &rusqlite::Connection,
-                                      entity: Entid,
-                                      attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>> {
-        lookup_value_for_attribute(sqlite, &*self.current_schema(), entity, attribute)
+/// If `error` is the transactor's `InvalidEntity`, chain it to one that also gives the text of
+/// that entity in `transaction`.  The text is only looked for once something has failed.
+fn with_entity_text(error: Error, transaction: &str) -> Error {
+    let index = match error {
+        Error(ErrorKind::DbError(::mentat_db::ErrorKind::InvalidEntity(index)), _) => index,
+        _ => return error,
+    };
+    let text = edn::parse::value(transaction).ok().and_then(|value| match value.inner {
+        edn::SpannedValue::Vector(entities) =>
+            entities.get(index)
+                    .and_then(|entity| transaction.get(entity.span.0 as usize..entity.span.1 as usize))
+                    .map(|text| text.to_string()),
+        _ => None,
+    });
+    match text {
+        Some(text) => Error::with_chain(error, ErrorKind::InvalidEntity(index, text)),
+        None => error,
     }
+}
 
-    /// Take a SQLite transaction.
-    /// IMMEDIATE means 'start the transaction now, but don't exclude readers'. It prevents other
-    /// connections from taking immediate or exclusive transactions. This is appropriate for our
-    /// writes and `InProgress`: it means we are ready to write whenever we want to, and nobody else
-    /// can start a transaction that's not `DEFERRED`, but we don't need exclusivity yet.
-    pub fn begin_transaction<'m, 'conn>(&'m mut self, sqlite: &'conn mut rusqlite::Connection) -> Result<InProgress<'m, 'conn>> {
-        let tx = sqlite.transaction_with_behavior(TransactionBehavior::Immediate)?;
-        let (current_generation, current_partition_map, current_schema) =
-        {
-            // The mutex is taken during this block.
-            let ref current: Metadata = *self.metadata.lock().unwrap();
-            (current.generation,
-             // Expensive, but the partition map is updated after every committed transaction.
-             current.partition_map.clone(),
-             // Cheap.
-             current.schema.clone())
-        };
+/// Like `parse_transaction`, but for a transaction that has already been parsed as EDN.  Parse
+/// errors can't include a line and column, since there's no source text.
+fn parse_transaction_value(transaction: &edn::Value) -> Result<Vec<mentat_tx::entities::Entity>> {
+    if !transaction.is_vector() {
+        bail!(ErrorKind::TransactionNotAVector(transaction.type_name()));
+    }
+    let assertion_vector: edn::ValueAndSpan = transaction.clone().into();
+    let entities = mentat_tx_parser::Tx::parse(&assertion_vector)?;
+    Ok(entities)
+}
 
-        Ok(InProgress {
-            mutex: &self.metadata,
-            transaction: tx,
-            generation: current_generation,
-            partition_map: current_partition_map,
-            schema: (*current_schema).clone(),
-            last_report: None,
-        })
+impl<'a, 'c> InProgress<'a, 'c> {
+    /// Transact `entities` within this transaction.  The resulting report is available from
+    /// `last_report`.  If the transaction fails, `self` is left as it was, and can still be used,
+    /// committed, or rolled back.
+    pub fn transact_entities<I>(&mut self, entities: I) -> Result<()> where I: IntoIterator<Item=mentat_tx::entities::Entity> {
+        self.transact_entities_substituted(entities, false, None)
     }
 
-    /// Transact entities against the Mentat store, using the given connection and the current
-    /// metadata.
-    pub fn transact(&mut self,
-                    sqlite: &mut rusqlite::Connection,
-                    transaction: &str) -> Result<TxReport> {
-        // Parse outside the SQL transaction. This is a tradeoff: we are limiting the scope of the
-        // transaction, and indeed we don't even create a SQL transaction if the provided input is
-        // invalid, but it means SQLite errors won't be found until the parse is complete, and if
-        // there's a race for the database (don't do that!) we are less likely to win it.
-        let assertion_vector = edn::parse::value(transaction)?;
-        let entities = mentat_tx_parser::Tx::parse(&assertion_vector)?;
+    /// Like `transact_entities`, but calling `f` with the number of entities processed so far each
+    /// time another `report_every` have been processed, and once more with the total when the
+    /// transaction succeeds, if that wasn't a multiple of `report_every`.  If `report_every` is 0,
+    /// `f` is only called with the total.
+    ///
+    /// Entities are counted as they're read, before anything is written.  No lock is held while
+    /// `f` runs, so it's free to log or update a user interface.  This is
+    /// `transact_entities_with_options`, reporting only the number of entities.
+    pub fn transact_entities_with_progress<I, F>(&mut self, entities: I, report_every: usize, mut f: F) -> Result<()>
+        where I: IntoIterator<Item=mentat_tx::entities::Entity>,
+              F: FnMut(usize) {
+        let mut last = None;
+        let mut options = TransactOptions {
+            progress: Some(Box::new(|progress: TransactProgress| {
+                // The final report repeats the last if `report_every` divides the total.
+                if last != Some(progress.entities) {
+                    last = Some(progress.entities);
+                    f(progress.entities);
+                }
+            })),
+            report_every: report_every,
+            cancel: None,
+        };
+        self.transact_entities_with_options(entities, &mut options)
+    }
 
-        let report = self.begin_transaction(sqlite)?
-                         .transact_entities(entities)?
-                         .commit()?
-                         .expect("we always get a report");
+    /// Like `transact_entities`, but checking `cancel` as each entity is read, and again as the
+    /// transactor processes each entity and before it writes anything.  If it's set, the
+    /// transaction stops, whatever it wrote is undone, and this fails with
+    /// `ErrorKind::TransactInterrupted`; `self` is left as it was, so the caller can roll back.
+    /// This is `transact_entities_with_options`, checking after every entity.
+    pub fn transact_entities_interruptible<I>(&mut self, entities: I, cancel: Arc<AtomicBool>) -> Result<()>
+        where I: IntoIterator<Item=mentat_tx::entities::Entity> {
+        let mut options = TransactOptions {
+            progress: None,
+            report_every: 1,
+            cancel: Some(cancel),
+        };
+        self.transact_entities_with_options(entities, &mut options)
+    }
 
-        Ok(report)
+    /// Like `transact_entities`, but reporting progress to, and checking for cancellation by,
+    /// `options`.  If the transaction is cancelled, nothing is written and this fails with
+    /// `ErrorKind::TransactInterrupted`; `self` is left as it was, so the caller can roll back.
+    ///
+    /// Entities are read into memory before any of them is transacted, so that a cancellation
+    /// while they're being read can't leave a partial transaction.
+    pub fn transact_entities_with_options<I>(&mut self, entities: I, options: &mut TransactOptions) -> Result<()>
+        where I: IntoIterator<Item=mentat_tx::entities::Entity> {
+        let mut progress = TransactProgress::default();
+        self.transact_entities_with_progress_so_far(entities, options, &mut progress)?;
+        options.report(progress);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Transact `entities`, adding to `progress`, and reporting it and checking for cancellation
+    /// as `options` says.  The final progress is left to the caller to report.
+    fn transact_entities_with_progress_so_far<I>(&mut self, entities: I, options: &mut TransactOptions, progress: &mut TransactProgress) -> Result<()>
+        where I: IntoIterator<Item=mentat_tx::entities::Entity> {
+        let mut read = vec![];
+        for entity in entities {
+            read.push(entity);
+            progress.entities += 1;
+            if options.report_every > 0 && progress.entities % options.report_every == 0 {
+                options.report(*progress);
+                options.check_cancelled(*progress)?;
+            }
+        }
 
-    extern crate mentat_parser_utils;
-    use mentat_core::{
-        TypedValue,
-    };
+        // The transactor checks `cancel` too, before it writes anything.
+        let written = self.datoms_asserted + self.datoms_retracted;
+        match self.transact_entities_substituted(read, false, options.cancel.as_ref().map(|cancel| &**cancel)) {
+            Err(Error(ErrorKind::DbError(::mentat_db::ErrorKind::TransactInterrupted), _)) =>
+                bail!(ErrorKind::TransactInterrupted(progress.entities, progress.datoms)),
+            result => result?,
+        }
+        progress.datoms += self.datoms_asserted + self.datoms_retracted - written;
+        Ok(())
+    }
 
-    use mentat_db::USER0;
+    /// Like `transact_entities`, but using the transactor's bulk-load path: assertions about the
+    /// entities the transaction creates are written without first searching for existing datoms.
+    /// The result is the same; this is just faster when importing many new entities.  See
+    /// `mentat_db::transact_bulk`.
+    pub fn transact_entities_bulk<I>(&mut self, entities: I) -> Result<()> where I: IntoIterator<Item=mentat_tx::entities::Entity> {
+        self.transact_entities_substituted(entities, true, None)
+    }
 
-    #[test]
-    fn test_transact_does_not_collide_existing_entids() {
-        let mut sqlite = db::new_connection("").unwrap();
-        let mut conn = Conn::connect(&mut sqlite).unwrap();
+    /// Transact `entities`, first substituting the tempids resolved by earlier transactions in
+    /// this one, on the bulk-load path if `bulk` is set, and stopping if `cancel` is set.  The
+    /// partition map is cloned, rather than taken, so that `self` is left usable if the
+    /// transaction fails.
+    fn transact_entities_substituted<I>(&mut self, entities: I, bulk: bool, cancel: Option<&AtomicBool>) -> Result<()> where I: IntoIterator<Item=mentat_tx::entities::Entity> {
+        let partition_map = self.partition_map.clone();
+        let (report, next_partition_map, next_schema) = {
+            let schema = &self.schema;
+            let tempids = &self.tempids;
+            let minted = &self.minted_tempids;
+            let entities = entities.into_iter().map(|entity| substitute_tempids(schema, tempids, minted, entity));
+            let options = TxOptions {
+                bulk: bulk,
+                keywords: Some(self.keywords),
+                cancel: cancel,
+            };
+            transact_checked(&self.transaction, &self.schema, self.constraints, || {
+                Ok(transact_with_options(&self.transaction, partition_map, &self.schema, &self.schema, options, entities)?)
+            })?
+        };
+        self.record_transaction(report, next_partition_map, next_schema)
+    }
 
-        // Let's find out the next ID that'll be allocated. We're going to try to collide with it
-        // a bit later.
-        let next = conn.metadata.lock().expect("metadata")
-                       .partition_map[":db.part/user"].index;
-        let t = format!("[[:db/add {} :db.schema/attribute \"tempid\"]]", next + 1);
+    /// Replay a transaction read from an export, with its original transaction ID and instant.
+    ///
+    /// Unlike `transact_entities`, this doesn't substitute tempids resolved earlier in this
+    /// transaction: each exported transaction already names its entities by entid, and any tempid
+    /// in it belonged to the transaction that was exported, not to this one.
+    fn replay_transaction(&mut self, tx: ExportedTransaction) -> Result<()> {
+        let partition_map = mem::replace(&mut self.partition_map, PartitionMap::default());
+        let (report, next_partition_map, next_schema) =
+            transact_checked(&self.transaction, &self.schema, self.constraints, || {
+                Ok(transact_as(&self.transaction, partition_map, &self.schema, &self.schema, tx.tx_id, tx.tx_instant, tx.entities)?)
+            })?;
+        self.record_transaction(report, next_partition_map, next_schema)
+    }
 
-        match conn.transact(&mut sqlite, t.as_str()).unwrap_err() {
-            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::UnrecognizedEntid(e)), _) => {
-                assert_eq!(e, next + 1);
-            },
-            x => panic!("expected transact error, got {:?}", x),
+    fn record_transaction(&mut self, report: TxReport, next_partition_map: PartitionMap, next_schema: Option<Schema>) -> Result<()> {
+        self.partition_map = next_partition_map;
+        if let Some(schema) = next_schema {
+            self.schema = schema;
         }
 
-        // Transact two more tempids.
-        let t = "[[:db/add \"one\" :db.schema/attribute \"more\"]]";
-        let report = conn.transact(&mut sqlite, t)
-                         .expect("transact succeeded");
-        assert_eq!(report.tempids["more"], next);
-        assert_eq!(report.tempids["one"], next + 1);
+        let (asserted, retracted) = count_transacted_datoms(&self.transaction, &self.schema, report.tx_id)?;
+        self.datoms_asserted += asserted;
+        self.datoms_retracted += retracted;
+
+        self.tempids.extend(report.tempids.iter().map(|(name, &e)| (name.clone(), e)));
+        self.minted_tempids.extend(report.minted_tempids.iter().map(|(&x, &e)| (x, e)));
+        self.last_report = Some(report);
+        Ok(())
     }
 
-    #[test]
-    fn test_transact_does_not_collide_new_entids() {
-        let mut sqlite = db::new_connection("").unwrap();
-        let mut conn = Conn::connect(&mut sqlite).unwrap();
+    /// Create or update the entity with the value `key_value` for the unique attribute
+    /// `unique_attr`, asserting each of the given attribute-value `pairs` on it.
+    ///
+    /// If no entity has that value, a new entity is created with it.  Returns the entid of the
+    /// existing or newly created entity.  It is an error if `unique_attr` is not `:db/unique`.
+    pub fn upsert(&mut self,
+                  unique_attr: &edn::NamespacedKeyword,
+                  key_value: TypedValue,
+                  pairs: Vec<(edn::NamespacedKeyword, TypedValue)>) -> Result<KnownEntid> {
+        let a = unique_attribute(&self.schema, unique_attr)?;
 
-        // Let's find out the next ID that'll be allocated. We're going to try to collide with it.
-        let next = conn.metadata.lock().expect("metadata").partition_map[":db.part/user"].index;
+        // A minted tempid can't be one that an earlier transaction in this one resolved.
+        let tempid = mint_tempid();
+        let existing = lookup_unique(&self.transaction, a, &key_value)?;
+        let e = match existing {
+            Some(e) => EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+            None => EntidOrLookupRefOrTempId::TempId(tempid.clone()),
+        };
 
-        // If this were to be resolved, we'd get [:db/add 65537 :db.schema/attribute 65537], but
-        // we should reject this, because the first ID was provided by the user!
-        let t = format!("[[:db/add {} :db.schema/attribute \"tempid\"]]", next);
+        let atom = |value: TypedValue| {
+            AtomOrLookupRefOrVectorOrMapNotation::Atom(value.to_edn_value_pair().0.with_spans())
+        };
 
-        match conn.transact(&mut sqlite, t.as_str()).unwrap_err() {
-            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::UnrecognizedEntid(e)), _) => {
-                // All this, despite this being the ID we were about to allocate!
-                assert_eq!(e, next);
-            },
-            x => panic!("expected transact error, got {:?}", x),
+        let mut entities: Vec<Entity> = Vec::with_capacity(pairs.len() + 1);
+        if existing.is_none() {
+            entities.push(Entity::AddOrRetract {
+                op: OpType::Add,
+                e: e.clone(),
+                a: mentat_tx::entities::Entid::Ident(unique_attr.clone()),
+                v: atom(key_value),
+            });
+        }
+        for (attr, value) in pairs {
+            entities.push(Entity::AddOrRetract {
+                op: OpType::Add,
+                e: e.clone(),
+                a: mentat_tx::entities::Entid::Ident(attr),
+                v: atom(value),
+            });
         }
 
-        // And if we subsequently transact in a way that allocates one ID, we _will_ use that one.
-        // Note that `10` is a bootstrapped entid; we use it here as a known-good value.
-        let t = "[[:db/add 10 :db.schema/attribute \"temp\"]]";
-        let report = conn.transact(&mut sqlite, t)
-                         .expect("transact succeeded");
-        assert_eq!(report.tempids["temp"], next);
-    }
+        self.transact_entities(entities)?;
 
-    /// Return the entid that will be allocated to the next transacted tempid.
-    fn get_next_entid(conn: &Conn) -> i64 {
-        let partition_map = &conn.metadata.lock().unwrap().partition_map;
-        partition_map.get(":db.part/user").unwrap().index
+        match existing {
+            Some(e) => Ok(KnownEntid(e)),
+            None => Ok(self.last_report
+                           .as_ref()
+                           .and_then(|report| report.resolved(&tempid))
+                           .expect("upserted tempid is always allocated")),
+        }
     }
 
-    #[test]
-    fn test_compound_transact() {
-        let mut sqlite = db::new_connection("").unwrap();
-        let mut conn = Conn::connect(&mut sqlite).unwrap();
+    /// Rename the attribute `from` to `to` by retracting its `:db/ident` and asserting the new one.
+    ///
+    /// The attribute keeps its entid, so existing datoms refer to it by its new name without any
+    /// data migration.  It is an error if `from` isn't an attribute, or if `to` already names an
+    /// entity.
+    pub fn rename_attribute(&mut self,
+                            from: &edn::NamespacedKeyword,
+                            to: &edn::NamespacedKeyword) -> Result<()> {
+        let (_, KnownEntid(e)) = require_attribute_for_ident(&self.schema, from)?;
+        if self.schema.get_entid(to).is_some() {
+            bail!(ErrorKind::IdentAlreadyInUse(to.clone()));
+        }
 
+        let ident = |kw: &edn::NamespacedKeyword| {
+            AtomOrLookupRefOrVectorOrMapNotation::Atom(edn::Value::NamespacedKeyword(kw.clone()).with_spans())
+        };
+        let entities = vec![
+            Entity::AddOrRetract {
+                op: OpType::Retract,
+                e: EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+                a: mentat_tx::entities::Entid::Ident(edn::NamespacedKeyword::new("db", "ident")),
+                v: ident(from),
+            },
+            Entity::AddOrRetract {
+                op: OpType::Add,
+                e: EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+                a: mentat_tx::entities::Entid::Ident(edn::NamespacedKeyword::new("db", "ident")),
+                v: ident(to),
+            },
+        ];
+
+        self.transact_entities(entities)
+    }
+
+    /// Turn `:db/index` on or off for `attribute` by asserting it.
+    ///
+    /// Turning indexing on adds the attribute's existing datoms to the value index too, so that
+    /// queries looking the attribute up by value can use it straight away; turning it off takes
+    /// them out.
+    /// See `q_explain` to check which indexes a query uses.  It is an error if `attribute` isn't an
+    /// attribute.
+    pub fn set_attribute_indexed(&mut self, attribute: &edn::NamespacedKeyword, indexed: bool) -> Result<()> {
+        let (_, KnownEntid(e)) = require_attribute_for_ident(&self.schema, attribute)?;
+
+        let entities = vec![
+            Entity::AddOrRetract {
+                op: OpType::Add,
+                e: EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+                a: mentat_tx::entities::Entid::Ident(edn::NamespacedKeyword::new("db", "index")),
+                v: AtomOrLookupRefOrVectorOrMapNotation::Atom(edn::Value::Boolean(indexed).with_spans()),
+            },
+        ];
+
+        self.transact_entities(entities)
+    }
+
+    /// Document `attribute` by asserting `doc` as its `:db/doc`, replacing any documentation it had.
+    /// See `Conn::attribute_doc`.  It is an error if `attribute` isn't an attribute.
+    pub fn set_attribute_doc(&mut self, attribute: &edn::NamespacedKeyword, doc: &str) -> Result<()> {
+        let (_, KnownEntid(e)) = require_attribute_for_ident(&self.schema, attribute)?;
+
+        let entities = vec![
+            Entity::AddOrRetract {
+                op: OpType::Add,
+                e: EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+                a: mentat_tx::entities::Entid::Ident(edn::NamespacedKeyword::new("db", "doc")),
+                v: AtomOrLookupRefOrVectorOrMapNotation::Atom(edn::Value::Text(doc.to_string()).with_spans()),
+            },
+        ];
+
+        self.transact_entities(entities)
+    }
+
+    /// Retract every datom of `attribute` whose value satisfies `pred`, in a single transaction
+    /// within this one, and return the number retracted.  If nothing matches, nothing is
+    /// transacted.  It is an error if `attribute` isn't an attribute.
+    ///
+    /// Every datom of the attribute is read to be tested, so this suits occasional cleanup, like
+    /// retracting expired session tokens, rather than frequent use.
+    pub fn retract_matching<F>(&mut self, attribute: &edn::NamespacedKeyword, pred: F) -> Result<usize>
+        where F: Fn(&TypedValue) -> bool {
+        let (attr, KnownEntid(a)) = require_attribute_for_ident(&self.schema, attribute)?;
+        let sql = format!("SELECT e, {}, value_type_tag FROM datoms WHERE a = ? ORDER BY e", datom_value_sql(attr.fulltext));
+
+        let mut entities: Vec<Entity> = vec![];
+        {
+            let mut stmt = self.transaction.prepare(&sql)?;
+            let mut rows = stmt.query(&[&a])?;
+            while let Some(row) = rows.next() {
+                let row = row?;
+                let e: Entid = row.get_checked(0)?;
+                let value = TypedValue::from_sql_value_pair(row.get_checked(1)?, row.get_checked(2)?)?;
+                if pred(&value) {
+                    entities.push(Entity::AddOrRetract {
+                        op: OpType::Retract,
+                        e: EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+                        a: mentat_tx::entities::Entid::Entid(a),
+                        v: AtomOrLookupRefOrVectorOrMapNotation::Atom(value.to_edn_value_pair().0.with_spans()),
+                    });
+                }
+            }
+        }
+
+        let count = entities.len();
+        if count == 0 {
+            return Ok(0);
+        }
+
+        self.transact_entities(entities)?;
+        Ok(count)
+    }
+
+    /// Install a new, empty partition named `name` whose first entid is `start`.  The partition is
+    /// persisted with the rest of this transaction, and is then listed by `Conn::partitions`.
+    ///
+    /// A partition's entids run from its start up to the start of the next partition, and
+    /// allocating beyond that fails with `mentat_db::ErrorKind::PartitionExhausted`.  The new
+    /// partition therefore takes the rest of the range of the partition that `start` falls within,
+    /// which from then on ends at `start`.  `name` must be a keyword like `:app.part/things` that
+    /// doesn't already name a partition, and `start` must lie beyond the next entid to be
+    /// allocated from the partition it falls within, so that no entid can be allocated from both.
+    ///
+    /// Tempids are still allocated from `:db.part/user`.
+    pub fn create_partition(&mut self, name: &str, start: Entid) -> Result<()> {
+        if ::mentat_db::to_namespaced_keyword(name).is_err() {
+            bail!(ErrorKind::InvalidPartition(format!("'{}' is not a namespaced keyword", name)));
+        }
+        if start < 0 {
+            bail!(ErrorKind::InvalidPartition(format!("{} cannot start at negative entid {}", name, start)));
+        }
+        for (existing, partition) in self.partition_map.iter() {
+            if existing == name {
+                bail!(ErrorKind::InvalidPartition(format!("{} already exists", name)));
+            }
+            let end = partition_end(&self.partition_map, partition.start);
+            if partition.start <= start && start < end && start <= partition.index {
+                bail!(ErrorKind::InvalidPartition(format!("{} starting at {} overlaps {}, which has allocated up to {}", name, start, existing, partition.index)));
+            }
+        }
+
+        self.transaction.execute("INSERT INTO parts VALUES (?, ?, ?)", &[&name, &start, &start])?;
+        self.partition_map.insert(name.to_string(), Partition::new(start, start));
+        Ok(())
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata.
+    pub fn q_once<T>(&self,
+                     query: &str,
+                     inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once(&*(self.transaction),
+               &self.schema,
+               query,
+               inputs)
+    }
+
+    /// Like `q_once`, but taking a query that has already been parsed as EDN.
+    pub fn q_once_parsed<T>(&self,
+                            query: &edn::Value,
+                            inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once_parsed(&*(self.transaction),
+                      &self.schema,
+                      query,
+                      inputs)
+    }
+
+    /// Query the Mentat store, using the current metadata, constrained by `options`.
+    pub fn q_once_with_options<T>(&self,
+                                  query: &str,
+                                  inputs: T,
+                                  options: &QueryOptions) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once_with_options(&*(self.transaction),
+                            &self.schema,
+                            query,
+                            inputs,
+                            options)
+    }
+
+    /// Query the Mentat store, passing each row of results to `f` as it is produced.
+    /// See `query::q_once_each`.
+    pub fn q_once_each<T, F>(&self,
+                             query: &str,
+                             inputs: T,
+                             f: F) -> Result<usize>
+        where T: Into<Option<QueryInputs>>,
+              F: FnMut(QueryRow) -> Result<ControlFlow>
+        {
+
+        self.counters.increment_queries();
+        q_once_each(&*(self.transaction),
+                    &self.schema,
+                    query,
+                    inputs,
+                    f)
+    }
+
+    pub fn lookup_value_for_attribute<E>(&self,
+                                         entity: E,
+                                         attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        self.counters.increment_queries();
+        lookup_value_for_attribute(&*(self.transaction), &self.schema, entity.into(), attribute)
+    }
+
+    pub fn transact(&mut self, transaction: &str) -> Result<()> {
+        let entities = parse_transaction(transaction)?;
+        self.transact_entities(entities).map_err(|e| with_entity_text(e, transaction))
+    }
+
+    /// Like `transact`, but taking a transaction that has already been parsed as EDN.
+    pub fn transact_parsed(&mut self, transaction: &edn::Value) -> Result<()> {
+        let entities = parse_transaction_value(transaction)?;
+        self.transact_entities(entities)
+    }
+
+    /// Transact the entities built by `builder`.  Look up the entids its tempids became in
+    /// `last_report`.
+    pub fn transact_builder(&mut self, builder: TermBuilder) -> Result<()> {
+        self.transact_entities(builder.build())
+    }
+
+    /// Resolve `ident` to its entid using the in-progress schema, which includes any idents
+    /// transacted earlier in this transaction.
+    pub fn entid_for_ident(&self, ident: &edn::NamespacedKeyword) -> Option<KnownEntid> {
+        self.schema.get_entid(ident).map(KnownEntid)
+    }
+
+    /// Return the ident of `entid` in the in-progress schema, if it has one.
+    pub fn ident_for_entid<E>(&self, entid: E) -> Option<edn::NamespacedKeyword> where E: Into<Entid> {
+        self.schema.get_ident(entid.into()).cloned()
+    }
+
+    /// Return the attribute named `ident` in the in-progress schema, and its entid, if `ident`
+    /// names an attribute.  Attributes installed earlier in this transaction are included.
+    pub fn attribute_for_ident(&self, ident: &edn::NamespacedKeyword) -> Option<(Attribute, KnownEntid)> {
+        attribute_for_ident(&self.schema, ident)
+    }
+
+    pub fn last_report(&self) -> Option<&TxReport> {
+        self.last_report.as_ref()
+    }
+
+    /// The entid each string tempid resolved to in the transactions made so far in this
+    /// `InProgress`.  A later transaction that uses one of these tempids refers to the same entity.
+    pub fn resolved_tempids(&self) -> &BTreeMap<String, Entid> {
+        &self.tempids
+    }
+
+    /// Start a SQLite savepoint named `name` within this transaction.  A later call to
+    /// `rollback_to_savepoint` discards everything transacted since, including entid allocations
+    /// and schema changes, without abandoning earlier work.
+    ///
+    /// Savepoints nest, and names may be reused: the most recent savepoint with a given name wins.
+    pub fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.transaction.execute_batch(&format!("SAVEPOINT {}", quote_savepoint_name(name)))?;
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            partition_map: self.partition_map.clone(),
+            schema: self.schema.clone(),
+            last_report: self.last_report.clone(),
+            datoms_asserted: self.datoms_asserted,
+            datoms_retracted: self.datoms_retracted,
+            tempids: self.tempids.clone(),
+            minted_tempids: self.minted_tempids.clone(),
+        });
+        Ok(())
+    }
+
+    fn savepoint_index(&self, name: &str) -> Result<usize> {
+        self.savepoints.iter()
+                       .rposition(|savepoint| savepoint.name == name)
+                       .ok_or_else(|| ErrorKind::UnknownSavepoint(name.to_string()).into())
+    }
+
+    /// Undo everything done since the savepoint `name` was started.  As in SQLite, the savepoint
+    /// itself remains active, but any savepoints started after it are discarded.
+    pub fn rollback_to_savepoint(&mut self, name: &str) -> Result<()> {
+        let index = self.savepoint_index(name)?;
+        self.transaction.execute_batch(&format!("ROLLBACK TO {}", quote_savepoint_name(name)))?;
+        self.savepoints.truncate(index + 1);
+
+        let savepoint = &self.savepoints[index];
+        self.partition_map = savepoint.partition_map.clone();
+        self.schema = savepoint.schema.clone();
+        self.last_report = savepoint.last_report.clone();
+        self.datoms_asserted = savepoint.datoms_asserted;
+        self.datoms_retracted = savepoint.datoms_retracted;
+        self.tempids = savepoint.tempids.clone();
+        self.minted_tempids = savepoint.minted_tempids.clone();
+        Ok(())
+    }
+
+    /// Forget the savepoint `name`, and any savepoints started after it, keeping the work done since.
+    pub fn release_savepoint(&mut self, name: &str) -> Result<()> {
+        let index = self.savepoint_index(name)?;
+        self.transaction.execute_batch(&format!("RELEASE {}", quote_savepoint_name(name)))?;
+        self.savepoints.truncate(index);
+        Ok(())
+    }
+
+    /// Run `f` when this transaction is committed, after everything has been transacted but
+    /// before anything is committed to SQLite.  `f` sees the transaction's data, and can query it,
+    /// through an `InProgressView`.  If `f` fails, `commit` rolls the transaction back and fails
+    /// with `f`'s error.
+    ///
+    /// This suits application invariants that need queries to check, rather than a look at each
+    /// datom.  Setting a hook replaces any set before.
+    pub fn set_precommit_hook(&mut self, f: PrecommitHook) {
+        self.precommit_hook = Some(f);
+    }
+
+    fn view(&self) -> InProgressView {
+        InProgressView {
+            transaction: &self.transaction,
+            counters: self.counters,
+            schema: &self.schema,
+            last_report: self.last_report.as_ref(),
+        }
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        self.last_report = None;
+        self.transaction.rollback()?;
+        self.counters.transactions_rolled_back.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn commit(self) -> Result<Option<TxReport>> {
+        // Run the hook before taking the mutex: it can take as long as it likes.
+        let checked = match self.precommit_hook {
+            Some(ref hook) => hook(&self.view()),
+            None => Ok(()),
+        };
+        if let Err(e) = checked {
+            self.rollback()?;
+            return Err(e);
+        }
+
+        let span = spans::commit();
+        span.record_i64("generation", self.generation as i64);
+
+        // Read what watchers want to know while the transaction's changes can still be seen.
+        let changes = watch::changes_since(&self.transaction, &self.schema, self.watches, self.first_tx)?;
+
+        {
+            // The mutex is taken during this block.
+            let mut metadata = try_lock_metadata(self.mutex)?;
+
+            let generation_check_passed = self.generation == metadata.generation;
+            span.record_bool("generation_check_passed", generation_check_passed);
+
+            if !generation_check_passed {
+                // Somebody else wrote!
+                // This should not occur -- an attempt to take a competing IMMEDIATE transaction
+                // will fail with `SQLITE_BUSY`, causing this function to abort.  `Conn::transact`
+                // retries if a busy timeout is set.
+                bail!(ErrorKind::LostTransactRace);
+            }
+
+            // Commit the SQLite transaction while we hold the mutex.  Until the metadata is
+            // updated to match, a panic would leave it out of step with the store.
+            metadata.committing = true;
+            if let Err(e) = self.transaction.commit() {
+                metadata.committing = false;
+                match e {
+                    rusqlite::Error::SqliteFailure(ref e, _) if e.code == rusqlite::ErrorCode::DatabaseBusy => {
+                        bail!(ErrorKind::DatabaseBusy);
+                    },
+                    e => bail!(e),
+                }
+            }
+
+            metadata.generation += 1;
+            metadata.partition_map = self.partition_map;
+            if self.schema != *(metadata.schema) {
+                metadata.schema = Arc::new(self.schema);
+            }
+            metadata.committing = false;
+            self.changed.notify_all();
+        }
+
+        self.counters.transactions_committed.fetch_add(1, Ordering::Relaxed);
+        self.counters.datoms_asserted.fetch_add(self.datoms_asserted, Ordering::Relaxed);
+        self.counters.datoms_retracted.fetch_add(self.datoms_retracted, Ordering::Relaxed);
+
+        if !changes.is_empty() {
+            let dropped = watch::notify(self.watches, &changes);
+            self.counters.watch_changes_dropped.fetch_add(dropped, Ordering::Relaxed);
+        }
+
+        if let Some(ref report) = self.last_report {
+            span.record_i64("tx_id", report.tx_id);
+
+            // Outside the metadata mutex, so that a panicking callback can't poison it.  A callback
+            // that panicked before leaves the callbacks as they were, so carry on.
+            let callbacks = self.commit_callbacks.lock().unwrap_or_else(|e| e.into_inner());
+            for callback in callbacks.iter() {
+                callback(report);
+            }
+        }
+
+        Ok(self.last_report)
+    }
+}
+
+impl<'v> InProgressView<'v> {
+    /// The schema as the transaction has left it so far.
+    pub fn schema(&self) -> &Schema {
+        self.schema
+    }
+
+    /// The report of the most recent successful transact, if any.  See `InProgress::last_report`.
+    pub fn last_report(&self) -> Option<&TxReport> {
+        self.last_report
+    }
+
+    /// Query the store as the transaction has left it so far.
+    pub fn q_once<T>(&self,
+                     query: &str,
+                     inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once(self.transaction,
+               self.schema,
+               query,
+               inputs)
+    }
+}
+
+impl<'a, 'c> Snapshot<'a, 'c> {
+    /// The schema of the store as of the moment the snapshot was taken.
+    pub fn schema(&self) -> &Schema {
+        &*self.schema
+    }
+
+    /// Query the snapshot of the Mentat store.
+    pub fn q_once<T>(&self,
+                     query: &str,
+                     inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once(&*(self.transaction),
+               &*self.schema,
+               query,
+               inputs)
+    }
+
+    /// Query the snapshot of the Mentat store, constrained by `options`.
+    pub fn q_once_with_options<T>(&self,
+                                  query: &str,
+                                  inputs: T,
+                                  options: &QueryOptions) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once_with_options(&*(self.transaction),
+                            &*self.schema,
+                            query,
+                            inputs,
+                            options)
+    }
+
+    /// Query the snapshot of the Mentat store, passing each row of results to `f` as it is
+    /// produced.  See `query::q_once_each`.
+    pub fn q_once_each<T, F>(&self,
+                             query: &str,
+                             inputs: T,
+                             f: F) -> Result<usize>
+        where T: Into<Option<QueryInputs>>,
+              F: FnMut(QueryRow) -> Result<ControlFlow>
+        {
+
+        self.counters.increment_queries();
+        q_once_each(&*(self.transaction),
+                    &*self.schema,
+                    query,
+                    inputs,
+                    f)
+    }
+
+    pub fn lookup_value_for_attribute<E>(&self,
+                                         entity: E,
+                                         attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        self.counters.increment_queries();
+        lookup_value_for_attribute(&*(self.transaction), &*self.schema, entity.into(), attribute)
+    }
+
+    /// Release the snapshot's read transaction.  Nothing was written, so nothing is lost.
+    pub fn close(self) -> Result<()> {
+        self.transaction.rollback()?;
+        Ok(())
+    }
+}
+
+impl Conn {
+    // Intentionally not public.
+    fn new(partition_map: PartitionMap, schema: Schema) -> Conn {
+        Conn {
+            metadata: Mutex::new(Metadata::new(0, partition_map, Arc::new(schema))),
+            changed: Condvar::new(),
+            counters: Counters::default(),
+            busy_timeout: None,
+            read_only: false,
+            constraints: Constraints::default(),
+            commit_callbacks: Mutex::new(vec![]),
+            watches: Arc::new(Mutex::new(Watches::default())),
+            keywords: Mutex::new(BoundedInternSet::with_capacity(KEYWORD_CACHE_CAPACITY)),
+        }
+    }
+
+    pub fn connect(sqlite: &mut rusqlite::Connection) -> Result<Conn> {
+        let db = db::ensure_current_version(sqlite)
+            .chain_err(|| "Unable to initialize Mentat store")?;
+        Ok(Conn::new(db.partition_map, db.schema))
+    }
+
+    /// Open the Mentat store in the SQLite database at `path`, creating and initializing it if
+    /// necessary, and return a `Conn` together with the SQLite connection that backs it.
+    ///
+    /// The SQLite connection is configured as by `mentat_db::db::new_connection`: in particular,
+    /// foreign keys are enforced and the journal is in WAL mode.  An empty `path` opens an
+    /// in-memory store.
+    pub fn open<P>(path: P) -> Result<(Conn, rusqlite::Connection)> where P: AsRef<Path> {
+        let mut sqlite = db::new_connection(path)?;
+        let conn = Conn::connect(&mut sqlite)?;
+        Ok((conn, sqlite))
+    }
+
+    /// Connect to an existing Mentat store without ever writing to it.  The store's version is
+    /// checked, but it is never created or migrated: a store that would need that fails with
+    /// `mentat_db::ErrorKind::CannotMigrateReadOnlyStore`.
+    ///
+    /// The returned `Conn` can query as usual, but `transact` and `begin_transaction` fail with
+    /// `ErrorKind::ReadOnlyConnection`.
+    pub fn connect_read_only(sqlite: &rusqlite::Connection) -> Result<Conn> {
+        let db = db::ensure_current_version_read_only(sqlite)?;
+        let mut conn = Conn::new(db.partition_map, db.schema);
+        conn.read_only = true;
+        Ok(conn)
+    }
+
+    /// Open the existing Mentat store at `path` read-only, as by `connect_read_only`.  The SQLite
+    /// connection is itself opened read-only, as by `mentat_db::db::new_read_only_connection`.
+    pub fn open_read_only<P>(path: P) -> Result<(Conn, rusqlite::Connection)> where P: AsRef<Path> {
+        let sqlite = db::new_read_only_connection(path)?;
+        let conn = Conn::connect_read_only(&sqlite)?;
+        Ok((conn, sqlite))
+    }
+
+    /// True if this `Conn` was opened with `connect_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            bail!(ErrorKind::ReadOnlyConnection);
+        }
+        Ok(())
+    }
+
+    /// Create a new in-memory Mentat store, returning a `Conn` and the SQLite connection that
+    /// backs it.  Convenient for tests; use `open` or `connect` for a file-backed store.
+    pub fn in_memory() -> Result<(Conn, rusqlite::Connection)> {
+        Conn::open("")
+    }
+
+    /// Yield the current `Schema` instance.
+    ///
+    /// This never fails, even if a panic poisoned the metadata mutex: if that panic interrupted a
+    /// commit, the schema may not reflect it.  Use `try_current_schema` to fail instead.
+    pub fn current_schema(&self) -> Arc<Schema> {
+        // We expect the mutex to be held for very short intervals, but a panic during a critical
+        // update section is possible, since the lock encapsulates committing a SQL transaction.
+        // See `lock_metadata`.
+        //
+        // This approach might need to change when we support interrupting query threads (#297), and
+        // will definitely need to change if we support interrupting transactor threads.
+        lock_metadata(&self.metadata).schema.clone()
+    }
+
+    /// Yield the current `Schema` instance, failing with `ErrorKind::MetadataPoisoned` if a panic
+    /// interrupted a commit, so that it may not be current.
+    pub fn try_current_schema(&self) -> Result<Arc<Schema>> {
+        Ok(try_lock_metadata(&self.metadata)?.schema.clone())
+    }
+
+    /// Resolve `ident` to its entid using the current schema, without touching SQLite.
+    pub fn entid_for_ident(&self, ident: &edn::NamespacedKeyword) -> Option<KnownEntid> {
+        self.current_schema().get_entid(ident).map(KnownEntid)
+    }
+
+    /// Return the ident of `entid` in the current schema, if it has one, without touching SQLite.
+    pub fn ident_for_entid<E>(&self, entid: E) -> Option<edn::NamespacedKeyword> where E: Into<Entid> {
+        self.current_schema().get_ident(entid.into()).cloned()
+    }
+
+    /// Return the attribute named `ident` in the current schema, and its entid, if `ident` names an
+    /// attribute.  Like `entid_for_ident`, this never touches SQLite.
+    pub fn attribute_for_ident(&self, ident: &edn::NamespacedKeyword) -> Option<(Attribute, KnownEntid)> {
+        attribute_for_ident(&*self.current_schema(), ident)
+    }
+
+    /// Return the ident of each attribute in the current schema that is `:db/unique`, and whether
+    /// it's `:db.unique/identity` or `:db.unique/value`, ordered by ident.  Like
+    /// `attribute_for_ident`, this never touches SQLite.
+    pub fn unique_attributes(&self) -> Vec<(edn::NamespacedKeyword, UniqueKind)> {
+        let schema = self.current_schema();
+        let mut unique: Vec<(edn::NamespacedKeyword, UniqueKind)> =
+            schema.schema_map.iter()
+                             .filter_map(|(entid, attribute)| {
+                                 match (attribute.unique.as_ref(), schema.get_ident(*entid)) {
+                                     (Some(kind), Some(ident)) => Some((ident.clone(), kind.clone())),
+                                     _ => None,
+                                 }
+                             })
+                             .collect();
+        unique.sort();
+        unique
+    }
+
+    /// Describe each partition of the entid space: its name, its first entid, and the next entid it
+    /// will allocate.
+    pub fn partitions(&self) -> Vec<PartitionInfo> {
+        partition_infos(&lock_metadata(&self.metadata).partition_map)
+    }
+
+    /// Return the generation of this `Conn`'s metadata, which increases by one with each committed
+    /// transaction.  Compare it to a generation recorded earlier to learn whether the store has
+    /// changed since, and so whether state derived from it is stale.
+    ///
+    /// Generations count only transactions committed through this `Conn`, starting from zero
+    /// when it is opened; they are not persisted.
+    pub fn generation(&self) -> u64 {
+        lock_metadata(&self.metadata).generation
+    }
+
+    /// Block until a transaction committed through this `Conn` advances the generation beyond
+    /// `since_generation`, and return the new generation.  Returns at once if that's already
+    /// happened, and fails with `ErrorKind::WaitTimeout` if it doesn't happen within `timeout`.
+    ///
+    /// A consumer that keeps derived state fresh can loop: record `generation`, query, then wait
+    /// for a change before querying again.  Several changes may be reported by a single return.
+    pub fn wait_for_change(&self, since_generation: u64, timeout: Duration) -> Result<u64> {
+        let deadline = Instant::now() + timeout;
+        let mut metadata = lock_metadata(&self.metadata);
+        while metadata.generation <= since_generation {
+            let now = Instant::now();
+            if now >= deadline {
+                bail!(ErrorKind::WaitTimeout(timeout));
+            }
+            metadata = self.changed.wait_timeout(metadata, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        Ok(metadata.generation)
+    }
+
+    /// Render the vocabulary installed in the store as transactable EDN.  See
+    /// `schema_diff::vocabulary_to_edn`.
+    pub fn dump_schema(&self) -> edn::Value {
+        vocabulary_to_edn(&*self.current_schema())
+    }
+
+    /// Yield a snapshot of the counters maintained by this `Conn`.
+    ///
+    /// This does not take the metadata mutex.
+    pub fn stats(&self) -> ConnStats {
+        self.counters.snapshot()
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata.
+    pub fn q_once<T>(&self,
+                     sqlite: &rusqlite::Connection,
+                     query: &str,
+                     inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once(sqlite,
+               &*self.current_schema(),
+               query,
+               inputs)
+    }
+
+    /// Like `q_once`, but taking a query that has already been parsed as EDN.
+    pub fn q_once_parsed<T>(&self,
+                            sqlite: &rusqlite::Connection,
+                            query: &edn::Value,
+                            inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once_parsed(sqlite,
+                      &*self.current_schema(),
+                      query,
+                      inputs)
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata, constrained by
+    /// `options`.
+    pub fn q_once_with_options<T>(&self,
+                                  sqlite: &rusqlite::Connection,
+                                  query: &str,
+                                  inputs: T,
+                                  options: &QueryOptions) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_once_with_options(sqlite,
+                            &*self.current_schema(),
+                            query,
+                            inputs,
+                            options)
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata, failing with
+    /// `ErrorKind::QueryTimedOut` if the query hasn't completed within `timeout`.
+    ///
+    /// The timer is cancelled as soon as the query completes, so it can't interrupt later work on
+    /// `sqlite`.  See `QueryOptions::timeout`.
+    pub fn q_once_with_timeout<T>(&self,
+                                  sqlite: &rusqlite::Connection,
+                                  query: &str,
+                                  inputs: T,
+                                  timeout: Duration) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.q_once_with_options(sqlite, query, inputs, &QueryOptions::default().timeout(timeout))
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata, passing each
+    /// row of results to `f` as it is produced.  See `query::q_once_each`.
+    pub fn q_once_each<T, F>(&self,
+                             sqlite: &rusqlite::Connection,
+                             query: &str,
+                             inputs: T,
+                             f: F) -> Result<usize>
+        where T: Into<Option<QueryInputs>>,
+              F: FnMut(QueryRow) -> Result<ControlFlow>
+        {
+
+        self.counters.increment_queries();
+        q_once_each(sqlite,
+                    &*self.current_schema(),
+                    query,
+                    inputs,
+                    f)
+    }
+
+    /// Explain how the given query would be run, using the given connection and the current
+    /// metadata, without running it.
+    pub fn q_explain<T>(&self,
+                        sqlite: &rusqlite::Connection,
+                        query: &str,
+                        inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        self.counters.increment_queries();
+        q_explain(sqlite,
+                  &*self.current_schema(),
+                  query,
+                  inputs)
+    }
+
+    /// Look up the value of `attribute` on `entity`.  Usually `entity` is a `KnownEntid` returned
+    /// by `entid_for_ident`, a `TxReport`, or `InProgress::upsert`.
+    pub fn lookup_value_for_attribute<E>(&self,
+                                         sqlite: &rusqlite::Connection,
+                                         entity: E,
+                                         attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        self.counters.increment_queries();
+        lookup_value_for_attribute(sqlite, &*self.current_schema(), entity.into(), attribute)
+    }
+
+    /// Return the `:db/doc` string documenting `attribute`, if it has one, as installed with the
+    /// attribute or set by `InProgress::set_attribute_doc`.  It is an error if `attribute` isn't an
+    /// attribute.
+    pub fn attribute_doc(&self,
+                         sqlite: &rusqlite::Connection,
+                         attribute: &edn::NamespacedKeyword) -> Result<Option<String>> {
+        let schema = self.current_schema();
+        let (_, KnownEntid(e)) = require_attribute_for_ident(&*schema, attribute)?;
+        self.counters.increment_queries();
+        let doc = lookup_value_for_attribute(sqlite, &*schema, e, &edn::NamespacedKeyword::new("db", "doc"))?;
+        Ok(doc.and_then(|doc| doc.into_string()).map(|doc| (*doc).clone()))
+    }
+
+    /// Pull the attributes of `entity` named by `pattern`, an EDN vector of attribute keywords
+    /// such as `[:db/id :person/name :person/_friends]`.  A reverse attribute, with a leading
+    /// underscore, yields the entities that refer to `entity` by the forward attribute, always as
+    /// a collection.  See `pull`.
+    pub fn pull<E>(&self,
+                   sqlite: &rusqlite::Connection,
+                   entity: E,
+                   pattern: &str) -> Result<Pulled>
+        where E: Into<Entid> {
+        self.counters.increment_queries();
+        pull::pull(sqlite, &*self.current_schema(), entity.into(), pattern)
+    }
+
+    /// Return the entity that already has `value` for the `:db/unique` attribute `attribute`, if
+    /// any, so that a collision can be reported before transacting rather than by a failed
+    /// transaction.  This is the value-to-entity counterpart of `lookup_value_for_attribute`.
+    ///
+    /// Fails with `ErrorKind::NotUniqueAttribute` if `attribute` isn't `:db/unique`.
+    pub fn exists_unique(&self,
+                         sqlite: &rusqlite::Connection,
+                         attribute: &edn::NamespacedKeyword,
+                         value: &TypedValue) -> Result<Option<KnownEntid>> {
+        let a = unique_attribute(&*self.current_schema(), attribute)?;
+        self.counters.increment_queries();
+        Ok(lookup_unique(sqlite, a, value)?.map(KnownEntid))
+    }
+
+    /// Return the transactions committed after the transaction `tx`, in order, for shipping to a
+    /// peer.
+    ///
+    /// Each transaction includes its `:db/txInstant` datom, and each datom records whether it was
+    /// added or retracted.  `Transactions::into_edn` renders them as
+    /// `[[[e a v tx added] ...] ...]`, with numeric entities and attributes named by ident, so that
+    /// they can be replayed using explicit entids.
+    ///
+    /// The rendering isn't self-describing: a ref to an entity with an ident is rendered as that
+    /// ident's keyword, so a replayer must use the attribute's `:db/valueType` to tell a ref from a
+    /// keyword value.  These are the datoms to ship; `TxReport::to_edn` doesn't include them.
+    pub fn transactions_since(&self,
+                              sqlite: &rusqlite::Connection,
+                              tx: Entid) -> Result<dumps::Transactions> {
+        dumps::transactions_after(sqlite, &*self.current_schema(), tx)
+            .map_err(|e| e.into())
+    }
+
+    /// Return the ID of the latest transaction in the log, or `TX0` if there is none.  A replica
+    /// that has seen everything up to this transaction can later ask for what follows with
+    /// `transactions_since`.
+    pub fn head_tx(&self,
+                   sqlite: &rusqlite::Connection) -> Result<Entid> {
+        let head: Option<Entid> = sqlite.query_row("SELECT MAX(tx) FROM transactions", &[], |row| row.get(0))?;
+        Ok(head.unwrap_or(TX0))
+    }
+
+    /// Render the transactions committed after the transaction `since_tx` for reading, each
+    /// headed by its ID and `:db/txInstant` and listing the datoms it added and retracted, one per
+    /// line.  See `dumps::Transactions::to_pretty_string`.
+    ///
+    /// This is for debugging: use `transactions_since` for something to process.
+    pub fn dump_transactions(&self,
+                             sqlite: &rusqlite::Connection,
+                             since_tx: Entid) -> Result<String> {
+        Ok(self.transactions_since(sqlite, since_tx)?.to_pretty_string())
+    }
+
+    /// Compute a deterministic hash over all datoms in the store, excluding `:db/txInstant`.
+    /// Stores holding the same datoms hash identically, regardless of insertion order.
+    /// See `mentat_db::db::state_hash`.
+    pub fn state_hash(&self,
+                      sqlite: &rusqlite::Connection) -> Result<[u8; 32]> {
+        db::state_hash(sqlite)
+            .map_err(|e| e.into())
+    }
+
+    /// Write the whole store -- its partition map, its schema, and every transaction since it was
+    /// created -- to `w` as EDN, from a consistent snapshot.  See `mentat::export` for the format.
+    pub fn export_edn(&self,
+                      sqlite: &mut rusqlite::Connection,
+                      w: &mut Write) -> Result<()> {
+        let snapshot = self.snapshot(sqlite)?;
+        export::write_export(&snapshot.transaction, &*snapshot.schema, w)?;
+        snapshot.close()
+    }
+
+    /// Rebuild a store exported by `export_edn` into this one, which must be freshly created, by
+    /// replaying the exported transactions with their original entids, transaction IDs, and
+    /// instants.  Nothing is imported unless everything is.
+    pub fn import_edn(&mut self,
+                      sqlite: &mut rusqlite::Connection,
+                      r: &mut Read) -> Result<()> {
+        let exported = export::read_export(r)?;
+
+        let mut in_progress = self.begin_transaction(sqlite)?;
+
+        let existing: i64 = in_progress.transaction.query_row("SELECT count(*) FROM transactions WHERE tx > ?", &[&TX0], |row| row.get(0))?;
+        if existing > 0 {
+            bail!(ErrorKind::InvalidExport("can only import into an empty store".to_string()));
+        }
+
+        // Changing the set of partitions isn't supported.
+        let same_partitions = exported.partition_map.len() == in_progress.partition_map.len() &&
+            exported.partition_map.iter().all(|(name, partition)| {
+                in_progress.partition_map.get(name).map(|p| p.start == partition.start && p.index <= partition.index).unwrap_or(false)
+            });
+        if !same_partitions {
+            bail!(ErrorKind::InvalidExport("partitions don't match this store".to_string()));
+        }
+        in_progress.partition_map = exported.partition_map;
+
+        for tx in exported.transactions {
+            in_progress.replay_transaction(tx)?;
+        }
+
+        if in_progress.schema.to_edn_value() != exported.schema {
+            bail!(ErrorKind::InvalidExport("imported schema doesn't match the exported schema".to_string()));
+        }
+
+        in_progress.commit().map(|_| ())
+    }
+
+    /// Check the store for corruption, such as might follow a crash, against the current schema.
+    /// See `mentat_db::verify`.
+    pub fn verify(&self,
+                  sqlite: &rusqlite::Connection) -> Result<VerificationReport> {
+        verify(sqlite, &*self.current_schema())
+            .map_err(|e| e.into())
+    }
+
+    /// Check the invariants of the store -- SQLite's own integrity check, attributes and their value
+    /// types, cardinality and uniqueness -- returning every issue found.  An empty list means the
+    /// store is sound.  This is `verify` without the report.
+    pub fn check_integrity(&self,
+                           sqlite: &rusqlite::Connection) -> Result<Vec<IntegrityIssue>> {
+        self.verify(sqlite).map(|report| report.problems)
+    }
+
+    /// Forget retracted datoms, and the assertions they retracted, from transactions before
+    /// `before_tx`, and then `VACUUM` the store to reclaim the space they occupied.
+    ///
+    /// The current state of the store, and so the result of any query, is unaffected; only the
+    /// history reported by `transactions_since` is lost.  See `mentat_db::db::compact`.
+    pub fn compact(&mut self,
+                   sqlite: &mut rusqlite::Connection,
+                   before_tx: Entid) -> Result<CompactionReport> {
+        self.ensure_writable()?;
+
+        // Don't let a transaction through this `Conn` interleave with compaction.
+        let _metadata = try_lock_metadata(&self.metadata)?;
+
+        let size_before = database_size(sqlite)?;
+
+        let tx = sqlite.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let datoms_removed = db::compact(&tx, before_tx)?;
+        tx.commit()?;
+
+        // `VACUUM` can't run inside a transaction.
+        sqlite.execute_batch("VACUUM")?;
+
+        let size_after = database_size(sqlite)?;
+
+        Ok(CompactionReport {
+            datoms_removed: datoms_removed,
+            bytes_reclaimed: size_before.saturating_sub(size_after),
+        })
+    }
+
+    /// Delete fulltext strings that no datom, current or historical, refers to, and then reclaim
+    /// free space as directed by `vacuum`.  See `mentat_db::db::collect_fulltext_garbage`.
+    ///
+    /// Retracted strings stay referenced by the transaction log, so they're only collected once
+    /// their history has been forgotten, by `compact` or by `:db/noHistory`.
+    pub fn gc(&mut self,
+              sqlite: &mut rusqlite::Connection,
+              vacuum: VacuumMode) -> Result<GcReport> {
+        self.ensure_writable()?;
+
+        // Hold the metadata mutex throughout, as a transaction does, so that no transaction through
+        // this `Conn` can add a reference to a string we're about to delete.
+        let metadata = try_lock_metadata(&self.metadata)?;
+
+        let size_before = database_size(sqlite)?;
+
+        let tx = sqlite.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let fulltext_values_removed = db::collect_fulltext_garbage(&tx, &metadata.schema)?;
+        tx.commit()?;
+
+        // Neither can run inside a transaction.
+        match vacuum {
+            VacuumMode::None => {},
+            VacuumMode::Full => sqlite.execute_batch("VACUUM")?,
+            VacuumMode::Incremental => sqlite.execute_batch("PRAGMA incremental_vacuum")?,
+        }
+
+        let size_after = database_size(sqlite)?;
+
+        Ok(GcReport {
+            fulltext_values_removed: fulltext_values_removed,
+            bytes_reclaimed: size_before.saturating_sub(size_after),
+        })
+    }
+
+    /// Wait up to `timeout` for competing writers when beginning a transaction, rather than
+    /// failing as soon as the store is found to be locked.  SQLite's busy handler retries with
+    /// increasing backoff until the timeout elapses.  A zero `timeout` never waits.
+    ///
+    /// The timeout is applied to the SQLite connection passed to each subsequent
+    /// `begin_transaction`.  `transact` and its relatives also retry, with exponential backoff
+    /// until the timeout elapses, a transaction that fails with `ErrorKind::DatabaseBusy` or
+    /// `ErrorKind::LostTransactRace`.  Without a timeout they never retry.
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.busy_timeout = Some(timeout);
+    }
+
+    /// Check every value asserted for `attribute` by later transactions through this `Conn`,
+    /// including those made with `begin_transaction`, with `f`.  If `f` rejects a value, the
+    /// transaction making the assertion fails with `ErrorKind::ValidationFailed`, carrying
+    /// `f`'s message, and writes nothing.  Retractions aren't checked.
+    ///
+    /// An attribute may have several validators, all of which must accept a value.  The attribute
+    /// needn't exist yet: it's looked up by ident as each transaction is validated.
+    pub fn register_validator(&mut self, attribute: edn::NamespacedKeyword, f: Validator) {
+        self.constraints.register_validator(attribute, f);
+    }
+
+    /// Require that every entity with `attribute` also have each of the `required` attributes.
+    /// After each later transaction through this `Conn`, including those made with
+    /// `begin_transaction`, each entity it asserted or retracted anything about is checked as the
+    /// store then stands, so a required attribute may have been asserted earlier or in the same
+    /// transaction.  If an entity falls short, the transaction fails with
+    /// `ErrorKind::RequiredAttributesMissing`, listing every entity and missing attribute, and
+    /// writes nothing.
+    ///
+    /// Requirements for the same `attribute` accumulate.
+    pub fn require_attributes_for(&mut self, attribute: edn::NamespacedKeyword, required: Vec<edn::NamespacedKeyword>) {
+        self.constraints.require_attributes_for(attribute, required);
+    }
+
+    /// Call `f` with the report of each transaction committed through this `Conn` from now on,
+    /// whether by `transact` or by committing an `InProgress`.  Callbacks are called in the order
+    /// they were registered, after the commit, and outside the metadata mutex; nothing is called
+    /// for a transaction that's rolled back or fails to commit, or that transacted nothing.
+    ///
+    /// This is meant for cheap reactions like scheduling a sync or invalidating a cache.  A
+    /// callback mustn't transact through, or register callbacks with, this `Conn`.
+    pub fn on_commit(&self, f: CommitCallback) {
+        self.commit_callbacks.lock().unwrap_or_else(|e| e.into_inner()).push(f);
+    }
+
+    /// Watch `attributes` for changes.  After each transaction committed through this `Conn` that
+    /// asserts or retracts a datom for any of them, a `TxChange` listing those datoms is sent to
+    /// the returned receiver.  Dropping the `WatchHandle` ends the watch.
+    ///
+    /// Sending never blocks or fails the writer: if `watch::WATCH_CAPACITY` changes are waiting
+    /// to be received, or the receiver has been dropped, the change is dropped and counted in
+    /// `ConnStats::watch_changes_dropped`.
+    pub fn watch(&self, attributes: BTreeSet<edn::NamespacedKeyword>) -> (WatchHandle, mpsc::Receiver<TxChange>) {
+        watch::watch(&self.watches, attributes)
+    }
+
+    /// Hand this `Conn`, and the SQLite connection to write through, to a background thread that
+    /// transacts whatever is submitted to the returned `AsyncConn`, in order.
+    ///
+    /// Each committed transaction is visible to queries through `AsyncConn::conn` as soon as it
+    /// commits.  Those queries need their own SQLite connection to the same store, so this is
+    /// mostly useful for a file-backed store.
+    pub fn into_async(self, sqlite: rusqlite::Connection) -> AsyncConn {
+        AsyncConn::new(self, sqlite)
+    }
+
+    /// Forbid, or again allow, changes to the schema by later transactions through this `Conn`.
+    /// While the schema is locked, a transaction that would add or alter an attribute, or any
+    /// other ident, fails with `ErrorKind::SchemaLocked` and writes nothing; other transactions
+    /// are unaffected.  This guards a production store against schema drift from application
+    /// writes.
+    ///
+    /// Transactions already begun keep the setting they began with.
+    pub fn set_schema_locked(&mut self, locked: bool) {
+        self.constraints.set_schema_locked(locked);
+    }
+
+    /// Take a consistent, read-only view of the store that is unaffected by transactions committed
+    /// later, by this or any other connection.  See `Snapshot`.
+    ///
+    /// The SQLite transaction is `DEFERRED`, so it doesn't contend with writers.  In WAL mode,
+    /// writers can continue to commit while the snapshot is open.
+    pub fn snapshot<'m, 'conn>(&'m self, sqlite: &'conn mut rusqlite::Connection) -> Result<Snapshot<'m, 'conn>> {
+        // Hold the metadata mutex so that no commit through this `Conn` can land between reading
+        // the schema and fixing the SQLite view.
+        let metadata = lock_metadata(&self.metadata);
+
+        let tx = sqlite.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+        // A deferred transaction doesn't fix its view of the database until its first read.
+        tx.query_row("SELECT count(*) FROM sqlite_master", &[], |_| ())?;
+
+        Ok(Snapshot {
+            transaction: tx,
+            counters: &self.counters,
+            schema: metadata.schema.clone(),
+        })
+    }
+
+    /// Take a `DEFERRED` SQLite transaction for reading.  Unlike `begin_transaction`, this never
+    /// contends with writers for the write lock, and other connections may write while it is open.
+    ///
+    /// The returned `InProgressRead` offers queries but no way to transact.  It reads from a
+    /// consistent view of the store; see `snapshot`.
+    pub fn begin_read<'m, 'conn>(&'m self, sqlite: &'conn mut rusqlite::Connection) -> Result<InProgressRead<'m, 'conn>> {
+        self.snapshot(sqlite)
+    }
+
+    /// Take a SQLite transaction.
+    /// IMMEDIATE means 'start the transaction now, but don't exclude readers'. It prevents other
+    /// connections from taking immediate or exclusive transactions. This is appropriate for our
+    /// writes and `InProgress`: it means we are ready to write whenever we want to, and nobody else
+    /// can start a transaction that's not `DEFERRED`, but we don't need exclusivity yet.
+    /// Read-only work should use `begin_read` instead.
+    ///
+    /// If another connection -- perhaps in another process -- holds the write lock, this waits as
+    /// configured by `set_busy_timeout`, and then fails with `ErrorKind::DatabaseBusy`.
+    ///
+    /// This only needs `&self`, so other threads sharing the `Conn` can query it or
+    /// `wait_for_change` while the transaction is open.
+    pub fn begin_transaction<'m, 'conn>(&'m self, sqlite: &'conn mut rusqlite::Connection) -> Result<InProgress<'m, 'conn>> {
+        self.ensure_writable()?;
+
+        let span = spans::begin_transaction();
+
+        if let Some(timeout) = self.busy_timeout {
+            let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+            sqlite.execute_batch(&format!("PRAGMA busy_timeout = {}", millis))?;
+        }
+
+        let tx = match sqlite.transaction_with_behavior(TransactionBehavior::Immediate) {
+            Ok(tx) => tx,
+            Err(rusqlite::Error::SqliteFailure(ref e, _)) if e.code == rusqlite::ErrorCode::DatabaseBusy => {
+                bail!(ErrorKind::DatabaseBusy);
+            },
+            Err(e) => bail!(e),
+        };
+        let (current_generation, current_partition_map, current_schema) =
+        {
+            // The mutex is taken during this block.
+            let ref current: Metadata = *try_lock_metadata(&self.metadata)?;
+            (current.generation,
+             // Expensive, but the partition map is updated after every committed transaction.
+             current.partition_map.clone(),
+             // Cheap.
+             current.schema.clone())
+        };
+
+        span.record_i64("generation", current_generation as i64);
+
+        let first_tx = current_partition_map[PartitionName::Tx.as_str()].index;
+
+        Ok(InProgress {
+            mutex: &self.metadata,
+            changed: &self.changed,
+            counters: &self.counters,
+            transaction: tx,
+            generation: current_generation,
+            partition_map: current_partition_map,
+            schema: (*current_schema).clone(),
+            last_report: None,
+            datoms_asserted: 0,
+            datoms_retracted: 0,
+            savepoints: vec![],
+            constraints: &self.constraints,
+            precommit_hook: None,
+            commit_callbacks: &self.commit_callbacks,
+            watches: &*self.watches,
+            keywords: &self.keywords,
+            first_tx: first_tx,
+            tempids: BTreeMap::new(),
+            minted_tempids: BTreeMap::new(),
+        })
+    }
+
+    /// Transact entities against the Mentat store, using the given connection and the current
+    /// metadata.
+    pub fn transact(&mut self,
+                    sqlite: &mut rusqlite::Connection,
+                    transaction: &str) -> Result<TxReport> {
+        // Parse outside the SQL transaction. This is a tradeoff: we are limiting the scope of the
+        // transaction, and indeed we don't even create a SQL transaction if the provided input is
+        // invalid, but it means SQLite errors won't be found until the parse is complete, and if
+        // there's a race for the database (don't do that!) we are less likely to win it.
+        self.ensure_writable()?;
+
+        let span = spans::transact();
+
+        let entities = parse_transaction(transaction)?;
+        self.transact_entities(sqlite, entities, span).map_err(|e| with_entity_text(e, transaction))
+    }
+
+    /// Like `transact`, but taking a transaction that has already been parsed as EDN, such as one
+    /// received from elsewhere in structured form.
+    pub fn transact_parsed(&mut self,
+                           sqlite: &mut rusqlite::Connection,
+                           transaction: &edn::Value) -> Result<TxReport> {
+        self.ensure_writable()?;
+
+        let span = spans::transact();
+
+        let entities = parse_transaction_value(transaction)?;
+        self.transact_entities(sqlite, entities, span)
+    }
+
+    /// Transact the entities built by `builder`.  Look up the entids its tempids became with
+    /// `TxReport::resolved`.
+    pub fn transact_builder(&mut self,
+                            sqlite: &mut rusqlite::Connection,
+                            builder: TermBuilder) -> Result<TxReport> {
+        self.ensure_writable()?;
+
+        let span = spans::transact();
+
+        self.transact_entities(sqlite, builder.build(), span)
+    }
+
+    /// Assert the single datom `[e a v]`, without writing EDN.  `a` is resolved against the current
+    /// schema, and `v` is checked against the attribute's value type, being coerced where the
+    /// transactor allows, as if it had been written in a transaction.
+    pub fn add(&mut self,
+               sqlite: &mut rusqlite::Connection,
+               e: Entid,
+               a: &edn::NamespacedKeyword,
+               v: TypedValue) -> Result<TxReport> {
+        self.transact_datom(sqlite, OpType::Add, e, a, v)
+    }
+
+    /// Retract the single datom `[e a v]`.  See `add`.
+    pub fn retract(&mut self,
+                   sqlite: &mut rusqlite::Connection,
+                   e: Entid,
+                   a: &edn::NamespacedKeyword,
+                   v: TypedValue) -> Result<TxReport> {
+        self.transact_datom(sqlite, OpType::Retract, e, a, v)
+    }
+
+    fn transact_datom(&mut self,
+                      sqlite: &mut rusqlite::Connection,
+                      op: OpType,
+                      e: Entid,
+                      a: &edn::NamespacedKeyword,
+                      v: TypedValue) -> Result<TxReport> {
+        self.ensure_writable()?;
+
+        let span = spans::transact();
+
+        let (attribute, _) = self.attribute_for_ident(a)
+                                 .ok_or_else(|| Error::from(ErrorKind::UnknownAttribute(a.clone())))?;
+        let (value, _) = v.to_edn_value_pair();
+
+        // In a transaction, a string value for a ref attribute names a tempid.  Here, it can
+        // only be a mistake.
+        if attribute.value_type == ValueType::Ref && v.value_type() == ValueType::String {
+            bail!(ErrorKind::DbError(::mentat_db::ErrorKind::ValueTypeMismatch(a.to_string(), ValueType::Ref, value)));
+        }
+
+        let entity = Entity::AddOrRetract {
+            op: op,
+            e: EntidOrLookupRefOrTempId::Entid(mentat_tx::entities::Entid::Entid(e)),
+            a: mentat_tx::entities::Entid::Ident(a.clone()),
+            v: AtomOrLookupRefOrVectorOrMapNotation::Atom(value.with_spans()),
+        };
+        self.transact_entities(sqlite, vec![entity], span)
+    }
+
+    /// Transact the entities of the EDN transactions read from `r`, such as a large import file,
+    /// without holding all of the input in memory.  Returns the number of entities transacted.
+    ///
+    /// `r` holds a sequence of transaction vectors, whose entities are parsed one at a time and
+    /// transacted `chunk` at a time (at least one), committed as `commit` says.  Because each
+    /// chunk is a Mentat transaction of its own, a tempid only refers to the same entity within a
+    /// chunk.
+    ///
+    /// Progress through the whole input is reported to `options`, which can also cancel the
+    /// import.  Cancelling rolls back the SQL transaction in progress: with `ChunkCommit::Once`,
+    /// the store is left unchanged; with `ChunkCommit::EachChunk`, chunks already committed stay.
+    ///
+    /// An entity that can't be parsed fails with `ErrorKind::TransactionStreamError`, giving the
+    /// line and byte offset at which it starts.
+    pub fn transact_from_read(&mut self,
+                              sqlite: &mut rusqlite::Connection,
+                              r: &mut Read,
+                              chunk: usize,
+                              commit: ChunkCommit,
+                              options: &mut TransactOptions) -> Result<usize> {
+        self.ensure_writable()?;
+
+        let chunk = ::std::cmp::max(chunk, 1);
+        let mut entities = EntityReader::new(r).peekable();
+        let mut progress = TransactProgress::default();
+
+        while entities.peek().is_some() {
+            let mut in_progress = self.begin_transaction(sqlite)?;
+            loop {
+                let batch: Vec<Entity> = entities.by_ref().take(chunk).collect::<Result<_>>()?;
+                in_progress.transact_entities_with_progress_so_far(batch, options, &mut progress)?;
+
+                if commit == ChunkCommit::EachChunk || entities.peek().is_none() {
+                    break;
+                }
+            }
+            in_progress.commit()?;
+        }
+
+        options.report(progress);
+        Ok(progress.entities)
+    }
+
+    /// Transact `entities` in a transaction of their own, retrying as `set_busy_timeout` says.
+    fn transact_entities(&mut self,
+                         sqlite: &mut rusqlite::Connection,
+                         entities: Vec<Entity>,
+                         span: spans::OperationSpan) -> Result<TxReport> {
+        let deadline = match self.busy_timeout {
+            Some(timeout) => Instant::now() + timeout,
+            None => return self.transact_entities_once(sqlite, entities, &span),
+        };
+
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            match self.transact_entities_once(sqlite, entities.clone(), &span) {
+                Err(Error(ErrorKind::DatabaseBusy, _)) |
+                Err(Error(ErrorKind::LostTransactRace, _)) if Instant::now() + backoff < deadline => {
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, Duration::from_millis(100));
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn transact_entities_once(&mut self,
+                              sqlite: &mut rusqlite::Connection,
+                              entities: Vec<Entity>,
+                              span: &spans::OperationSpan) -> Result<TxReport> {
+        let mut in_progress = self.begin_transaction(sqlite)?;
+        in_progress.transact_entities(entities)?;
+        span.record_i64("datoms_asserted", in_progress.datoms_asserted as i64);
+        span.record_i64("datoms_retracted", in_progress.datoms_retracted as i64);
+
+        let report = in_progress.commit()?
+                                .expect("we always get a report");
+        span.record_i64("tx_id", report.tx_id);
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate mentat_parser_utils;
+    use mentat_core::{
+        TypedValue,
+        ValueType,
+    };
+
+    use mentat_db::USER0;
+
+    use pull::PullValue;
+    use query::{
+        QueryResults,
+        Variable,
+    };
+    use mentat_db::debug::TempStorePath;
+
+    #[test]
+    fn test_transact_does_not_collide_existing_entids() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        // Let's find out the next ID that'll be allocated. We're going to try to collide with it
+        // a bit later.
+        let next = conn.metadata.lock().expect("metadata")
+                       .partition_map[PartitionName::User.as_str()].index;
+        let t = format!("[[:db/add {} :db.schema/attribute \"tempid\"]]", next + 1);
+
+        let err = conn.transact(&mut sqlite, t.as_str()).unwrap_err();
+        assert_eq!(err.iter().last().unwrap().to_string(), format!("unrecognized or no ident found for entid: {}", next + 1));
+        match err {
+            Error(ErrorKind::InvalidEntity(0, _), _) => {},
+            x => panic!("expected transact error, got {:?}", x),
+        }
+
+        // Transact two more tempids.
+        let t = "[[:db/add \"one\" :db.schema/attribute \"more\"]]";
+        let report = conn.transact(&mut sqlite, t)
+                         .expect("transact succeeded");
+        assert_eq!(report.tempids["more"], next);
+        assert_eq!(report.tempids["one"], next + 1);
+    }
+
+    #[test]
+    fn test_transact_does_not_collide_new_entids() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        // Let's find out the next ID that'll be allocated. We're going to try to collide with it.
+        let next = conn.metadata.lock().expect("metadata").partition_map[PartitionName::User.as_str()].index;
+
+        // If this were to be resolved, we'd get [:db/add 65537 :db.schema/attribute 65537], but
+        // we should reject this, because the first ID was provided by the user!
+        let t = format!("[[:db/add {} :db.schema/attribute \"tempid\"]]", next);
+
+        // All this, despite this being the ID we were about to allocate!
+        let err = conn.transact(&mut sqlite, t.as_str()).unwrap_err();
+        assert_eq!(err.iter().last().unwrap().to_string(), format!("unrecognized or no ident found for entid: {}", next));
+        match err {
+            Error(ErrorKind::InvalidEntity(0, _), _) => {},
+            x => panic!("expected transact error, got {:?}", x),
+        }
+
+        // And if we subsequently transact in a way that allocates one ID, we _will_ use that one.
+        // `:db/ident` is bootstrapped, so its entid is a known-good value.
+        let db_ident = conn.entid_for_ident(&edn::NamespacedKeyword::new("db", "ident")).expect("bootstrapped");
+        let t = format!("[[:db/add {} :db.schema/attribute \"temp\"]]", db_ident.0);
+        let report = conn.transact(&mut sqlite, t.as_str())
+                         .expect("transact succeeded");
+        assert_eq!(report.tempids["temp"], next);
+    }
+
+    #[test]
+    fn test_transact_entid_out_of_partition_range() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        // The transaction partition is the highest; nothing has been allocated beyond its index.
+        let ceiling = conn.metadata.lock().expect("metadata").partition_map[PartitionName::Tx.as_str()].index;
+        let t = format!("[[:db/add {} :db.schema/attribute \"tempid\"]]", ceiling + 1000);
+
+        let err = conn.transact(&mut sqlite, t.as_str()).unwrap_err();
+        assert_eq!(err.iter().last().unwrap().to_string(), format!("entid outside all known partition ranges: {}", ceiling + 1000));
+        match err {
+            Error(ErrorKind::InvalidEntity(0, _), _) => {},
+            x => panic!("expected transact error, got {:?}", x),
+        }
+
+        // Nor is a negative entid in any partition.
+        let err = conn.transact(&mut sqlite, "[[:db/add -5 :db.schema/attribute \"tempid\"]]").unwrap_err();
+        assert_eq!(err.iter().last().unwrap().to_string(), "entid outside all known partition ranges: -5");
+    }
+
+    /// Return the entid that will be allocated to the next transacted tempid.
+    fn get_next_entid(conn: &Conn) -> i64 {
+        let partition_map = &conn.metadata.lock().unwrap().partition_map;
+        partition_map.get(PartitionName::User.as_str()).unwrap().index
+    }
+
+    #[test]
+    fn test_partitions() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let partitions = conn.partitions();
+        let names: Vec<PartitionName> = partitions.iter().map(|p| p.name.clone()).collect();
+        assert_eq!(names, vec![PartitionName::Db, PartitionName::Tx, PartitionName::User]);
+
+        let user = partitions.iter().find(|p| p.name == PartitionName::User).cloned().expect("user partition");
+        assert_eq!(user.start, USER0);
+        assert_eq!(user.allocated(), 0);
+
+        conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]
+                                     [:db/add \"two\" :db/ident :a/keyword2]]")
+            .expect("transact succeeded");
+
+        let after = conn.partitions();
+        let user_after = after.iter().find(|p| p.name == PartitionName::User).expect("user partition");
+        assert_eq!(user_after.allocated(), 2);
+        let tx = partitions.iter().find(|p| p.name == PartitionName::Tx).expect("tx partition");
+        let tx_after = after.iter().find(|p| p.name == PartitionName::Tx).expect("tx partition");
+        assert_eq!(tx_after.index, tx.index + 1);
+
+        assert_eq!(PartitionName::from(":db.part/user"), PartitionName::User);
+        assert_eq!(PartitionName::from(":app.part/things"), PartitionName::Custom(":app.part/things".to_string()));
+        assert_eq!(PartitionName::Custom(":app.part/things".to_string()).to_string(), ":app.part/things");
+    }
+
+    /// A schema for `people`.
+    const PEOPLE_SCHEMA: &'static str = "[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                          {:db/ident :test/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                                          {:db/ident :test/bio :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]";
+
+    /// `n` people, each with three attributes, in two transactions, as EDN.
+    fn people(n: usize) -> String {
+        let bio = "x".repeat(250);
+        let mut s = String::new();
+        for half in 0..2 {
+            s.push_str("[\n");
+            for i in (half * n / 2)..((half + 1) * n / 2) {
+                s.push_str(&format!("  {{:test/name \"person {}\" :test/age {} :test/bio \"{}\"}}\n", i, i, bio));
+            }
+            s.push_str("]\n");
+        }
+        s
+    }
+
+    fn count_user_datoms(sqlite: &::rusqlite::Connection) -> i64 {
+        sqlite.query_row("SELECT count(*) FROM datoms WHERE e >= ? AND e < ?", &[&USER0, &TX0], |row| row.get(0))
+              .expect("counted")
+    }
+
+    #[test]
+    fn test_transact_from_read() {
+        let n = 10_000;
+        let input = people(n);
+        assert!(input.len() > 2_000_000);
+
+        // Every chunk in one transaction.
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+        let generation = conn.generation();
+
+        let reported = Arc::new(Mutex::new(vec![]));
+        let mut options = TransactOptions::default();
+        options.report_every = 3000;
+        options.progress = {
+            let reported = reported.clone();
+            Some(Box::new(move |progress: TransactProgress| reported.lock().unwrap().push(progress.entities)))
+        };
+        let transacted = conn.transact_from_read(&mut sqlite, &mut input.as_bytes(), 3000, ChunkCommit::Once, &mut options)
+                             .expect("transacted");
+        assert_eq!(transacted, n);
+        assert_eq!(*reported.lock().unwrap(), vec![3000, 6000, 9000, 10000]);
+        assert_eq!(conn.generation(), generation + 1);
+        assert_eq!(count_user_datoms(&sqlite), 3 * n as i64);
+
+        let age = conn.q_once(&sqlite, "[:find ?age . :where [?p :test/name \"person 1234\"] [?p :test/age ?age]]", None)
+                      .expect("query")
+                      .into_scalar()
+                      .expect("scalar");
+        assert_eq!(age, Some(TypedValue::Long(1234)));
+
+        // Each chunk in its own transaction.
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+        let generation = conn.generation();
+
+        let transacted = conn.transact_from_read(&mut sqlite, &mut input.as_bytes(), 3000, ChunkCommit::EachChunk, &mut TransactOptions::default())
+                             .expect("transacted");
+        assert_eq!(transacted, n);
+        assert_eq!(conn.generation(), generation + 4);
+        assert_eq!(count_user_datoms(&sqlite), 3 * n as i64);
+    }
+
+    #[test]
+    fn test_transact_from_read_errors() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+
+        // The third entity, at the start of the third line, isn't valid EDN.
+        let input = "[{:test/name \"a\"}\n {:test/name \"b\"}\n {:test/name}]";
+        let location = |result: Result<usize>| {
+            match result {
+                Err(Error(ErrorKind::TransactionStreamError(line, offset, _), _)) => (line, offset),
+                x => panic!("expected TransactionStreamError, got {:?}", x),
+            }
+        };
+
+        // Nothing is committed unless everything is.
+        assert_eq!(location(conn.transact_from_read(&mut sqlite, &mut input.as_bytes(), 1, ChunkCommit::Once, &mut TransactOptions::default())), (3, 37));
+        assert_eq!(count_user_datoms(&sqlite), 0);
+
+        // The chunks before the error are committed.
+        assert_eq!(location(conn.transact_from_read(&mut sqlite, &mut input.as_bytes(), 1, ChunkCommit::EachChunk, &mut TransactOptions::default())), (3, 37));
+        assert_eq!(count_user_datoms(&sqlite), 2);
+    }
+
+    #[test]
+    fn test_generation() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        let start = conn.generation();
+
+        conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]").expect("transact succeeded");
+        assert_eq!(conn.generation(), start + 1);
+
+        // Neither failed nor rolled back transactions advance the generation.
+        assert!(conn.transact(&mut sqlite, "[[:db/add \"two\" :a/unknown \"x\"]]").is_err());
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact("[[:db/add \"two\" :db/ident :a/keyword2]]").expect("transacted");
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(conn.generation(), start + 1);
+
+        conn.transact(&mut sqlite, "[[:db/add \"two\" :db/ident :a/keyword2]]").expect("transact succeeded");
+        assert_eq!(conn.generation(), start + 2);
+    }
+
+    #[test]
+    fn test_wait_for_change() {
+        let (conn, mut sqlite) = Conn::in_memory().unwrap();
+        let conn = Arc::new(conn);
+        let start = conn.generation();
+
+        match conn.wait_for_change(start, Duration::from_millis(10)) {
+            Err(Error(ErrorKind::WaitTimeout(_), _)) => {},
+            x => panic!("expected WaitTimeout, got {:?}", x),
+        }
+
+        let waiter = {
+            let conn = conn.clone();
+            ::std::thread::spawn(move || conn.wait_for_change(start, Duration::from_secs(30)))
+        };
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact("[[:db/add \"one\" :db/ident :a/keyword1]]").expect("transacted");
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(waiter.join().expect("joined").expect("woken"), start + 1);
+
+        // A change that's already happened is reported at once.
+        assert_eq!(conn.wait_for_change(start, Duration::from_secs(0)).expect("changed"), start + 1);
+    }
+
+    #[test]
+    fn test_create_partition() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        let things = PartitionName::Custom(":test.part/things".to_string());
+
+        // Rolling back discards the partition.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.create_partition(":test.part/things", 0x100000).expect("created");
+            in_progress.rollback().expect("rolled back");
+        }
+        assert!(conn.partitions().iter().all(|p| p.name != things));
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.create_partition(":test.part/things", 0x100000).expect("created");
+
+            let invalid = |result: Result<()>| {
+                match result {
+                    Err(Error(ErrorKind::InvalidPartition(_), _)) => {},
+                    x => panic!("expected InvalidPartition, got {:?}", x),
+                }
+            };
+            invalid(in_progress.create_partition(":test.part/things", 0x200000));
+            invalid(in_progress.create_partition("things", 0x200000));
+            invalid(in_progress.create_partition(":test.part/negative", -1));
+
+            // Within the entids allocated from :db.part/db, or at the start of another partition.
+            invalid(in_progress.create_partition(":test.part/other", 10));
+            invalid(in_progress.create_partition(":test.part/other", USER0));
+            invalid(in_progress.create_partition(":test.part/other", 0x100000));
+
+            in_progress.commit().expect("committed");
+        }
+
+        let created = conn.partitions().into_iter().find(|p| p.name == things).expect("created partition");
+        assert_eq!(created.start, 0x100000);
+        assert_eq!(created.allocated(), 0);
+        assert_eq!(created.end, TX0);
+
+        // The partition is persisted, and transacting doesn't disturb it.
+        conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]").expect("transact succeeded");
+        let reopened = Conn::connect(&mut sqlite).expect("connected");
+        assert_eq!(reopened.partitions(), conn.partitions());
+        assert!(reopened.partitions().contains(&created));
+
+        // :db.part/user now ends where the new partition starts, and can't allocate into it.
+        let user = reopened.partitions().into_iter().find(|p| p.name == PartitionName::User).expect("user partition");
+        assert_eq!(user.end, 0x100000);
+        sqlite.execute("UPDATE parts SET idx = ? WHERE part = ':db.part/user'", &[&(0x100000 - 1)]).expect("updated");
+        let mut reopened = Conn::connect(&mut sqlite).expect("connected");
+        reopened.transact(&mut sqlite, "[[:db/add \"last\" :db/ident :a/keyword2]]").expect("transact succeeded");
+        match reopened.transact(&mut sqlite, "[[:db/add \"beyond\" :db/ident :a/keyword3]]") {
+            Err(Error(ErrorKind::DbError(::mentat_db::ErrorKind::PartitionExhausted(ref part)), _)) => assert_eq!(part, ":db.part/user"),
+            x => panic!("expected PartitionExhausted, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_upsert() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        conn.transact(&mut sqlite, r#"[
+            {:db/ident :person/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity :db/index true}
+            {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :person/handle :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/value :db/index true}
+        ]"#).expect("schema transacted");
+
+        let email = edn::NamespacedKeyword::new("person", "email");
+        let name = edn::NamespacedKeyword::new("person", "name");
+        let handle = edn::NamespacedKeyword::new("person", "handle");
+
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+
+        let created = in_progress.upsert(&email,
+                                         TypedValue::typed_string("a@example.com"),
+                                         vec![(name.clone(), TypedValue::typed_string("Alice"))])
+                                 .expect("created");
+        let updated = in_progress.upsert(&email,
+                                         TypedValue::typed_string("a@example.com"),
+                                         vec![(name.clone(), TypedValue::typed_string("Alicia"))])
+                                 .expect("updated");
+        assert_eq!(created, updated);
+        assert_eq!(in_progress.lookup_value_for_attribute(created, &name).expect("lookup"),
+                   Some(TypedValue::typed_string("Alicia")));
+
+        let other = in_progress.upsert(&email, TypedValue::typed_string("b@example.com"), vec![])
+                               .expect("created");
+        assert!(other != created);
+        assert_eq!(in_progress.lookup_value_for_attribute(other, &email).expect("lookup"),
+                   Some(TypedValue::typed_string("b@example.com")));
+
+        // Upserting requires a unique attribute.
+        match in_progress.upsert(&name, TypedValue::typed_string("Alicia"), vec![]).unwrap_err() {
+            Error(ErrorKind::NotUniqueAttribute(kw), _) => assert_eq!(kw, name),
+            x => panic!("expected NotUniqueAttribute, got {:?}", x),
+        }
+
+        // The tempids of earlier transactions don't leak into upserts: each new key is a new entity.
+        in_progress.transact(r#"[[:db/add "upsert" :person/name "Mallory"]]"#).expect("transacted");
+        let third = in_progress.upsert(&email, TypedValue::typed_string("c@example.com"), vec![])
+                               .expect("created");
+        assert!(third != created && third != other);
+        assert_eq!(third, in_progress.upsert(&email, TypedValue::typed_string("c@example.com"), vec![])
+                                     .expect("found"));
+
+        // An upsert that fails while writing, here because another entity already has the
+        // unique handle, leaves nothing behind and the transaction usable.
+        let bob = in_progress.upsert(&email,
+                                     TypedValue::typed_string("bob@example.com"),
+                                     vec![(handle.clone(), TypedValue::typed_string("bob"))])
+                             .expect("created");
+        let people_before = in_progress.q_once("[:find (count ?e) . :where [?e :person/email _]]", None)
+                                       .expect("counted");
+        assert!(in_progress.upsert(&email,
+                                   TypedValue::typed_string("a@example.com"),
+                                   vec![(name.clone(), TypedValue::typed_string("Al")),
+                                        (handle.clone(), TypedValue::typed_string("bob"))])
+                           .is_err());
+        assert!(in_progress.upsert(&email,
+                                   TypedValue::typed_string("d@example.com"),
+                                   vec![(handle.clone(), TypedValue::typed_string("bob"))])
+                           .is_err());
+        assert_eq!(in_progress.lookup_value_for_attribute(created, &name).expect("lookup"),
+                   Some(TypedValue::typed_string("Alicia")));
+        assert_eq!(in_progress.lookup_value_for_attribute(created, &handle).expect("lookup"), None);
+        assert_eq!(in_progress.lookup_value_for_attribute(bob, &handle).expect("lookup"),
+                   Some(TypedValue::typed_string("bob")));
+        assert_eq!(in_progress.q_once("[:find (count ?e) . :where [?e :person/email _]]", None).expect("counted"),
+                   people_before);
+
+        let dave = in_progress.upsert(&email,
+                                      TypedValue::typed_string("d@example.com"),
+                                      vec![(handle.clone(), TypedValue::typed_string("dave"))])
+                              .expect("created after a failed upsert");
+        assert!(dave != bob && dave != created);
+        in_progress.commit().expect("committed");
+
+        let after = conn.q_once(&sqlite, "[:find ?h . :where [?e :person/email \"d@example.com\"] [?e :person/handle ?h]]", None)
+                        .expect("queried");
+        assert_eq!(after.results, QueryResults::Scalar(Some(TypedValue::typed_string("dave"))));
+    }
+
+    #[test]
+    fn test_compound_transact() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let tempid_offset = get_next_entid(&conn);
+
+        let t = "[[:db/add \"one\" :db/ident :a/keyword1] \
+                  [:db/add \"two\" :db/ident :a/keyword2]]";
+
+        // This can refer to `t`, 'cos they occur in separate txes.
+        let t2 = "[{:db.schema/attribute \"three\", :db/ident :a/keyword1}]";
+
+        // Scoped borrow of `conn`.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact(t).expect("transacted successfully");
+            let one = in_progress.last_report().unwrap().tempids.get("one").expect("found one").clone();
+            let two = in_progress.last_report().unwrap().tempids.get("two").expect("found two").clone();
+            assert!(one != two);
+            assert!(one == tempid_offset || one == tempid_offset + 1);
+            assert!(two == tempid_offset || two == tempid_offset + 1);
+
+            let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                                    .expect("query succeeded");
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+
+            in_progress.transact(t2).expect("t2 succeeded");
+            let report = in_progress.commit()
+                                    .expect("commit succeeded");
+            let three = report.unwrap().tempids.get("three").expect("found three").clone();
+            assert!(one != three);
+            assert!(two != three);
+        }
+
+        // The DB part table changed.
+        let tempid_offset_after = get_next_entid(&conn);
+        assert_eq!(tempid_offset + 3, tempid_offset_after);
+    }
+
+    #[test]
+    fn test_compound_rollback() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let tempid_offset = get_next_entid(&conn);
+
+        // Nothing in the store => USER0 should be our starting point.
+        assert_eq!(tempid_offset, USER0);
+
+        let t = "[[:db/add \"one\" :db/ident :a/keyword1] \
+                  [:db/add \"two\" :db/ident :a/keyword2]]";
+
+        // Scoped borrow of `sqlite`.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact(t).expect("transacted successfully");
+
+            let one = in_progress.last_report().unwrap().tempids.get("one").expect("found it").clone();
+            let two = in_progress.last_report().unwrap().tempids.get("two").expect("found it").clone();
+
+            // The IDs are contiguous, starting at the previous part index.
+            assert!(one != two);
+            assert!(one == tempid_offset || one == tempid_offset + 1);
+            assert!(two == tempid_offset || two == tempid_offset + 1);
+
+            // Inside the InProgress we can see our changes.
+            let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                                    .expect("query succeeded");
+
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+
+            // And we can do direct lookup, too.
+            let kw = in_progress.lookup_value_for_attribute(one, &edn::NamespacedKeyword::new("db", "ident"))
+                                .expect("lookup succeeded");
+            assert_eq!(kw, Some(TypedValue::Keyword(edn::NamespacedKeyword::new("a", "keyword1").into())));
+
+            in_progress.rollback()
+                       .expect("rollback succeeded");
+        }
+
+        let after = conn.q_once(&mut sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                        .expect("query succeeded");
+        assert_eq!(after.results, QueryResults::Scalar(None));
+
+        // The DB part table is unchanged.
+        let tempid_offset_after = get_next_entid(&conn);
+        assert_eq!(tempid_offset, tempid_offset_after);
+    }
+
+    #[test]
+    fn test_transact_without_rebinding() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let tempid_offset = get_next_entid(&conn);
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact("[[:db/add \"one\" :db/ident :a/keyword1]]").expect("transacted successfully");
+
+            // Query to decide what to write next.
+            let one = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                                 .expect("query succeeded");
+            assert_eq!(one.results, QueryResults::Scalar(Some(TypedValue::Ref(tempid_offset))));
+
+            // A failed transaction leaves the rest of the work in progress intact.
+            assert!(in_progress.transact("[[:db/add \"bad\" :a/unknown 1]]").is_err());
+            assert_eq!(in_progress.last_report().unwrap().tempids["one"], tempid_offset);
+
+            in_progress.transact("[[:db/add \"two\" :db/ident :a/keyword2]]").expect("transacted successfully");
+            assert_eq!(in_progress.last_report().unwrap().tempids["two"], tempid_offset + 1);
+
+            in_progress.commit().expect("commit succeeded");
+        }
+
+        let after = conn.q_once(&sqlite, "[:find [?x ...] :where [?x :db/ident :a/keyword2]]", None)
+                        .expect("query succeeded");
+        assert_eq!(after.results, QueryResults::Coll(vec![TypedValue::Ref(tempid_offset + 1)]));
+        assert_eq!(get_next_entid(&conn), tempid_offset + 2);
+    }
+
+    #[test]
+    fn test_attribute_for_ident() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let db_ident = edn::NamespacedKeyword::new("db", "ident");
+        let (attribute, entid) = conn.attribute_for_ident(&db_ident).expect("bootstrapped");
+        assert!(attribute.unique.is_some());
+        assert_eq!(conn.ident_for_entid(entid), Some(db_ident.clone()));
+
+        let email = edn::NamespacedKeyword::new("person", "email");
+        assert_eq!(conn.attribute_for_ident(&email), None);
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact("[{:db/ident :person/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                   {:db/ident :person/nickname}]")
+                       .expect("transacted successfully");
+
+            // The attribute is visible within the transaction that installed it.
+            let (attribute, entid) = in_progress.attribute_for_ident(&email).expect("installed");
+            assert_eq!(attribute.value_type, ValueType::String);
+            assert!(attribute.unique.is_some());
+            assert_eq!(in_progress.entid_for_ident(&email), Some(entid));
+            assert_eq!(in_progress.ident_for_entid(entid), Some(email.clone()));
+
+            // An ident that isn't an attribute resolves, but has no attribute.
+            let nickname = edn::NamespacedKeyword::new("person", "nickname");
+            assert!(in_progress.entid_for_ident(&nickname).is_some());
+            assert_eq!(in_progress.attribute_for_ident(&nickname), None);
+
+            in_progress.commit().expect("committed");
+        }
+
+        // And, once committed, from the `Conn`.
+        let (attribute, _) = conn.attribute_for_ident(&email).expect("installed");
+        assert_eq!(attribute.value_type, ValueType::String);
+    }
+
+    #[test]
+    fn test_transact_entities_with_progress() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let t: String = (0..10).map(|i| format!("[:db/add \"e{}\" :db/ident :test/e{}]", i, i)).collect();
+        let entities = parse_transaction(&format!("[{}]", t)).expect("parsed");
+
+        let mut reported = vec![];
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact_entities_with_progress(entities.clone(), 3, |n| reported.push(n))
+                       .expect("transacted successfully");
+            assert_eq!(in_progress.last_report().unwrap().tempids.len(), 10);
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(reported, vec![3, 6, 9, 10]);
+
+        // An exact multiple isn't reported twice, and 0 reports only the total.
+        for &(report_every, ref expected) in &[(5, vec![5, 10]), (0, vec![10])] {
+            let mut reported = vec![];
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact_entities_with_progress(entities.clone(), report_every, |n| reported.push(n))
+                       .expect("transacted successfully");
+            in_progress.rollback().expect("rolled back");
+            assert_eq!(&reported, expected);
+        }
+    }
+
+    #[test]
+    fn test_transact_entities_interruptible() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let t: String = (0..10).map(|i| format!("[:db/add \"e{}\" :db/ident :test/e{}]", i, i)).collect();
+        let entities = parse_transaction(&format!("[{}]", t)).expect("parsed");
+        let tempid_offset = get_next_entid(&conn);
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact("[[:db/add \"one\" :db/ident :a/keyword1]]").expect("transacted successfully");
+
+            // Cancel part-way through reading the entities.
+            let cancel = Arc::new(AtomicBool::new(false));
+            let mut read = 0;
+            let result = in_progress.transact_entities_interruptible(entities.clone().into_iter().inspect(|_| {
+                read += 1;
+                if read == 3 {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }), cancel.clone());
+            match result {
+                Err(Error(ErrorKind::TransactInterrupted(3, 0), _)) => {},
+                x => panic!("expected TransactInterrupted, got {:?}", x),
+            }
+            assert_eq!(read, 3);
+
+            // Nothing from the interrupted transaction is visible, and earlier work is intact.
+            assert_eq!(in_progress.entid_for_ident(&edn::NamespacedKeyword::new("test", "e0")), None);
+            assert_eq!(in_progress.last_report().unwrap().tempids["one"], tempid_offset);
+
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(get_next_entid(&conn), tempid_offset);
+
+        // Cancel once every entity has been read: the transactor notices, and writes nothing.
+        {
+            struct CancelWhenExhausted<I> {
+                entities: I,
+                cancel: Arc<AtomicBool>,
+            }
+
+            impl<I> Iterator for CancelWhenExhausted<I> where I: Iterator {
+                type Item = I::Item;
+
+                fn next(&mut self) -> Option<I::Item> {
+                    let next = self.entities.next();
+                    if next.is_none() {
+                        self.cancel.store(true, Ordering::SeqCst);
+                    }
+                    next
+                }
+            }
+
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            let cancel = Arc::new(AtomicBool::new(false));
+            let result = in_progress.transact_entities_interruptible(CancelWhenExhausted {
+                entities: entities.clone().into_iter(),
+                cancel: cancel.clone(),
+            }, cancel.clone());
+            match result {
+                Err(Error(ErrorKind::TransactInterrupted(10, 0), _)) => {},
+                x => panic!("expected TransactInterrupted, got {:?}", x),
+            }
+            assert_eq!(in_progress.entid_for_ident(&edn::NamespacedKeyword::new("test", "e0")), None);
+            assert!(in_progress.last_report().is_none());
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(get_next_entid(&conn), tempid_offset);
+
+        // Without cancellation, everything is transacted.
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+        in_progress.transact_entities_interruptible(entities, Arc::new(AtomicBool::new(false)))
+                   .expect("transacted successfully");
+        assert_eq!(in_progress.last_report().unwrap().tempids.len(), 10);
+        in_progress.commit().expect("committed");
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let (first, third) = {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact("[[:db/add \"one\" :db/ident :a/keyword1]]")
+                       .expect("transacted successfully");
+            let first = in_progress.last_report().cloned().expect("report");
+
+            in_progress.savepoint("batch").expect("savepoint");
+            in_progress.transact("[[:db/add \"two\" :db/ident :a/keyword2]]")
+                       .expect("transacted successfully");
+            let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword2]]", None)
+                                    .expect("query succeeded");
+            assert!(during.results != QueryResults::Scalar(None));
+
+            in_progress.rollback_to_savepoint("batch").expect("rolled back to savepoint");
+            assert_eq!(in_progress.last_report(), Some(&first));
+
+            match in_progress.rollback_to_savepoint("missing") {
+                Err(Error(ErrorKind::UnknownSavepoint(ref name), _)) => assert_eq!(name, "missing"),
+                x => panic!("expected an unknown savepoint error, got {:?}", x),
+            }
+
+            in_progress.transact("[[:db/add \"three\" :db/ident :a/keyword3]]")
+                       .expect("transacted successfully");
+            let third = in_progress.last_report().cloned().expect("report");
+
+            // Neither entids nor transaction IDs skip over the rolled back batch.
+            assert_eq!(third.tempids["three"], first.tempids["one"] + 1);
+            assert_eq!(third.tx_id, first.tx_id + 1);
+
+            in_progress.release_savepoint("batch").expect("released savepoint");
+            assert!(in_progress.release_savepoint("batch").is_err());
+
+            let report = in_progress.commit().expect("committed").expect("report");
+            assert_eq!(report, third);
+            (first, third)
+        };
+
+        let lookup = |conn: &Conn, sqlite: &rusqlite::Connection, name: &str| {
+            let query = format!("[:find ?x . :where [?x :db/ident :a/{}]]", name);
+            conn.q_once(sqlite, query.as_str(), None).expect("query succeeded").results
+        };
+        assert_eq!(lookup(&conn, &sqlite, "keyword1"), QueryResults::Scalar(Some(TypedValue::Ref(first.tempids["one"]))));
+        assert_eq!(lookup(&conn, &sqlite, "keyword2"), QueryResults::Scalar(None));
+        assert_eq!(lookup(&conn, &sqlite, "keyword3"), QueryResults::Scalar(Some(TypedValue::Ref(third.tempids["three"]))));
+
+        // The committed partition map continues right after the last allocated entid.
+        assert_eq!(get_next_entid(&conn), third.tempids["three"] + 1);
+    }
+
+    #[test]
+    fn test_open() {
+        let path = TempStorePath::new("open");
+
+        let one = {
+            let (mut conn, mut sqlite) = Conn::open(&path).expect("opened");
+            let foreign_keys: i64 = sqlite.query_row("PRAGMA foreign_keys", &[], |row| row.get(0)).unwrap();
+            assert_eq!(foreign_keys, 1);
+            let journal_mode: String = sqlite.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).unwrap();
+            assert_eq!(journal_mode, "wal");
+
+            let report = conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]")
+                             .expect("transact succeeded");
+            report.tempids.get("one").expect("found one").clone()
+        };
+
+        // Reopening sees what was transacted.
+        {
+            let (conn, sqlite) = Conn::open(&path).expect("reopened");
+            let found = conn.q_once(&sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                            .expect("query succeeded")
+                            .into_scalar()
+                            .expect("scalar");
+            assert_eq!(found, Some(TypedValue::Ref(one)));
+        }
+    }
+
+    #[test]
+    fn test_q_once_with_timeout() {
+        let (conn, sqlite) = Conn::in_memory().unwrap();
+
+        // Millions of candidate rows, but only a handful of results.  This is never run to
+        // completion: each run is cut short by its timeout.
+        let slow = r#"[:find ?a
+                       :where [?a _ _] [?b :db/ident _] [?c :db/ident _] [?d :db/ident _]]"#;
+        let fast = "[:find ?x . :where [?x :db/ident :db/ident]]";
+
+        let assert_timed_out = |timeout: Duration| {
+            match conn.q_once_with_timeout(&sqlite, slow, None, timeout) {
+                Err(::errors::Error(::errors::ErrorKind::QueryTimedOut(elapsed), _)) => {
+                    assert!(elapsed >= timeout);
+                    assert!(elapsed < timeout + Duration::from_secs(5), "interrupted promptly: {:?}", elapsed);
+                },
+                x => panic!("expected QueryTimedOut, got {:?}", x.map(|_| ())),
+            }
+        };
+        assert_timed_out(Duration::from_millis(50));
+
+        // A query that finishes in time cancels its timer.  If that timer fired during the next
+        // query, it would fail early with a raw SQLite interruption rather than time out itself.
+        let output = conn.q_once_with_timeout(&sqlite, fast, None, Duration::from_millis(50)).expect("query");
+        assert_eq!(output.len(), 1);
+        assert_timed_out(Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_busy_timeout() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let path = TempStorePath::new("busy");
+
+        let (mut conn, mut sqlite) = Conn::open(&path).expect("opened");
+
+        // Another connection takes the write lock and holds it briefly.
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let holder_path = path.to_path_buf();
+        let holder = thread::spawn(move || {
+            let other = db::new_connection(&holder_path).expect("opened");
+            other.execute_batch("BEGIN IMMEDIATE").expect("locked");
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+            thread::sleep(Duration::from_millis(200));
+            other.execute_batch("COMMIT").expect("unlocked");
+        });
+        locked_rx.recv().unwrap();
+
+        // Without waiting, we fail immediately.
+        conn.set_busy_timeout(Duration::from_millis(0));
+        match conn.begin_transaction(&mut sqlite) {
+            Err(Error(ErrorKind::DatabaseBusy, _)) => {},
+            Err(e) => panic!("expected a busy error, got {:?}", e),
+            Ok(_) => panic!("expected a busy error"),
+        }
+
+        // With a generous timeout, we wait for the other connection to finish.
+        release_tx.send(()).unwrap();
+        conn.set_busy_timeout(Duration::from_secs(10));
+        conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]")
+            .expect("transact succeeded after waiting");
+
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = TempStorePath::new("snapshot");
+
+        let (mut conn, mut sqlite) = Conn::open(&path).expect("opened");
+        conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]").expect("transact succeeded");
+
+        // Writes go through another connection to the same store while the snapshot is open.
+        let (mut writer, mut writer_sqlite) = Conn::open(&path).expect("opened");
+
+        let query = "[:find [?i ...] :where [_ :db/ident ?i]]";
+        let keyword2 = TypedValue::Keyword(edn::NamespacedKeyword::new("a", "keyword2").into());
+        let idents = |output: QueryOutput| output.into_coll().expect("coll");
+
+        {
+            let snapshot = conn.snapshot(&mut sqlite).expect("snapshot");
+            assert!(snapshot.schema().get_entid(&edn::NamespacedKeyword::new("a", "keyword1")).is_some());
+
+            writer.transact(&mut writer_sqlite, "[[:db/add \"two\" :db/ident :a/keyword2]]").expect("transact succeeded");
+
+            // The writer sees the change; the snapshot does not.
+            let written = idents(writer.q_once(&writer_sqlite, query, None).expect("query succeeded"));
+            assert!(written.contains(&keyword2));
+            let seen = idents(snapshot.q_once(query, None).expect("query succeeded"));
+            assert!(!seen.contains(&keyword2));
+            assert_eq!(seen.len() + 1, written.len());
+
+            snapshot.close().expect("closed");
+        }
+
+        // A fresh snapshot sees everything.
+        {
+            let snapshot = conn.snapshot(&mut sqlite).expect("snapshot");
+            assert!(idents(snapshot.q_once(query, None).expect("query succeeded")).contains(&keyword2));
+            snapshot.close().expect("closed");
+        }
+    }
+
+    #[test]
+    fn test_begin_read() {
+        let path = TempStorePath::new("begin-read");
+
+        let (mut conn, mut sqlite) = Conn::open(&path).expect("opened");
+        let one = conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]")
+                      .expect("transact succeeded")
+                      .tempids["one"];
+
+        let (mut writer, mut writer_sqlite) = Conn::open(&path).expect("opened");
+        writer.set_busy_timeout(Duration::from_millis(0));
+
+        // A reader can start while another connection holds the write lock...
+        let other = db::new_connection(&path).expect("opened");
+        other.execute_batch("BEGIN IMMEDIATE").expect("locked");
+
+        {
+            let in_progress = conn.begin_read(&mut sqlite).expect("began reading");
+            let kw = in_progress.lookup_value_for_attribute(one, &edn::NamespacedKeyword::new("db", "ident"))
+                                .expect("lookup succeeded");
+            assert_eq!(kw, Some(TypedValue::Keyword(edn::NamespacedKeyword::new("a", "keyword1").into())));
+
+            // ... and doesn't stop a writer from proceeding, without waiting, once the lock is free.
+            other.execute_batch("COMMIT").expect("unlocked");
+            writer.transact(&mut writer_sqlite, "[[:db/add \"two\" :db/ident :a/keyword2]]")
+                  .expect("transact succeeded while reading");
+
+            in_progress.close().expect("closed");
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = TempStorePath::new("read-only");
+
+        // An empty file isn't initialized, and we won't do that for it.
+        rusqlite::Connection::open(&path).expect("created");
+        match Conn::open_read_only(&path) {
+            Err(Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::CannotMigrateReadOnlyStore(0, _)), _)) => {},
+            Err(e) => panic!("expected a migration error, got {:?}", e),
+            Ok(_) => panic!("expected a migration error"),
+        }
+        path.remove();
+
+        let one = {
+            let (mut conn, mut sqlite) = Conn::open(&path).expect("opened");
+            conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]")
+                .expect("transact succeeded")
+                .tempids["one"]
+        };
+
+        let (mut conn, mut sqlite) = Conn::open_read_only(&path).expect("opened read-only");
+        assert!(conn.is_read_only());
+
+        let found = conn.q_once(&sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                        .expect("query succeeded")
+                        .into_scalar()
+                        .expect("scalar");
+        assert_eq!(found, Some(TypedValue::Ref(one)));
+
+        match conn.transact(&mut sqlite, "[[:db/add \"two\" :db/ident :a/keyword2]]") {
+            Err(Error(ErrorKind::ReadOnlyConnection, _)) => {},
+            x => panic!("expected a read-only error, got {:?}", x),
+        }
+        match conn.begin_transaction(&mut sqlite) {
+            Err(Error(ErrorKind::ReadOnlyConnection, _)) => {},
+            Err(e) => panic!("expected a read-only error, got {:?}", e),
+            Ok(_) => panic!("expected a read-only error"),
+        }
+
+        // Reads in a transaction are still fine.
+        let in_progress = conn.begin_read(&mut sqlite).expect("began reading");
+        in_progress.close().expect("closed");
+    }
+
+    #[test]
+    fn test_rename_attribute() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        conn.transact(&mut sqlite, r#"[{:db/ident :old/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                       {:db/ident :other/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#)
+            .expect("transact succeeded");
+        let e = conn.transact(&mut sqlite, r#"[[:db/add "e" :old/name "Alice"]]"#)
+                    .expect("transact succeeded")
+                    .tempids["e"];
+
+        let old_name = edn::NamespacedKeyword::new("old", "name");
+        let new_name = edn::NamespacedKeyword::new("new", "name");
+        let attribute = conn.entid_for_ident(&old_name).expect("attribute");
+
+        let before = conn.q_once(&sqlite, r#"[:find ?e . :where [?e :old/name "Alice"]]"#, None)
+                         .expect("query succeeded");
+        assert_eq!(before.results, QueryResults::Scalar(Some(TypedValue::Ref(e))));
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+
+            // Names must be unique, and only attributes can be renamed.
+            match in_progress.rename_attribute(&old_name, &edn::NamespacedKeyword::new("other", "name")) {
+                Err(Error(ErrorKind::IdentAlreadyInUse(ref kw), _)) => assert_eq!(kw.name, "name"),
+                x => panic!("expected an ident in use error, got {:?}", x),
+            }
+            match in_progress.rename_attribute(&edn::NamespacedKeyword::new("no", "such"), &new_name) {
+                Err(Error(ErrorKind::UnknownAttribute(_), _)) => {},
+                x => panic!("expected an unknown attribute error, got {:?}", x),
+            }
+
+            in_progress.rename_attribute(&old_name, &new_name).expect("renamed");
+            let during = in_progress.q_once(r#"[:find ?e . :where [?e :new/name "Alice"]]"#, None)
+                                    .expect("query succeeded");
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(e))));
+
+            in_progress.commit().expect("committed");
+        }
+
+        // Same entid, new name, same data.
+        assert_eq!(conn.entid_for_ident(&new_name), Some(attribute));
+        assert_eq!(conn.entid_for_ident(&old_name), None);
+        assert_eq!(conn.ident_for_entid(attribute), Some(new_name.clone()));
+
+        let after = conn.q_once(&sqlite, r#"[:find ?e . :where [?e :new/name "Alice"]]"#, None)
+                        .expect("query succeeded");
+        assert_eq!(after.results, QueryResults::Scalar(Some(TypedValue::Ref(e))));
+    }
+
+    #[test]
+    fn test_verify() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}]"#)
+            .expect("transact succeeded");
+        conn.transact(&mut sqlite, r#"[[:db/add "a" :test/name "Alice"]]"#).expect("transact succeeded");
+        conn.transact(&mut sqlite, r#"[[:db/add "a" :test/name "Alicia"]]"#).expect("transact succeeded");
+
+        // Fulltext values referenced only by history aren't orphans.
+        assert!(conn.verify(&sqlite).expect("verified").is_ok());
+
+        sqlite.execute("INSERT INTO fulltext_values (text) VALUES ('orphan')", &[]).expect("inserted");
+        sqlite.execute("UPDATE parts SET idx = start WHERE part = ':db.part/user'", &[]).expect("updated");
+
+        // Every problem is reported, not just the first.
+        let report = conn.verify(&sqlite).expect("verified");
+        assert_eq!(report.problems.len(), 2);
+    }
+
+    #[test]
+    fn test_check_integrity() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                       {:db/ident :test/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#)
+            .expect("transact succeeded");
+        let report = conn.transact(&mut sqlite, r#"[[:db/add "a" :test/name "Alice"]
+                                                    [:db/add "b" :test/name "Bob"]
+                                                    [:db/add "a" :test/age 30]]"#)
+                         .expect("transact succeeded");
+        let (a, b) = (report.tempids["a"], report.tempids["b"]);
+        let schema = conn.current_schema();
+        let name = schema.get_entid(&edn::NamespacedKeyword::new("test", "name")).expect("name");
+        let age = schema.get_entid(&edn::NamespacedKeyword::new("test", "age")).expect("age");
+
+        assert!(conn.check_integrity(&sqlite).expect("checked").is_empty());
+
+        // Break several invariants at once, behind the transactor's back.
+        sqlite.execute("INSERT INTO datoms (e, a, v, tx, value_type_tag) VALUES (?, ?, 31, ?, 5)",
+                       &[&a, &age, &report.tx_id]).expect("inserted");
+        sqlite.execute("UPDATE datoms SET unique_value = 0, v = 'Alice' WHERE e = ? AND a = ?",
+                       &[&b, &name]).expect("updated");
+        sqlite.execute("UPDATE datoms SET value_type_tag = 10 WHERE e = ? AND a = ? AND v = 30",
+                       &[&a, &age]).expect("updated");
+
+        let mut entities = vec![a, b];
+        entities.sort();
+
+        let issues = conn.check_integrity(&sqlite).expect("checked");
+        assert_eq!(issues, vec![
+            IntegrityIssue::ValueTypeMismatch { e: a, a: age, expected: ValueType::Long, value_type_tag: 10 },
+            IntegrityIssue::CardinalityOneViolation { e: a, a: age, count: 2 },
+            IntegrityIssue::UniqueViolation { a: name, entities: entities },
+        ]);
+    }
+
+    #[test]
+    fn test_compact() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        conn.transact(&mut sqlite, r#"[{:db/ident :test/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#)
+            .expect("transact succeeded");
+
+        // Each new value retracts the last, leaving plenty of history behind.
+        let first = conn.transact(&mut sqlite, format!(r#"[[:db/add "e" :test/text "{}"]]"#, "0".repeat(1000)).as_str())
+                        .expect("transact succeeded");
+        let e = first.tempids["e"];
+        let mut last = first.tx_id;
+        for i in 1..50 {
+            last = conn.transact(&mut sqlite, format!(r#"[[:db/add {} :test/text "{}"]]"#, e, i.to_string().repeat(1000 / i.to_string().len())).as_str())
+                       .expect("transact succeeded")
+                       .tx_id;
+        }
+
+        let query = r#"[:find ?v . :where [_ :test/text ?v]]"#;
+        let before = conn.q_once(&sqlite, query, None).expect("query succeeded").results;
+        let hash_before = conn.state_hash(&sqlite).expect("hash");
+
+        // Nothing was retracted before the first value was asserted.
+        let report = conn.compact(&mut sqlite, first.tx_id).expect("compacted");
+        assert_eq!(report.datoms_removed, 0);
+
+        // 49 replaced values, each retracted once.
+        let report = conn.compact(&mut sqlite, last + 1).expect("compacted");
+        assert_eq!(report.datoms_removed, 49 * 2);
+        assert!(report.bytes_reclaimed > 0);
+
+        // The current state is unchanged.
+        assert_eq!(conn.q_once(&sqlite, query, None).expect("query succeeded").results, before);
+        assert_eq!(conn.state_hash(&sqlite).expect("hash"), hash_before);
+
+        // Besides each transaction's `:db/txInstant`, only the assertion of the current value
+        // remains in the log.
+        let transactions = conn.transactions_since(&sqlite, first.tx_id - 1).expect("transactions");
+        assert_eq!(transactions.len(), 50);
+        let total: usize = transactions.iter().map(|datoms| datoms.len()).sum();
+        assert_eq!(total, 50 + 1);
+
+        // Compacting again finds nothing more to do.
+        assert_eq!(conn.compact(&mut sqlite, last + 1).expect("compacted").datoms_removed, 0);
+
+        // And the store can still be transacted against.
+        conn.transact(&mut sqlite, format!(r#"[[:db/add {} :test/text "again"]]"#, e).as_str())
+            .expect("transact succeeded");
+    }
+
+    #[test]
+    fn test_gc() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        conn.transact(&mut sqlite, r#"[{:db/ident :test/text :db/valueType :db.type/string :db/cardinality :db.cardinality/many :db/fulltext true :db/index true}]"#)
+            .expect("transact succeeded");
+        let report = conn.transact(&mut sqlite, r#"[[:db/add "e" :test/text "kept"]
+                                                    [:db/add "e" :test/text "dropped"]]"#)
+                         .expect("transact succeeded");
+        let e = report.tempids["e"];
+        let last = conn.transact(&mut sqlite, format!(r#"[[:db/retract {} :test/text "dropped"]]"#, e).as_str())
+                       .expect("transact succeeded")
+                       .tx_id;
+
+        let texts = |sqlite: &rusqlite::Connection| -> Vec<String> {
+            let mut texts: Vec<String> = dumps::fulltext_values(sqlite).expect("fulltext_values").0.into_iter().map(|(_, text)| text).collect();
+            texts.sort();
+            texts
+        };
+        assert_eq!(texts(&sqlite), vec!["dropped".to_string(), "kept".to_string()]);
+
+        // The retracted string is still part of the store's history.
+        let report = conn.gc(&mut sqlite, VacuumMode::None).expect("collected");
+        assert_eq!(report.fulltext_values_removed, 0);
+        assert_eq!(texts(&sqlite), vec!["dropped".to_string(), "kept".to_string()]);
+
+        // Once the history is forgotten, the string is garbage.
+        conn.compact(&mut sqlite, last + 1).expect("compacted");
+        let report = conn.gc(&mut sqlite, VacuumMode::Full).expect("collected");
+        assert_eq!(report.fulltext_values_removed, 1);
+        assert_eq!(texts(&sqlite), vec!["kept".to_string()]);
+        assert!(conn.verify(&sqlite).expect("verified").is_ok());
+
+        // Strings that are still asserted remain searchable.
+        let results = conn.q_once(&sqlite, r#"[:find ?e . :where [(fulltext $ :test/text "kept") [[?e]]]]"#, None)
+                          .expect("query succeeded");
+        assert_eq!(results.results, QueryResults::Scalar(Some(TypedValue::Ref(e))));
+    }
+
+    #[test]
+    fn test_connection_options() {
+        use mentat_db::db::{
+            ConnectionOptions,
+            JournalMode,
+            Synchronous,
+        };
+
+        let path = TempStorePath::new("options");
+
+        let options = ConnectionOptions::default()
+            .journal_mode(JournalMode::Wal)
+            .synchronous(Synchronous::Normal)
+            .cache_size(-4000);
+
+        {
+            let (mut sqlite, applied) = db::new_connection_with_options(&path, &options).expect("opened");
+            assert!(applied.took_effect());
+            assert_eq!(applied.journal_mode, Some(JournalMode::Wal));
+
+            let journal_mode: String = sqlite.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).unwrap();
+            assert_eq!(journal_mode, "wal");
+            let synchronous: i64 = sqlite.query_row("PRAGMA synchronous", &[], |row| row.get(0)).unwrap();
+            assert_eq!(synchronous, 1);
+
+            let mut conn = Conn::connect(&mut sqlite).expect("connected");
+            let one = conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]")
+                          .expect("transact succeeded")
+                          .tempids["one"];
+            let found = conn.q_once(&sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                            .expect("query succeeded");
+            assert_eq!(found.results, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+        }
+
+        // A different journal mode can be chosen.
+        {
+            let options = ConnectionOptions::default().journal_mode(JournalMode::Truncate);
+            let (_, applied) = db::new_connection_with_options(&path, &options).expect("opened");
+            assert_eq!(applied.journal_mode, Some(JournalMode::Truncate));
+            assert!(applied.took_effect());
+        }
+
+        // In-memory stores can't use WAL, and we report that.
+        let (_, applied) = db::new_connection_with_options("", &options).expect("opened");
+        assert_eq!(applied.journal_mode, Some(JournalMode::Memory));
+        assert!(!applied.took_effect());
+    }
+
+    #[test]
+    fn test_stats() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        assert_eq!(conn.stats(), ConnStats::default());
+
+        conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]
+                                     [:db/add \"two\" :db/ident :a/keyword2]]")
+            .expect("transact succeeded");
+
+        conn.q_once(&sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+            .expect("query succeeded");
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact("[[:db/add \"three\" :db/ident :a/keyword3]]")
+                       .expect("transacted successfully");
+            in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword3]]", None)
+                       .expect("query succeeded");
+            in_progress.rollback().expect("rollback succeeded");
+        }
+
+        assert_eq!(conn.stats(), ConnStats {
+            transactions_committed: 1,
+            transactions_rolled_back: 1,
+            queries: 2,
+            datoms_asserted: 2,
+            datoms_retracted: 0,
+            watch_changes_dropped: 0,
+        });
+    }
+
+    #[test]
+    fn test_transact_and_query_parsed() {
+        let kw = |ns: &str, name: &str| edn::Value::NamespacedKeyword(edn::NamespacedKeyword::new(ns, name));
+        let parsed = edn::Value::Vector(vec![
+            edn::Value::Vector(vec![kw("db", "add"), edn::Value::Text("one".to_string()), kw("db", "ident"), kw("a", "keyword1")]),
+            edn::Value::Vector(vec![kw("db", "add"), edn::Value::Text("two".to_string()), kw("db", "ident"), kw("a", "keyword2")]),
+        ]);
+
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        let report = conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]
+                                                 [:db/add \"two\" :db/ident :a/keyword2]]")
+                         .expect("transact succeeded");
+
+        let (mut parsed_conn, mut parsed_sqlite) = Conn::in_memory().unwrap();
+        let parsed_report = parsed_conn.transact_parsed(&mut parsed_sqlite, &parsed)
+                                       .expect("transact_parsed succeeded");
+
+        assert_eq!(report.tx_id, parsed_report.tx_id);
+        assert_eq!(report.tempids, parsed_report.tempids);
+
+        let query = "[:find ?x . :where [?x :db/ident :a/keyword2]]";
+        let parsed_query = edn::parse::value(query).expect("parsed").without_spans();
+        let expected = conn.q_once(&sqlite, query, None).expect("query succeeded");
+        let results = parsed_conn.q_once_parsed(&parsed_sqlite, &parsed_query, None).expect("query succeeded");
+        assert_eq!(results, expected);
+        assert_eq!(results.into_scalar().expect("scalar"), Some(TypedValue::Ref(report.tempids["two"])));
+
+        // The same checks apply to pre-parsed transactions as to strings.
+        match parsed_conn.transact_parsed(&mut parsed_sqlite, &kw("db", "add")).unwrap_err() {
+            Error(ErrorKind::TransactionNotAVector(found), _) => assert_eq!(found, "namespaced keyword"),
+            x => panic!("expected TransactionNotAVector, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_transactions_since() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let first = conn.transact(&mut sqlite, "[[:db/add \"one\" :db/ident :a/keyword1]]")
+                        .expect("transact succeeded");
+        let second = conn.transact(&mut sqlite, "[[:db/add \"two\" :db/ident :a/keyword2]]")
+                         .expect("transact succeeded");
+
+        let transactions = conn.transactions_since(&sqlite, first.tx_id).expect("transactions");
+        assert_eq!(transactions.len(), 1);
+
+        // The transaction includes its :db/txInstant and the added flag of each datom.
+        let datoms = transactions.iter().next().expect("a transaction");
+        assert_eq!(datoms.len(), 2);
+        assert!(datoms.iter().all(|datom| datom.tx() == second.tx_id && datom.added() == Some(true)));
+
+        let ident = |name: &str| mentat_tx::entities::Entid::Ident(edn::NamespacedKeyword::new("db", name));
+        assert!(datoms.iter().any(|datom| {
+            datom.e() == &mentat_tx::entities::Entid::Entid(second.tx_id) &&
+            datom.a() == &ident("txInstant") &&
+            datom.value().is_instant()
+        }));
+        assert!(datoms.iter().any(|datom| {
+            datom.e() == &mentat_tx::entities::Entid::Entid(second.tempids["two"]) &&
+            datom.a() == &ident("ident") &&
+            datom.value() == &edn::Value::NamespacedKeyword(edn::NamespacedKeyword::new("a", "keyword2"))
+        }));
+
+        assert!(conn.transactions_since(&sqlite, second.tx_id).expect("transactions").is_empty());
+
+        let kw = |name: &str| edn::Value::Keyword(edn::Keyword::new(name));
+        let report = second.to_edn();
+        let report = report.as_map().expect("report is a map");
+        assert_eq!(report.len(), 3);
+        assert_eq!(report.get(&kw("tx-id")), Some(&edn::Value::Integer(second.tx_id)));
+        assert_eq!(report.get(&kw("tx-instant")), Some(&edn::Value::Instant(second.tx_instant)));
+        let tempids = report.get(&kw("tempids")).and_then(|t| t.as_map()).expect("tempids is a map");
+        assert_eq!(tempids.get(&edn::Value::Text("two".to_string())), Some(&edn::Value::Integer(second.tempids["two"])));
+
+        // The rendered report parses back to the same EDN, instant and all.
+        let rendered = second.to_edn().to_pretty_string();
+        assert_eq!(edn::parse::value(&rendered).expect("to parse EDN").without_spans(), second.to_edn());
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let (mut conn1, mut sqlite1) = Conn::in_memory().unwrap();
+        let (mut conn2, mut sqlite2) = Conn::in_memory().unwrap();
+
+        // Fresh stores agree.
+        assert_eq!(conn1.state_hash(&sqlite1).expect("hash"), conn2.state_hash(&sqlite2).expect("hash"));
+
+        let schema = r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}
+                         {:db/ident :test/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#;
+        conn1.transact(&mut sqlite1, schema).expect("transact succeeded");
+        conn2.transact(&mut sqlite2, schema).expect("transact succeeded");
+
+        // The same datoms, asserted in a different order.  Fulltext values land in different
+        // `fulltext_values` rows.
+        conn1.transact(&mut sqlite1, r#"[[:db/add "a" :test/name "Alice"]
+                                         [:db/add "a" :test/age 30]
+                                         [:db/add "b" :test/name "Bob"]]"#).expect("transact succeeded");
+        conn2.transact(&mut sqlite2, r#"[[:db/add "b" :test/name "Bob"]
+                                         [:db/add "a" :test/age 30]
+                                         [:db/add "a" :test/name "Alice"]]"#).expect("transact succeeded");
+
+        let hash1 = conn1.state_hash(&sqlite1).expect("hash");
+        assert_eq!(hash1, conn2.state_hash(&sqlite2).expect("hash"));
+
+        // Any further change is reflected in the hash.
+        conn2.transact(&mut sqlite2, r#"[[:db/add "c" :test/age 31]]"#).expect("transact succeeded");
+        assert_ne!(hash1, conn2.state_hash(&sqlite2).expect("hash"));
+    }
+
+    #[test]
+    fn test_transact_errors() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        // Good: empty transaction.
+        let report = conn.transact(&mut sqlite, "[]").unwrap();
+        assert_eq!(report.tx_id, 0x10000000 + 1);
+
+        // Bad EDN: missing closing ']'.
+        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :a/keyword]");
+        match report.unwrap_err() {
+            Error(ErrorKind::EdnParseError(e), _) => {
+                assert_eq!(e.line, 1);
+            },
+            x => panic!("expected EDN parse error, got {:?}", x),
+        }
+
+        // Bad EDN on the third line: the error locates it.
+        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :a/keyword]\n [:db/add \"u\" :db/ident :b/keyword]\n [:db/add \"v\" :db/ident #]]");
+        match report.unwrap_err() {
+            Error(ErrorKind::EdnParseError(e), _) => {
+                assert_eq!(e.line, 3);
+            },
+            x => panic!("expected EDN parse error, got {:?}", x),
+        }
+
+        // Good EDN, but not a transaction vector: forgot the outer brackets.
+        let report = conn.transact(&mut sqlite, "{:db/id \"t\" :db/ident :a/keyword}");
+        match report.unwrap_err() {
+            Error(ErrorKind::TransactionNotAVector("map"), _) => { },
+            x => panic!("expected transaction not a vector error, got {:?}", x),
+        }
+
+        // Good EDN.
+        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :a/keyword]]").unwrap();
+        assert_eq!(report.tx_id, 0x10000000 + 2);
+
+        // Bad transaction data: missing leading :db/add.
+        let report = conn.transact(&mut sqlite, "[[\"t\" :db/ident :b/keyword]]");
+        match report.unwrap_err() {
+            Error(ErrorKind::TxParseError(::mentat_tx_parser::errors::ErrorKind::EntityParseError(0, Some((1, 2)), message)), _) => {
+                assert_eq!(message, "expected :db/add or :db/retract, found text");
+            },
+            x => panic!("expected entity parse error, got {:?}", x),
+        }
+
+        // Bad transaction data on the second line: the error locates it, and says which entity
+        // failed and why.
+        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :b/keyword]\n \"t\"\n {:db/ident :c/keyword}]");
+        let err = report.unwrap_err();
+        assert_eq!(err.to_string(), "entity 1 (line 2, column 2): expected :db/add, :db/retract, or a map, found text");
+        match err {
+            Error(ErrorKind::TxParseError(::mentat_tx_parser::errors::ErrorKind::EntityParseError(1, Some((2, 2)), _)), _) => { },
+            x => panic!("expected entity parse error, got {:?}", x),
+        }
+
+        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :b/keyword]\n\n   [:db/add \"t\" :db/ident]]");
+        match report.unwrap_err() {
+            Error(ErrorKind::TxParseError(::mentat_tx_parser::errors::ErrorKind::EntityParseError(1, Some((3, 4)), message)), _) => {
+                assert_eq!(message, "expected [:db/add e a v], found 3 values");
+            },
+            x => panic!("expected entity parse error, got {:?}", x),
+        }
+
+        // Good transaction data.
+        let report = conn.transact(&mut sqlite, "[[:db/add \"u\" :db/ident :b/keyword]]").unwrap();
+        assert_eq!(report.tx_id, 0x10000000 + 3);
+
+        // Bad transaction based on state of store: conflicting upsert.
+        let report = conn.transact(&mut sqlite, "[[:db/add \"u\" :db/ident :a/keyword]
+                                                  [:db/add \"u\" :db/ident :b/keyword]]");
+        match report.unwrap_err() {
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::NotYetImplemented(_)), _) => { },
+            x => panic!("expected EDN parse error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_transact_entities_bulk() {
+        let schema = "[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                       {:db/ident :test/code :db/valueType :db.type/long :db/cardinality :db.cardinality/one :db/unique :db.unique/value}
+                       {:db/ident :test/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+                       {:db/ident :test/bio :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}]";
+        let existing = r#"[{:db/id "a" :test/name "existing" :test/code 0}]"#;
+
+        // New entities, with refs between them and to an existing entity; an upsert and a
+        // retraction touching the existing entity; and a retraction about a new entity.
+        let mut batch = String::from("[");
+        for i in 1..1000 {
+            batch.push_str(&format!(r#"{{:db/id "p{}" :test/name "person {}" :test/code {} :test/friend "p{}" :test/bio "bio {}"}}"#, i, i, i, i - 1, i));
+        }
+        batch.push_str(r#"{:db/id "p0" :test/name "existing" :test/friend "p1"}
+                          [:db/retract "p0" :test/code 0]
+                          [:db/retract "p999" :test/name "nobody"]]"#);
+
+        let load = |bulk: bool| -> (edn::Value, i64) {
+            let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+            conn.transact(&mut sqlite, schema).expect("transacted schema");
+            conn.transact(&mut sqlite, existing).expect("transacted existing");
+
+            {
+                let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+                let entities = parse_transaction(&batch).expect("parsed");
+                if bulk {
+                    in_progress.transact_entities_bulk(entities).expect("transacted");
+                } else {
+                    in_progress.transact_entities(entities).expect("transacted");
+                }
+                in_progress.commit().expect("committed");
+            }
+
+            let datoms = dumps::datoms(&sqlite, &*conn.current_schema()).expect("datoms").into_edn();
+            let log: i64 = sqlite.query_row("SELECT count(*) FROM transactions", &[], |row| row.get(0)).expect("counted");
+            (datoms, log)
+        };
+
+        // The bulk path produces exactly the datoms, and log entries, of the usual path.
+        let (datoms, log) = load(false);
+        let (bulk_datoms, bulk_log) = load(true);
+        assert_eq!(datoms, bulk_datoms);
+        assert_eq!(log, bulk_log);
+
+        // Uniqueness is still enforced, both against existing datoms and within the batch.
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, schema).expect("transacted schema");
+        conn.transact(&mut sqlite, existing).expect("transacted existing");
+        let before = count_user_datoms(&sqlite);
+        for batch in &[r#"[{:test/name "new" :test/code 0}]"#,
+                       r#"[{:test/name "new 1" :test/code 1} {:test/name "new 2" :test/code 1}]"#] {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            assert!(in_progress.transact_entities_bulk(parse_transaction(batch).expect("parsed")).is_err());
+        }
+        assert_eq!(count_user_datoms(&sqlite), before);
+    }
+
+    #[test]
+    fn test_unique_attributes() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        // Bootstrapped attributes are included.
+        let db_ident = edn::NamespacedKeyword::new("db", "ident");
+        assert!(conn.unique_attributes().contains(&(db_ident, UniqueKind::Identity)));
+
+        conn.transact(&mut sqlite, "[{:db/ident :test/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                     {:db/ident :test/code :db/valueType :db.type/long :db/cardinality :db.cardinality/one :db/unique :db.unique/value}
+                                     {:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transacted schema");
+
+        let unique: Vec<_> = conn.unique_attributes().into_iter().filter(|&(ref ident, _)| ident.namespace == "test").collect();
+        assert_eq!(unique, vec![(edn::NamespacedKeyword::new("test", "code"), UniqueKind::Value),
+                                (edn::NamespacedKeyword::new("test", "email"), UniqueKind::Identity)]);
+    }
+
+    #[test]
+    fn test_transact_entities_with_options() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let t: String = (0..10).map(|i| format!("[:db/add \"e{}\" :db/ident :test/e{}]", i, i)).collect();
+        let entities = parse_transaction(&format!("[{}]", t)).expect("parsed");
         let tempid_offset = get_next_entid(&conn);
 
-        let t = "[[:db/add \"one\" :db/ident :a/keyword1] \
-                  [:db/add \"two\" :db/ident :a/keyword2]]";
+        // Progress is reported every `report_every` entities, and once more at the end.
+        let reported = Arc::new(Mutex::new(vec![]));
+        let mut options = TransactOptions::default();
+        options.report_every = 3;
+        options.progress = {
+            let reported = reported.clone();
+            Some(Box::new(move |progress: TransactProgress| reported.lock().unwrap().push((progress.entities, progress.datoms))))
+        };
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact_entities_with_options(entities.clone(), &mut options)
+                       .expect("transacted successfully");
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(*reported.lock().unwrap(), vec![(3, 0), (6, 0), (9, 0), (10, 10)]);
 
-        // This can refer to `t`, 'cos they occur in separate txes.
-        let t2 = "[{:db.schema/attribute \"three\", :db/ident :a/keyword1}]";
+        // Cancelling part-way writes nothing.
+        let cancel = Arc::new(AtomicBool::new(false));
+        options.cancel = Some(cancel.clone());
+        options.progress = Some(Box::new(move |progress: TransactProgress| {
+            if progress.entities == 6 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        }));
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            match in_progress.transact_entities_with_options(entities, &mut options) {
+                Err(Error(ErrorKind::TransactInterrupted(6, 0), _)) => {},
+                x => panic!("expected TransactInterrupted, got {:?}", x),
+            }
+            assert_eq!(in_progress.entid_for_ident(&edn::NamespacedKeyword::new("test", "e0")), None);
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(get_next_entid(&conn), tempid_offset);
+    }
 
-        // Scoped borrow of `conn`.
+    #[test]
+    fn test_transact_from_read_cancelled() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+        let generation = conn.generation();
+        let datoms = count_user_datoms(&sqlite);
+
+        // Cancel after reading half the entities, by which time four chunks have been written.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut options = TransactOptions::default();
+        options.report_every = 25;
+        options.cancel = Some(cancel.clone());
+        options.progress = Some(Box::new(move |progress: TransactProgress| {
+            if progress.entities == 50 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        }));
+
+        let input = people(100);
+        match conn.transact_from_read(&mut sqlite, &mut input.as_bytes(), 10, ChunkCommit::Once, &mut options) {
+            Err(Error(ErrorKind::TransactInterrupted(50, 120), _)) => {},
+            x => panic!("expected TransactInterrupted, got {:?}", x),
+        }
+
+        // Everything is rolled back.
+        assert_eq!(conn.generation(), generation);
+        assert_eq!(count_user_datoms(&sqlite), datoms);
+    }
+
+    #[test]
+    fn test_exists_unique() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :test/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/value}
+                                     {:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transacted schema");
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :test/email "a@example.com" :test/name "Alice"}]"#)
+                         .expect("transacted data");
+        let alice = KnownEntid(report.tempids["a"]);
+
+        let email = edn::NamespacedKeyword::new("test", "email");
+        assert_eq!(conn.exists_unique(&sqlite, &email, &TypedValue::typed_string("a@example.com")).expect("looked up"),
+                   Some(alice));
+        assert_eq!(conn.exists_unique(&sqlite, &email, &TypedValue::typed_string("b@example.com")).expect("looked up"),
+                   None);
+
+        // The attribute must be unique.
+        let name = edn::NamespacedKeyword::new("test", "name");
+        match conn.exists_unique(&sqlite, &name, &TypedValue::typed_string("Alice")) {
+            Err(Error(ErrorKind::NotUniqueAttribute(kw), _)) => assert_eq!(kw, name),
+            x => panic!("expected NotUniqueAttribute, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_transact_error_identifies_entity() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                     {:db/ident :test/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]")
+            .expect("transacted schema");
+
+        // The fourth entity has a value of the wrong type.  Map notation is exploded into several
+        // assertions, but they're all reported as the entity given.
+        let t = r#"[{:test/name "a" :test/age 1}
+                    [:db/add "b" :test/name "b"]
+                    {:test/name "c" :test/age 3}
+                    {:db/id "d" :test/age "four"}
+                    {:test/name "e"}]"#;
+        let err = conn.transact(&mut sqlite, t).unwrap_err();
+        assert_eq!(err.iter().last().unwrap().to_string(),
+                   "value '\"four\"' for attribute :test/age is not the expected Mentat value type Long");
+        match err {
+            Error(ErrorKind::InvalidEntity(index, edn), _) => {
+                assert_eq!(index, 3);
+                assert_eq!(edn, r#"{:db/id "d" :test/age "four"}"#);
+            },
+            x => panic!("expected InvalidEntity, got {:?}", x),
+        }
+
+        // Entities are counted as given, not as exploded.
+        let t = r#"[{:test/name "a" :test/age 1} [:db/add "b" :test/unknown 2]]"#;
+        match conn.transact(&mut sqlite, t).unwrap_err() {
+            Error(ErrorKind::InvalidEntity(index, edn), _) => {
+                assert_eq!(index, 1);
+                assert_eq!(edn, r#"[:db/add "b" :test/unknown 2]"#);
+            },
+            x => panic!("expected InvalidEntity, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_add_and_retract() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        let report = conn.transact(&mut sqlite, "[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                                  {:db/ident :test/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+                                                  {:db/id \"e\" :test/name \"Alice\"}]")
+                         .expect("transacted schema");
+        let e = report.tempids["e"];
+
+        let name = edn::NamespacedKeyword::new("test", "name");
+        let friend = edn::NamespacedKeyword::new("test", "friend");
+        let datoms = count_user_datoms(&sqlite);
+
+        conn.add(&mut sqlite, e, &name, TypedValue::typed_string("Alicia")).expect("added");
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, e, &name).expect("lookup"),
+                   Some(TypedValue::typed_string("Alicia")));
+        assert_eq!(count_user_datoms(&sqlite), datoms);
+
+        conn.retract(&mut sqlite, e, &name, TypedValue::typed_string("Alicia")).expect("retracted");
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, e, &name).expect("lookup"), None);
+        assert_eq!(count_user_datoms(&sqlite), datoms - 1);
+
+        // The attribute must exist, and the value must have its type.
+        let unknown = edn::NamespacedKeyword::new("test", "unknown");
+        match conn.add(&mut sqlite, e, &unknown, TypedValue::Long(1)) {
+            Err(Error(ErrorKind::UnknownAttribute(kw), _)) => assert_eq!(kw, unknown),
+            x => panic!("expected UnknownAttribute, got {:?}", x),
+        }
+        assert!(conn.add(&mut sqlite, e, &name, TypedValue::Long(1)).is_err());
+        assert!(conn.add(&mut sqlite, e, &friend, TypedValue::typed_string("Bob")).is_err());
+        assert_eq!(count_user_datoms(&sqlite), datoms - 1);
+
+        // Refs can be given as entids.
+        conn.add(&mut sqlite, e, &friend, TypedValue::Ref(e)).expect("added");
+        assert_eq!(count_user_datoms(&sqlite), datoms);
+    }
+
+    #[test]
+    fn test_register_validator() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+
+        let age = edn::NamespacedKeyword::new("test", "age");
+        conn.register_validator(age.clone(), Box::new(|value: &TypedValue| {
+            match *value {
+                TypedValue::Long(age) if age >= 0 && age < 150 => Ok(()),
+                _ => Err("not a plausible age".to_string()),
+            }
+        }));
+
+        // A value the validator accepts is transacted as usual, as are values for attributes
+        // without validators.
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :test/name "Alice" :test/age 30}]"#)
+                         .expect("transacted valid age");
+        let alice = report.tempids["a"];
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, alice, &age).expect("looked up"),
+                   Some(TypedValue::Long(30)));
+
+        // A rejected value fails the whole transaction, leaving nothing behind.
+        let before = count_user_datoms(&sqlite);
+        let err = conn.transact(&mut sqlite, r#"[{:test/name "Bob" :test/age 20} {:test/name "Carol" :test/age 200}]"#)
+                      .unwrap_err();
+        match err {
+            Error(ErrorKind::ValidationFailed(ref attribute, TypedValue::Long(200), ref message), _) => {
+                assert_eq!(attribute, &age);
+                assert_eq!(message, "not a plausible age");
+            },
+            x => panic!("expected ValidationFailed, got {:?}", x),
+        }
+        assert_eq!(count_user_datoms(&sqlite), before);
+
+        // Within an `InProgress`, the failed transaction is undone, and the transaction can go on.
         {
-            let in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
-            let in_progress = in_progress.transact(t).expect("transacted successfully");
-            let one = in_progress.last_report().unwrap().tempids.get("one").expect("found one").clone();
-            let two = in_progress.last_report().unwrap().tempids.get("two").expect("found two").clone();
-            assert!(one != two);
-            assert!(one == tempid_offset || one == tempid_offset + 1);
-            assert!(two == tempid_offset || two == tempid_offset + 1);
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(r#"[{:test/name "Dave" :test/age 40}]"#).expect("transacted valid age");
+            assert!(in_progress.transact(r#"[{:test/name "Eve" :test/age -1}]"#).is_err());
+            assert!(in_progress.transact_entities_bulk(parse_transaction(r#"[{:test/name "Eve" :test/age -1}]"#).unwrap()).is_err());
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(count_user_datoms(&sqlite), before + 2);
 
-            let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
-                                    .expect("query succeeded");
-            assert_eq!(during, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+        // Retractions aren't validated, so a value that predates the validator can be retracted.
+        conn.register_validator(age.clone(), Box::new(|_: &TypedValue| Err("no more ages".to_string())));
+        conn.transact(&mut sqlite, format!("[[:db/retract {} :test/age 30]]", alice).as_str())
+            .expect("retracted age");
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, alice, &age).expect("looked up"), None);
+    }
 
-            let report = in_progress.transact(t2)
-                                    .expect("t2 succeeded")
-                                    .commit()
-                                    .expect("commit succeeded");
-            let three = report.unwrap().tempids.get("three").expect("found three").clone();
-            assert!(one != three);
-            assert!(two != three);
+    #[test]
+    fn test_set_schema_locked() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+        conn.set_schema_locked(true);
+
+        // Data transactions are unaffected.
+        conn.transact(&mut sqlite, r#"[{:test/name "Alice" :test/age 30}]"#).expect("transacted data");
+
+        // Adding an attribute, or altering one, fails, and writes nothing.
+        let schema = conn.current_schema();
+        let before = count_user_datoms(&sqlite);
+        for t in &["[{:db/ident :test/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]",
+                   "[[:db/add :test/age :db/index true]]",
+                   r#"[{:test/name "Bob"} [:db/add "x" :db/ident :test/x]]"#] {
+            match conn.transact(&mut sqlite, t).unwrap_err() {
+                Error(ErrorKind::SchemaLocked, _) => {},
+                x => panic!("expected SchemaLocked, got {:?}", x),
+            }
         }
+        assert_eq!(conn.current_schema(), schema);
+        assert_eq!(count_user_datoms(&sqlite), before);
 
-        // The DB part table changed.
-        let tempid_offset_after = get_next_entid(&conn);
-        assert_eq!(tempid_offset + 3, tempid_offset_after);
+        // Unlocking allows schema changes again.
+        conn.set_schema_locked(false);
+        conn.transact(&mut sqlite, "[{:db/ident :test/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transacted schema");
     }
 
     #[test]
-    fn test_compound_rollback() {
-        let mut sqlite = db::new_connection("").unwrap();
-        let mut conn = Conn::connect(&mut sqlite).unwrap();
+    fn test_require_attributes_for() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
 
-        let tempid_offset = get_next_entid(&conn);
+        let name = edn::NamespacedKeyword::new("test", "name");
+        let age = edn::NamespacedKeyword::new("test", "age");
 
-        // Nothing in the store => USER0 should be our starting point.
-        assert_eq!(tempid_offset, USER0);
+        // An entity that has an age before anyone asks for it.
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :test/age 30}]"#).expect("transacted age");
+        let alice = report.tempids["a"];
 
-        let t = "[[:db/add \"one\" :db/ident :a/keyword1] \
-                  [:db/add \"two\" :db/ident :a/keyword2]]";
+        conn.require_attributes_for(name.clone(), vec![age.clone()]);
 
-        // Scoped borrow of `sqlite`.
+        // Satisfied by a pre-existing datom.
+        conn.transact(&mut sqlite, format!(r#"[[:db/add {} :test/name "Alice"]]"#, alice).as_str())
+            .expect("transacted name");
+
+        // Satisfied within the same transaction.
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "b" :test/name "Bob" :test/age 40}]"#)
+                         .expect("transacted name and age");
+        let bob = report.tempids["b"];
+
+        // Not satisfied at all: nothing is written.
+        let before = count_user_datoms(&sqlite);
+        match conn.transact(&mut sqlite, r#"[{:test/name "Carol"} {:test/name "Dave" :test/age 50}]"#).unwrap_err() {
+            Error(ErrorKind::RequiredAttributesMissing(missing), _) => {
+                assert_eq!(missing.len(), 1);
+                assert_eq!(missing[0].1, age);
+            },
+            x => panic!("expected RequiredAttributesMissing, got {:?}", x),
+        }
+        assert_eq!(count_user_datoms(&sqlite), before);
+
+        // Violated by retracting the required attribute.
+        match conn.transact(&mut sqlite, format!("[[:db/retract {} :test/age 40]]", bob).as_str()).unwrap_err() {
+            Error(ErrorKind::RequiredAttributesMissing(missing), _) => assert_eq!(missing, vec![(bob, age.clone())]),
+            x => panic!("expected RequiredAttributesMissing, got {:?}", x),
+        }
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, bob, &age).expect("looked up"),
+                   Some(TypedValue::Long(40)));
+
+        // Retracting both is fine.
+        conn.transact(&mut sqlite, format!(r#"[[:db/retract {} :test/age 40] [:db/retract {} :test/name "Bob"]]"#, bob, bob).as_str())
+            .expect("retracted name and age");
+    }
+
+    #[test]
+    fn test_pull_reverse_attribute() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                     {:db/ident :person/friends :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+                                     {:db/ident :person/boss :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]")
+            .expect("transacted schema");
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :person/name "Alice"}
+                                                   {:db/id "b" :person/name "Bob" :person/friends "a" :person/boss "a"}
+                                                   {:db/id "c" :person/name "Carol" :person/friends ["a" "b"] :person/boss "a"}]"#)
+                         .expect("transacted people");
+        let (alice, bob, carol) = (report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+        let mut bob_and_carol = vec![TypedValue::Ref(bob), TypedValue::Ref(carol)];
+        bob_and_carol.sort();
+
+        let kw = |name: &str| edn::NamespacedKeyword::new("person", name);
+
+        let pulled = conn.pull(&sqlite, alice, "[:db/id :person/name :person/friends :person/_friends :person/_boss]").expect("pulled");
+        assert_eq!(pulled.get(&edn::NamespacedKeyword::new("db", "id")), Some(&PullValue::One(TypedValue::Ref(alice))));
+        assert_eq!(pulled.get(&kw("name")), Some(&PullValue::One(TypedValue::String(Arc::new("Alice".to_string())))));
+        // Alice has no friends of her own, so the forward attribute is omitted.
+        assert_eq!(pulled.get(&kw("friends")), None);
+        assert_eq!(pulled.get(&kw("_friends")), Some(&PullValue::Many(bob_and_carol.clone())));
+        // Reverse refs are collections even for a cardinality-one attribute.
+        assert_eq!(pulled.get(&kw("_boss")), Some(&PullValue::Many(bob_and_carol.clone())));
+
+        let pulled = conn.pull(&sqlite, bob, "[:person/_friends :person/_boss]").expect("pulled");
+        assert_eq!(pulled.get(&kw("_friends")), Some(&PullValue::Many(vec![TypedValue::Ref(carol)])));
+        assert_eq!(pulled.get(&kw("_boss")), None);
+
+        // Only ref attributes can be followed in reverse.
+        match conn.pull(&sqlite, alice, "[:person/_name]").unwrap_err() {
+            Error(ErrorKind::InvalidPullPattern(_), _) => {},
+            x => panic!("expected InvalidPullPattern, got {:?}", x),
+        }
+        match conn.pull(&sqlite, alice, "[:person/_enemies]").unwrap_err() {
+            Error(ErrorKind::UnknownAttribute(ref a), _) => assert_eq!(a, &kw("enemies")),
+            x => panic!("expected UnknownAttribute, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_precommit_hook() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+
+        // An invariant only a query can check: no two people share an age.
+        fn ages_are_distinct(view: &InProgressView) -> Result<()> {
+            assert!(view.last_report().is_some());
+            assert!(view.schema().get_entid(&edn::NamespacedKeyword::new("test", "age")).is_some());
+            let rows = view.q_once("[:find ?age ?e :where [?e :test/age ?age]]", None)?.into_rel()?;
+            let ages: ::std::collections::BTreeSet<TypedValue> = rows.iter().map(|row| row[0].clone()).collect();
+            if ages.len() != rows.len() {
+                bail!("two people share an age");
+            }
+            Ok(())
+        }
+
+        let before = count_user_datoms(&sqlite);
         {
-            let in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
-            let in_progress = in_progress.transact(t).expect("transacted successfully");
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.set_precommit_hook(Box::new(ages_are_distinct));
+            in_progress.transact(r#"[{:test/name "Alice" :test/age 30} {:test/name "Bob" :test/age 40}]"#).expect("transacted");
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(count_user_datoms(&sqlite), before + 4);
 
-            let one = in_progress.last_report().unwrap().tempids.get("one").expect("found it").clone();
-            let two = in_progress.last_report().unwrap().tempids.get("two").expect("found it").clone();
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.set_precommit_hook(Box::new(ages_are_distinct));
+            in_progress.transact(r#"[{:test/name "Carol" :test/age 30}]"#).expect("transacted");
+            let err = in_progress.commit().unwrap_err();
+            assert_eq!(err.to_string(), "two people share an age");
+        }
 
-            // The IDs are contiguous, starting at the previous part index.
-            assert!(one != two);
-            assert!(one == tempid_offset || one == tempid_offset + 1);
-            assert!(two == tempid_offset || two == tempid_offset + 1);
+        // Carol was rolled back.
+        assert_eq!(count_user_datoms(&sqlite), before + 4);
+        assert_eq!(conn.stats().transactions_rolled_back, 1);
+    }
 
-            // Inside the InProgress we can see our changes.
-            let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
-                                    .expect("query succeeded");
+    #[test]
+    fn test_on_commit() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
 
-            assert_eq!(during, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+        let calls: Arc<Mutex<Vec<(&'static str, Entid)>>> = Arc::new(Mutex::new(vec![]));
+        for &name in &["first", "second"] {
+            let calls = calls.clone();
+            conn.on_commit(Box::new(move |report: &TxReport| calls.lock().unwrap().push((name, report.tx_id))));
+        }
 
-            // And we can do direct lookup, too.
-            let kw = in_progress.lookup_value_for_attribute(one, &edn::NamespacedKeyword::new("db", "ident"))
-                                .expect("lookup succeeded");
-            assert_eq!(kw, Some(TypedValue::Keyword(edn::NamespacedKeyword::new("a", "keyword1").into())));
+        // Callbacks are called in registration order, for `transact` and for `InProgress`.
+        let report = conn.transact(&mut sqlite, r#"[{:test/name "Alice"}]"#).expect("transacted");
+        assert_eq!(*calls.lock().unwrap(), vec![("first", report.tx_id), ("second", report.tx_id)]);
 
-            in_progress.rollback()
-                       .expect("rollback succeeded");
+        let report = {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(r#"[{:test/name "Bob"}]"#).expect("transacted");
+            in_progress.commit().expect("committed").expect("a report")
+        };
+        assert_eq!(calls.lock().unwrap().len(), 4);
+        assert_eq!(calls.lock().unwrap()[3], ("second", report.tx_id));
+
+        // Nothing is called on rollback, or when a transaction fails.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(r#"[{:test/name "Carol"}]"#).expect("transacted");
+            in_progress.rollback().expect("rolled back");
         }
+        assert!(conn.transact(&mut sqlite, r#"[{:test/name 1}]"#).is_err());
+        assert_eq!(calls.lock().unwrap().len(), 4);
 
-        let after = conn.q_once(&mut sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
-                        .expect("query succeeded");
-        assert_eq!(after, QueryResults::Scalar(None));
+        // A panicking callback doesn't poison the metadata mutex: the transaction is committed,
+        // and the `Conn` carries on.
+        conn.on_commit(Box::new(|_: &TxReport| panic!("callback failed")));
+        let generation = conn.generation();
+        let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            conn.transact(&mut sqlite, r#"[{:test/name "Dave"}]"#)
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(conn.generation(), generation + 1);
+        assert_eq!(calls.lock().unwrap().len(), 6);
 
-        // The DB part table is unchanged.
-        let tempid_offset_after = get_next_entid(&conn);
-        assert_eq!(tempid_offset, tempid_offset_after);
+        assert!(::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            conn.transact(&mut sqlite, r#"[{:test/name "Eve"}]"#)
+        })).is_err());
+        assert_eq!(conn.generation(), generation + 2);
+        assert_eq!(calls.lock().unwrap().len(), 8);
     }
 
     #[test]
-    fn test_transact_errors() {
-        let mut sqlite = db::new_connection("").unwrap();
-        let mut conn = Conn::connect(&mut sqlite).unwrap();
+    fn test_dump_transactions() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        let schema = conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+        let added = conn.transact(&mut sqlite, r#"[{:db/id "a" :test/name "Alice" :test/age 30}]"#).expect("transacted");
+        let alice = added.tempids["a"];
+        let retracted = conn.transact(&mut sqlite, format!("[[:db/retract {} :test/age 30]]", alice).as_str()).expect("retracted");
 
-        // Good: empty transaction.
-        let report = conn.transact(&mut sqlite, "[]").unwrap();
-        assert_eq!(report.tx_id, 0x10000000 + 1);
+        let dump = conn.dump_transactions(&sqlite, schema.tx_id).expect("dumped");
+        let blocks: Vec<&str> = dump.split("\n\n").collect();
+        assert_eq!(blocks.len(), 2);
 
-        // Bad EDN: missing closing ']'.
-        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :a/keyword]");
-        match report.unwrap_err() {
-            Error(ErrorKind::EdnParseError(_), _) => { },
-            x => panic!("expected EDN parse error, got {:?}", x),
+        let lines: Vec<&str> = blocks[0].lines().map(|line| line.trim_right()).collect();
+        assert!(lines[0].starts_with(&format!(";; Transaction {} at #inst \"", added.tx_id)));
+        assert_eq!(lines.len(), 4);
+        // Ordered by entity, so the transaction's own `:db/txInstant` comes last.
+        assert_eq!(lines[1].split_whitespace().collect::<Vec<_>>(),
+                   vec![&format!("[[{}", alice)[..], ":test/name", "\"Alice\"", &added.tx_id.to_string()[..], "true]"]);
+
+        let lines: Vec<&str> = blocks[1].lines().map(|line| line.trim_right()).collect();
+        assert!(lines[0].starts_with(&format!(";; Transaction {} at #inst \"", retracted.tx_id)));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].split_whitespace().collect::<Vec<_>>(),
+                   vec![&format!("[[{}", alice)[..], ":test/age", "30", &retracted.tx_id.to_string()[..], "false]"]);
+    }
+
+    #[test]
+    fn test_watch() {
+        use edn::ToMicros;
+
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+
+        let age = edn::NamespacedKeyword::new("test", "age");
+        let (handle, changes) = conn.watch(vec![age.clone()].into_iter().collect());
+
+        // Only the watched attribute is reported.
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :test/name "Alice" :test/age 30}]"#).expect("transacted");
+        let alice = report.tempids["a"];
+        let change = changes.try_recv().expect("a change");
+        assert_eq!(change.tx_id, report.tx_id);
+        // The store keeps instants to the microsecond.
+        assert_eq!(change.tx_instant.to_micros(), report.tx_instant.to_micros());
+        assert_eq!(change.datoms, vec![(alice, age.clone(), TypedValue::Long(30), true)]);
+
+        // A transaction that doesn't touch it isn't reported at all.
+        conn.transact(&mut sqlite, format!(r#"[[:db/add {} :test/name "Alicia"]]"#, alice).as_str()).expect("transacted");
+        assert!(changes.try_recv().is_err());
+
+        // Each transaction within an `InProgress` is reported, but only once it's committed.
+        let (first, second) = {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(format!("[[:db/add {} :test/age 31]]", alice).as_str()).expect("transacted");
+            let first = in_progress.last_report().expect("a report").tx_id;
+            in_progress.transact(format!("[[:db/retract {} :test/age 31]]", alice).as_str()).expect("transacted");
+            let second = in_progress.last_report().expect("a report").tx_id;
+            assert!(changes.try_recv().is_err());
+            in_progress.commit().expect("committed");
+            (first, second)
+        };
+        let change = changes.try_recv().expect("a change");
+        assert_eq!(change.tx_id, first);
+        assert_eq!(change.datoms, vec![(alice, age.clone(), TypedValue::Long(30), false),
+                                       (alice, age.clone(), TypedValue::Long(31), true)]);
+        let change = changes.try_recv().expect("a change");
+        assert_eq!(change.tx_id, second);
+        assert_eq!(change.datoms, vec![(alice, age.clone(), TypedValue::Long(31), false)]);
+
+        // A rolled back transaction isn't reported.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(format!("[[:db/add {} :test/age 32]]", alice).as_str()).expect("transacted");
+            in_progress.rollback().expect("rolled back");
         }
+        assert!(changes.try_recv().is_err());
 
-        // Good EDN.
-        let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :a/keyword]]").unwrap();
-        assert_eq!(report.tx_id, 0x10000000 + 2);
+        // Dropping the handle ends the watch; a dropped receiver doesn't trouble the writer.
+        drop(handle);
+        conn.transact(&mut sqlite, format!("[[:db/add {} :test/age 33]]", alice).as_str()).expect("transacted");
+        assert!(changes.try_recv().is_err());
 
-        // Bad transaction data: missing leading :db/add.
-        let report = conn.transact(&mut sqlite, "[[\"t\" :db/ident :b/keyword]]");
-        match report.unwrap_err() {
-            Error(ErrorKind::TxParseError(::mentat_tx_parser::errors::ErrorKind::ParseError(_)), _) => { },
-            x => panic!("expected EDN parse error, got {:?}", x),
+        let (_handle, changes) = conn.watch(vec![age.clone()].into_iter().collect());
+        drop(changes);
+        conn.transact(&mut sqlite, format!("[[:db/add {} :test/age 34]]", alice).as_str()).expect("transacted");
+        assert_eq!(conn.stats().watch_changes_dropped, 1);
+    }
+
+    #[test]
+    fn test_set_attribute_indexed() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :test/bio :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transact succeeded");
+        let e = conn.transact(&mut sqlite, r#"[[:db/add "e" :test/bio "likes cheese"]]"#)
+                    .expect("transact succeeded")
+                    .tempids["e"];
+
+        let bio = edn::NamespacedKeyword::new("test", "bio");
+        let a = conn.entid_for_ident(&bio).expect("attribute").0;
+        let query = r#"[:find ?e . :where [?e :test/bio "likes cheese"]]"#;
+        let uses_index = |conn: &Conn, sqlite: &rusqlite::Connection| -> bool {
+            let plan = conn.q_explain(sqlite, query, None).expect("explained").plan.expect("plan");
+            plan.contains("idx_datoms_avet")
+        };
+        assert!(!uses_index(&conn, &sqlite));
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            match in_progress.set_attribute_indexed(&edn::NamespacedKeyword::new("no", "such"), true) {
+                Err(Error(ErrorKind::UnknownAttribute(_), _)) => {},
+                x => panic!("expected an unknown attribute error, got {:?}", x),
+            }
+            in_progress.set_attribute_indexed(&bio, true).expect("indexed");
+            in_progress.commit().expect("committed");
         }
 
-        // Good transaction data.
-        let report = conn.transact(&mut sqlite, "[[:db/add \"u\" :db/ident :b/keyword]]").unwrap();
-        assert_eq!(report.tx_id, 0x10000000 + 3);
+        // The existing datom is found through the new index.
+        assert!(conn.current_schema().attribute_for_entid(a).expect("attribute").index);
+        assert!(uses_index(&conn, &sqlite));
+        let found = conn.q_once(&sqlite, query, None).expect("query succeeded");
+        assert_eq!(found.results, QueryResults::Scalar(Some(TypedValue::Ref(e))));
 
-        // Bad transaction based on state of store: conflicting upsert.
-        let report = conn.transact(&mut sqlite, "[[:db/add \"u\" :db/ident :a/keyword]
-                                                  [:db/add \"u\" :db/ident :b/keyword]]");
-        match report.unwrap_err() {
-            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::NotYetImplemented(_)), _) => { },
-            x => panic!("expected EDN parse error, got {:?}", x),
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.set_attribute_indexed(&bio, false).expect("unindexed");
+            in_progress.commit().expect("committed");
+        }
+
+        assert!(!conn.current_schema().attribute_for_entid(a).expect("attribute").index);
+        assert!(!uses_index(&conn, &sqlite));
+        let found = conn.q_once(&sqlite, query, None).expect("query succeeded");
+        assert_eq!(found.results, QueryResults::Scalar(Some(TypedValue::Ref(e))));
+    }
+
+    #[test]
+    fn test_retract_matching() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :session/token :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                     {:db/ident :session/expires :db/valueType :db.type/long :db/cardinality :db.cardinality/many}]")
+            .expect("transact succeeded");
+        conn.transact(&mut sqlite, r#"[{:db/id "a" :session/token "a" :session/expires [10 40]}
+                                       {:db/id "b" :session/token "b" :session/expires 20}
+                                       {:db/id "c" :session/token "c" :session/expires 30}]"#)
+            .expect("transact succeeded");
+
+        let expires = edn::NamespacedKeyword::new("session", "expires");
+        let expired = |v: &TypedValue| match *v {
+            TypedValue::Long(x) => x < 25,
+            _ => false,
+        };
+        let remaining = |conn: &Conn, sqlite: &rusqlite::Connection| -> Vec<TypedValue> {
+            let mut values = conn.q_once(sqlite, "[:find [?x ...] :where [_ :session/expires ?x]]", None)
+                                 .expect("query succeeded")
+                                 .into_coll()
+                                 .expect("coll");
+            values.sort();
+            values
+        };
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            match in_progress.retract_matching(&edn::NamespacedKeyword::new("no", "such"), |_| true) {
+                Err(Error(ErrorKind::UnknownAttribute(_), _)) => {},
+                x => panic!("expected an unknown attribute error, got {:?}", x),
+            }
+
+            assert_eq!(in_progress.retract_matching(&expires, &expired).expect("retracted"), 2);
+
+            // Nothing left to match, so nothing is transacted.
+            let tx_id = in_progress.last_report().expect("report").tx_id;
+            assert_eq!(in_progress.retract_matching(&expires, &expired).expect("retracted"), 0);
+            assert_eq!(in_progress.last_report().expect("report").tx_id, tx_id);
+
+            // Rolling back undoes the retractions.
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(remaining(&conn, &sqlite), vec![TypedValue::Long(10), TypedValue::Long(20), TypedValue::Long(30), TypedValue::Long(40)]);
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            assert_eq!(in_progress.retract_matching(&expires, &expired).expect("retracted"), 2);
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(remaining(&conn, &sqlite), vec![TypedValue::Long(30), TypedValue::Long(40)]);
+    }
+
+    #[test]
+    fn test_poisoned_metadata() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, PEOPLE_SCHEMA).expect("transacted schema");
+        conn.transact(&mut sqlite, r#"[{:test/name "Alice"}]"#).expect("transacted");
+
+        // A panic while the metadata mutex is held poisons it.
+        assert!(::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _metadata = conn.metadata.lock().unwrap();
+            panic!("panicked holding the metadata mutex");
+        })).is_err());
+        assert!(conn.metadata.is_poisoned());
+
+        // Nothing was interrupted, so queries and transactions carry on.
+        let names = |conn: &Conn, sqlite: &rusqlite::Connection| -> usize {
+            conn.q_once(sqlite, "[:find [?name ...] :where [_ :test/name ?name]]", None)
+                .expect("query succeeded")
+                .into_coll()
+                .expect("coll")
+                .len()
+        };
+        assert_eq!(names(&conn, &sqlite), 1);
+        assert!(conn.try_current_schema().is_ok());
+        let generation = conn.generation();
+        conn.transact(&mut sqlite, r#"[{:test/name "Bob"}]"#).expect("transacted");
+        assert_eq!(conn.generation(), generation + 1);
+        assert_eq!(names(&conn, &sqlite), 2);
+
+        // A panic that interrupts a commit leaves the metadata out of step with the store.  Reading
+        // carries on, but writing, and asking for the schema fallibly, fail.
+        assert!(::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let mut metadata = lock_metadata(&conn.metadata);
+            metadata.committing = true;
+            panic!("panicked committing");
+        })).is_err());
+
+        assert_eq!(names(&conn, &sqlite), 2);
+        assert!(conn.current_schema().get_entid(&edn::NamespacedKeyword::new("test", "name")).is_some());
+        match conn.try_current_schema() {
+            Err(Error(ErrorKind::MetadataPoisoned, _)) => {},
+            x => panic!("expected a poisoned metadata error, got {:?}", x),
+        }
+        match conn.transact(&mut sqlite, r#"[{:test/name "Carol"}]"#) {
+            Err(Error(ErrorKind::MetadataPoisoned, _)) => {},
+            x => panic!("expected a poisoned metadata error, got {:?}", x),
+        }
+        assert_eq!(names(&conn, &sqlite), 2);
+    }
+
+    #[test]
+    fn test_transact_nested_maps() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                     {:db/ident :person/address :db/valueType :db.type/ref :db/cardinality :db.cardinality/one :db/isComponent true}
+                                     {:db/ident :address/city :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                     {:db/ident :address/location :db/valueType :db.type/ref :db/cardinality :db.cardinality/one :db/isComponent true}
+                                     {:db/ident :location/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transact succeeded");
+
+        // Each nested map becomes an entity of its own, referred to by the entity it's nested in.
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "p"
+                                                     :person/name "Alice"
+                                                     :person/address {:address/city "NYC"
+                                                                      :address/location {:location/name "Midtown"}}}]"#)
+                         .expect("transact succeeded");
+        let person = report.tempids["p"];
+        assert_eq!(report.tempids.len(), 1);
+
+        let found = conn.q_once(&sqlite, "[:find [?address ?city ?location ?name]
+                                           :in ?person
+                                           :where [?person :person/address ?address]
+                                                  [?address :address/city ?city]
+                                                  [?address :address/location ?location]
+                                                  [?location :location/name ?name]]",
+                                QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?person"), TypedValue::Ref(person))]))
+                        .expect("query succeeded")
+                        .into_tuple()
+                        .expect("tuple")
+                        .expect("found");
+        let (address, location) = match (&found[0], &found[2]) {
+            (&TypedValue::Ref(address), &TypedValue::Ref(location)) => (address, location),
+            x => panic!("expected refs, got {:?}", x),
+        };
+        assert!(person != address && address != location && person != location);
+        assert_eq!(found[1], TypedValue::typed_string("NYC"));
+        assert_eq!(found[3], TypedValue::typed_string("Midtown"));
+    }
+
+    #[test]
+    fn test_tempid_resolutions() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :person/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                     {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transact succeeded");
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :person/email "alice@example.com"}]"#)
+                         .expect("transact succeeded");
+        let alice = report.tempids["a"];
+        assert_eq!(report.tempid_resolutions["a"].newly_allocated, true);
+
+        // One tempid names a new entity; the other upserts to Alice by her email.
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "b" :person/email "bob@example.com" :person/name "Bob"}
+                                                    {:db/id "a" :person/email "alice@example.com" :person/name "Alice"}]"#)
+                         .expect("transact succeeded");
+        assert_eq!(report.tempid_resolutions.len(), 2);
+
+        let bob = &report.tempid_resolutions["b"];
+        assert_eq!(bob.entid, report.tempids["b"]);
+        assert!(bob.entid != alice);
+        assert_eq!(bob.newly_allocated, true);
+        assert_eq!(bob.upserted_via, None);
+
+        let upserted = &report.tempid_resolutions["a"];
+        assert_eq!(upserted.entid, alice);
+        assert_eq!(upserted.newly_allocated, false);
+        assert_eq!(upserted.upserted_via,
+                   Some((edn::NamespacedKeyword::new("person", "email"), TypedValue::typed_string("alice@example.com"))));
+    }
+
+    #[test]
+    fn test_tempids_across_transactions() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                                     {:db/ident :person/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}]")
+            .expect("transact succeeded");
+
+        let (one, two) = {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact(r#"[[:db/add "one" :person/name "Alice"]]"#).expect("transacted");
+            let one = in_progress.resolved_tempids()["one"];
+
+            // The second transaction refers to "one" as a value and, in map notation, as an entity.
+            in_progress.transact(r#"[[:db/add "two" :person/name "Bob"]
+                                     [:db/add "two" :person/friend "one"]
+                                     {:db/id "one" :person/friend "two"}]"#).expect("transacted");
+            let two = in_progress.resolved_tempids()["two"];
+            assert_eq!(in_progress.resolved_tempids().len(), 2);
+            assert_eq!(in_progress.resolved_tempids()["one"], one);
+
+            // A string value of a non-ref attribute is just a string.
+            in_progress.transact(r#"[[:db/add "three" :person/name "one"]]"#).expect("transacted");
+
+            // The bulk-load path resolves earlier tempids too.
+            let entities = parse_transaction(r#"[[:db/add "four" :person/friend "one"]]"#).expect("parsed");
+            in_progress.transact_entities_bulk(entities).expect("transacted");
+            assert_eq!(in_progress.resolved_tempids().len(), 4);
+            assert_eq!(in_progress.resolved_tempids()["one"], one);
+            in_progress.commit().expect("committed");
+            (one, two)
+        };
+        assert!(one != two);
+
+        let friends = conn.q_once(&sqlite, "[:find ?x ?y :where [?x :person/friend ?y]]", None)
+                          .expect("query succeeded")
+                          .into_rel()
+                          .expect("rel");
+        assert_eq!(friends.len(), 3);
+        assert!(friends.contains(&vec![TypedValue::Ref(one), TypedValue::Ref(two)]));
+        assert!(friends.contains(&vec![TypedValue::Ref(two), TypedValue::Ref(one)]));
+
+        let names = conn.q_once(&sqlite, "[:find [?name ...] :where [_ :person/name ?name]]", None)
+                        .expect("query succeeded")
+                        .into_coll()
+                        .expect("coll");
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&TypedValue::typed_string("one")));
+    }
+
+    #[test]
+    fn test_attribute_doc() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, r#"[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one
+                                        :db/doc "A person's full name."}
+                                       {:db/ident :person/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#)
+            .expect("transact succeeded");
+
+        let name = edn::NamespacedKeyword::new("person", "name");
+        let age = edn::NamespacedKeyword::new("person", "age");
+        assert_eq!(conn.attribute_doc(&sqlite, &name).expect("doc"), Some("A person's full name.".to_string()));
+        assert_eq!(conn.attribute_doc(&sqlite, &age).expect("doc"), None);
+
+        // Documentation can be added or replaced later.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.set_attribute_doc(&age, "Age in whole years.").expect("documented");
+            in_progress.set_attribute_doc(&name, "Full name.").expect("documented");
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(conn.attribute_doc(&sqlite, &age).expect("doc"), Some("Age in whole years.".to_string()));
+        assert_eq!(conn.attribute_doc(&sqlite, &name).expect("doc"), Some("Full name.".to_string()));
+
+        // Only attributes can be documented this way.
+        let unknown = edn::NamespacedKeyword::new("no", "such");
+        match conn.attribute_doc(&sqlite, &unknown) {
+            Err(Error(ErrorKind::UnknownAttribute(ref a), _)) if *a == unknown => {},
+            x => panic!("expected UnknownAttribute, got {:?}", x),
+        }
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+        match in_progress.set_attribute_doc(&unknown, "Nothing.") {
+            Err(Error(ErrorKind::UnknownAttribute(ref a), _)) if *a == unknown => {},
+            x => panic!("expected UnknownAttribute, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_head_tx() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        // Bootstrapping is itself a transaction.
+        assert_eq!(conn.head_tx(&sqlite).expect("head"), TX0);
+
+        let mut reports = vec![];
+        for i in 0..3 {
+            let report = conn.transact(&mut sqlite, &format!("[[:db/add \"e\" :db/ident :a/keyword{}]]", i))
+                             .expect("transact succeeded");
+            assert_eq!(conn.head_tx(&sqlite).expect("head"), report.tx_id);
+            reports.push(report);
+        }
+
+        // Nothing follows the head.
+        let head = conn.head_tx(&sqlite).expect("head");
+        assert!(conn.transactions_since(&sqlite, head).expect("transactions").is_empty());
+        assert_eq!(conn.transactions_since(&sqlite, reports[0].tx_id).expect("transactions").len(), 2);
+
+        // A transaction that fails doesn't move the head.
+        assert!(conn.transact(&mut sqlite, "[[:db/add \"e\" :db/ident 1]]").is_err());
+        assert_eq!(conn.head_tx(&sqlite).expect("head"), head);
+    }
+
+    #[test]
+    fn test_failed_transact_writes_nothing() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :item/code :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/value}]")
+            .expect("transact succeeded");
+
+        let count = |sqlite: &rusqlite::Connection, table: &str| -> i64 {
+            sqlite.query_row(&format!("SELECT COUNT(*) FROM {}", table), &[], |row| row.get(0)).expect("counted")
+        };
+        let transactions = count(&sqlite, "transactions");
+        let datoms = count(&sqlite, "datoms");
+
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+
+            // Two entities with the same unique value get past the transactor, and fail in SQLite
+            // only once the transaction's rows have been written to `transactions`.
+            in_progress.transact(r#"[[:db/add "a" :item/code "x"] [:db/add "b" :item/code "x"]]"#)
+                       .expect_err("unique value conflict");
+            in_progress.commit().expect("committed");
+        }
+
+        assert_eq!(count(&sqlite, "transactions"), transactions);
+        assert_eq!(count(&sqlite, "datoms"), datoms);
+    }
+
+    #[test]
+    fn test_keyword_values_interned() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :item/kind :db/valueType :db.type/keyword :db/cardinality :db.cardinality/one}]")
+            .expect("transact succeeded");
+        let before = conn.keywords.lock().unwrap().len();
+
+        for _ in 0..3 {
+            conn.transact(&mut sqlite, "[[:db/add \"a\" :item/kind :kind/book] [:db/add \"b\" :item/kind :kind/film]]")
+                .expect("transact succeeded");
         }
+
+        // Each distinct keyword value is interned once, however many times it's transacted.
+        assert_eq!(conn.keywords.lock().unwrap().len(), before + 2);
+        let kinds = conn.q_once(&sqlite, "[:find ?e ?kind :where [?e :item/kind ?kind]]", None)
+                        .expect("query succeeded")
+                        .into_rel()
+                        .expect("rel");
+        assert_eq!(kinds.len(), 6);
     }
 }
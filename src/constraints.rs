@@ -0,0 +1,149 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Application-defined constraints on what a transaction may write, checked after the transactor
+//! has written it and before it's recorded.  A transaction that breaks a constraint is undone.
+//! See `Conn::register_validator`, `Conn::require_attributes_for`, and `Conn::set_schema_locked`.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use rusqlite;
+
+use edn;
+
+use mentat_core::{
+    Entid,
+    Schema,
+    TypedValue,
+};
+
+use mentat_db::db::{
+    TypedSQLValue,
+    datom_value_sql,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// A check on a value asserted for an attribute, returning a message saying what's wrong with a
+/// value it rejects.  See `Conn::register_validator`.
+pub type Validator = Box<Fn(&TypedValue) -> ::std::result::Result<(), String> + Send + Sync>;
+
+/// The constraints registered with a `Conn`.
+#[derive(Default)]
+pub struct Constraints {
+    /// The validators registered for each attribute, by ident.
+    validators: BTreeMap<edn::NamespacedKeyword, Vec<Validator>>,
+
+    /// The attributes that an entity with each attribute must also have, by ident.
+    required_attributes: BTreeMap<edn::NamespacedKeyword, BTreeSet<edn::NamespacedKeyword>>,
+
+    /// If true, no transaction may change the schema.
+    schema_locked: bool,
+}
+
+impl Constraints {
+    pub fn register_validator(&mut self, attribute: edn::NamespacedKeyword, f: Validator) {
+        self.validators.entry(attribute).or_insert_with(Vec::new).push(f);
+    }
+
+    pub fn require_attributes_for(&mut self, attribute: edn::NamespacedKeyword, required: Vec<edn::NamespacedKeyword>) {
+        self.required_attributes.entry(attribute).or_insert_with(BTreeSet::new).extend(required);
+    }
+
+    pub fn set_schema_locked(&mut self, locked: bool) {
+        self.schema_locked = locked;
+    }
+
+    /// True if there's nothing to check.
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty() && self.required_attributes.is_empty() && !self.schema_locked
+    }
+
+    /// Check the transaction `tx_id`, just written to `sqlite`, leaving `schema`, which it changed
+    /// if `schema_changed`.
+    pub fn check(&self, sqlite: &rusqlite::Connection, schema: &Schema, tx_id: Entid, schema_changed: bool) -> Result<()> {
+        if self.schema_locked && schema_changed {
+            bail!(ErrorKind::SchemaLocked);
+        }
+        self.validate_values(sqlite, schema, tx_id)?;
+        self.check_required_attributes(sqlite, schema, tx_id)
+    }
+
+    /// Check each value asserted by the transaction `tx_id` for an attribute with validators
+    /// against them, failing with `ErrorKind::ValidationFailed` at the first value rejected.
+    /// Retractions aren't checked.
+    fn validate_values(&self, sqlite: &rusqlite::Connection, schema: &Schema, tx_id: Entid) -> Result<()> {
+        for (ident, checks) in &self.validators {
+            let (a, fulltext) = match schema.get_entid(ident).and_then(|a| schema.attribute_for_entid(a).map(|attribute| (a, attribute.fulltext))) {
+                Some(x) => x,
+                // Nothing can have been asserted for an attribute that doesn't exist.
+                None => continue,
+            };
+
+            let sql = format!("SELECT {}, value_type_tag FROM transactions WHERE tx = ? AND a = ? AND added = 1", datom_value_sql(fulltext));
+            let mut stmt = sqlite.prepare(&sql)?;
+            let mut rows = stmt.query(&[&tx_id, &a])?;
+            while let Some(row) = rows.next() {
+                let row = row?;
+                let value = TypedValue::from_sql_value_pair(row.get_checked(0)?, row.get_checked(1)?)?;
+                for check in checks {
+                    if let Err(message) = check(&value) {
+                        bail!(ErrorKind::ValidationFailed(ident.clone(), value, message));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that each entity the transaction `tx_id` asserted or retracted anything about, and
+    /// that now has an attribute with required attributes, has them too, failing with
+    /// `ErrorKind::RequiredAttributesMissing` listing every entity and attribute missing.
+    ///
+    /// This looks at the store as the transaction left it, so a required attribute may have been
+    /// asserted earlier or by the same transaction.
+    fn check_required_attributes(&self, sqlite: &rusqlite::Connection, schema: &Schema, tx_id: Entid) -> Result<()> {
+        let mut missing: Vec<(Entid, edn::NamespacedKeyword)> = vec![];
+        let mut stmt = sqlite.prepare_cached(
+            "SELECT DISTINCT t.e FROM transactions AS t
+             WHERE t.tx = ?
+             AND EXISTS (SELECT 1 FROM datoms AS d WHERE d.e = t.e AND d.a = ?)
+             AND NOT EXISTS (SELECT 1 FROM datoms AS d WHERE d.e = t.e AND d.a = ?)")?;
+
+        for (ident, required) in &self.required_attributes {
+            let a = match schema.get_entid(ident) {
+                Some(a) => a,
+                // No entity can have an attribute that doesn't exist.
+                None => continue,
+            };
+            for required_ident in required {
+                // If the required attribute doesn't exist, no entity can have it: look for an
+                // attribute that can't match anything.
+                let required_a = schema.get_entid(required_ident).unwrap_or(-1);
+                let mut rows = stmt.query(&[&tx_id, &a, &required_a])?;
+                while let Some(row) = rows.next() {
+                    missing.push((row?.get_checked(0)?, required_ident.clone()));
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            missing.sort();
+            bail!(ErrorKind::RequiredAttributesMissing(missing));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Compare two sets of datoms, such as those produced by `mentat_db::dumps::datoms` for two
+//! replicas of a store.
+//!
+//! This is the planning step for reconciliation: the datoms present only in `a` are what `b` is
+//! missing, and the datoms present only in `b` are what `b` would have to retract to match `a`.
+
+use std::collections::{
+    BTreeMap,
+    HashSet,
+};
+use std::fmt;
+
+use rusqlite;
+
+use edn;
+
+use mentat_db::dumps;
+use mentat_db::dumps::{
+    Datom,
+    Datoms,
+};
+use mentat_tx::entities::Entid;
+
+use conn::Conn;
+use errors::Result;
+
+/// Assert that two stores hold exactly the same datoms, printing their differences if they don't.
+/// Each store is given as a `Conn` and its SQLite connection:
+///
+/// ```ignore
+/// assert_datoms_eq!((&conn_a, &sqlite_a), (&conn_b, &sqlite_b));
+/// ```
+#[macro_export]
+macro_rules! assert_datoms_eq {
+    ( ($conn_a: expr, $sqlite_a: expr), ($conn_b: expr, $sqlite_b: expr) ) => {{
+        let diff = $crate::datom_diff::diff_stores($sqlite_a, $conn_a, $sqlite_b, $conn_b)
+            .expect("to be able to diff stores");
+        assert!(diff.is_empty(), "Expected stores to hold the same datoms, but they differ:\n{}", diff);
+    }}
+}
+
+/// The datoms that differ between two datom sets.
+///
+/// Both sides preserve the deterministic `(e, a, (value_type_tag, v), tx)` ordering of the
+/// `Datoms` they were drawn from.
+pub struct DatomDiff {
+    /// Datoms present in `a` but not in `b`.
+    pub only_in_a: Datoms,
+
+    /// Datoms present in `b` but not in `a`.
+    pub only_in_b: Datoms,
+}
+
+impl DatomDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty()
+    }
+
+    /// Pair up the datoms that give the same entity a different value for the same attribute: each
+    /// `(e, a)` with exactly one datom present only in `a` and exactly one present only in `b`.
+    ///
+    /// This is how a changed cardinality-one value shows up.  Differences in cardinality-many
+    /// attributes, where there's no single value to compare, are left unpaired.
+    pub fn changed_values(&self) -> Vec<(&Datom, &Datom)> {
+        fn by_entity_attribute(datoms: &Datoms) -> BTreeMap<(Entid, Entid), Vec<&Datom>> {
+            let mut m: BTreeMap<(Entid, Entid), Vec<&Datom>> = BTreeMap::default();
+            for datom in datoms {
+                m.entry((datom.e().clone(), datom.a().clone())).or_insert_with(Vec::new).push(datom);
+            }
+            m
+        }
+
+        let in_b = by_entity_attribute(&self.only_in_b);
+        by_entity_attribute(&self.only_in_a)
+            .into_iter()
+            .filter_map(|(key, from_a)| {
+                match in_b.get(&key) {
+                    Some(from_b) if from_a.len() == 1 && from_b.len() == 1 => Some((from_a[0], from_b[0])),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns a symbolic representation of the diff suitable for logging, like
+    /// `{:only-in-a [[e a v] ...] :only-in-b [[e a v] ...]}`.
+    pub fn to_edn_value(&self) -> edn::Value {
+        let mut m: BTreeMap<edn::Value, edn::Value> = BTreeMap::default();
+        m.insert(edn::Value::Keyword(edn::Keyword::new("only-in-a")), self.only_in_a.into_edn());
+        m.insert(edn::Value::Keyword(edn::Keyword::new("only-in-b")), self.only_in_b.into_edn());
+        edn::Value::Map(m)
+    }
+}
+
+impl fmt::Display for DatomDiff {
+    /// Lists the datoms present only in `a`, then those present only in `b`, one per line.
+    /// Changed values are listed again together, like `[ e a ] old -> new`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no differences\n");
+        }
+
+        write!(f, "only in a:\n")?;
+        for datom in &self.only_in_a {
+            write!(f, "  {}\n", datom)?;
+        }
+        write!(f, "only in b:\n")?;
+        for datom in &self.only_in_b {
+            write!(f, "  {}\n", datom)?;
+        }
+
+        let changed = self.changed_values();
+        if !changed.is_empty() {
+            write!(f, "changed:\n")?;
+            for (from_a, from_b) in changed {
+                let e_a = match from_a.into_edn() {
+                    edn::Value::Vector(mut v) => {
+                        v.truncate(2);
+                        edn::Value::Vector(v)
+                    },
+                    v => v,
+                };
+                write!(f, "  {} {} -> {}\n", e_a, from_a.value(), from_b.value())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return the datoms in `from` that are not in `other`, in the order they appear in `from`.
+fn difference(from: &Datoms, other: &HashSet<&Datom>) -> Datoms {
+    Datoms(from.iter().filter(|datom| !other.contains(datom)).cloned().collect())
+}
+
+/// Compute which datoms are present only in `a` and which only in `b`.
+///
+/// Datoms are compared in full, including their transaction ID: the same assertion made in
+/// different transactions counts as a difference.
+pub fn diff_datoms(a: &Datoms, b: &Datoms) -> DatomDiff {
+    let in_a: HashSet<&Datom> = a.iter().collect();
+    let in_b: HashSet<&Datom> = b.iter().collect();
+
+    DatomDiff {
+        only_in_a: difference(a, &in_b),
+        only_in_b: difference(b, &in_a),
+    }
+}
+
+/// Compute which datoms are present only in the store behind `conn_a`, and which only in the store
+/// behind `conn_b`.  Neither store's `:db/txInstant` datoms are compared.
+pub fn diff_stores(sqlite_a: &rusqlite::Connection, conn_a: &Conn,
+                   sqlite_b: &rusqlite::Connection, conn_b: &Conn) -> Result<DatomDiff> {
+    let a = dumps::datoms(sqlite_a, &*conn_a.current_schema())?;
+    let b = dumps::datoms(sqlite_b, &*conn_b.current_schema())?;
+    Ok(diff_datoms(&a, &b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_datoms() {
+        let (mut conn_a, mut sqlite_a) = Conn::in_memory().unwrap();
+        let (mut conn_b, mut sqlite_b) = Conn::in_memory().unwrap();
+
+        let datoms = |conn: &Conn, sqlite: &::rusqlite::Connection| {
+            dumps::datoms(sqlite, &*conn.current_schema()).expect("datoms")
+        };
+
+        // Freshly bootstrapped stores are identical.
+        assert!(diff_datoms(&datoms(&conn_a, &sqlite_a), &datoms(&conn_b, &sqlite_b)).is_empty());
+
+        let a = conn_a.transact(&mut sqlite_a, r#"[[:db/add "x" :db/ident :test/shared]
+                                                   [:db/add "y" :db/ident :test/only-a]]"#)
+                      .expect("transacted");
+        let b = conn_b.transact(&mut sqlite_b, r#"[[:db/add "x" :db/ident :test/shared]
+                                                   [:db/add "y" :db/ident :test/only-b]]"#)
+                      .expect("transacted");
+        assert_eq!(a.tempids, b.tempids);
+
+        let diff = diff_datoms(&datoms(&conn_a, &sqlite_a), &datoms(&conn_b, &sqlite_b));
+        assert!(!diff.is_empty());
+
+        let expected = edn::parse::value(&format!("{{:only-in-a [[{y} :db/ident :test/only-a]] \
+                                                     :only-in-b [[{y} :db/ident :test/only-b]]}}",
+                                                  y = a.tempids["y"]))
+            .expect("to parse EDN")
+            .without_spans();
+        assert_eq!(diff.to_edn_value(), expected);
+
+        // Diffing a set against itself finds nothing.
+        let same = datoms(&conn_a, &sqlite_a);
+        assert!(diff_datoms(&same, &same).is_empty());
+    }
+
+    #[test]
+    fn test_diff_stores() {
+        let (mut conn_a, mut sqlite_a) = Conn::in_memory().unwrap();
+        let (mut conn_b, mut sqlite_b) = Conn::in_memory().unwrap();
+
+        let schema = r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                         {:db/ident :test/tag :db/valueType :db.type/keyword :db/cardinality :db.cardinality/many}]"#;
+        conn_a.transact(&mut sqlite_a, schema).expect("transacted");
+        conn_b.transact(&mut sqlite_b, schema).expect("transacted");
+
+        // Identical stores.
+        let a = conn_a.transact(&mut sqlite_a, r#"[{:db/id "x" :test/name "Alice"}]"#).expect("transacted");
+        conn_b.transact(&mut sqlite_b, r#"[{:db/id "x" :test/name "Alice"}]"#).expect("transacted");
+        assert_datoms_eq!((&conn_a, &sqlite_a), (&conn_b, &sqlite_b));
+        let x = a.tempids["x"];
+
+        // An added datom.
+        conn_a.transact(&mut sqlite_a, format!("[[:db/add {} :test/tag :test/red]]", x).as_str()).expect("transacted");
+        let diff = diff_stores(&sqlite_a, &conn_a, &sqlite_b, &conn_b).expect("diffed");
+        assert_eq!(diff.only_in_a.len(), 1);
+        assert!(diff.only_in_b.is_empty());
+        assert!(diff.changed_values().is_empty());
+        assert_eq!(diff.to_string(),
+                   format!("only in a:\n  [ {x} :test/tag :test/red ]\nonly in b:\n", x = x));
+        conn_b.transact(&mut sqlite_b, format!("[[:db/add {} :test/tag :test/red]]", x).as_str()).expect("transacted");
+        assert_datoms_eq!((&conn_a, &sqlite_a), (&conn_b, &sqlite_b));
+
+        // A changed value.
+        conn_a.transact(&mut sqlite_a, format!("[[:db/add {} :test/name \"Alicia\"]]", x).as_str()).expect("transacted");
+        conn_b.transact(&mut sqlite_b, format!("[[:db/add {} :test/name \"Ali\"]]", x).as_str()).expect("transacted");
+        let diff = diff_stores(&sqlite_a, &conn_a, &sqlite_b, &conn_b).expect("diffed");
+        let changed = diff.changed_values();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0.value(), &edn::Value::Text("Alicia".to_string()));
+        assert_eq!(changed[0].1.value(), &edn::Value::Text("Ali".to_string()));
+        assert_eq!(diff.to_string(),
+                   format!("only in a:\n  [ {x} :test/name \"Alicia\" ]\n\
+                            only in b:\n  [ {x} :test/name \"Ali\" ]\n\
+                            changed:\n  [ {x} :test/name ] \"Alicia\" -> \"Ali\"\n", x = x));
+    }
+}
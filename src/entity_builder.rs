@@ -0,0 +1,251 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Build a transaction from Rust values, rather than by writing EDN.  See `TermBuilder`.
+//!
+//! A `TermBuilder` hands out `TempIdHandle`s for the new entities it describes.  A handle minted
+//! by `TermBuilder::tempid` is unique: unlike a string tempid, it can't be mistaken for one that
+//! some other code happened to choose.  Look up the entid a handle became with
+//! `TxReport::resolved`:
+//!
+//! ```ignore
+//! let mut builder = TermBuilder::new();
+//! let alice = builder.tempid();
+//! builder.add(&alice, NamespacedKeyword::new("person", "name"), TypedValue::typed_string("Alice"));
+//! let report = conn.transact_builder(&mut sqlite, builder)?;
+//! let alice = report.resolved(&alice);
+//! ```
+//!
+//! Use a handle as the value of a ref attribute with `TermBuilder::add_ref`.  Within an
+//! `InProgress`, a handle resolved by one transaction names the same entity in later ones.
+
+use std::ops::Deref;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    KnownEntid,
+    TypedValue,
+};
+
+use mentat_db::TypedSQLValue;
+
+use mentat_tx::entities::{
+    AtomOrLookupRefOrVectorOrMapNotation,
+    Entid,
+    EntidOrLookupRefOrTempId,
+    Entity,
+    OpType,
+    TempId,
+};
+
+/// The number of internal tempids minted so far, so that no two are ever the same.
+static MINTED_TEMPIDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Mint an internal tempid distinct from every other, so that code transacting a new entity on a
+/// caller's behalf can find it in the report without colliding with the caller's own tempids.
+pub fn mint_tempid() -> TempId {
+    let minted = MINTED_TEMPIDS.fetch_add(1, Ordering::SeqCst) as i64;
+    // Negative internal tempids are reserved for callers; see `TempId`.
+    TempId::Internal(-1 - minted)
+}
+
+/// A tempid handed out by a `TermBuilder`.  It dereferences to its `TempId`, so that
+/// `report.resolved(&handle)` finds the entid it became.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct TempIdHandle(TempId);
+
+impl Deref for TempIdHandle {
+    type Target = TempId;
+
+    fn deref(&self) -> &TempId {
+        &self.0
+    }
+}
+
+/// Something that can be the entity of a term: an entity that already exists, or a tempid.
+pub trait IntoTermEntity {
+    fn into_term_entity(self) -> EntidOrLookupRefOrTempId;
+}
+
+impl IntoTermEntity for KnownEntid {
+    fn into_term_entity(self) -> EntidOrLookupRefOrTempId {
+        EntidOrLookupRefOrTempId::Entid(Entid::Entid(self.0))
+    }
+}
+
+impl IntoTermEntity for TempIdHandle {
+    fn into_term_entity(self) -> EntidOrLookupRefOrTempId {
+        EntidOrLookupRefOrTempId::TempId(self.0)
+    }
+}
+
+impl<'a> IntoTermEntity for &'a TempIdHandle {
+    fn into_term_entity(self) -> EntidOrLookupRefOrTempId {
+        EntidOrLookupRefOrTempId::TempId(self.0.clone())
+    }
+}
+
+/// Accumulates assertions and retractions to be transacted together, by `Conn::transact_builder`
+/// or `InProgress::transact_builder`.
+#[derive(Clone, Debug, Default)]
+pub struct TermBuilder {
+    terms: Vec<Entity>,
+}
+
+impl TermBuilder {
+    pub fn new() -> TermBuilder {
+        TermBuilder::default()
+    }
+
+    /// A handle for the string tempid `name`, as if `name` were written in a transaction.  The
+    /// same name in the same transaction is the same entity, wherever it comes from.
+    pub fn named_tempid(&mut self, name: &str) -> TempIdHandle {
+        TempIdHandle(TempId::External(name.to_string()))
+    }
+
+    /// A handle for a new entity, distinct from every other.  This mints an internal tempid, so
+    /// it can't collide with any string tempid, nor with a tempid minted by another builder.
+    pub fn tempid(&mut self) -> TempIdHandle {
+        TempIdHandle(mint_tempid())
+    }
+
+    fn push<E>(&mut self, op: OpType, e: E, a: NamespacedKeyword, v: TypedValue) -> &mut Self where E: IntoTermEntity {
+        self.terms.push(Entity::AddOrRetract {
+            op: op,
+            e: e.into_term_entity(),
+            a: Entid::Ident(a),
+            v: AtomOrLookupRefOrVectorOrMapNotation::Atom(v.to_edn_value_pair().0.with_spans()),
+        });
+        self
+    }
+
+    /// Assert `[e a v]`.
+    pub fn add<E>(&mut self, e: E, a: NamespacedKeyword, v: TypedValue) -> &mut Self where E: IntoTermEntity {
+        self.push(OpType::Add, e, a, v)
+    }
+
+    /// Assert `[e a v]`, where `a` is a ref attribute and `v` the entity named by a handle.
+    pub fn add_ref<E>(&mut self, e: E, a: NamespacedKeyword, v: &TempIdHandle) -> &mut Self where E: IntoTermEntity {
+        self.terms.push(Entity::AddOrRetract {
+            op: OpType::Add,
+            e: e.into_term_entity(),
+            a: Entid::Ident(a),
+            v: AtomOrLookupRefOrVectorOrMapNotation::TempId(v.0.clone()),
+        });
+        self
+    }
+
+    /// Retract `[e a v]`.
+    pub fn retract<E>(&mut self, e: E, a: NamespacedKeyword, v: TypedValue) -> &mut Self where E: IntoTermEntity {
+        self.push(OpType::Retract, e, a, v)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The entities built, ready for `InProgress::transact_entities`.
+    pub fn build(self) -> Vec<Entity> {
+        self.terms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use conn::Conn;
+
+    #[test]
+    fn test_builder_tempids() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, "[{:db/ident :item/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .expect("transact succeeded");
+        let name = NamespacedKeyword::new("item", "name");
+
+        // Two anonymous tempids, and a string tempid.
+        let mut builder = TermBuilder::new();
+        let first = builder.tempid();
+        let second = builder.tempid();
+        let item = builder.named_tempid("item");
+        assert!(first != second);
+        builder.add(&first, name.clone(), TypedValue::typed_string("first"))
+               .add(&second, name.clone(), TypedValue::typed_string("second"))
+               .add(&item, name.clone(), TypedValue::typed_string("item"));
+
+        let report = conn.transact_builder(&mut sqlite, builder).expect("transacted");
+        let first = report.resolved(&first).expect("first resolved");
+        let second = report.resolved(&second).expect("second resolved");
+        let item = report.resolved(&item).expect("item resolved");
+        assert!(first != second && first != item && second != item);
+        assert_eq!(report.minted_tempids.len(), 2);
+        assert_eq!(report.tempids.len(), 1);
+
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, first, &name).expect("looked up"),
+                   Some(TypedValue::typed_string("first")));
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, second, &name).expect("looked up"),
+                   Some(TypedValue::typed_string("second")));
+
+        // Minted tempids are never reused, even by another builder.
+        let mut other = TermBuilder::new();
+        let third = other.tempid();
+        other.add(&third, name.clone(), TypedValue::typed_string("third"));
+        let report = conn.transact_builder(&mut sqlite, other).expect("transacted");
+        let third = report.resolved(&third).expect("third resolved");
+        assert!(third != first && third != second);
+    }
+
+    #[test]
+    fn test_builder_refs_across_transactions() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        conn.transact(&mut sqlite, r#"[
+            {:db/ident :item/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :item/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+        ]"#).expect("transact succeeded");
+        let name = NamespacedKeyword::new("item", "name");
+        let parent = NamespacedKeyword::new("item", "parent");
+
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+
+        // A ref between two new entities in one builder.
+        let mut builder = TermBuilder::new();
+        let root = builder.tempid();
+        let child = builder.tempid();
+        builder.add(&root, name.clone(), TypedValue::typed_string("root"))
+               .add(&child, name.clone(), TypedValue::typed_string("child"))
+               .add_ref(&child, parent.clone(), &root);
+        in_progress.transact_builder(builder).expect("transacted");
+        let (root_e, child_e) = {
+            let report = in_progress.last_report().expect("report");
+            (report.resolved(&root).expect("root resolved"), report.resolved(&child).expect("child resolved"))
+        };
+        assert_eq!(in_progress.lookup_value_for_attribute(child_e, &parent).expect("looked up"),
+                   Some(TypedValue::Ref(root_e.0)));
+
+        // The same handles in a later builder name the same entities, as an entity and as a ref.
+        let mut builder = TermBuilder::new();
+        let grandchild = builder.tempid();
+        builder.add(&root, name.clone(), TypedValue::typed_string("renamed"))
+               .add(&grandchild, name.clone(), TypedValue::typed_string("grandchild"))
+               .add_ref(&grandchild, parent.clone(), &child);
+        in_progress.transact_builder(builder).expect("transacted");
+        let grandchild_e = in_progress.last_report().and_then(|report| report.resolved(&grandchild)).expect("grandchild resolved");
+        assert_eq!(in_progress.lookup_value_for_attribute(root_e, &name).expect("looked up"),
+                   Some(TypedValue::typed_string("renamed")));
+        assert_eq!(in_progress.lookup_value_for_attribute(grandchild_e, &parent).expect("looked up"),
+                   Some(TypedValue::Ref(child_e.0)));
+        in_progress.commit().expect("committed");
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Read the entities of EDN transactions from an `io::Read` one at a time, so that a large
+//! transaction file needn't be held in memory, as a string and again as parsed EDN, to be
+//! transacted.  See `Conn::transact_from_read`.
+//!
+//! The input is scanned a byte at a time just far enough to find where each entity ends, by
+//! matching brackets outside strings, comments, and character literals; only then is the entity
+//! parsed.
+
+use std::io::{
+    BufReader,
+    Bytes,
+    Read,
+};
+
+use edn;
+use edn::utils::FormScanner;
+
+use mentat_tx::entities::Entity;
+use mentat_tx_parser;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// Yields the entities of a sequence of EDN transaction vectors, like `[{...} [:db/add ...]] [...]`,
+/// read from `R`.  The vectors are only containers: entities are yielded one at a time however
+/// they're grouped.
+///
+/// An error is reported as `ErrorKind::TransactionStreamError`, with the line and byte offset at
+/// which the offending entity starts.  Nothing more is read after an error.
+pub struct EntityReader<R> where R: Read {
+    bytes: Bytes<BufReader<R>>,
+
+    /// The 1-based line, and the 0-based byte offset, of the next byte to be read.
+    line: usize,
+    offset: usize,
+
+    /// Whether we're between the brackets of a transaction vector.
+    in_transaction: bool,
+
+    /// Entities parsed but not yet yielded, in reverse order.
+    pending: Vec<Entity>,
+
+    failed: bool,
+}
+
+fn stream_error<T>(line: usize, offset: usize, message: &str) -> Result<T> {
+    bail!(ErrorKind::TransactionStreamError(line, offset, message.to_string()))
+}
+
+/// Parse the text of a single entity.
+fn parse_entity(text: &str) -> Result<Vec<Entity>> {
+    let value = edn::parse::value(text)?;
+    let span = value.span;
+    let transaction = edn::ValueAndSpan::new(edn::SpannedValue::Vector(vec![value]), span);
+    match mentat_tx_parser::Tx::parse(&transaction) {
+        Ok(entities) => Ok(entities),
+        // The stream reports where the entity starts; just say what's wrong with it.
+        Err(mentat_tx_parser::Error(mentat_tx_parser::ErrorKind::EntityParseError(_, _, message), _)) => Err(message.into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl<R> EntityReader<R> where R: Read {
+    pub fn new(r: R) -> EntityReader<R> {
+        EntityReader {
+            bytes: BufReader::new(r).bytes(),
+            line: 1,
+            offset: 0,
+            in_transaction: false,
+            pending: vec![],
+            failed: false,
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        match self.bytes.next() {
+            None => Ok(None),
+            Some(b) => {
+                let b = b?;
+                self.offset += 1;
+                if b == b'\n' {
+                    self.line += 1;
+                }
+                Ok(Some(b))
+            },
+        }
+    }
+
+    /// Read the next byte of the entity that starts at `line` and `offset`, which mustn't end
+    /// before the entity does.
+    fn next_byte_in_entity(&mut self, line: usize, offset: usize) -> Result<u8> {
+        match self.next_byte()? {
+            Some(b) => Ok(b),
+            None => stream_error(line, offset, "unexpected end of input in entity"),
+        }
+    }
+
+    /// Read the text of the entity whose first byte, `first`, was read at `line` and `offset`.
+    fn read_entity(&mut self, first: u8, line: usize, offset: usize) -> Result<String> {
+        let mut text = vec![first];
+        let mut scanner = FormScanner::new();
+        scanner.push(first);
+        while scanner.depth() > 0 {
+            let b = self.next_byte_in_entity(line, offset)?;
+            text.push(b);
+            scanner.push(b);
+        }
+
+        match String::from_utf8(text) {
+            Ok(text) => Ok(text),
+            Err(_) => stream_error(line, offset, "entity is not valid UTF-8"),
+        }
+    }
+
+    /// Read and parse the next entity, or return `None` at the end of the input.
+    fn next_entities(&mut self) -> Result<Option<Vec<Entity>>> {
+        loop {
+            let (line, offset) = (self.line, self.offset);
+            let b = match self.next_byte()? {
+                Some(b) => b,
+                None if self.in_transaction => return stream_error(line, offset, "unexpected end of input: expected ]"),
+                None => return Ok(None),
+            };
+
+            match b {
+                b' ' | b'\t' | b'\r' | b'\n' | b',' => (),
+                b';' => {
+                    while let Some(c) = self.next_byte()? {
+                        if c == b'\n' {
+                            break;
+                        }
+                    }
+                },
+                b'[' if !self.in_transaction => self.in_transaction = true,
+                b']' if self.in_transaction => self.in_transaction = false,
+                b'[' | b'{' => {
+                    let text = self.read_entity(b, line, offset)?;
+                    return match parse_entity(&text) {
+                        Ok(entities) => Ok(Some(entities)),
+                        Err(e) => stream_error(line, offset, &e.to_string()),
+                    };
+                },
+                _ if self.in_transaction => return stream_error(line, offset, "expected an entity"),
+                _ => return stream_error(line, offset, "expected a transaction vector"),
+            }
+        }
+    }
+}
+
+impl<R> Iterator for EntityReader<R> where R: Read {
+    type Item = Result<Entity>;
+
+    fn next(&mut self) -> Option<Result<Entity>> {
+        if self.failed {
+            return None;
+        }
+
+        if let Some(entity) = self.pending.pop() {
+            return Some(Ok(entity));
+        }
+
+        match self.next_entities() {
+            Ok(Some(mut entities)) => {
+                entities.reverse();
+                self.pending = entities;
+                self.next()
+            },
+            Ok(None) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use errors::Error;
+
+    use mentat_tx::entities::OpType;
+
+    fn read(input: &str) -> Result<Vec<Entity>> {
+        EntityReader::new(input.as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_entity_reader() {
+        let input = r#"[[:db/add "a" :db/ident :test/a]
+                        {:db/ident :test/b, :db/doc "a [string] with \"brackets\""} ; a comment ]
+                       ]
+                       ; Transactions are just containers.
+                       [] [[:db/retract 1 :db/ident :test/c]]"#;
+        let entities = read(input).expect("read");
+        assert_eq!(entities.len(), 3);
+        match (&entities[0], &entities[1], &entities[2]) {
+            (&Entity::AddOrRetract { op: OpType::Add, .. },
+             &Entity::MapNotation(ref map),
+             &Entity::AddOrRetract { op: OpType::Retract, .. }) => assert_eq!(map.len(), 2),
+            x => panic!("unexpected entities {:?}", x),
+        }
+
+        assert_eq!(read("").expect("read"), vec![]);
+        assert_eq!(read(" [ ] ").expect("read"), vec![]);
+    }
+
+    #[test]
+    fn test_entity_reader_errors() {
+        let error = |input: &str| -> (usize, usize) {
+            match read(input) {
+                Err(Error(ErrorKind::TransactionStreamError(line, offset, _), _)) => (line, offset),
+                x => panic!("expected TransactionStreamError, got {:?}", x),
+            }
+        };
+
+        // An entity that isn't EDN, or isn't an entity, is located by where it starts.
+        assert_eq!(error("[{:db/ident :test/a}\n {:db/ident}]"), (2, 22));
+        assert_eq!(error("[{:db/ident :test/a}\n [:db/add \"a\"]]"), (2, 22));
+
+        // Input ending inside an entity, or inside a transaction.
+        assert_eq!(error("[{:db/ident :test/a}\n {:db/ident \"a]}"), (2, 22));
+        assert_eq!(error("[{:db/ident :test/a}\n"), (2, 21));
+
+        // Something other than an entity or a transaction.
+        assert_eq!(error("[:db/add 1 :db/ident :test/a]"), (1, 1));
+        assert_eq!(error("{:db/ident :test/a}"), (1, 0));
+
+        // Nothing is read after an error.
+        let mut reader = EntityReader::new("[{:db/ident}] [{:db/ident :test/a}]".as_bytes());
+        assert!(reader.next().expect("an error").is_err());
+        assert!(reader.next().is_none());
+    }
+}
@@ -13,8 +13,14 @@
 use rusqlite;
 
 use std::collections::BTreeSet;
+use std::io;
+use std::time::Duration;
 
 use edn;
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
 use mentat_db;
 use mentat_query;
 use mentat_query_algebrizer;
@@ -31,6 +37,7 @@ error_chain! {
 
     foreign_links {
         EdnParseError(edn::ParseError);
+        IoError(io::Error);
         Rusqlite(rusqlite::Error);
     }
 
@@ -50,6 +57,11 @@ error_chain! {
             display("variables {:?} unbound at query execution time", names)
         }
 
+        InvalidPullPattern(t: String) {
+            description("invalid pull pattern")
+            display("invalid pull pattern: {}", t)
+        }
+
         InvalidArgumentName(name: String) {
             description("invalid argument name")
             display("invalid argument name: '{}'", name)
@@ -59,5 +71,124 @@ error_chain! {
             description("unknown attribute")
             display("unknown attribute: '{}'", kw)
         }
+
+        QueryInterrupted {
+            description("query interrupted")
+            display("query interrupted")
+        }
+
+        TransactInterrupted(entities: usize, datoms: usize) {
+            description("transaction interrupted")
+            display("transaction interrupted after reading {} entities and writing {} datoms", entities, datoms)
+        }
+
+        ValidationFailed(attribute: mentat_query::NamespacedKeyword, value: TypedValue, message: String) {
+            description("value rejected by a validator")
+            display("value {:?} rejected for '{}': {}", value, attribute, message)
+        }
+
+        QueryTimedOut(elapsed: Duration) {
+            description("query timed out")
+            display("query timed out after {}ms", elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64)
+        }
+
+        WaitTimeout(timeout: Duration) {
+            description("timed out waiting for a change")
+            display("no change within {}ms", timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64)
+        }
+
+        ResultLimitExceeded(max_rows: usize) {
+            description("query produced too many results")
+            display("query produced more than {} rows", max_rows)
+        }
+
+        TransactionNotAVector(found: &'static str) {
+            description("transaction is not a vector")
+            display("expected a transaction vector, got {}; did you forget the outer brackets?", found)
+        }
+
+        NotUniqueAttribute(kw: mentat_query::NamespacedKeyword) {
+            description("attribute is not unique")
+            display("attribute is not :db/unique: '{}'", kw)
+        }
+
+        IdentAlreadyInUse(kw: mentat_query::NamespacedKeyword) {
+            description("ident already in use")
+            display("ident already in use: '{}'", kw)
+        }
+
+        BadSchemaDefinition(t: String) {
+            description("bad schema definition")
+            display("bad schema definition: {}", t)
+        }
+
+        RequiredAttributesMissing(missing: Vec<(Entid, mentat_query::NamespacedKeyword)>) {
+            description("entities are missing required attributes")
+            display("entities are missing required attributes: {}",
+                    missing.iter().map(|&(e, ref a)| format!("{} {}", e, a)).collect::<Vec<_>>().join(", "))
+        }
+
+        SchemaLocked {
+            description("schema is locked")
+            display("cannot change the schema while it is locked")
+        }
+
+        MetadataPoisoned {
+            description("metadata poisoned")
+            display("a panic interrupted a commit, leaving the metadata out of step with the store; reopen the store")
+        }
+
+        TransactorStopped {
+            description("transactor thread stopped")
+            display("the background transactor thread stopped before replying")
+        }
+
+        ReadOnlyConnection {
+            description("connection is read-only")
+            display("cannot write using a read-only connection")
+        }
+
+        DatabaseBusy {
+            description("database is locked")
+            display("database is locked by another connection")
+        }
+
+        LostTransactRace {
+            description("lost the transact race")
+            display("another transaction through this connection committed first")
+        }
+
+        UnknownSavepoint(name: String) {
+            description("unknown savepoint")
+            display("no savepoint named '{}' in this transaction", name)
+        }
+
+        InvalidExport(t: String) {
+            description("invalid export")
+            display("invalid export: {}", t)
+        }
+
+        InvalidMigration(t: String) {
+            description("invalid migration")
+            display("invalid migration: {}", t)
+        }
+
+        InvalidPartition(t: String) {
+            description("invalid partition")
+            display("invalid partition: {}", t)
+        }
+
+        /// An entity of a transaction given as text couldn't be turned into datoms.  The entity
+        /// is identified by its index in the transaction, counting from 0, and its text; the next
+        /// error in the chain says what was wrong with it.
+        InvalidEntity(entity_index: usize, entity_edn: String) {
+            description("invalid transaction entity")
+            display("entity {} is invalid: {}", entity_index, entity_edn)
+        }
+
+        TransactionStreamError(line: usize, offset: usize, message: String) {
+            description("error reading a stream of transactions")
+            display("error reading the entity at line {}, byte {}: {}", line, offset, message)
+        }
     }
 }
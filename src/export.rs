@@ -0,0 +1,349 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Export the contents of a store as EDN, and read such an export back in.  See
+//! `Conn::export_edn` and `Conn::import_edn`.
+//!
+//! An export looks like
+//!
+//! ```edn
+//! {:partitions {:db.part/db {:start 0 :index 41} ...}
+//!  :schema {...}
+//!  :transactions [{:tx 268435457
+//!                  :instant #instmicros 1493399581314000
+//!                  :datoms [[:db/add 65536 65 "Alice"] ...]}
+//!                 ...]}
+//! ```
+//!
+//! Entities, attributes, and transactions are all recorded by entid, so that replaying the
+//! transactions into a fresh store reproduces them exactly.  `:schema` is the schema of the store
+//! when it was exported; it's informational, and is checked after import.
+//!
+//! Each transaction's `:datoms` are a transaction that reproduces it: the `:db/txInstant` datom is
+//! recorded as `:instant`, and a retraction of a cardinality-one attribute that's replaced in the
+//! same transaction is left to the transactor.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::{
+    Read,
+    Write,
+};
+
+use rusqlite;
+
+use edn;
+use edn::{
+    DateTime,
+    ToMicros,
+    Utc,
+};
+use edn::utils::escape_text;
+
+use mentat_core::{
+    Entid,
+    Schema,
+    TypedValue,
+};
+
+use mentat_db::TX0;
+use mentat_db::db::TypedSQLValue;
+use mentat_db::types::Partition;
+use mentat_db::verify::attribute_list;
+use mentat_db::PartitionMap;
+
+use mentat_tx::entities::Entity;
+use mentat_tx_parser;
+
+use errors::*;
+
+/// A transaction read from an export, ready to be replayed.
+pub struct ExportedTransaction {
+    pub tx_id: Entid,
+    pub tx_instant: DateTime<Utc>,
+    pub entities: Vec<Entity>,
+}
+
+/// The contents of an export.
+pub struct Export {
+    pub partition_map: PartitionMap,
+    pub schema: edn::Value,
+    pub transactions: Vec<ExportedTransaction>,
+}
+
+fn bad_export<T>(message: &str) -> Result<T> {
+    bail!(ErrorKind::InvalidExport(message.to_string()))
+}
+
+/// Render `value` as EDN that parses back to the same value for an attribute of its type.
+fn write_value(value: &TypedValue) -> Result<String> {
+    Ok(match value {
+        &TypedValue::Ref(x) => x.to_string(),
+        &TypedValue::Boolean(x) => x.to_string(),
+        &TypedValue::Long(x) => x.to_string(),
+        &TypedValue::Double(x) => {
+            let x = x.into_inner();
+            if x.is_nan() {
+                "#f NaN".to_string()
+            } else if x.is_infinite() {
+                if x > 0.0 { "#f +Infinity".to_string() } else { "#f -Infinity".to_string() }
+            } else {
+                // Always includes an exponent, so that integral doubles don't read back as longs.
+                format!("{:e}", x)
+            }
+        },
+        // Exact to the microsecond, unlike RFC 3339 strings.
+        &TypedValue::Instant(ref x) => format!("#instmicros {}", x.to_micros()),
+        &TypedValue::String(ref x) => format!("\"{}\"", escape_text(x)),
+        &TypedValue::Uuid(ref x) => format!("#uuid \"{}\"", x.hyphenated()),
+        &TypedValue::Keyword(ref x) => x.to_string(),
+    })
+}
+
+/// Write every transaction after the bootstrap transaction, the partition map, and the schema of
+/// the store to `w`.
+pub fn write_export(sqlite: &rusqlite::Connection, schema: &Schema, w: &mut Write) -> Result<()> {
+    let tx_instant = schema.get_entid(&edn::NamespacedKeyword::new("db", "txInstant"))
+                           .expect(":db/txInstant is always present");
+
+    write!(w, "{{:partitions {{")?;
+    let mut stmt = sqlite.prepare("SELECT part, start, idx FROM parts ORDER BY start, part")?;
+    let parts: Result<Vec<(String, i64, i64)>> = stmt.query_and_then(&[], |row| -> Result<(String, i64, i64)> {
+        Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?))
+    })?.collect();
+    for (i, (part, start, index)) in parts?.into_iter().enumerate() {
+        if i > 0 {
+            write!(w, "\n              ")?;
+        }
+        write!(w, "{} {{:start {} :index {}}}", part, start, index)?;
+    }
+    write!(w, "}}\n :schema {}\n :transactions [", schema.to_edn_value())?;
+
+    // Fulltext values are stored as rowids, and `transactions` doesn't say which rows are fulltext
+    // indexed.
+    let fulltext_attributes = attribute_list(schema, |attribute| attribute.fulltext);
+    let s = format!(r#"
+      SELECT t.tx, t.e, t.a, t.v, t.value_type_tag, t.added, f.text
+      FROM transactions AS t
+      LEFT JOIN fulltext_values AS f ON t.a IN ({}) AND f.rowid = t.v
+      WHERE t.tx > ?
+      ORDER BY t.tx ASC, t.e ASC, t.a ASC, t.value_type_tag ASC, t.v ASC, t.added ASC"#,
+                    fulltext_attributes);
+    let mut stmt = sqlite.prepare(s.as_str())?;
+    let rows: Result<Vec<(Entid, Entid, Entid, TypedValue, bool)>> = stmt.query_and_then(&[&TX0], |row| -> Result<(Entid, Entid, Entid, TypedValue, bool)> {
+        let text: Option<String> = row.get_checked(6)?;
+        let value = match text {
+            Some(text) => TypedValue::String(text.into()),
+            None => TypedValue::from_sql_value_pair(row.get_checked(3)?, row.get_checked(4)?)?,
+        };
+        Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?, value, row.get_checked(5)?))
+    })?.collect();
+    let rows = rows?;
+
+    let mut first = true;
+    let mut i = 0;
+    while i < rows.len() {
+        let tx = rows[i].0;
+        let end = rows[i..].iter().position(|row| row.0 != tx).map(|n| i + n).unwrap_or(rows.len());
+        let datoms = &rows[i..end];
+        i = end;
+
+        let instant = datoms.iter().filter_map(|&(_, e, a, ref v, added)| {
+            if e == tx && a == tx_instant && added { Some(v) } else { None }
+        }).next();
+        let instant = match instant {
+            Some(&TypedValue::Instant(ref instant)) => instant.to_micros(),
+            _ => bail!(ErrorKind::InvalidExport(format!("transaction {} has no :db/txInstant", tx))),
+        };
+
+        // The transactor retracts the old value of a cardinality-one attribute itself.
+        let replaced: BTreeSet<(Entid, Entid)> = datoms.iter()
+                                                       .filter(|&&(_, _, _, _, added)| added)
+                                                       .map(|&(_, e, a, _, _)| (e, a))
+                                                       .collect();
+
+        if !first {
+            write!(w, "\n                ")?;
+        }
+        first = false;
+        write!(w, "{{:tx {}\n                  :instant #instmicros {}\n                  :datoms [", tx, instant)?;
+
+        let mut first_datom = true;
+        for &(_, e, a, ref v, added) in datoms {
+            if e == tx && a == tx_instant {
+                continue;
+            }
+            if !added {
+                let attribute = schema.attribute_for_entid(a)
+                                      .ok_or_else(|| ErrorKind::InvalidExport(format!("no attribute {} in transaction {}", a, tx)))?;
+                if !attribute.multival && replaced.contains(&(e, a)) {
+                    continue;
+                }
+            }
+
+            if !first_datom {
+                write!(w, "\n                           ")?;
+            }
+            first_datom = false;
+            write!(w, "[{} {} {} {}]", if added { ":db/add" } else { ":db/retract" }, e, a, write_value(v)?)?;
+        }
+        write!(w, "]}}")?;
+    }
+    write!(w, "]}}\n")?;
+    Ok(())
+}
+
+fn get<'a>(map: &'a BTreeMap<edn::Value, edn::Value>, key: &str) -> Result<&'a edn::Value> {
+    match map.get(&edn::Value::Keyword(edn::Keyword::new(key))) {
+        Some(value) => Ok(value),
+        None => bail!(ErrorKind::InvalidExport(format!("missing :{}", key))),
+    }
+}
+
+fn get_integer(map: &BTreeMap<edn::Value, edn::Value>, key: &str) -> Result<i64> {
+    match get(map, key)?.as_integer() {
+        Some(x) => Ok(x),
+        None => bail!(ErrorKind::InvalidExport(format!(":{} is not an integer", key))),
+    }
+}
+
+/// Read an export written by `write_export`.
+pub fn read_export(r: &mut Read) -> Result<Export> {
+    let mut s = String::new();
+    r.read_to_string(&mut s)?;
+    let value = edn::parse::value(s.as_str())?.without_spans();
+
+    let export = match value.as_map() {
+        Some(map) => map,
+        None => return bad_export("expected a map"),
+    };
+
+    let mut partition_map = PartitionMap::default();
+    match get(export, "partitions")?.as_map() {
+        Some(parts) => {
+            for (part, partition) in parts {
+                let (part, partition) = match (part.as_namespaced_keyword(), partition.as_map()) {
+                    (Some(part), Some(partition)) => (part, partition),
+                    _ => return bad_export("expected partitions like {:db.part/name {:start 0 :index 0}}"),
+                };
+                partition_map.insert(part.to_string(),
+                                     Partition::new(get_integer(partition, "start")?, get_integer(partition, "index")?));
+            }
+        },
+        None => return bad_export(":partitions is not a map"),
+    }
+
+    let schema = get(export, "schema")?.clone();
+
+    let mut transactions = vec![];
+    match get(export, "transactions")?.as_vector() {
+        Some(txs) => {
+            for tx in txs {
+                let tx = match tx.as_map() {
+                    Some(tx) => tx,
+                    None => return bad_export("expected transactions like {:tx 268435457 :instant #instmicros 0 :datoms []}"),
+                };
+                let tx_instant = match get(tx, "instant")?.as_instant() {
+                    Some(instant) => instant,
+                    None => return bad_export(":instant is not an instant"),
+                };
+                let datoms: edn::ValueAndSpan = get(tx, "datoms")?.clone().into();
+                transactions.push(ExportedTransaction {
+                    tx_id: get_integer(tx, "tx")?,
+                    tx_instant: tx_instant,
+                    entities: mentat_tx_parser::Tx::parse(&datoms)?,
+                });
+            }
+        },
+        None => return bad_export(":transactions is not a vector"),
+    }
+
+    Ok(Export {
+        partition_map: partition_map,
+        schema: schema,
+        transactions: transactions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_db::debug;
+
+    use conn::Conn;
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        conn.transact(&mut sqlite, r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}
+                                       {:db/ident :test/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                                       {:db/ident :test/height :db/valueType :db.type/double :db/cardinality :db.cardinality/one}
+                                       {:db/ident :test/born :db/valueType :db.type/instant :db/cardinality :db.cardinality/one}
+                                       {:db/ident :test/id :db/valueType :db.type/uuid :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+                                       {:db/ident :test/kind :db/valueType :db.type/keyword :db/cardinality :db.cardinality/one}
+                                       {:db/ident :test/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+                                       {:db/ident :test/active :db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}]"#)
+            .expect("transact succeeded");
+        let report = conn.transact(&mut sqlite, r#"[{:db/id "a"
+                                                     :test/name "Alice"
+                                                     :test/age 30
+                                                     :test/height 2.0
+                                                     :test/born #inst "1987-06-05T04:03:02.123456Z"
+                                                     :test/id #uuid "550e8400-e29b-41d4-a716-446655440000"
+                                                     :test/kind :test/person
+                                                     :test/active true}]"#)
+                         .expect("transact succeeded");
+        let a = report.tempids["a"];
+        let report = conn.transact(&mut sqlite, format!(r#"[[:db/add "b" :test/friend {a}]
+                                                           [:db/add {a} :test/friend "b"]
+                                                           [:db/add {a} :test/age 31]]"#, a = a).as_str())
+                         .expect("transact succeeded");
+        let b = report.tempids["b"];
+        conn.transact(&mut sqlite, format!(r#"[[:db/retract {a} :test/friend {b}]
+                                               [:db/retract {a} :test/active true]
+                                               [:db/add {b} :test/name "Bob \"the \\ builder\""]]"#, a = a, b = b).as_str())
+            .expect("transact succeeded");
+
+        let mut exported = vec![];
+        conn.export_edn(&mut sqlite, &mut exported).expect("exported");
+
+        let (mut restored, mut restored_sqlite) = Conn::in_memory().unwrap();
+        restored.import_edn(&mut restored_sqlite, &mut &exported[..]).expect("imported");
+
+        // The same history, down to transaction IDs and instants.
+        let transactions = |conn: &Conn, sqlite: &rusqlite::Connection| {
+            debug::transactions_after(sqlite, &*conn.current_schema(), TX0).expect("transactions").into_edn()
+        };
+        assert_eq!(transactions(&conn, &sqlite), transactions(&restored, &restored_sqlite));
+        assert_eq!(conn.state_hash(&sqlite).expect("hash"), restored.state_hash(&restored_sqlite).expect("hash"));
+        assert_eq!(conn.current_schema(), restored.current_schema());
+
+        // The restored store carries on allocating where the original left off.
+        let next = conn.transact(&mut sqlite, r#"[[:db/add "c" :test/age 1]]"#).expect("transact succeeded");
+        let restored_next = restored.transact(&mut restored_sqlite, r#"[[:db/add "c" :test/age 1]]"#).expect("transact succeeded");
+        assert_eq!(next.tx_id, restored_next.tx_id);
+        assert_eq!(next.tempids, restored_next.tempids);
+
+        // Exporting the restored store gives the same export.
+        let mut reexported = vec![];
+        let mut exported = vec![];
+        conn.export_edn(&mut sqlite, &mut exported).expect("exported");
+        restored.export_edn(&mut restored_sqlite, &mut reexported).expect("exported");
+        assert_eq!(String::from_utf8(exported.clone()).unwrap(), String::from_utf8(reexported).unwrap());
+
+        // Only an empty store can be imported into.
+        match restored.import_edn(&mut restored_sqlite, &mut &exported[..]) {
+            Err(Error(ErrorKind::InvalidExport(_), _)) => {},
+            x => panic!("expected an invalid export error, got {:?}", x),
+        }
+    }
+}
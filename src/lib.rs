@@ -15,6 +15,10 @@ extern crate error_chain;
 
 extern crate rusqlite;
 
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+
 extern crate edn;
 extern crate mentat_core;
 extern crate mentat_db;
@@ -31,8 +35,20 @@ use rusqlite::Connection;
 
 pub mod errors;
 pub mod ident;
+pub mod async_conn;
 pub mod conn;
+mod constraints;
+pub mod datom_diff;
+pub mod entity_builder;
+mod entity_stream;
+pub mod export;
+pub mod migrations;
+pub mod pull;
 pub mod query;
+pub mod schema_diff;
+mod spans;
+pub mod store;
+pub mod watch;
 
 pub fn get_name() -> String {
     return String::from("mentat");
@@ -44,26 +60,103 @@ pub fn get_connection() -> Connection {
 }
 
 pub use mentat_core::{
+    KnownEntid,
     TypedValue,
     ValueType,
 };
 
+pub use mentat_core::attribute::Unique as UniqueKind;
+
 pub use mentat_db::{
+    AppliedConnectionOptions,
+    ConnectionOptions,
+    JournalMode,
+    IntegrityIssue,
+    PartitionInfo,
+    PartitionName,
+    Synchronous,
+    TempIdResolution,
+    TxReport,
+    VerificationProblem,
+    VerificationReport,
     new_connection,
+    new_connection_with_options,
+};
+
+#[cfg(feature = "sqlcipher")]
+pub use mentat_db::{
+    change_encryption_key,
+    new_connection_with_key,
 };
 
 pub use query::{
+    ControlFlow,
+    FromTuple,
+    FromValue,
     NamespacedKeyword,
     PlainSymbol,
+    QueryExplanation,
     QueryInputs,
+    QueryInputsBuilder,
+    QueryInterrupt,
+    QueryOptions,
+    QueryOutput,
     QueryResults,
+    QueryRow,
     Variable,
+    q_explain,
     q_once,
+    q_once_each,
+    q_once_parsed,
+    q_once_with_options,
+};
+
+pub use schema_diff::{
+    AttributeFlag,
+    SchemaDiff,
+    diff_schemas,
+    schema_diff,
+    vocabulary_to_edn,
+};
+
+pub use datom_diff::{
+    DatomDiff,
+    diff_datoms,
+    diff_stores,
 };
 
+pub use migrations::Migrator;
+
 pub use conn::{
+    ChunkCommit,
+    CompactionReport,
     Conn,
+    ConnStats,
+    GcReport,
+    InProgressRead,
     Metadata,
+    Snapshot,
+    TransactOptions,
+    TransactProgress,
+    VacuumMode,
+};
+
+pub use constraints::Validator;
+
+pub use entity_builder::{
+    IntoTermEntity,
+    TempIdHandle,
+    TermBuilder,
+};
+
+pub use async_conn::{
+    AsyncConn,
+    TxFuture,
+};
+
+pub use store::{
+    Reader,
+    Store,
 };
 
 #[cfg(test)]
@@ -0,0 +1,261 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Versioned application schema migrations.
+//!
+//! `mentat_db::db::ensure_current_version` manages the version of Mentat's own storage; this
+//! module does the same for an application's vocabulary and data.  A `Migrator` is an ordered
+//! list of numbered steps:
+//!
+//! ```ignore
+//! Migrator::new()
+//!     .version(1, "[{:db/ident :person/name :db/valueType :db.type/string ...}]")
+//!     .version_with(2, |in_progress| in_progress.transact("[...]"))
+//!     .run(&mut conn, &mut sqlite)?;
+//! ```
+//!
+//! The version that has been applied is recorded in the store itself, as the value of
+//! `:mentat.migrations/version` on the entity `:mentat.migrations/app`.  Each step is applied in
+//! its own transaction, together with the assertion of its version number, so a step that fails
+//! leaves the store at the last version that was fully applied.
+
+use rusqlite;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::TypedValue;
+
+use conn::{
+    Conn,
+    InProgress,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// A migration step: either EDN to transact, or a Rust function that transacts against the
+/// transaction in which the step is applied.
+enum Step {
+    Transact(String),
+    Function(Box<for<'a, 'c> Fn(&mut InProgress<'a, 'c>) -> Result<()>>),
+}
+
+/// An ordered sequence of numbered migration steps.  See the module documentation.
+pub struct Migrator {
+    steps: Vec<(i64, Step)>,
+}
+
+fn version_attribute() -> NamespacedKeyword {
+    NamespacedKeyword::new("mentat.migrations", "version")
+}
+
+fn app_ident() -> NamespacedKeyword {
+    NamespacedKeyword::new("mentat.migrations", "app")
+}
+
+/// Return the version of the application schema recorded in the store, or 0 if no migration has
+/// ever been applied.
+pub fn current_version(conn: &Conn, sqlite: &rusqlite::Connection) -> Result<i64> {
+    let app = match conn.entid_for_ident(&app_ident()) {
+        Some(app) => app,
+        None => return Ok(0),
+    };
+
+    match conn.lookup_value_for_attribute(sqlite, app, &version_attribute())? {
+        Some(TypedValue::Long(version)) => Ok(version),
+        None => Ok(0),
+        Some(v) => bail!(ErrorKind::InvalidMigration(format!("recorded version {:?} is not a long", v))),
+    }
+}
+
+impl Migrator {
+    pub fn new() -> Migrator {
+        Migrator {
+            steps: vec![],
+        }
+    }
+
+    /// Add a step that transacts the given EDN to bring the store to `version`.
+    pub fn version(mut self, version: i64, transaction: &str) -> Migrator {
+        self.steps.push((version, Step::Transact(transaction.to_string())));
+        self
+    }
+
+    /// Add a step that runs `f` to bring the store to `version`.  `f` may transact as many times
+    /// as it likes; everything it does is committed, or not, together.
+    pub fn version_with<F>(mut self, version: i64, f: F) -> Migrator
+        where F: 'static + for<'a, 'c> Fn(&mut InProgress<'a, 'c>) -> Result<()> {
+        self.steps.push((version, Step::Function(Box::new(f))));
+        self
+    }
+
+    /// The version a store is at after every step has been applied.
+    pub fn latest_version(&self) -> i64 {
+        self.steps.iter().map(|&(version, _)| version).max().unwrap_or(0)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut previous = 0;
+        for &(version, _) in &self.steps {
+            if version <= previous {
+                bail!(ErrorKind::InvalidMigration(format!("version {} must be positive and follow version {}", version, previous)));
+            }
+            previous = version;
+        }
+        Ok(())
+    }
+
+    /// Install the attribute and entity that record the applied version, if they aren't already.
+    fn ensure_bookkeeping(conn: &mut Conn, sqlite: &mut rusqlite::Connection) -> Result<()> {
+        if conn.entid_for_ident(&app_ident()).is_some() {
+            return Ok(());
+        }
+        conn.transact(sqlite, "[{:db/ident :mentat.migrations/version
+                                 :db/valueType :db.type/long
+                                 :db/cardinality :db.cardinality/one}
+                                {:db/ident :mentat.migrations/app}]")?;
+        Ok(())
+    }
+
+    /// Apply, in order, each step newer than the version recorded in the store, and return the
+    /// version the store is then at.
+    ///
+    /// Each step is committed in its own transaction together with its version number.  If a step
+    /// fails, its transaction is rolled back and its error returned: the store remains at the
+    /// version of the last step that succeeded.
+    pub fn run(&self, conn: &mut Conn, sqlite: &mut rusqlite::Connection) -> Result<i64> {
+        self.validate()?;
+
+        let mut version = current_version(conn, sqlite)?;
+        if version > self.latest_version() {
+            bail!(ErrorKind::InvalidMigration(format!("store is at version {}, but the latest migration is version {}", version, self.latest_version())));
+        }
+
+        for &(step_version, ref step) in &self.steps {
+            if step_version <= version {
+                continue;
+            }
+
+            Migrator::ensure_bookkeeping(conn, sqlite)?;
+
+            let mut in_progress = conn.begin_transaction(sqlite)?;
+            match *step {
+                Step::Transact(ref transaction) => in_progress.transact(transaction)?,
+                Step::Function(ref f) => f(&mut in_progress)?,
+            }
+            in_progress.transact(&format!("[[:db/add :mentat.migrations/app :mentat.migrations/version {}]]", step_version))?;
+            in_progress.commit()?;
+
+            version = step_version;
+        }
+
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(conn: &Conn, sqlite: &rusqlite::Connection) -> Vec<TypedValue> {
+        let mut names = conn.q_once(sqlite, "[:find [?name ...] :where [_ :person/name ?name]]", None)
+                            .expect("query")
+                            .into_coll()
+                            .expect("coll");
+        names.sort();
+        names
+    }
+
+    fn migrator() -> Migrator {
+        Migrator::new()
+            .version(1, "[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .version(2, r#"[{:person/name "Alice"}]"#)
+            .version_with(3, |in_progress| {
+                in_progress.transact(r#"[{:person/name "Bob"}]"#)?;
+                in_progress.transact(r#"[{:person/name "Carol"}]"#)
+            })
+    }
+
+    #[test]
+    fn test_fresh_store() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+        assert_eq!(current_version(&conn, &sqlite).unwrap(), 0);
+
+        assert_eq!(migrator().run(&mut conn, &mut sqlite).expect("migrated"), 3);
+        assert_eq!(current_version(&conn, &sqlite).unwrap(), 3);
+        assert_eq!(names(&conn, &sqlite),
+                   vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Bob"), TypedValue::typed_string("Carol")]);
+
+        // Running again does nothing.
+        assert_eq!(migrator().run(&mut conn, &mut sqlite).expect("migrated"), 3);
+        assert_eq!(names(&conn, &sqlite).len(), 3);
+    }
+
+    #[test]
+    fn test_partially_migrated_store() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let first = Migrator::new()
+            .version(1, "[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+            .version(2, r#"[{:person/name "Alice"}]"#);
+        assert_eq!(first.run(&mut conn, &mut sqlite).expect("migrated"), 2);
+        assert_eq!(names(&conn, &sqlite), vec![TypedValue::typed_string("Alice")]);
+
+        // Only the remaining step is applied: Alice isn't added twice.
+        assert_eq!(migrator().run(&mut conn, &mut sqlite).expect("migrated"), 3);
+        assert_eq!(names(&conn, &sqlite),
+                   vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Bob"), TypedValue::typed_string("Carol")]);
+
+        // A store can't be migrated by an older set of steps.
+        match first.run(&mut conn, &mut sqlite) {
+            Err(::errors::Error(ErrorKind::InvalidMigration(_), _)) => {},
+            x => panic!("expected InvalidMigration, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_failing_step() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let failing = migrator()
+            .version_with(4, |in_progress| {
+                // The first transaction succeeds, but is rolled back with the rest of the step.
+                in_progress.transact(r#"[{:person/name "Dave"}]"#)?;
+                in_progress.transact(r#"[{:person/unknown "Eve"}]"#)
+            })
+            .version(5, r#"[{:person/name "Frank"}]"#);
+
+        assert!(failing.run(&mut conn, &mut sqlite).is_err());
+        assert_eq!(current_version(&conn, &sqlite).unwrap(), 3);
+        assert_eq!(names(&conn, &sqlite),
+                   vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Bob"), TypedValue::typed_string("Carol")]);
+
+        // The store is still usable, and a corrected migration picks up where the last one left off.
+        let fixed = migrator().version(4, r#"[{:person/name "Dave"}]"#);
+        assert_eq!(fixed.run(&mut conn, &mut sqlite).expect("migrated"), 4);
+        assert_eq!(names(&conn, &sqlite).len(), 4);
+    }
+
+    #[test]
+    fn test_versions_must_increase() {
+        let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+
+        let out_of_order = Migrator::new()
+            .version(2, "[]")
+            .version(1, "[]");
+        match out_of_order.run(&mut conn, &mut sqlite) {
+            Err(::errors::Error(ErrorKind::InvalidMigration(_), _)) => {},
+            x => panic!("expected InvalidMigration, got {:?}", x),
+        }
+        assert_eq!(current_version(&conn, &sqlite).unwrap(), 0);
+    }
+}
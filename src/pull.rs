@@ -0,0 +1,127 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A minimal pull API: fetch the values of a list of attributes of a single entity.  See
+//! `Conn::pull`.
+//!
+//! A pattern is an EDN vector of attribute keywords, like `[:person/name :person/friends]`.  A
+//! reverse attribute, like `:person/_friends`, names the entities that refer to the pulled entity
+//! by `:person/friends`, and `:db/id` names the entity itself.  Nested patterns, wildcards, and
+//! attribute options aren't supported yet.
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use edn;
+
+use mentat_core::{
+    Entid,
+    Schema,
+    SQLValueType,
+    TypedValue,
+    ValueType,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+use query::{
+    lookup_values,
+};
+
+/// The value of one attribute of a pulled entity.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PullValue {
+    /// The value of a `:db.cardinality/one` attribute, or `:db/id`.
+    One(TypedValue),
+
+    /// The values of a `:db.cardinality/many` attribute, in no particular order, or the entities
+    /// referring to the pulled entity by a reverse attribute, ordered by entid.
+    Many(Vec<TypedValue>),
+}
+
+/// The attributes of a pulled entity, keyed as written in the pattern.  An attribute the entity
+/// has no value for is omitted.
+pub type Pulled = BTreeMap<edn::NamespacedKeyword, PullValue>;
+
+/// Parse `pattern`, an EDN vector of attribute keywords.
+fn parse_pattern(pattern: &str) -> Result<Vec<edn::NamespacedKeyword>> {
+    let items = match edn::parse::value(pattern)?.without_spans() {
+        edn::Value::Vector(items) => items,
+        other => bail!(ErrorKind::InvalidPullPattern(format!("expected a vector, got {}", other))),
+    };
+
+    let mut keywords = Vec::with_capacity(items.len());
+    for item in items {
+        match item {
+            edn::Value::NamespacedKeyword(keyword) => keywords.push(keyword),
+            other => bail!(ErrorKind::InvalidPullPattern(format!("expected an attribute, got {}", other))),
+        }
+    }
+    Ok(keywords)
+}
+
+/// Return the entities that refer to `entity` by the ref attribute `attribute`.
+fn referring_entities(sqlite: &rusqlite::Connection, entity: Entid, attribute: Entid) -> Result<Vec<TypedValue>> {
+    let mut stmt = sqlite.prepare_cached("SELECT e FROM datoms WHERE a = ? AND v = ? AND value_type_tag = ? ORDER BY e")?;
+    let mut rows = stmt.query(&[&attribute, &entity, &ValueType::Ref.value_type_tag()])?;
+    let mut referring = vec![];
+    while let Some(row) = rows.next() {
+        referring.push(TypedValue::Ref(row?.get_checked(0)?));
+    }
+    Ok(referring)
+}
+
+/// Pull the attributes named by `pattern` of `entity`.
+pub fn pull(sqlite: &rusqlite::Connection, schema: &Schema, entity: Entid, pattern: &str) -> Result<Pulled> {
+    let db_id = edn::NamespacedKeyword::new("db", "id");
+
+    let mut pulled = Pulled::new();
+    for keyword in parse_pattern(pattern)? {
+        if keyword == db_id {
+            pulled.insert(keyword, PullValue::One(TypedValue::Ref(entity)));
+            continue;
+        }
+
+        // A reverse attribute is named by its forward attribute, with a leading underscore.
+        let forward = keyword.unreversed().unwrap_or_else(|| keyword.clone());
+        let (a, attribute) = match schema.get_entid(&forward).and_then(|a| schema.attribute_for_entid(a).map(|attribute| (a, attribute))) {
+            Some(x) => x,
+            None => bail!(ErrorKind::UnknownAttribute(forward)),
+        };
+
+        let value = if keyword.is_backward() {
+            if attribute.value_type != ValueType::Ref {
+                bail!(ErrorKind::InvalidPullPattern(format!("{} is not a ref attribute, so {} can't be followed", forward, keyword)));
+            }
+            // Any number of entities might refer to this one, whatever the attribute's
+            // cardinality.
+            let referring = referring_entities(sqlite, entity, a)?;
+            if referring.is_empty() { None } else { Some(PullValue::Many(referring)) }
+        } else {
+            let mut values = lookup_values(sqlite, schema, entity, a)?;
+            if values.is_empty() {
+                None
+            } else if attribute.multival {
+                Some(PullValue::Many(values))
+            } else {
+                Some(PullValue::One(values.remove(0)))
+            }
+        };
+
+        if let Some(value) = value {
+            pulled.insert(keyword, value);
+        }
+    }
+    Ok(pulled)
+}
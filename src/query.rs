@@ -8,7 +8,23 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+
 use rusqlite;
+use rusqlite::Rows;
+
+use edn;
 use rusqlite::types::ToSql;
 
 use mentat_core::{
@@ -17,13 +33,17 @@ use mentat_core::{
     TypedValue,
 };
 
+use mentat_db::debug;
+
 use mentat_query_algebrizer::{
     AlgebraicQuery,
+    EmptyBecause,
     algebrize_with_inputs,
 };
 
 pub use mentat_query_algebrizer::{
     QueryInputs,
+    QueryInputsBuilder,
 };
 
 pub use mentat_query::{
@@ -32,9 +52,12 @@ pub use mentat_query::{
     Variable,
 };
 
+pub use mentat_query::{
+    FindQuery,
+};
+
 use mentat_query::{
     Element,
-    FindQuery,
     FindSpec,
     Pattern,
     PatternNonValuePlace,
@@ -43,6 +66,7 @@ use mentat_query::{
 };
 
 use mentat_query_parser::{
+    parse_find,
     parse_find_string,
 };
 
@@ -51,10 +75,19 @@ use mentat_sql::{
 };
 
 use mentat_query_translator::{
+    ProjectedSelect,
     query_to_select,
 };
 
+use mentat_query_projector::{
+    Projector,
+    column_index,
+};
+
 pub use mentat_query_projector::{
+    FromTuple,
+    FromValue,
+    QueryOutput,
     QueryResults,
 };
 
@@ -63,7 +96,9 @@ use errors::{
     Result,
 };
 
-pub type QueryExecutionResult = Result<QueryResults>;
+use spans;
+
+pub type QueryExecutionResult = Result<QueryOutput>;
 
 pub trait IntoResult {
     fn into_scalar_result(self) -> Result<Option<TypedValue>>;
@@ -161,12 +196,7 @@ pub fn lookup_values_for_attribute<'sqlite, 'schema, 'attribute>
     lookup_values(sqlite, schema, entity, lookup_attribute(schema, attribute)?)
 }
 
-fn run_algebrized_query<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebrized: AlgebraicQuery) -> QueryExecutionResult {
-    if algebrized.is_known_empty() {
-        // We don't need to do any SQL work at all.
-        return Ok(QueryResults::empty(&algebrized.find_spec));
-    }
-
+fn check_all_bound(algebrized: &AlgebraicQuery) -> Result<()> {
     // Because we are running once, we can check that all of our `:in` variables are bound at this point.
     // If they aren't, the user has made an error -- perhaps writing the wrong variable in `:in`, or
     // not binding in the `QueryInput`.
@@ -174,8 +204,13 @@ fn run_algebrized_query<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebriz
     if !unbound.is_empty() {
         bail!(ErrorKind::UnboundVariables(unbound.into_iter().map(|v| v.to_string()).collect()));
     }
+    Ok(())
+}
 
-    let select = query_to_select(algebrized)?;
+/// Run the SQL for `select`, handing the resulting rows and the projector that knows how to
+/// interpret them to `f`.
+fn run_select<'sqlite, F, R>(sqlite: &'sqlite rusqlite::Connection, select: ProjectedSelect, f: F) -> Result<R>
+    where F: for<'stmt> FnOnce(Rows<'stmt>, &Projector) -> Result<R> {
     let SQLQuery { sql, args } = select.query.to_sql_query()?;
 
     let mut statement = sqlite.prepare(sql.as_str())?;
@@ -190,16 +225,210 @@ fn run_algebrized_query<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebriz
         statement.query_named(refs.as_slice())?
     };
 
-    select.projector
-          .project(rows)
-          .map_err(|e| e.into())
+    f(rows, &*select.projector)
+}
+
+/// A token that can be used to interrupt a query running on another thread.
+///
+/// Interrupting sets a flag that the query checks between rows, and also interrupts SQLite, so that
+/// even a single long-running SQL step is aborted.  An interrupted query fails with
+/// `ErrorKind::QueryInterrupted`.  Interruption is permanent: any later query run with the same
+/// token fails immediately.
+#[derive(Clone)]
+pub struct QueryInterrupt {
+    interrupted: Arc<AtomicBool>,
+    handle: Arc<rusqlite::InterruptHandle>,
+}
+
+impl QueryInterrupt {
+    /// Make a token that can interrupt queries run against `sqlite`.
+    pub fn new(sqlite: &rusqlite::Connection) -> QueryInterrupt {
+        QueryInterrupt {
+            interrupted: Arc::new(AtomicBool::new(false)),
+            handle: Arc::new(sqlite.get_interrupt_handle()),
+        }
+    }
+
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.handle.interrupt();
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for QueryInterrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QueryInterrupt {{ interrupted: {} }}", self.is_interrupted())
+    }
+}
+
+/// Options that constrain how a query is run.  The default imposes no constraints.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOptions {
+    /// If set, fail with `ErrorKind::ResultLimitExceeded` as soon as the query produces more than
+    /// this many rows, rather than materializing an arbitrarily large result set.
+    pub max_rows: Option<usize>,
+
+    /// If set, the query can be interrupted using this token.
+    pub interrupt: Option<QueryInterrupt>,
+
+    /// If set, fail with `ErrorKind::QueryTimedOut` if the query hasn't completed within this
+    /// duration.
+    pub timeout: Option<Duration>,
+}
+
+impl QueryOptions {
+    /// Return these options with the query limited to running for at most `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> QueryOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn is_unconstrained(&self) -> bool {
+        self.max_rows.is_none() && self.interrupt.is_none() && self.timeout.is_none()
+    }
+
+    fn check_interrupted(&self) -> Result<()> {
+        match self.interrupt {
+            Some(ref interrupt) if interrupt.is_interrupted() => bail!(ErrorKind::QueryInterrupted),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Interrupts a SQLite connection if not dropped before a timeout elapses.
+///
+/// Dropping a `Timer` waits for its thread to exit, so a timer that outlives its query can never
+/// interrupt a later query on the same connection.
+struct Timer {
+    start: Instant,
+    fired: Arc<AtomicBool>,
+    cancel: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    fn start(sqlite: &rusqlite::Connection, timeout: Duration) -> Timer {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let fired = Arc::new(AtomicBool::new(false));
+        let handle = sqlite.get_interrupt_handle();
+
+        let thread = {
+            let fired = fired.clone();
+            thread::spawn(move || {
+                // We never send: the channel disconnects when the `Timer` is dropped.
+                if let Err(mpsc::RecvTimeoutError::Timeout) = cancelled.recv_timeout(timeout) {
+                    fired.store(true, Ordering::SeqCst);
+                    handle.interrupt();
+                }
+            })
+        };
+
+        Timer {
+            start: Instant::now(),
+            fired: fired,
+            cancel: Some(cancel),
+            thread: Some(thread),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.fired.load(Ordering::SeqCst) {
+            bail!(ErrorKind::QueryTimedOut(self.start.elapsed()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.cancel.take();
+        if let Some(thread) = self.thread.take() {
+            // The timer thread can't panic.
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Shape `rows`, each of which is the projection of a single SQL row, into results of the same
+/// kind as `empty`.
+fn shape_rows(empty: QueryResults, rows: Vec<Vec<TypedValue>>) -> QueryResults {
+    match empty {
+        QueryResults::Scalar(_) => QueryResults::Scalar(rows.into_iter().next().and_then(|mut row| row.pop())),
+        QueryResults::Tuple(_) => QueryResults::Tuple(rows.into_iter().next()),
+        QueryResults::Coll(_) => QueryResults::Coll(rows.into_iter().filter_map(|mut row| row.pop()).collect()),
+        QueryResults::Rel(_) => QueryResults::Rel(rows),
+    }
+}
+
+fn run_algebrized_query<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebrized: AlgebraicQuery) -> QueryExecutionResult {
+    run_algebrized_query_with_options(sqlite, algebrized, &QueryOptions::default())
+}
+
+fn run_algebrized_query_with_options<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebrized: AlgebraicQuery, options: &QueryOptions) -> QueryExecutionResult {
+    if algebrized.is_known_empty() {
+        // We don't need to do any SQL work at all.
+        return Ok(QueryOutput::empty(algebrized.find_spec));
+    }
+
+    check_all_bound(&algebrized)?;
+
+    let spec = algebrized.find_spec.clone();
+    let empty = QueryResults::empty(&spec);
+    let select = query_to_select(algebrized)?;
+
+    if options.is_unconstrained() {
+        let results = run_select(sqlite, select, |rows, projector| {
+            projector.project(rows)
+                     .map_err(|e| e.into())
+        })?;
+        return Ok(QueryOutput::new(spec, results));
+    }
+
+    let timer = options.timeout.map(|timeout| Timer::start(sqlite, timeout));
+    let check = || -> Result<()> {
+        options.check_interrupted()?;
+        if let Some(ref timer) = timer {
+            timer.check()?;
+        }
+        Ok(())
+    };
+
+    check()?;
+
+    // Check our constraints as we go, so that we never hold more than `max_rows` rows, and don't
+    // keep working after being interrupted or timing out.
+    let results = run_select(sqlite, select, |mut rows, projector| {
+        let mut out: Vec<Vec<TypedValue>> = vec![];
+        while let Some(r) = rows.next() {
+            check()?;
+            let row = r?;
+            if let Some(max_rows) = options.max_rows {
+                if out.len() >= max_rows {
+                    bail!(ErrorKind::ResultLimitExceeded(max_rows));
+                }
+            }
+            out.push(projector.project_row(&row)?);
+        }
+        Ok(shape_rows(empty, out))
+    });
+
+    // Interrupting SQLite surfaces as an arbitrary SQLite error; report it as an interruption or
+    // a timeout, as appropriate.
+    if results.is_err() {
+        check()?;
+    }
+    results.map(|results| QueryOutput::new(spec, results))
 }
 
 /// Take an EDN query string, a reference to an open SQLite connection, a Mentat schema, and an
 /// optional collection of input bindings (which should be keyed by `"?varname"`), and execute the
 /// query immediately, blocking the current thread.
 /// Returns a structure that corresponds to the kind of input query, populated with `TypedValue`
-/// instances.
+/// instances, together with the variables that name its columns.
 /// The caller is responsible for ensuring that the SQLite connection has an open transaction if
 /// isolation is required.
 pub fn q_once<'sqlite, 'schema, 'query, T>
@@ -209,8 +438,229 @@ pub fn q_once<'sqlite, 'schema, 'query, T>
  inputs: T) -> QueryExecutionResult
         where T: Into<Option<QueryInputs>>
 {
+    q_once_with_options(sqlite, schema, query, inputs, &QueryOptions::default())
+}
+
+/// Like `q_once`, but constrained by the provided `QueryOptions`.
+pub fn q_once_with_options<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ options: &QueryOptions) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let span = spans::query(query);
+
+    let parsed = parse_find_string(query)?;
+    let results = run_find_query(sqlite, schema, parsed, inputs.into(), options)?;
+    span.record_i64("rows", results.len() as i64);
+    Ok(results)
+}
+
+/// Like `q_once`, but taking a query that has already been parsed as EDN, such as one received
+/// from elsewhere in structured form.
+pub fn q_once_parsed<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query edn::Value,
+ inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let span = spans::query(query);
+
+    let expr: edn::ValueAndSpan = query.clone().into();
+    let parsed = parse_find(&expr)?;
+    let results = run_find_query(sqlite, schema, parsed, inputs.into(), &QueryOptions::default())?;
+    span.record_i64("rows", results.len() as i64);
+    Ok(results)
+}
+
+fn run_find_query<'sqlite, 'schema>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ parsed: FindQuery,
+ inputs: Option<QueryInputs>,
+ options: &QueryOptions) -> QueryExecutionResult
+{
+    let algebrized = algebrize_with_inputs(schema, parsed, 0, inputs.unwrap_or(QueryInputs::default()))?;
+    run_algebrized_query_with_options(sqlite, algebrized, options)
+}
+
+/// Returned by the callback passed to `q_once_each` to indicate whether more rows are wanted.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ControlFlow {
+    Continue,
+    Break,
+}
+
+/// A single row of query results, as produced by `q_once_each`.
+///
+/// Values are in the order of the query's `:find` elements, and can be retrieved by position or by
+/// the name of the variable that bound them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryRow<'v> {
+    variables: &'v [Variable],
+    values: Vec<TypedValue>,
+}
+
+impl<'v> QueryRow<'v> {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The variables bound by this row, in order.
+    pub fn variables(&self) -> &[Variable] {
+        self.variables
+    }
+
+    pub fn values(&self) -> &[TypedValue] {
+        self.values.as_slice()
+    }
+
+    pub fn into_values(self) -> Vec<TypedValue> {
+        self.values
+    }
+
+    /// Return the value at `index`, or `None` if the index is out of bounds.
+    pub fn get(&self, index: usize) -> Option<&TypedValue> {
+        self.values.get(index)
+    }
+
+    /// Return the value bound to the variable named `name`, which should include the leading
+    /// `?`, or `None` if the query doesn't project that variable exactly once.  See
+    /// `QueryOutput::column_index`.
+    pub fn get_by_name(&self, name: &str) -> Option<&TypedValue> {
+        column_index(self.variables, name).and_then(|index| self.values.get(index))
+    }
+}
+
+/// Like `q_once`, but rather than materializing the results, project each row in turn and pass it
+/// to `f` as soon as it is read from SQLite.  Iteration stops -- and no further SQL work is done --
+/// as soon as `f` returns `ControlFlow::Break` or an error.
+///
+/// Returns the number of rows passed to `f`.
+pub fn q_once_each<'sqlite, 'schema, 'query, T, F>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ mut f: F) -> Result<usize>
+        where T: Into<Option<QueryInputs>>,
+              F: FnMut(QueryRow) -> Result<ControlFlow>
+{
+    let span = spans::query(query);
+
     let parsed = parse_find_string(query)?;
     let algebrized = algebrize_with_inputs(schema, parsed, 0, inputs.into().unwrap_or(QueryInputs::default()))?;
 
-    run_algebrized_query(sqlite, algebrized)
+    if algebrized.is_known_empty() {
+        // We don't need to do any SQL work at all.
+        span.record_i64("rows", 0);
+        return Ok(0);
+    }
+
+    check_all_bound(&algebrized)?;
+
+    let variables: Vec<Variable> = algebrized.find_spec.variables();
+
+    let select = query_to_select(algebrized)?;
+    let count = run_select(sqlite, select, |mut rows, projector| {
+        let mut count = 0;
+        while let Some(r) = rows.next() {
+            let row = r?;
+            let values = projector.project_row(&row)?;
+            count += 1;
+            let row = QueryRow {
+                variables: variables.as_slice(),
+                values: values,
+            };
+            if f(row)? == ControlFlow::Break {
+                break;
+            }
+        }
+        Ok(count)
+    })?;
+
+    span.record_i64("rows", count as i64);
+    Ok(count)
+}
+
+/// A description of how Mentat would run a query, produced by `q_explain`.
+#[derive(Debug)]
+pub struct QueryExplanation {
+    /// The parsed query.
+    pub parsed: FindQuery,
+
+    /// A rendering of the algebrized query: the constraints, column bindings, and types derived
+    /// from the query and the schema.
+    pub algebrized: String,
+
+    /// If set, the query is known to produce no results, and no SQL would be run.
+    pub empty_because: Option<EmptyBecause>,
+
+    /// The SQL that would be run, unless the query is known to produce no results.
+    pub sql: Option<String>,
+
+    /// The named parameters that would be bound when running `sql`.
+    pub args: Vec<(String, rusqlite::types::Value)>,
+
+    /// SQLite's `EXPLAIN QUERY PLAN` for `sql`, formatted like `mentat_db::debug::dump_sql_query`.
+    pub plan: Option<String>,
+}
+
+impl QueryExplanation {
+    pub fn is_known_empty(&self) -> bool {
+        self.empty_because.is_some()
+    }
+}
+
+/// Explain how the given EDN query would be run against the given SQLite connection and Mentat
+/// schema, without running it.
+pub fn q_explain<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>>
+{
+    // `FindQuery` isn't `Clone`, and algebrizing consumes it, so we parse twice.
+    let parsed = parse_find_string(query)?;
+    let algebrized = algebrize_with_inputs(schema, parse_find_string(query)?, 0, inputs.into().unwrap_or(QueryInputs::default()))?;
+    let rendered = format!("{:#?}", algebrized.cc);
+
+    if algebrized.is_known_empty() {
+        return Ok(QueryExplanation {
+            parsed: parsed,
+            algebrized: rendered,
+            empty_because: algebrized.cc.empty_because.clone(),
+            sql: None,
+            args: vec![],
+            plan: None,
+        });
+    }
+
+    let select = query_to_select(algebrized)?;
+    let SQLQuery { sql, args } = select.query.to_sql_query()?;
+
+    let plan = {
+        let refs: Vec<(&str, &ToSql)> =
+            args.iter()
+                .map(|&(ref k, ref v)| (k.as_str(), v.as_ref() as &ToSql))
+                .collect();
+        debug::dump_sql_query_named(sqlite, format!("EXPLAIN QUERY PLAN {}", sql).as_str(), refs.as_slice(), None)?
+    };
+
+    Ok(QueryExplanation {
+        parsed: parsed,
+        algebrized: rendered,
+        empty_because: None,
+        sql: Some(sql),
+        args: args.into_iter().map(|(k, v)| (k, (*v).clone())).collect(),
+        plan: Some(plan),
+    })
 }
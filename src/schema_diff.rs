@@ -0,0 +1,544 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Compare a `Schema` against a desired set of attribute definitions, or against another `Schema`.
+//!
+//! This is the planning step for installing or altering vocabulary: it reports which attributes
+//! would be installed, which existing attributes would be altered (and how), and which attributes
+//! are known to the store but absent from the desired definitions.  Nothing is transacted.
+//!
+//! `vocabulary_to_edn` goes the other way, rendering the attributes installed in a store as
+//! definitions that can be transacted into another.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use edn;
+use edn::{
+    NamespacedKeyword,
+};
+
+use mentat_core::{
+    attribute,
+    Attribute,
+    Schema,
+    ValueType,
+};
+
+use mentat_tx::entities::{
+    AtomOrLookupRefOrVectorOrMapNotation,
+    Entid,
+    EntidOrLookupRefOrTempId,
+    Entity,
+    OpType,
+    TempId,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// An attribute flag that can differ between the current and the desired definition of an
+/// attribute.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum AttributeFlag {
+    ValueType,
+    Cardinality,
+    Unique,
+    Index,
+    Fulltext,
+    IsComponent,
+    NoHistory,
+}
+
+impl AttributeFlag {
+    /// The schema attribute that controls this flag, like `:db/index`.
+    pub fn to_edn_value(&self) -> edn::Value {
+        let name = match *self {
+            AttributeFlag::ValueType => "valueType",
+            AttributeFlag::Cardinality => "cardinality",
+            AttributeFlag::Unique => "unique",
+            AttributeFlag::Index => "index",
+            AttributeFlag::Fulltext => "fulltext",
+            AttributeFlag::IsComponent => "isComponent",
+            AttributeFlag::NoHistory => "noHistory",
+        };
+        edn::Value::NamespacedKeyword(NamespacedKeyword::new("db", name))
+    }
+}
+
+/// The differences between a current `Schema` and a desired set of attribute definitions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SchemaDiff {
+    /// Attributes that are desired but not present in the current schema.
+    pub added: BTreeMap<NamespacedKeyword, Attribute>,
+
+    /// Attributes present in both, together with the flags whose values differ.
+    pub altered: BTreeMap<NamespacedKeyword, BTreeSet<AttributeFlag>>,
+
+    /// Attributes present in the current schema but absent from the desired definitions.
+    ///
+    /// Mentat's own bootstrapped attributes (those in the `db` and `db.*` namespaces) are never
+    /// reported here.
+    pub removed: BTreeSet<NamespacedKeyword>,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.altered.is_empty() && self.removed.is_empty()
+    }
+
+    /// Returns a symbolic representation of the diff suitable for logging, like
+    /// `{:added [{:db/ident :foo/bar ...}] :altered {:foo/baz [:db/index]} :removed [:foo/noo]}`.
+    pub fn to_edn_value(&self) -> edn::Value {
+        let added = self.added.iter()
+            .map(|(ident, attribute)| attribute.to_edn_value(Some(ident.clone())))
+            .collect();
+
+        let altered = self.altered.iter()
+            .map(|(ident, flags)| {
+                (edn::Value::NamespacedKeyword(ident.clone()),
+                 edn::Value::Vector(flags.iter().map(|f| f.to_edn_value()).collect()))
+            })
+            .collect();
+
+        let removed = self.removed.iter()
+            .map(|ident| edn::Value::NamespacedKeyword(ident.clone()))
+            .collect();
+
+        let mut m: BTreeMap<edn::Value, edn::Value> = BTreeMap::default();
+        m.insert(edn::Value::Keyword(edn::Keyword::new("added")), edn::Value::Vector(added));
+        m.insert(edn::Value::Keyword(edn::Keyword::new("altered")), edn::Value::Map(altered));
+        m.insert(edn::Value::Keyword(edn::Keyword::new("removed")), edn::Value::Vector(removed));
+        edn::Value::Map(m)
+    }
+}
+
+/// True if `ident` names one of Mentat's own attributes, like `:db/ident` or `:db.schema/version`.
+fn is_bootstrap_ident(ident: &NamespacedKeyword) -> bool {
+    ident.namespace == "db" || ident.namespace.starts_with("db.")
+}
+
+fn bad_definition<T>(message: String) -> Result<T> {
+    bail!(ErrorKind::BadSchemaDefinition(message))
+}
+
+fn expect_keyword(a: &NamespacedKeyword, v: &edn::Value) -> Result<NamespacedKeyword> {
+    match *v {
+        edn::Value::NamespacedKeyword(ref k) => Ok(k.clone()),
+        _ => bad_definition(format!("expected [... {} :namespaced/keyword] but got [... {} {}]", a, a, v)),
+    }
+}
+
+fn expect_boolean(a: &NamespacedKeyword, v: &edn::Value) -> Result<bool> {
+    match *v {
+        edn::Value::Boolean(x) => Ok(x),
+        _ => bad_definition(format!("expected [... {} true|false] but got [... {} {}]", a, a, v)),
+    }
+}
+
+/// Apply a single `[ident a v]` schema assertion to `attribute`.
+fn apply_assertion(attribute: &mut Attribute, a: &NamespacedKeyword, v: &edn::Value) -> Result<()> {
+    if a.namespace != "db" {
+        return bad_definition(format!("unrecognized schema attribute {}", a));
+    }
+
+    match a.name.as_str() {
+        "valueType" => {
+            let t = expect_keyword(a, v)?;
+            attribute.value_type = match (t.namespace.as_str(), t.name.as_str()) {
+                ("db.type", "ref") => ValueType::Ref,
+                ("db.type", "boolean") => ValueType::Boolean,
+                ("db.type", "instant") => ValueType::Instant,
+                ("db.type", "long") => ValueType::Long,
+                ("db.type", "double") => ValueType::Double,
+                ("db.type", "string") => ValueType::String,
+                ("db.type", "keyword") => ValueType::Keyword,
+                ("db.type", "uuid") => ValueType::Uuid,
+                _ => return bad_definition(format!("unrecognized value type {}", t)),
+            };
+        },
+        "cardinality" => {
+            let c = expect_keyword(a, v)?;
+            attribute.multival = match (c.namespace.as_str(), c.name.as_str()) {
+                ("db.cardinality", "one") => false,
+                ("db.cardinality", "many") => true,
+                _ => return bad_definition(format!("unrecognized cardinality {}", c)),
+            };
+        },
+        "unique" => {
+            let u = expect_keyword(a, v)?;
+            attribute.unique = match (u.namespace.as_str(), u.name.as_str()) {
+                ("db.unique", "value") => Some(attribute::Unique::Value),
+                ("db.unique", "identity") => Some(attribute::Unique::Identity),
+                _ => return bad_definition(format!("unrecognized uniqueness {}", u)),
+            };
+        },
+        "index" => { attribute.index = expect_boolean(a, v)?; },
+        "fulltext" => { attribute.fulltext = expect_boolean(a, v)?; },
+        "isComponent" => { attribute.component = expect_boolean(a, v)?; },
+        "noHistory" => { attribute.no_history = expect_boolean(a, v)?; },
+
+        // These don't contribute to the shape of the attribute.
+        "ident" | "id" | "doc" => {},
+
+        _ => return bad_definition(format!("unrecognized schema attribute {}", a)),
+    }
+    Ok(())
+}
+
+/// Return the flags of `current` that differ in `desired`.
+fn differing_flags(current: &Attribute, desired: &Attribute) -> BTreeSet<AttributeFlag> {
+    let mut flags = BTreeSet::new();
+    if current.value_type != desired.value_type {
+        flags.insert(AttributeFlag::ValueType);
+    }
+    if current.multival != desired.multival {
+        flags.insert(AttributeFlag::Cardinality);
+    }
+    if current.unique != desired.unique {
+        flags.insert(AttributeFlag::Unique);
+    }
+    if current.index != desired.index {
+        flags.insert(AttributeFlag::Index);
+    }
+    if current.fulltext != desired.fulltext {
+        flags.insert(AttributeFlag::Fulltext);
+    }
+    if current.component != desired.component {
+        flags.insert(AttributeFlag::IsComponent);
+    }
+    if current.no_history != desired.no_history {
+        flags.insert(AttributeFlag::NoHistory);
+    }
+    flags
+}
+
+fn ident_of(a: &Entid) -> Result<NamespacedKeyword> {
+    match *a {
+        Entid::Ident(ref ident) => Ok(ident.clone()),
+        Entid::Entid(e) => bad_definition(format!("expected an ident but got entid {}", e)),
+    }
+}
+
+fn atom_of(a: &NamespacedKeyword, v: &AtomOrLookupRefOrVectorOrMapNotation) -> Result<edn::Value> {
+    match *v {
+        AtomOrLookupRefOrVectorOrMapNotation::Atom(ref v) => Ok(v.clone().without_spans()),
+        _ => bad_definition(format!("expected an atom value for {}", a)),
+    }
+}
+
+/// Group the desired attribute definitions by ident, as `[a v]` pairs.
+///
+/// Definitions can be given in map notation, like `{:db/ident :foo/bar :db/valueType ...}`, or as
+/// `[:db/add e a v]` assertions where `e` is an ident or a tempid that is given a `:db/ident`.
+fn definitions_by_ident(desired: &[Entity]) -> Result<BTreeMap<NamespacedKeyword, Vec<(NamespacedKeyword, edn::Value)>>> {
+    let db_ident = NamespacedKeyword::new("db", "ident");
+    let db_id = NamespacedKeyword::new("db", "id");
+
+    let mut definitions: BTreeMap<NamespacedKeyword, Vec<(NamespacedKeyword, edn::Value)>> = BTreeMap::new();
+    let mut tempids: BTreeMap<TempId, NamespacedKeyword> = BTreeMap::new();
+    let mut pending: Vec<(TempId, NamespacedKeyword, edn::Value)> = vec![];
+
+    for entity in desired {
+        match *entity {
+            Entity::MapNotation(ref map_notation) => {
+                let mut pairs = vec![];
+                let mut ident = None;
+                for (a, v) in map_notation {
+                    let a = ident_of(a)?;
+                    let v = atom_of(&a, v)?;
+                    if a == db_ident || (a == db_id && v.is_namespaced_keyword()) {
+                        ident = Some(expect_keyword(&a, &v)?);
+                    }
+                    pairs.push((a, v));
+                }
+                match ident {
+                    Some(ident) => definitions.entry(ident).or_insert_with(Vec::new).extend(pairs),
+                    None => return bad_definition(format!("attribute definition without :db/ident")),
+                }
+            },
+
+            Entity::AddOrRetract { ref op, ref e, ref a, ref v } => {
+                if *op != OpType::Add {
+                    return bad_definition(format!("attribute definitions cannot retract"));
+                }
+                let a = ident_of(a)?;
+                let v = atom_of(&a, v)?;
+                match *e {
+                    EntidOrLookupRefOrTempId::Entid(ref e) => {
+                        let e = ident_of(e)?;
+                        definitions.entry(e).or_insert_with(Vec::new).push((a, v));
+                    },
+                    EntidOrLookupRefOrTempId::TempId(ref t) => {
+                        if a == db_ident {
+                            tempids.insert(t.clone(), expect_keyword(&a, &v)?);
+                        }
+                        pending.push((t.clone(), a, v));
+                    },
+                    EntidOrLookupRefOrTempId::LookupRef(_) => {
+                        return bad_definition(format!("attribute definitions cannot use lookup refs"));
+                    },
+                }
+            },
+        }
+    }
+
+    for (t, a, v) in pending {
+        match tempids.get(&t) {
+            Some(ident) => definitions.entry(ident.clone()).or_insert_with(Vec::new).push((a, v)),
+            None => return bad_definition(format!("tempid {} is not given a :db/ident", t)),
+        }
+    }
+
+    Ok(definitions)
+}
+
+/// Compare `current` against the `desired` attribute definitions.
+///
+/// Desired definitions for attributes that already exist are interpreted as alterations: flags
+/// that are not mentioned keep their current values.  Definitions for new attributes must include
+/// `:db/valueType`.
+pub fn schema_diff(current: &Schema, desired: &[Entity]) -> Result<SchemaDiff> {
+    let definitions = definitions_by_ident(desired)?;
+
+    let mut diff = SchemaDiff::default();
+
+    for (ident, pairs) in definitions.iter() {
+        let existing = current.attribute_for_ident(ident);
+        let mut attribute = existing.cloned().unwrap_or_default();
+        for &(ref a, ref v) in pairs {
+            apply_assertion(&mut attribute, a, v)?;
+        }
+
+        match existing {
+            None => {
+                if !pairs.iter().any(|&(ref a, _)| a.namespace == "db" && a.name == "valueType") {
+                    return bad_definition(format!("new attribute {} does not set :db/valueType", ident));
+                }
+                diff.added.insert(ident.clone(), attribute);
+            },
+            Some(existing) => {
+                let flags = differing_flags(existing, &attribute);
+                if !flags.is_empty() {
+                    diff.altered.insert(ident.clone(), flags);
+                }
+            },
+        }
+    }
+
+    for entid in current.schema_map.keys() {
+        if let Some(ident) = current.get_ident(*entid) {
+            if !is_bootstrap_ident(ident) && !definitions.contains_key(ident) {
+                diff.removed.insert(ident.clone());
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Compare two schemas attribute by attribute, matching attributes by ident.
+///
+/// Attributes in `new` but not `old` are added, and attributes in `old` but not `new` are removed;
+/// as with `schema_diff`, Mentat's own bootstrapped attributes are never reported as removed.
+pub fn diff_schemas(old: &Schema, new: &Schema) -> SchemaDiff {
+    let mut diff = SchemaDiff::default();
+
+    for (entid, attribute) in new.schema_map.iter() {
+        if let Some(ident) = new.get_ident(*entid) {
+            match old.attribute_for_ident(ident) {
+                None => {
+                    diff.added.insert(ident.clone(), attribute.clone());
+                },
+                Some(existing) => {
+                    let flags = differing_flags(existing, attribute);
+                    if !flags.is_empty() {
+                        diff.altered.insert(ident.clone(), flags);
+                    }
+                },
+            }
+        }
+    }
+
+    for entid in old.schema_map.keys() {
+        if let Some(ident) = old.get_ident(*entid) {
+            if !is_bootstrap_ident(ident) && !new.identifies_attribute(ident) {
+                diff.removed.insert(ident.clone());
+            }
+        }
+    }
+
+    diff
+}
+
+/// Render the attributes installed in `schema`, other than Mentat's own bootstrapped attributes,
+/// as a vector of attribute definitions in map notation, like
+/// `[{:db/ident :foo/bar :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]`.
+///
+/// Transacting the result into a new store installs an equivalent vocabulary.
+pub fn vocabulary_to_edn(schema: &Schema) -> edn::Value {
+    edn::Value::Vector(schema.schema_map.iter()
+        .filter_map(|(entid, attribute)| {
+            match schema.get_ident(*entid) {
+                Some(ident) if !is_bootstrap_ident(ident) => Some(attribute.to_edn_value(Some(ident.clone()))),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_db::db;
+    use mentat_tx_parser;
+
+    use conn::Conn;
+
+    fn parse(s: &str) -> Vec<Entity> {
+        let value = edn::parse::value(s).expect("to parse EDN");
+        mentat_tx_parser::Tx::parse(&value).expect("to parse entities")
+    }
+
+    #[test]
+    fn test_schema_diff_bootstrap_is_empty() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let conn = Conn::connect(&mut sqlite).unwrap();
+
+        let diff = schema_diff(&conn.current_schema(), &[]).expect("diffed");
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn test_schema_diff() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        conn.transact(&mut sqlite, r#"[
+            {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :foo/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+        ]"#).expect("transacted");
+
+        let desired = parse(r#"[
+            {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/many :db/index true}
+            [:db/add "n" :db/ident :foo/nick]
+            [:db/add "n" :db/valueType :db.type/string]
+            [:db/add "n" :db/cardinality :db.cardinality/one]
+        ]"#);
+
+        let diff = schema_diff(&conn.current_schema(), &desired[..]).expect("diffed");
+
+        assert_eq!(diff.added.keys().cloned().collect::<Vec<_>>(),
+                   vec![NamespacedKeyword::new("foo", "nick")]);
+        assert_eq!(diff.added[&NamespacedKeyword::new("foo", "nick")].value_type, ValueType::String);
+
+        let mut flags = BTreeSet::new();
+        flags.insert(AttributeFlag::Cardinality);
+        flags.insert(AttributeFlag::Index);
+        assert_eq!(diff.altered.get(&NamespacedKeyword::new("foo", "name")), Some(&flags));
+
+        assert_eq!(diff.removed.iter().cloned().collect::<Vec<_>>(),
+                   vec![NamespacedKeyword::new("foo", "age")]);
+
+        let expected = edn::parse::value(r#"{:added [{:db/ident :foo/nick
+                                                      :db/valueType :db.type/string
+                                                      :db/cardinality :db.cardinality/one}]
+                                             :altered {:foo/name [:db/cardinality :db/index]}
+                                             :removed [:foo/age]}"#)
+            .expect("to parse EDN")
+            .without_spans();
+        assert_eq!(diff.to_edn_value(), expected);
+    }
+
+    #[test]
+    fn test_vocabulary_round_trip() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        conn.transact(&mut sqlite, r#"[
+            {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+            {:db/ident :foo/bio :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}
+            {:db/ident :foo/tags :db/valueType :db.type/keyword :db/cardinality :db.cardinality/many :db/unique :db.unique/value}
+            {:db/ident :foo/parts :db/valueType :db.type/ref :db/cardinality :db.cardinality/many :db/isComponent true}
+            {:db/ident :foo/seen :db/valueType :db.type/instant :db/cardinality :db.cardinality/one :db/noHistory true}
+        ]"#).expect("transacted");
+
+        let vocabulary = conn.dump_schema();
+        match vocabulary {
+            edn::Value::Vector(ref attributes) => assert_eq!(attributes.len(), 5),
+            _ => panic!("expected a vector of attributes"),
+        }
+
+        // The vocabulary can be transacted as-is into an empty store to reproduce the schema.
+        let mut other_sqlite = db::new_connection("").unwrap();
+        let mut other = Conn::connect(&mut other_sqlite).unwrap();
+        assert!(!diff_schemas(&conn.current_schema(), &other.current_schema()).is_empty());
+        other.transact(&mut other_sqlite, vocabulary.to_string().as_str()).expect("transacted");
+
+        let diff = diff_schemas(&conn.current_schema(), &other.current_schema());
+        assert!(diff.is_empty(), "unexpected differences: {}", diff.to_edn_value());
+
+        // Nor does the vocabulary differ from the schema it was drawn from.
+        let desired = parse(vocabulary.to_string().as_str());
+        assert!(schema_diff(&conn.current_schema(), &desired[..]).expect("diffed").is_empty());
+    }
+
+    #[test]
+    fn test_diff_schemas() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        let bootstrapped = conn.current_schema();
+
+        conn.transact(&mut sqlite, r#"[
+            {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :foo/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+        ]"#).expect("transacted");
+        let before = conn.current_schema();
+
+        conn.transact(&mut sqlite, r#"[
+            {:db/ident :foo/age :db/index true}
+        ]"#).expect("transacted");
+        let after = conn.current_schema();
+
+        let diff = diff_schemas(&bootstrapped, &before);
+        assert_eq!(diff.added.keys().cloned().collect::<Vec<_>>(),
+                   vec![NamespacedKeyword::new("foo", "age"), NamespacedKeyword::new("foo", "name")]);
+        assert!(diff.altered.is_empty());
+        assert!(diff.removed.is_empty());
+
+        let diff = diff_schemas(&before, &after);
+        assert!(diff.added.is_empty());
+        let mut flags = BTreeSet::new();
+        flags.insert(AttributeFlag::Index);
+        assert_eq!(diff.altered.get(&NamespacedKeyword::new("foo", "age")), Some(&flags));
+        assert!(diff.removed.is_empty());
+
+        let diff = diff_schemas(&after, &bootstrapped);
+        assert_eq!(diff.removed.iter().cloned().collect::<Vec<_>>(),
+                   vec![NamespacedKeyword::new("foo", "age"), NamespacedKeyword::new("foo", "name")]);
+    }
+
+    #[test]
+    fn test_schema_diff_requires_value_type() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let conn = Conn::connect(&mut sqlite).unwrap();
+
+        let desired = parse("[{:db/ident :foo/bar :db/cardinality :db.cardinality/one}]");
+        match schema_diff(&conn.current_schema(), &desired[..]).unwrap_err() {
+            ::errors::Error(ErrorKind::BadSchemaDefinition(_), _) => {},
+            x => panic!("expected bad schema definition, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Optional instrumentation of transacting and querying with `tracing` spans.
+//!
+//! With the `tracing` feature enabled, each `OperationSpan` is an entered `tracing` span that
+//! records its elapsed time in microseconds as `elapsed_us` when it is dropped.  Without the
+//! feature, every function here is a no-op.
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use std::fmt;
+    use std::time::Instant;
+
+    use tracing;
+    use tracing::field;
+
+    pub struct OperationSpan {
+        span: tracing::span::EnteredSpan,
+        start: Instant,
+    }
+
+    impl OperationSpan {
+        fn new(span: tracing::Span) -> OperationSpan {
+            OperationSpan {
+                span: span.entered(),
+                start: Instant::now(),
+            }
+        }
+
+        pub fn record_i64(&self, name: &'static str, value: i64) {
+            self.span.record(name, &value);
+        }
+
+        pub fn record_bool(&self, name: &'static str, value: bool) {
+            self.span.record(name, &value);
+        }
+    }
+
+    impl Drop for OperationSpan {
+        fn drop(&mut self) {
+            let elapsed = self.start.elapsed();
+            let micros = elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1_000) as u64;
+            self.span.record("elapsed_us", &micros);
+        }
+    }
+
+    pub fn transact() -> OperationSpan {
+        OperationSpan::new(info_span!("mentat.transact",
+                                      tx_id = field::Empty,
+                                      datoms_asserted = field::Empty,
+                                      datoms_retracted = field::Empty,
+                                      elapsed_us = field::Empty))
+    }
+
+    pub fn begin_transaction() -> OperationSpan {
+        OperationSpan::new(info_span!("mentat.begin_transaction",
+                                      generation = field::Empty,
+                                      elapsed_us = field::Empty))
+    }
+
+    pub fn commit() -> OperationSpan {
+        OperationSpan::new(info_span!("mentat.commit",
+                                      generation = field::Empty,
+                                      generation_check_passed = field::Empty,
+                                      tx_id = field::Empty,
+                                      elapsed_us = field::Empty))
+    }
+
+    pub fn query<Q: fmt::Display + ?Sized>(query: &Q) -> OperationSpan {
+        OperationSpan::new(info_span!("mentat.q_once",
+                                      query = %query,
+                                      rows = field::Empty,
+                                      elapsed_us = field::Empty))
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod enabled {
+    pub struct OperationSpan;
+
+    impl OperationSpan {
+        #[inline(always)]
+        pub fn record_i64(&self, _name: &'static str, _value: i64) {}
+
+        #[inline(always)]
+        pub fn record_bool(&self, _name: &'static str, _value: bool) {}
+    }
+
+    #[inline(always)]
+    pub fn transact() -> OperationSpan { OperationSpan }
+
+    #[inline(always)]
+    pub fn begin_transaction() -> OperationSpan { OperationSpan }
+
+    #[inline(always)]
+    pub fn commit() -> OperationSpan { OperationSpan }
+
+    #[inline(always)]
+    pub fn query<Q: ?Sized>(_query: &Q) -> OperationSpan { OperationSpan }
+}
+
+pub use self::enabled::*;
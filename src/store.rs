@@ -0,0 +1,212 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A store on disk with one writer and a pool of read-only SQLite connections, so that any number
+//! of threads can query while the writer writes.  See `Store::reader`.
+//!
+//! Readers share the writer's `Conn`, and so its schema.  Each query takes a `Snapshot`, so it
+//! sees a schema and data that agree, however the writer's transactions land around it.  The store
+//! is in WAL mode, so readers never wait for the writer, nor it for them.
+
+use std::cell::RefCell;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+use rusqlite;
+
+use mentat_db::db;
+
+use conn::Conn;
+use errors::Result;
+use query::{
+    QueryInputs,
+    QueryOutput,
+};
+
+/// How long, in milliseconds, a reader waits for SQLite's locks before failing.  Readers in WAL
+/// mode only contend briefly, while the WAL is being reset.
+const READER_BUSY_TIMEOUT_MS: u32 = 5000;
+
+/// Idle read-only connections, waiting to be handed out again.
+type Pool = Arc<Mutex<Vec<rusqlite::Connection>>>;
+
+/// A `Conn` shared by a single writer and any number of `Reader`s.  See the module documentation.
+pub struct Store {
+    conn: Arc<Conn>,
+    path: PathBuf,
+    pool_size: usize,
+    idle: Pool,
+}
+
+/// A read-only SQLite connection borrowed from a `Store`, for querying on one thread.  Dropping it
+/// returns the connection to the store's pool.
+pub struct Reader {
+    conn: Arc<Conn>,
+    sqlite: RefCell<Option<rusqlite::Connection>>,
+    pool_size: usize,
+    idle: Pool,
+}
+
+impl Store {
+    /// Open the Mentat store at `path`, as by `Conn::open`, keeping up to `pool_size` idle read-only
+    /// connections for reuse.  Returns the `Store` and the SQLite connection to write through.
+    ///
+    /// `path` must name a file: an in-memory store can't be shared between connections.
+    pub fn open<P>(path: P, pool_size: usize) -> Result<(Store, rusqlite::Connection)> where P: AsRef<Path> {
+        let (conn, sqlite) = Conn::open(path.as_ref())?;
+        Ok((Store::new(conn, path, pool_size), sqlite))
+    }
+
+    /// Share `conn`, connected to the store at `path`, with readers; see `open`.
+    pub fn new<P>(conn: Conn, path: P, pool_size: usize) -> Store where P: AsRef<Path> {
+        Store {
+            conn: Arc::new(conn),
+            path: path.as_ref().to_path_buf(),
+            pool_size: pool_size,
+            idle: Arc::new(Mutex::new(Vec::with_capacity(pool_size))),
+        }
+    }
+
+    /// The shared `Conn`.  Write with `Conn::begin_transaction` and the writer's SQLite connection.
+    pub fn conn(&self) -> &Arc<Conn> {
+        &self.conn
+    }
+
+    /// Hand out a reader, reusing an idle connection if there is one, and otherwise opening
+    /// another.  The reader can be moved to, and queried from, any thread.
+    pub fn reader(&self) -> Result<Reader> {
+        let pooled = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let sqlite = match pooled {
+            Some(sqlite) => sqlite,
+            None => {
+                let sqlite = db::new_read_only_connection(&self.path)?;
+                sqlite.execute_batch(&format!("PRAGMA busy_timeout = {}", READER_BUSY_TIMEOUT_MS))?;
+                sqlite
+            },
+        };
+        Ok(Reader {
+            conn: self.conn.clone(),
+            sqlite: RefCell::new(Some(sqlite)),
+            pool_size: self.pool_size,
+            idle: self.idle.clone(),
+        })
+    }
+
+    /// The number of idle connections in the pool.
+    pub fn idle_readers(&self) -> usize {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+impl Reader {
+    /// Query the store as it stands, through a snapshot; see `Conn::snapshot`.
+    pub fn q_once<T>(&self,
+                     query: &str,
+                     inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>
+        {
+
+        let mut sqlite = self.sqlite.borrow_mut();
+        let snapshot = self.conn.snapshot(sqlite.as_mut().expect("connection until dropped"))?;
+        snapshot.q_once(query, inputs)
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        if let Some(sqlite) = self.sqlite.borrow_mut().take() {
+            let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+            // Beyond the pool's size, just close the connection.
+            if idle.len() < self.pool_size {
+                idle.push(sqlite);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{
+        AtomicBool,
+        Ordering,
+    };
+    use std::thread;
+
+    use mentat_core::TypedValue;
+
+    use mentat_db::debug::TempStorePath;
+
+    #[test]
+    fn test_readers() {
+        let path = TempStorePath::new("store");
+
+        let (store, mut sqlite) = Store::open(&path, 2).expect("opened");
+        let store = Arc::new(store);
+        {
+            let mut in_progress = store.conn().begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact("[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+                       .expect("transacted schema");
+            in_progress.commit().expect("committed");
+        }
+
+        // Four threads query while the main thread transacts.  Each sees the names written so far,
+        // in order, and never fewer than it saw before.
+        let done = Arc::new(AtomicBool::new(false));
+        let query = "[:find [?name ...] :where [_ :test/name ?name]]";
+        let readers: Vec<_> = (0..4).map(|_| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut seen = 0;
+                loop {
+                    let finished = done.load(Ordering::SeqCst);
+                    let reader = store.reader().expect("reader");
+                    let names = reader.q_once(query, None).expect("queried").into_coll().expect("coll");
+                    assert!(names.len() >= seen);
+                    for i in 0..names.len() {
+                        assert!(names.contains(&TypedValue::typed_string(&format!("person {}", i))));
+                    }
+                    seen = names.len();
+                    if finished {
+                        return seen;
+                    }
+                }
+            })
+        }).collect();
+
+        for i in 0..20 {
+            let mut in_progress = store.conn().begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(&format!(r#"[[:db/add "p" :test/name "person {}"]]"#, i)).expect("transacted");
+            in_progress.commit().expect("committed");
+        }
+        done.store(true, Ordering::SeqCst);
+
+        for reader in readers {
+            assert_eq!(reader.join().expect("reader thread"), 20);
+        }
+
+        // Connections are returned to the pool, up to its size.
+        assert_eq!(store.idle_readers(), 2);
+        let reader = store.reader().expect("reader");
+        assert_eq!(store.idle_readers(), 1);
+        drop(reader);
+        assert_eq!(store.idle_readers(), 2);
+
+        drop(store);
+    }
+}
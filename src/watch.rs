@@ -0,0 +1,183 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Watch committed transactions for changes to particular attributes, receiving them over a
+//! channel.  See `Conn::watch`.
+//!
+//! The changes made by a transaction are read before it's committed, while the SQL transaction
+//! can still see them, and sent after.  Sending never blocks the writer: a change that a watcher
+//! has no room for, or no longer wants, is dropped and counted.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    Weak,
+};
+use std::sync::mpsc;
+
+use rusqlite;
+
+use edn;
+use edn::{
+    DateTime,
+    Utc,
+};
+
+use mentat_core::{
+    Entid,
+    Schema,
+    TypedValue,
+};
+
+use mentat_db::db::{
+    TypedSQLValue,
+    datom_value_sql,
+};
+
+use errors::Result;
+
+/// The number of changes a watcher can have waiting to be received before more are dropped.
+pub const WATCH_CAPACITY: usize = 1024;
+
+/// The changes a committed transaction made to watched attributes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TxChange {
+    pub tx_id: Entid,
+    pub tx_instant: DateTime<Utc>,
+
+    /// Each datom asserted or retracted for a watched attribute: the entity, the attribute, the
+    /// value, and `true` if it was asserted.  Ordered by entity, then attribute.
+    pub datoms: Vec<(Entid, edn::NamespacedKeyword, TypedValue, bool)>,
+}
+
+struct Watcher {
+    attributes: BTreeSet<edn::NamespacedKeyword>,
+    sender: mpsc::SyncSender<TxChange>,
+}
+
+/// The watchers registered with a `Conn`.
+#[derive(Default)]
+pub struct Watches {
+    next_id: usize,
+    watchers: BTreeMap<usize, Watcher>,
+}
+
+/// Keeps a watch registered.  Dropping it unregisters the watch; changes already sent can still
+/// be received.
+pub struct WatchHandle {
+    id: usize,
+    watches: Weak<Mutex<Watches>>,
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        if let Some(watches) = self.watches.upgrade() {
+            watches.lock().unwrap_or_else(|e| e.into_inner()).watchers.remove(&self.id);
+        }
+    }
+}
+
+/// Register a watch on `attributes` with `watches`.
+pub fn watch(watches: &Arc<Mutex<Watches>>, attributes: BTreeSet<edn::NamespacedKeyword>) -> (WatchHandle, mpsc::Receiver<TxChange>) {
+    let (sender, receiver) = mpsc::sync_channel(WATCH_CAPACITY);
+    let mut locked = watches.lock().unwrap_or_else(|e| e.into_inner());
+    let id = locked.next_id;
+    locked.next_id += 1;
+    locked.watchers.insert(id, Watcher {
+        attributes: attributes,
+        sender: sender,
+    });
+    (WatchHandle { id: id, watches: Arc::downgrade(watches) }, receiver)
+}
+
+/// Read the changes made to any watched attribute by the transactions `first_tx` and later, as
+/// `sqlite` sees them, ordered by transaction.
+pub fn changes_since(sqlite: &rusqlite::Connection, schema: &Schema, watches: &Mutex<Watches>, first_tx: Entid) -> Result<Vec<TxChange>> {
+    let watched: BTreeSet<edn::NamespacedKeyword> = {
+        let locked = watches.lock().unwrap_or_else(|e| e.into_inner());
+        locked.watchers.values().flat_map(|watcher| watcher.attributes.iter().cloned()).collect()
+    };
+    if watched.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut changes: BTreeMap<Entid, Vec<(Entid, edn::NamespacedKeyword, TypedValue, bool)>> = BTreeMap::new();
+    for ident in watched {
+        let (a, fulltext) = match schema.get_entid(&ident).and_then(|a| schema.attribute_for_entid(a).map(|attribute| (a, attribute.fulltext))) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let sql = format!("SELECT tx, e, {}, value_type_tag, added FROM transactions WHERE tx >= ? AND a = ? ORDER BY tx, e, value_type_tag, v, added",
+                          datom_value_sql(fulltext));
+        let mut stmt = sqlite.prepare_cached(&sql)?;
+        let mut rows = stmt.query(&[&first_tx, &a])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let tx: Entid = row.get_checked(0)?;
+            let value = TypedValue::from_sql_value_pair(row.get_checked(2)?, row.get_checked(3)?)?;
+            changes.entry(tx).or_insert_with(Vec::new).push((row.get_checked(1)?, ident.clone(), value, row.get_checked(4)?));
+        }
+    }
+
+    let tx_instant = schema.get_entid(&edn::NamespacedKeyword::new("db", "txInstant"))
+                           .expect(":db/txInstant is always present");
+    // The transaction entity's `:db/txInstant` is always asserted in that transaction, so `tx = e`
+    // lets SQLite use the index on `(tx, added)` rather than scanning the log.
+    let mut stmt = sqlite.prepare_cached("SELECT v, value_type_tag FROM transactions WHERE tx = ? AND e = ? AND a = ? AND added = 1")?;
+    let mut result = Vec::with_capacity(changes.len());
+    for (tx_id, mut datoms) in changes {
+        let (v, value_type_tag): (rusqlite::types::Value, i32) =
+            stmt.query_row(&[&tx_id, &tx_id, &tx_instant], |row| (row.get(0), row.get(1)))?;
+        let instant = match TypedValue::from_sql_value_pair(v, value_type_tag)? {
+            TypedValue::Instant(instant) => instant,
+            _ => unreachable!(),
+        };
+        // Each attribute's datoms are already ordered by entity, then value.
+        datoms.sort_by(|x, y| (x.0, &x.1).cmp(&(y.0, &y.1)));
+        result.push(TxChange {
+            tx_id: tx_id,
+            tx_instant: instant,
+            datoms: datoms,
+        });
+    }
+    Ok(result)
+}
+
+/// Send each watcher the part of each of `changes` that concerns it, if any.  Returns the number
+/// of changes dropped because a watcher's channel was full or its receiver gone.
+pub fn notify(watches: &Mutex<Watches>, changes: &[TxChange]) -> usize {
+    let locked = watches.lock().unwrap_or_else(|e| e.into_inner());
+    let mut dropped = 0;
+    for watcher in locked.watchers.values() {
+        for change in changes {
+            let datoms: Vec<_> = change.datoms.iter()
+                                       .filter(|datom| watcher.attributes.contains(&datom.1))
+                                       .cloned()
+                                       .collect();
+            if datoms.is_empty() {
+                continue;
+            }
+            let change = TxChange {
+                tx_id: change.tx_id,
+                tx_instant: change.tx_instant,
+                datoms: datoms,
+            };
+            if watcher.sender.try_send(change).is_err() {
+                dropped += 1;
+            }
+        }
+    }
+    dropped
+}
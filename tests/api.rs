@@ -15,9 +15,21 @@ extern crate mentat;
 #[allow(unused_imports)]
 use mentat::{
     Conn,
+    QueryOutput,
     QueryResults,
+    TxReport,
     TypedValue,
     ValueType,
     conn,
     new_connection,
 };
+
+// Verify that results can be handed to another thread.
+#[test]
+fn test_results_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<QueryOutput>();
+    assert_send::<QueryResults>();
+    assert_send::<TxReport>();
+    assert_send::<TypedValue>();
+}
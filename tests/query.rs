@@ -17,6 +17,8 @@ extern crate mentat_db;
 extern crate mentat_query_algebrizer;       // For errors.
 
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 use chrono::FixedOffset;
 
@@ -29,13 +31,20 @@ use mentat_core::{
 };
 
 use mentat::{
+    ControlFlow,
     NamespacedKeyword,
     PlainSymbol,
     QueryInputs,
+    QueryInterrupt,
+    QueryOptions,
+    QueryOutput,
     QueryResults,
     Variable,
     new_connection,
+    q_explain,
     q_once,
+    q_once_each,
+    q_once_with_options,
 };
 
 use mentat::conn::Conn;
@@ -61,7 +70,7 @@ fn test_rel() {
     assert_eq!(39, results.len());
 
     // Every row is a pair of a Ref and a Keyword.
-    if let QueryResults::Rel(ref rel) = results {
+    if let QueryResults::Rel(ref rel) = results.results {
         for r in rel {
             assert_eq!(r.len(), 2);
             assert!(r[0].matches_type(ValueType::Ref));
@@ -89,7 +98,7 @@ fn test_failing_scalar() {
 
     assert_eq!(0, results.len());
 
-    if let QueryResults::Scalar(None) = results {
+    if let QueryResults::Scalar(None) = results.results {
     } else {
         panic!("Expected failed scalar.");
     }
@@ -111,7 +120,7 @@ fn test_scalar() {
 
     assert_eq!(1, results.len());
 
-    if let QueryResults::Scalar(Some(TypedValue::Keyword(ref rc))) = results {
+    if let QueryResults::Scalar(Some(TypedValue::Keyword(ref rc))) = results.results {
         // Should be '24'.
         assert_eq!(&NamespacedKeyword::new("db.type", "keyword"), rc.as_ref());
         assert_eq!(24,
@@ -141,7 +150,7 @@ fn test_tuple() {
 
     assert_eq!(1, results.len());
 
-    if let QueryResults::Tuple(Some(ref tuple)) = results {
+    if let QueryResults::Tuple(Some(ref tuple)) = results.results {
         let cardinality_one = NamespacedKeyword::new("db.cardinality", "one");
         assert_eq!(tuple.len(), 2);
         assert_eq!(tuple[0], TypedValue::Boolean(true));
@@ -168,7 +177,7 @@ fn test_coll() {
 
     assert_eq!(39, results.len());
 
-    if let QueryResults::Coll(ref coll) = results {
+    if let QueryResults::Coll(ref coll) = results.results {
         assert!(coll.iter().all(|item| item.matches_type(ValueType::Ref)));
     } else {
         panic!("Expected coll.");
@@ -178,6 +187,63 @@ fn test_coll() {
     println!("Coll took {}µs", start.to(end).num_microseconds().unwrap());
 }
 
+#[test]
+fn test_columns() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    let names = |output: &QueryOutput| -> Vec<String> {
+        output.columns.iter().map(|v| v.to_string()).collect()
+    };
+
+    let rel = q_once(&c, &db.schema, "[:find ?x ?ident :where [?x :db/ident ?ident]]", None)
+        .expect("Query failed");
+    assert_eq!(names(&rel), vec!["?x", "?ident"]);
+    assert_eq!(rel.column_index("?ident"), Some(1));
+    assert_eq!(rel.column_index("?missing"), None);
+
+    // Values can be fetched by name, whatever the order of the `:find` clause.
+    let reordered = q_once(&c, &db.schema, "[:find ?ident ?x :where [?x :db/ident ?ident]]", None)
+        .expect("Query failed");
+    let pairs = |output: &QueryOutput| -> Vec<(TypedValue, TypedValue)> {
+        let mut pairs: Vec<(TypedValue, TypedValue)> =
+            (0..output.len()).map(|row| (output.get(row, "?x").cloned().expect("?x"),
+                                         output.get(row, "?ident").cloned().expect("?ident")))
+                             .collect();
+        pairs.sort();
+        pairs
+    };
+    assert!(!rel.is_empty());
+    assert_eq!(pairs(&rel), pairs(&reordered));
+    assert_eq!(rel.get(0, "?missing"), None);
+    assert_eq!(rel.get(rel.len(), "?x"), None);
+
+    // Positional access still works.
+    if let QueryResults::Rel(ref rows) = rel.results {
+        assert_eq!(rel.get(0, "?x"), Some(&rows[0][0]));
+    } else {
+        panic!("Expected rel.");
+    }
+
+    let tuple = q_once(&c, &db.schema,
+                       "[:find [?index ?cardinality]
+                         :where [:db/txInstant :db/index ?index]
+                                [:db/txInstant :db/cardinality ?cardinality]]",
+                       None)
+        .expect("Query failed");
+    assert_eq!(names(&tuple), vec!["?index", "?cardinality"]);
+
+    let coll = q_once(&c, &db.schema, "[:find [?e ...] :where [?e :db/ident _]]", None)
+        .expect("Query failed");
+    assert_eq!(names(&coll), vec!["?e"]);
+
+    // Columns are reported even when there are no results.
+    let scalar = q_once(&c, &db.schema, "[:find ?x . :where [?x :db/fulltext true]]", None)
+        .expect("Query failed");
+    assert_eq!(names(&scalar), vec!["?x"]);
+    assert_eq!(scalar.into_scalar().expect("Expected scalar"), None);
+}
+
 #[test]
 fn test_inputs() {
     let mut c = new_connection("").expect("Couldn't open conn.");
@@ -190,13 +256,50 @@ fn test_inputs() {
                          "[:find ?i . :in ?e :where [?e :db/ident ?i]]", inputs)
                         .expect("query to succeed");
 
-    if let QueryResults::Scalar(Some(TypedValue::Keyword(value))) = results {
+    if let QueryResults::Scalar(Some(TypedValue::Keyword(value))) = results.results {
         assert_eq!(value.as_ref(), &NamespacedKeyword::new("db.install", "valueType"));
     } else {
         panic!("Expected scalar.");
     }
 }
 
+#[test]
+fn test_inputs_builder() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    let inputs = QueryInputs::builder()
+        .bind("?a", NamespacedKeyword::new("db", "ident"))
+        .build()
+        .expect("valid inputs");
+    let results = q_once(&c, &db.schema,
+                         "[:find ?e . :in ?a :where [?e :db/ident ?a]]", inputs)
+        .expect("query to succeed")
+        .into_scalar()
+        .expect("scalar");
+    assert_eq!(results, db.schema.get_entid(&NamespacedKeyword::new("db", "ident")).map(TypedValue::Ref));
+
+    // Names are checked when the inputs are built.
+    match QueryInputs::builder().bind("x", 1).build().map(|_| ()) {
+        Err(mentat_query_algebrizer::Error(mentat_query_algebrizer::ErrorKind::InvalidInputVariableName(name), _)) => {
+            assert_eq!(name, "x");
+        },
+        x => panic!("Expected InvalidInputVariableName, got {:?}", x),
+    }
+    assert!(QueryInputs::builder().bind("?", 1).build().is_err());
+    assert!(QueryInputs::builder().bind("?x ?y", 1).build().is_err());
+
+    let inputs = QueryInputs::builder().bind("?x", "Alice").bind("?y", 30).build().expect("valid inputs");
+    assert_eq!(inputs.values.len(), 2);
+
+    match QueryInputs::builder().bind("?x", "Alice").bind("?y", 30).bind("?x", 1.5).build().map(|_| ()) {
+        Err(mentat_query_algebrizer::Error(mentat_query_algebrizer::ErrorKind::DuplicateInputBinding(var), _)) => {
+            assert_eq!(var, PlainSymbol::new("?x"));
+        },
+        x => panic!("Expected DuplicateInputBinding, got {:?}", x),
+    }
+}
+
 /// Ensure that a query won't be run without all of its `:in` variables being bound.
 #[test]
 fn test_unbound_inputs() {
@@ -237,7 +340,7 @@ fn test_instants_and_uuids() {
                         r#"[:find [?x ?u ?when]
                             :where [?x :foo/uuid ?u ?tx]
                                    [?tx :db/txInstant ?when]]"#, None);
-    match r {
+    match r.map(|output| output.results) {
         Result::Ok(QueryResults::Tuple(Some(vals))) => {
             let mut vals = vals.into_iter();
             match (vals.next(), vals.next(), vals.next(), vals.next()) {
@@ -277,7 +380,7 @@ fn test_tx() {
     let r = conn.q_once(&mut c,
                         r#"[:find ?tx 
                             :where [?x :foo/uuid #uuid "cf62d552-6569-4d1b-b667-04703041dfc4" ?tx]]"#, None);
-    match r {
+    match r.map(|output| output.results) {
         Result::Ok(QueryResults::Rel(ref v)) => {
             assert_eq!(*v, vec![
                 vec![TypedValue::Ref(t.tx_id),]
@@ -312,7 +415,7 @@ fn test_tx_as_input() {
                         r#"[:find ?uuid 
                             :in ?tx
                             :where [?x :foo/uuid ?uuid ?tx]]"#, inputs);
-    match r {
+    match r.map(|output| output.results) {
         Result::Ok(QueryResults::Rel(ref v)) => {
             assert_eq!(*v, vec![
                 vec![TypedValue::Uuid(Uuid::from_str("cf62d552-6569-4d1b-b667-04703041dfc4").expect("Valid UUID")),]
@@ -347,7 +450,7 @@ fn test_fulltext() {
     let r = conn.q_once(&mut c,
                         r#"[:find [?x ?val ?score]
                             :where [(fulltext $ :foo/fts "darkness") [[?x ?val _ ?score]]]]"#, None);
-    match r {
+    match r.map(|output| output.results) {
         Result::Ok(QueryResults::Tuple(Some(vals))) => {
             let mut vals = vals.into_iter();
             match (vals.next(), vals.next(), vals.next(), vals.next()) {
@@ -410,7 +513,7 @@ fn test_fulltext() {
                     [(fulltext $ :foo/fts ?term) [[?x ?val]]]]"#;
     let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?a"), TypedValue::Ref(a))]);
     let r = conn.q_once(&mut c, query, inputs);
-    match r {
+    match r.map(|output| output.results) {
         Result::Ok(QueryResults::Rel(rels)) => {
             assert_eq!(rels, vec![
                 vec![TypedValue::Ref(v),
@@ -422,6 +525,48 @@ fn test_fulltext() {
     }
 }
 
+#[test]
+fn test_fulltext_notes() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}
+        {:db/ident :note/title :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let t = conn.transact(&mut c, r#"[
+        {:db/id "n1" :note/title "groceries" :note/text "buy apples and bread"}
+        {:db/id "n2" :note/title "chores" :note/text "fix the bread maker"}
+    ]"#).unwrap();
+    let n1 = t.tempids.get("n1").cloned().expect("n1 was mapped");
+
+    // Only one note mentions apples.
+    let results = conn.q_once(&c, r#"[:find ?e ?value
+                                      :where [(fulltext $ :note/text "apples") [[?e ?value]]]]"#, None)
+                      .expect("Query failed")
+                      .into_rel()
+                      .expect("Expected rel");
+    assert_eq!(results, vec![vec![TypedValue::Ref(n1), TypedValue::typed_string("buy apples and bread")]]);
+
+    // Matches join with the rest of the query like any other binding.
+    let mut titles = conn.q_once(&c, r#"[:find [?title ...]
+                                         :where [(fulltext $ :note/text "bread") [[?e _]]]
+                                                [?e :note/title ?title]]"#, None)
+                         .expect("Query failed")
+                         .into_coll()
+                         .expect("Expected coll");
+    titles.sort();
+    assert_eq!(titles, vec![TypedValue::typed_string("chores"), TypedValue::typed_string("groceries")]);
+
+    // A string attribute that isn't fulltext-indexed can never match.
+    let results = conn.q_once(&c, r#"[:find ?e :where [(fulltext $ :note/title "groceries") [[?e]]]]"#, None)
+                      .expect("Query failed")
+                      .into_rel()
+                      .expect("Expected rel");
+    assert!(results.is_empty());
+}
+
 #[test]
 fn test_instant_range_query() {
     let mut c = new_connection("").expect("Couldn't open conn.");
@@ -446,7 +591,7 @@ fn test_instant_range_query() {
                             :where
                             [?x :foo/date ?date]
                             [(< ?date #inst "2017-01-01T11:00:02.000Z")]]"#, None);
-    match r {
+    match r.map(|output| output.results) {
         Result::Ok(QueryResults::Coll(vals)) => {
             assert_eq!(vals,
                        vec![TypedValue::Ref(*ids.get("b").unwrap()),
@@ -456,6 +601,101 @@ fn test_instant_range_query() {
     }
 }
 
+#[test]
+fn test_numeric_range_query() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :person/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one :db/index true}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:person/name "Alice" :person/age 25}
+        {:person/name "Bob" :person/age 30}
+        {:person/name "Carol" :person/age 41}
+    ]"#).unwrap();
+
+    let names = |query: &str| -> Vec<TypedValue> {
+        conn.q_once(&c, query, None)
+            .expect("Query failed")
+            .into_coll()
+            .expect("Expected coll")
+    };
+
+    assert_eq!(names("[:find [?name ...] :order ?name :where [?p :person/name ?name] [?p :person/age ?age] [(< ?age 30)]]"),
+               vec![TypedValue::typed_string("Alice")]);
+    assert_eq!(names("[:find [?name ...] :order ?name :where [?p :person/name ?name] [?p :person/age ?age] [(<= ?age 30)]]"),
+               vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Bob")]);
+    assert_eq!(names("[:find [?name ...] :order ?name :where [?p :person/name ?name] [?p :person/age ?age] [(> ?age 30)]]"),
+               vec![TypedValue::typed_string("Carol")]);
+
+    // Longs compare with doubles.
+    assert_eq!(names("[:find [?name ...] :order ?name :where [?p :person/name ?name] [?p :person/age ?age] [(>= ?age 29.5)]]"),
+               vec![TypedValue::typed_string("Bob"), TypedValue::typed_string("Carol")]);
+
+    // The comparison is done by SQLite, not after the fact.
+    let explanation = conn.q_explain(&c, "[:find ?p :where [?p :person/age ?age] [(< ?age 30)]]", None)
+                          .expect("explained");
+    let sql = explanation.sql.expect("SQL");
+    assert!(sql.contains(".v < 30"), "unexpected SQL: {}", sql);
+
+    // Strings aren't comparable.
+    match conn.q_once(&c, "[:find ?p :where [?p :person/name ?name] [(< ?name 30)]]", None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidArgument(PlainSymbol(s), ty, i)), _)) => {
+            assert_eq!(s, "<");
+            assert_eq!(ty, "numeric or instant");
+            assert_eq!(i, 0);
+        },
+        x => panic!("Expected InvalidArgument, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_ground() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :person/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:person/name "Alice" :person/age 25}
+        {:person/name "Bob" :person/age 30}
+        {:person/name "Carol" :person/age 41}
+    ]"#).unwrap();
+
+    let q = |query: &str| {
+        conn.q_once(&c, query, None).expect("Query failed").results
+    };
+
+    // Constants alone.
+    assert_eq!(q("[:find ?x . :where [(ground 5) ?x]]"),
+               QueryResults::Scalar(Some(TypedValue::Long(5))));
+    assert_eq!(q("[:find [?x ...] :order ?x :where [(ground [3 1 2]) [?x ...]]]"),
+               QueryResults::Coll(vec![TypedValue::Long(1), TypedValue::Long(2), TypedValue::Long(3)]));
+    assert_eq!(q(r#"[:find [?x ?y] :where [(ground [1 "one"]) [?x ?y]]]"#),
+               QueryResults::Tuple(Some(vec![TypedValue::Long(1), TypedValue::typed_string("one")])));
+
+    // Ground values join with the store like any other binding, and are typed by their literals.
+    assert_eq!(q("[:find [?name ...] :order ?name :where [(ground [25 41]) [?age ...]] [?p :person/age ?age] [?p :person/name ?name]]"),
+               QueryResults::Coll(vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Carol")]));
+    assert_eq!(q(r#"[:find [?p ...]
+                     :where [(ground [["Alice" 25] ["Bob" 31]]) [[?name ?age]]]
+                            [?p :person/name ?name]
+                            [?p :person/age ?age]]"#).len(),
+               1);
+
+    // A string can never be the value of a long attribute.
+    assert!(q(r#"[:find ?p :where [(ground ["25" "30"]) [?age ...]] [?p :person/age ?age]]"#).is_empty());
+
+    // Every row of a relation must bind every variable.
+    assert!(conn.q_once(&c, "[:find ?x :where [(ground [[1 2] [3]]) [[?x ?y]]]]", None).is_err());
+}
+
 #[test]
 fn test_lookup() {
     let mut c = new_connection("").expect("Couldn't open conn.");
@@ -499,3 +739,384 @@ fn test_lookup() {
     let fetched_many = conn.lookup_value_for_attribute(&c, *entid, &foo_many).unwrap().unwrap();
     assert!(two_longs.contains(&fetched_many));
 }
+
+#[test]
+fn test_q_once_each_matches_q_once() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    let query = "[:find ?x ?ident :where [?x :db/ident ?ident] :order ?x]";
+    let expected = q_once(&c, &db.schema, query, None)
+        .expect("Query failed")
+        .into_rel()
+        .expect("Expected rel");
+
+    let mut streamed: Vec<Vec<TypedValue>> = vec![];
+    let count = q_once_each(&c, &db.schema, query, None, |row| {
+        assert_eq!(row.len(), 2);
+        assert_eq!(row.get_by_name("?x"), row.get(0));
+        assert_eq!(row.get_by_name("?ident"), row.get(1));
+        assert_eq!(row.get_by_name("?missing"), None);
+        streamed.push(row.into_values());
+        Ok(ControlFlow::Continue)
+    }).expect("Query failed");
+
+    assert_eq!(count, expected.len());
+    assert_eq!(streamed, expected);
+}
+
+#[test]
+fn test_q_once_each_stops_early() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    let mut invocations = 0;
+    let count = q_once_each(&c, &db.schema, "[:find ?x :where [?x :db/ident _]]", None, |row| {
+        invocations += 1;
+        assert!(row.get_by_name("?x").expect("bound").matches_type(ValueType::Ref));
+        if invocations == 5 {
+            Ok(ControlFlow::Break)
+        } else {
+            Ok(ControlFlow::Continue)
+        }
+    }).expect("Query failed");
+
+    assert_eq!(invocations, 5);
+    assert_eq!(count, 5);
+
+    // Errors from the callback abort iteration and are returned to the caller.
+    let mut invocations = 0;
+    let result = q_once_each(&c, &db.schema, "[:find ?x :where [?x :db/ident _]]", None, |_| {
+        invocations += 1;
+        Err("stop".into())
+    });
+    assert!(result.is_err());
+    assert_eq!(invocations, 1);
+}
+
+#[test]
+fn test_max_rows() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :db/ident :foo/n]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    let entities: Vec<String> = (0..1000).map(|i| format!("[:db/add \"e{}\" :foo/n {}]", i, i)).collect();
+    conn.transact(&mut c, format!("[{}]", entities.join(" ")).as_str()).unwrap();
+
+    let query = "[:find ?e ?n :where [?e :foo/n ?n]]";
+
+    let options = QueryOptions { max_rows: Some(100), ..QueryOptions::default() };
+    match conn.q_once_with_options(&c, query, None, &options).unwrap_err() {
+        Error(ErrorKind::ResultLimitExceeded(100), _) => {},
+        x => panic!("expected ResultLimitExceeded, got {:?}", x),
+    }
+
+    let options = QueryOptions { max_rows: Some(1000), ..QueryOptions::default() };
+    let results = conn.q_once_with_options(&c, query, None, &options).expect("Query failed");
+    assert_eq!(results.len(), 1000);
+
+    // The limit applies to coll results, too, and the results match the unlimited query.
+    let schema = conn.current_schema();
+    let coll = "[:find [?n ...] :where [_ :foo/n ?n]]";
+    let limited = q_once_with_options(&c, &schema, coll, None, &options)
+        .expect("Query failed")
+        .into_coll()
+        .expect("Expected coll");
+    let unlimited = q_once(&c, &schema, coll, None)
+        .expect("Query failed")
+        .into_coll()
+        .expect("Expected coll");
+    assert_eq!(limited.len(), 1000);
+    assert_eq!(limited, unlimited);
+}
+
+#[test]
+fn test_interrupt() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    // A deliberately slow cross-join: tens of millions of rows.
+    let slow = r#"[:find ?a ?b ?c ?d ?e
+                   :where [?a :db/ident _] [?b :db/ident _] [?c :db/ident _] [?d :db/ident _] [?e :db/ident _]]"#;
+
+    let interrupt = QueryInterrupt::new(&c);
+    let options = QueryOptions { interrupt: Some(interrupt.clone()), ..QueryOptions::default() };
+
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        interrupt.interrupt();
+    });
+
+    match q_once_with_options(&c, &db.schema, slow, None, &options).unwrap_err() {
+        Error(ErrorKind::QueryInterrupted, _) => {},
+        x => panic!("expected QueryInterrupted, got {:?}", x),
+    }
+    interrupter.join().expect("interrupter thread to finish");
+
+    // The token stays interrupted.
+    match q_once_with_options(&c, &db.schema, "[:find ?x . :where [?x :db/ident :db/ident]]", None, &options).unwrap_err() {
+        Error(ErrorKind::QueryInterrupted, _) => {},
+        x => panic!("expected QueryInterrupted, got {:?}", x),
+    }
+
+    // Other queries on the same connection are unaffected.
+    let fresh = QueryOptions { interrupt: Some(QueryInterrupt::new(&c)), ..QueryOptions::default() };
+    let results = q_once_with_options(&c, &db.schema, "[:find ?x . :where [?x :db/ident :db/ident]]", None, &fresh)
+        .expect("Query failed");
+    assert_eq!(results.len(), 1);
+}
+
+#[test]
+fn test_timeout() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    // Millions of candidate rows, but only a handful of results.  This is never run to
+    // completion: each run is cut short by its timeout.
+    let slow = r#"[:find ?a
+                   :where [?a _ _] [?b :db/ident _] [?c :db/ident _] [?d :db/ident _]]"#;
+    let fast = "[:find ?x . :where [?x :db/ident :db/ident]]";
+
+    let assert_timed_out = |timeout: Duration| {
+        let options = QueryOptions::default().timeout(timeout);
+        match q_once_with_options(&c, &db.schema, slow, None, &options).unwrap_err() {
+            Error(ErrorKind::QueryTimedOut(elapsed), _) => {
+                assert!(elapsed >= timeout);
+                assert!(elapsed < timeout + Duration::from_secs(5), "interrupted promptly: {:?}", elapsed);
+            },
+            x => panic!("expected QueryTimedOut, got {:?}", x),
+        }
+    };
+    assert_timed_out(Duration::from_millis(50));
+
+    // A fast query completes before its timer fires.  If that timer fired during the next query,
+    // it would fail early with a raw SQLite interruption rather than time out itself.
+    let options = QueryOptions::default().timeout(Duration::from_millis(50));
+    let results = q_once_with_options(&c, &db.schema, fast, None, &options).expect("Query failed");
+    assert_eq!(results.len(), 1);
+    assert_timed_out(Duration::from_millis(200));
+}
+
+#[test]
+fn test_q_explain() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    let explanation = conn.q_explain(&c, "[:find ?x . :where [?x :db/ident :db/doc]]", None)
+                          .expect("explained");
+    assert!(!explanation.is_known_empty());
+    let sql = explanation.sql.expect("SQL");
+    assert!(sql.contains("FROM datoms"), "unexpected SQL: {}", sql);
+    let plan = explanation.plan.expect("plan");
+    assert!(plan.starts_with("EXPLAIN QUERY PLAN "));
+    assert!(plan.contains("datoms"), "unexpected plan: {}", plan);
+
+    // Explaining a query counts as a query.
+    assert_eq!(conn.stats().queries, 1);
+
+    // Unknown attributes can't match anything, so there's no SQL to explain.
+    let explanation = q_explain(&c, &conn.current_schema(), "[:find ?x :where [?x :foo/unknown ?y]]", None)
+                          .expect("explained");
+    assert!(explanation.is_known_empty());
+    assert!(explanation.sql.is_none());
+    assert!(explanation.plan.is_none());
+}
+
+#[test]
+fn test_aggregates_with() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/score]
+        [:db/add "s" :db/valueType :db.type/long]
+        [:db/add "s" :db/cardinality :db.cardinality/many]
+    ]"#).unwrap();
+    let t = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/score 5]
+        [:db/add "a" :foo/score 10]
+        [:db/add "b" :foo/score 5]
+    ]"#).unwrap();
+    let a = t.tempids.get("a").cloned().expect("a");
+    let b = t.tempids.get("b").cloned().expect("b");
+
+    let scalar = |conn: &Conn, query: &str| -> Option<TypedValue> {
+        conn.q_once(&c, query, None)
+            .expect("Query failed")
+            .into_scalar()
+            .expect("Expected scalar")
+    };
+
+    // The two fives are the same binding of `?s`, so they're summed only once…
+    assert_eq!(scalar(&conn, "[:find (sum ?s) . :where [_ :foo/score ?s]]"),
+               Some(TypedValue::Long(15)));
+
+    // … unless we keep the entity around to tell them apart.
+    assert_eq!(scalar(&conn, "[:find (sum ?s) . :with ?e :where [?e :foo/score ?s]]"),
+               Some(TypedValue::Long(20)));
+    assert_eq!(scalar(&conn, "[:find (count ?s) . :with ?e :where [?e :foo/score ?s]]"),
+               Some(TypedValue::Long(3)));
+    assert_eq!(scalar(&conn, "[:find (avg ?s) . :with ?e :where [?e :foo/score ?s]]"),
+               Some(TypedValue::Double((20.0 / 3.0).into())));
+    assert_eq!(scalar(&conn, "[:find (max ?s) . :where [_ :foo/score ?s]]"),
+               Some(TypedValue::Long(10)));
+
+    // Aggregating nothing at all produces no result.
+    assert_eq!(scalar(&conn, "[:find (count ?e) . :where [?e :foo/score 7]]"), None);
+
+    // Aggregates are grouped by the other variables in `:find`.
+    let mut rows = conn.q_once(&c, "[:find ?e (sum ?s) :where [?e :foo/score ?s]]", None)
+        .expect("Query failed")
+        .into_rel()
+        .expect("Expected rel");
+    rows.sort();
+    assert_eq!(rows, vec![vec![TypedValue::Ref(a), TypedValue::Long(15)],
+                          vec![TypedValue::Ref(b), TypedValue::Long(5)]]);
+
+    // Ordering by an aggregated-away variable is an error.
+    assert!(conn.q_once(&c, "[:find (sum ?s) :where [?e :foo/score ?s] :order ?e]", None).is_err());
+
+    // Two aggregates of the same variable share its name, so looking either up by name is
+    // ambiguous and finds nothing.
+    let ambiguous = "[:find (min ?s) (max ?s) :where [_ :foo/score ?s]]";
+    let output = conn.q_once(&c, ambiguous, None).expect("Query failed");
+    assert_eq!(output.column_index("?s"), None);
+    assert_eq!(output.get(0, "?s"), None);
+    assert_eq!(output.into_rel().expect("Expected rel"),
+               vec![vec![TypedValue::Long(5), TypedValue::Long(10)]]);
+    let rows = conn.q_once_each(&c, ambiguous, None, |row| {
+        assert_eq!(row.get_by_name("?s"), None);
+        assert_eq!(row.values(), &[TypedValue::Long(5), TypedValue::Long(10)][..]);
+        Ok(ControlFlow::Continue)
+    }).expect("Query failed");
+    assert_eq!(rows, 1);
+}
+
+#[test]
+fn test_not_and_not_join() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :task/assignee :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+        {:db/ident :task/completed :db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    // Alice has finished one of her two tasks; Bob hasn't finished his; Carol has none.
+    let t = conn.transact(&mut c, r#"[
+        {:db/id "alice" :person/name "Alice"}
+        {:db/id "bob" :person/name "Bob"}
+        {:db/id "carol" :person/name "Carol"}
+        {:db/id "t1" :task/assignee "alice" :task/completed true}
+        {:db/id "t2" :task/assignee "bob" :task/completed false}
+        {:db/id "t3" :task/assignee "alice" :task/completed false}
+    ]"#).unwrap();
+    let tempid = |name: &str| TypedValue::Ref(t.tempids.get(name).cloned().expect("tempid"));
+
+    let coll = |query: &str| -> Vec<TypedValue> {
+        let mut values = conn.q_once(&c, query, None)
+                             .expect("Query failed")
+                             .into_coll()
+                             .expect("Expected coll");
+        values.sort();
+        values
+    };
+
+    // `not` unifies every variable it mentions with the enclosing query.
+    let mut unfinished = vec![tempid("t2"), tempid("t3")];
+    unfinished.sort();
+    assert_eq!(coll("[:find [?t ...] :where [?t :task/assignee _] (not [?t :task/completed true])]"),
+               unfinished);
+
+    // `not-join` only unifies the variables it lists: here the inner `?t` ranges over all tasks.
+    assert_eq!(coll(r#"[:find [?name ...]
+                        :where [?p :person/name ?name]
+                               (not-join [?p]
+                                 [?t :task/assignee ?p]
+                                 [?t :task/completed true])]"#),
+               vec![TypedValue::typed_string("Bob"), TypedValue::typed_string("Carol")]);
+
+    // … even if the enclosing query binds a variable of the same name.
+    assert_eq!(coll(r#"[:find [?t ...]
+                        :where [?t :task/assignee ?p]
+                               (not-join [?p]
+                                 [?t :task/assignee ?p]
+                                 [?t :task/completed true])]"#),
+               vec![tempid("t2")]);
+
+    // Variables that a `not` needs must be bound outside it.
+    assert!(conn.q_once(&c, "[:find ?p :where [?p :person/name _] (not [?t :task/completed true])]", None).is_err());
+}
+
+#[test]
+fn test_or_and_or_join() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :task/priority :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+        {:db/ident :task/completed :db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}
+        {:db/ident :task/blocks :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    // t1 is urgent; t2 is done; t3 blocks t1; t4 is neither.
+    let t = conn.transact(&mut c, r#"[
+        {:db/id "t1" :task/priority 1 :task/completed false}
+        {:db/id "t2" :task/priority 3 :task/completed true}
+        {:db/id "t3" :task/priority 3 :task/completed false :task/blocks "t1"}
+        {:db/id "t4" :task/priority 2 :task/completed false}
+    ]"#).unwrap();
+    let tempid = |name: &str| TypedValue::Ref(t.tempids.get(name).cloned().expect("tempid"));
+    let sorted = |mut values: Vec<TypedValue>| { values.sort(); values };
+
+    let coll = |query: &str| -> Vec<TypedValue> {
+        let mut values = conn.q_once(&c, query, None)
+                             .expect("Query failed")
+                             .into_coll()
+                             .expect("Expected coll");
+        values.sort();
+        values
+    };
+
+    // Each branch of an `or` can constrain a different attribute of the same entity.
+    assert_eq!(coll(r#"[:find [?e ...]
+                        :where [?e :task/priority _]
+                               (or [?e :task/priority 1]
+                                   [?e :task/completed true])]"#),
+               sorted(vec![tempid("t1"), tempid("t2")]));
+
+    // `or-join` only unifies the variables it lists, so a branch can use its own inner variables.
+    assert_eq!(coll(r#"[:find [?e ...]
+                        :where [?e :task/priority _]
+                               (or-join [?e]
+                                 [?e :task/priority 1]
+                                 (and [?e :task/blocks ?other]
+                                      [?other :task/priority 1]))]"#),
+               sorted(vec![tempid("t1"), tempid("t3")]));
+
+    // Every branch of an `or` must mention the same variables.
+    let query = r#"[:find ?e :where [?e :task/priority _] (or [?e :task/priority 1] [?e :task/blocks ?other])]"#;
+    match conn.q_once(&c, query, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::NonMatchingVariablesInOrClause(expected, found)), _)) => {
+            assert_eq!(expected, "[?e]");
+            assert_eq!(found, "[?e ?other]");
+        },
+        x => panic!("Expected NonMatchingVariablesInOrClause, got {:?}", x),
+    }
+
+    // … and every branch of an `or-join` must mention the variables it joins.
+    let query = r#"[:find ?e :where [?e :task/priority _] (or-join [?e] [?e :task/priority 1] [?other :task/priority 2])]"#;
+    match conn.q_once(&c, query, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::NonMatchingVariablesInOrClause(expected, found)), _)) => {
+            assert_eq!(expected, "[?e]");
+            assert_eq!(found, "[?other]");
+        },
+        x => panic!("Expected NonMatchingVariablesInOrClause, got {:?}", x),
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![cfg(feature = "serde")]
+
+extern crate serde_json;
+
+extern crate mentat;
+extern crate mentat_core;
+
+use std::f64;
+use std::str::FromStr;
+
+use mentat_core::{
+    DateTime,
+    TypedValue,
+    Utc,
+    Uuid,
+};
+
+use mentat::{
+    Conn,
+    QueryResults,
+    TxReport,
+};
+
+fn round_trip_results(results: &QueryResults) -> QueryResults {
+    let json = serde_json::to_string(results).expect("serialized");
+    serde_json::from_str(&json).expect("deserialized")
+}
+
+#[test]
+fn test_typed_value_round_trip() {
+    let instant = DateTime::<Utc>::from_str("2017-04-28T20:23:05.187123456Z").expect("parsed");
+    let values = vec![
+        TypedValue::Ref(65536),
+        TypedValue::Boolean(true),
+        TypedValue::Long(65536),
+        TypedValue::from(1.5f64),
+        TypedValue::Instant(instant),
+        TypedValue::typed_string(":foo/bar"),
+        TypedValue::typed_ns_keyword("foo", "bar"),
+        TypedValue::Uuid(Uuid::from_str("550e8400-e29b-41d4-a716-446655440000").expect("parsed")),
+    ];
+
+    for value in values {
+        let json = serde_json::to_string(&value).expect("serialized");
+        let back: TypedValue = serde_json::from_str(&json).expect("deserialized");
+        assert_eq!(back, value);
+    }
+
+    // Refs and longs, and keywords and strings, are distinguished.
+    assert_eq!(serde_json::to_string(&TypedValue::Ref(5)).unwrap(), r#"{"ref":5}"#);
+    assert_eq!(serde_json::to_string(&TypedValue::Long(5)).unwrap(), r#"{"long":5}"#);
+    assert_eq!(serde_json::to_string(&TypedValue::typed_ns_keyword("foo", "bar")).unwrap(), r#"{"keyword":":foo/bar"}"#);
+    assert_eq!(serde_json::to_string(&TypedValue::typed_string(":foo/bar")).unwrap(), r#"{"string":":foo/bar"}"#);
+
+    assert!(serde_json::from_str::<TypedValue>(r#"{"keyword":"foo"}"#).is_err());
+    assert!(serde_json::from_str::<TypedValue>(r#"{"instant":"yesterday"}"#).is_err());
+}
+
+#[test]
+fn test_non_finite_double_round_trip() {
+    let cases = vec![
+        (f64::NAN, r#"{"double":"NaN"}"#),
+        (f64::INFINITY, r#"{"double":"Infinity"}"#),
+        (f64::NEG_INFINITY, r#"{"double":"-Infinity"}"#),
+    ];
+    for (d, expected) in cases {
+        let value = TypedValue::from(d);
+        let json = serde_json::to_string(&value).expect("serialized");
+        assert_eq!(json, expected);
+        let back: TypedValue = serde_json::from_str(&json).expect("deserialized");
+        assert_eq!(back, value);
+    }
+
+    assert_eq!(serde_json::to_string(&TypedValue::from(1.5f64)).unwrap(), r#"{"double":1.5}"#);
+    assert!(serde_json::from_str::<TypedValue>(r#"{"double":"1.5"}"#).is_err());
+}
+
+#[test]
+fn test_query_results_round_trip() {
+    let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+    conn.transact(&mut sqlite, r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#)
+        .expect("transacted");
+    let report = conn.transact(&mut sqlite, r#"[{:db/id "a" :test/name "Alice"} {:db/id "b" :test/name "Bob"}]"#)
+                     .expect("transacted");
+
+    let queries = vec![
+        r#"[:find ?x . :where [?x :test/name "Alice"]]"#,
+        r#"[:find ?x . :where [?x :test/name "Carol"]]"#,
+        r#"[:find [?x ?name] :where [?x :test/name ?name]]"#,
+        r#"[:find [?name ...] :where [_ :test/name ?name]]"#,
+        r#"[:find ?x ?name :where [?x :test/name ?name]]"#,
+    ];
+    for query in queries {
+        let results = conn.q_once(&sqlite, query, None).expect("query succeeded").results;
+        assert_eq!(round_trip_results(&results), results);
+    }
+
+    // Results are tagged with their shape.
+    let scalar = QueryResults::Scalar(Some(TypedValue::Ref(report.tempids["a"])));
+    assert_eq!(serde_json::to_string(&scalar).unwrap(),
+               format!(r#"{{"type":"scalar","values":{{"ref":{}}}}}"#, report.tempids["a"]));
+    assert_eq!(serde_json::to_string(&QueryResults::Scalar(None)).unwrap(), r#"{"type":"scalar","values":null}"#);
+    let rel = QueryResults::Rel(vec![vec![TypedValue::Long(1), TypedValue::typed_string("x")]]);
+    assert_eq!(serde_json::to_string(&rel).unwrap(), r#"{"type":"rel","values":[[{"long":1},{"string":"x"}]]}"#);
+    assert_eq!(round_trip_results(&QueryResults::Tuple(None)), QueryResults::Tuple(None));
+}
+
+#[test]
+fn test_tx_report_round_trip() {
+    let (mut conn, mut sqlite) = Conn::in_memory().unwrap();
+    let report = conn.transact(&mut sqlite, r#"[[:db/add "a" :db/ident :test/a] [:db/add "b" :db/ident :test/b]]"#)
+                     .expect("transacted");
+
+    let json = serde_json::to_value(&report).expect("serialized");
+    assert_eq!(json["tx_id"], serde_json::Value::from(report.tx_id));
+    assert_eq!(json["tempids"]["a"], serde_json::Value::from(report.tempids["a"]));
+    assert_eq!(json["tx_instant"], serde_json::Value::from(report.tx_instant.to_rfc3339()));
+
+    let back: TxReport = serde_json::from_value(json).expect("deserialized");
+    assert_eq!(back, report);
+}
@@ -28,9 +28,9 @@ use combine::combinator::{
     try
 };
 
-use errors as cli;
+use edn::utils::FormScanner;
 
-use edn;
+use errors as cli;
 
 pub static HELP_COMMAND: &'static str = &"help";
 pub static OPEN_COMMAND: &'static str = &"open";
@@ -42,34 +42,50 @@ pub static LONG_TRANSACT_COMMAND: &'static str = &"transact";
 pub static SHORT_TRANSACT_COMMAND: &'static str = &"t";
 pub static LONG_EXIT_COMMAND: &'static str = &"exit";
 pub static SHORT_EXIT_COMMAND: &'static str = &"e";
+pub static STATS_COMMAND: &'static str = &"stats";
+pub static EXPORT_COMMAND: &'static str = &"export";
+pub static SQL_COMMAND: &'static str = &"sql";
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Command {
     Close,
     Exit,
+    Export(String),
     Help(Vec<String>),
     Open(String),
     Query(String),
     Schema,
+    Sql(String),
+    Stats,
     Transact(String),
 }
 
+/// Returns true if every bracket, brace, and parenthesis opened in `edn` has been closed.
+/// Delimiters inside strings, comments, and character literals don't count.
+pub fn brackets_balanced(edn: &str) -> bool {
+    let mut scanner = FormScanner::new();
+    scanner.push_str(edn);
+    scanner.is_balanced()
+}
+
 impl Command {
     /// is_complete returns true if no more input is required for the command to be successfully executed.
-    /// false is returned if the command is not considered valid. 
-    /// Defaults to true for all commands except Query and Transact.
-    /// TODO: for query and transact commands, they will be considered complete if a parsable EDN has been entered as an argument
+    /// Defaults to true for all commands except Query and Transact, which are complete once
+    /// every bracket in their EDN argument has been closed.  The EDN may still fail to parse.
     pub fn is_complete(&self) -> bool {
         match self {
             &Command::Query(ref args) |
             &Command::Transact(ref args) => {
-                edn::parse::value(&args).is_ok()
+                brackets_balanced(&args)
             },
             &Command::Help(_) |
             &Command::Open(_) |
             &Command::Close |
             &Command::Exit |
-            &Command::Schema => true
+            &Command::Export(_) |
+            &Command::Schema |
+            &Command::Sql(_) |
+            &Command::Stats => true
         }
     }
 
@@ -96,11 +112,27 @@ impl Command {
             &Command::Schema => {
                 format!(".{}", SCHEMA_COMMAND)
             },
+            &Command::Stats => {
+                format!(".{}", STATS_COMMAND)
+            },
+            &Command::Export(ref args) => {
+                format!(".{} {}", EXPORT_COMMAND, args)
+            },
+            &Command::Sql(ref args) => {
+                format!(".{} {}", SQL_COMMAND, args)
+            },
         }
     }
 }
 
+/// Parse a line of input as a command.  Commands start with a `.`; input that starts with `[` or
+/// `{` is taken to be a query.
 pub fn command(s: &str) -> Result<Command, cli::Error> {
+    let trimmed = s.trim_left();
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        return Ok(Command::Query(trimmed.to_string()));
+    }
+
     let arguments = || sep_end_by::<Vec<_>, _, _>(many1(satisfy(|c: char| !c.is_whitespace())), many1::<Vec<_>, _>(space())).expected("arguments");
 
     let help_parser = string(HELP_COMMAND)
@@ -145,6 +177,36 @@ pub fn command(s: &str) -> Result<Command, cli::Error> {
                         Ok(Command::Schema)
                     });
 
+    let stats_parser = string(STATS_COMMAND)
+                    .with(no_arg_parser())
+                    .map(|args| {
+                        if !args.is_empty() {
+                            bail!(cli::ErrorKind::CommandParse(format!("Unrecognized argument {:?}", args[0])) );
+                        }
+                        Ok(Command::Stats)
+                    });
+
+    let export_parser = string(EXPORT_COMMAND)
+                    .with(spaces())
+                    .with(arguments())
+                    .map(|args| {
+                        if args.len() < 1 {
+                            bail!(cli::ErrorKind::CommandParse("Missing required argument".to_string()));
+                        }
+                        if args.len() > 1 {
+                            bail!(cli::ErrorKind::CommandParse(format!("Unrecognized argument {:?}", args[1])));
+                        }
+                        Ok(Command::Export(args[0].clone()))
+                    });
+
+    let sql_parser = string(SQL_COMMAND)
+                    .with(many1::<Vec<_>, _>(space()))
+                    .with(many1::<Vec<_>, _>(try(any())))
+                    .map(|sql| {
+                        let sql: String = sql.iter().collect();
+                        Ok(Command::Sql(sql.trim().to_string()))
+                    });
+
     let exit_parser = try(string(LONG_EXIT_COMMAND)).or(try(string(SHORT_EXIT_COMMAND)))
                     .with(no_arg_parser())
                     .map(|args| {
@@ -176,13 +238,16 @@ pub fn command(s: &str) -> Result<Command, cli::Error> {
 
     spaces()
     .skip(token('.'))
-    .with(choice::<[&mut Parser<Input = _, Output = Result<Command, cli::Error>>; 7], _>
+    .with(choice::<[&mut Parser<Input = _, Output = Result<Command, cli::Error>>; 10], _>
           ([&mut try(help_parser),
             &mut try(open_parser),
             &mut try(close_parser),
             &mut try(exit_parser),
             &mut try(query_parser),
             &mut try(schema_parser),
+            &mut try(stats_parser),
+            &mut try(export_parser),
+            &mut try(sql_parser),
             &mut try(transact_parser)]))
         .parse(s)
         .unwrap_or((Err(cli::ErrorKind::CommandParse(format!("Invalid command {:?}", s)).into()), "")).0
@@ -498,6 +563,66 @@ mod tests {
         assert_eq!(err.to_string(), format!("Invalid command {:?}", input));
     }
 
+    #[test]
+    fn test_bare_query() {
+        let input = "  [:find ?x :where [?x foo/bar ?y]]";
+        let cmd = command(&input).expect("Expected query command");
+        match cmd {
+            Command::Query(edn) => assert_eq!(edn, "[:find ?x :where [?x foo/bar ?y]]"),
+            _ => assert!(false)
+        }
+    }
+
+    #[test]
+    fn test_brackets_balanced() {
+        assert!(brackets_balanced("[:find ?x :where [?x :foo/bar ?y]]"));
+        assert!(brackets_balanced("{:a [1 2 (3)]}"));
+        assert!(!brackets_balanced("[:find ?x\n"));
+        assert!(!brackets_balanced("[:find ?x :where [?x :foo/bar ?y]\n"));
+
+        // Brackets in strings, comments, and character literals don't count.
+        assert!(brackets_balanced("[[:db/add \"s\" :foo/name \"[\"]]"));
+        assert!(brackets_balanced("[[:db/add \"s\" :foo/name \"\\\"[\"]]"));
+        assert!(!brackets_balanced("[[:db/add \"s\" :foo/name \"]]"));
+        assert!(brackets_balanced("[:find ?x ; ]]]\n :where [?x :foo/bar \\[]]"));
+        assert!(!brackets_balanced("[:find ?x ; ]]]\n"));
+    }
+
+    #[test]
+    fn test_transact_is_complete() {
+        assert!(!Command::Transact("[[:db/add \"s\"\n".to_string()).is_complete());
+        assert!(Command::Transact("[[:db/add \"s\"\n :db/ident :foo/bar]]".to_string()).is_complete());
+    }
+
+    #[test]
+    fn test_stats_parser() {
+        let cmd = command(".stats ").expect("Expected stats command");
+        assert_eq!(cmd, Command::Stats);
+
+        let input = ".stats arg1";
+        let err = command(&input).expect_err("Expected an error");
+        assert_eq!(err.to_string(), format!("Invalid command {:?}", input));
+    }
+
+    #[test]
+    fn test_export_parser() {
+        let cmd = command(".export /path/to/export.edn").expect("Expected export command");
+        assert_eq!(cmd, Command::Export("/path/to/export.edn".to_string()));
+
+        let err = command(".export").expect_err("Expected an error");
+        assert_eq!(err.to_string(), "Missing required argument");
+    }
+
+    #[test]
+    fn test_sql_parser() {
+        let cmd = command(".sql SELECT e, a FROM datoms WHERE v = 'x' \n").expect("Expected sql command");
+        assert_eq!(cmd, Command::Sql("SELECT e, a FROM datoms WHERE v = 'x'".to_string()));
+
+        let input = ".sql";
+        let err = command(&input).expect_err("Expected an error");
+        assert_eq!(err.to_string(), format!("Invalid command {:?}", input));
+    }
+
     #[test]
     fn test_parser_preceeding_trailing_whitespace() {
         let input = " .close ";
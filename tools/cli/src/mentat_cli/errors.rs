@@ -13,6 +13,7 @@
 use rusqlite;
 
 use mentat::errors as mentat;
+use mentat_db;
 
 error_chain! {
     types {
@@ -25,6 +26,7 @@ error_chain! {
 
     links {
         MentatError(mentat::Error, mentat::ErrorKind);
+        DbError(mentat_db::Error, mentat_db::ErrorKind);
     }
 
     errors {
@@ -83,7 +83,8 @@ impl InputReader {
 
         self.buffer.push_str(&line);
 
-        if self.buffer.is_empty() {
+        if self.buffer.trim().is_empty() {
+            self.buffer.clear();
             return Ok(Empty);
         }
 
@@ -92,14 +93,15 @@ impl InputReader {
         // if we have a command in process (i.e. an incomplete query or transaction),
         // then we already know which type of command it is and so we don't need to parse the
         // command again, only the content, which we do later.
-        // Therefore, we add the newly read in line to the existing command args.
+        // Therefore, we add the newly read in line to the existing command args, on a line of its
+        // own so that a comment on the previous line doesn't swallow it.
         // If there is no in process command, we parse the read in line as a new command.
         let cmd = match &self.in_process_cmd {
             &Some(Command::Query(ref args)) => {
-                Ok(Command::Query(args.clone() + " " + &line))
+                Ok(Command::Query(args.clone() + "\n" + &line))
             },
             &Some(Command::Transact(ref args)) => {
-                Ok(Command::Transact(args.clone() + " " + &line))
+                Ok(Command::Transact(args.clone() + "\n" + &line))
             },
             _ => {
                 command(&self.buffer)
@@ -65,7 +65,7 @@ pub fn run() -> i32 {
     }
 
     let mut last_arg: Option<&str> = None;
-    let cmds:Vec<command_parser::Command> = args.iter().filter_map(|arg| {
+    let mut cmds:Vec<command_parser::Command> = args.iter().filter_map(|arg| {
         match last_arg {
             Some("-d") => {
                 last_arg = None;
@@ -87,6 +87,11 @@ pub fn run() -> i32 {
         } 
     }).collect();
 
+    // `mentat_cli path/to/db` is shorthand for `mentat_cli -d path/to/db`.
+    if let Some(database) = matches.free.first() {
+        cmds.insert(0, command_parser::Command::Open(database.clone()));
+    }
+
     let repl = repl::Repl::new();
     if repl.is_ok() {
         repl.unwrap().run(Some(cmds));
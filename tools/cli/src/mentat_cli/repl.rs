@@ -9,15 +9,16 @@
 // specific language governing permissions and limitations under the License.
 
 use std::collections::HashMap;  
+use std::fs::File;
 use std::process;
 
-use mentat::query::QueryResults;
-use mentat_core::TypedValue;
-
 use command_parser::{
     Command, 
     HELP_COMMAND, 
     OPEN_COMMAND,
+    EXPORT_COMMAND,
+    SQL_COMMAND,
+    STATS_COMMAND,
     LONG_QUERY_COMMAND,
     SHORT_QUERY_COMMAND,
     SCHEMA_COMMAND,
@@ -48,6 +49,9 @@ lazy_static! {
         map.insert(LONG_QUERY_COMMAND, "Execute a query against the current open database.");
         map.insert(SHORT_QUERY_COMMAND, "Shortcut for `.query`. Execute a query against the current open database.");
         map.insert(SCHEMA_COMMAND, "Output the schema for the current open database.");
+        map.insert(STATS_COMMAND, "Output the number of datoms and transactions in the current open database, and how much of each partition has been allocated.");
+        map.insert(EXPORT_COMMAND, "Export the current open database as EDN to the file at path.");
+        map.insert(SQL_COMMAND, "Execute a SQL statement directly against the current open database.");
         map.insert(LONG_TRANSACT_COMMAND, "Execute a transact against the current open database.");
         map.insert(SHORT_TRANSACT_COMMAND, "Shortcut for `.transact`. Execute a transact against the current open database.");
         map
@@ -120,6 +124,14 @@ impl Repl {
                 };
                 
             }
+            Command::Stats => self.print_stats(),
+            Command::Export(path) => self.export(path),
+            Command::Sql(sql) => {
+                match self.store.sql(&sql) {
+                    Ok(s) => println!("{}", s),
+                    Err(e) => println!("{}", e)
+                };
+            },
             Command::Transact(transaction) => self.execute_transact(transaction),
             Command::Exit => {
                 self.close();
@@ -137,6 +149,33 @@ impl Repl {
         };
     }
 
+    fn print_stats(&self) {
+        let stats = match self.store.stats() {
+            Ok(stats) => stats,
+            Err(e) => return println!("{}", e),
+        };
+
+        println!("datoms: {}", stats.datoms);
+        println!("transactions: {}", stats.transactions);
+        println!("partitions:");
+        for partition in stats.partitions {
+            println!("  {}: start {}, next {} ({} allocated)", partition.name, partition.start, partition.index, partition.allocated());
+        }
+        println!("this session: {} transactions committed, {} rolled back, {} queries",
+                 stats.session.transactions_committed, stats.session.transactions_rolled_back, stats.session.queries);
+    }
+
+    fn export(&mut self, path: String) {
+        let mut file = match File::create(&path) {
+            Ok(file) => file,
+            Err(e) => return println!("{}", e),
+        };
+        match self.store.export_edn(&mut file) {
+            Ok(_) => println!("Exported to {:?}", path),
+            Err(e) => println!("{}", e)
+        };
+    }
+
     fn help_command(&self, args: Vec<String>) {
         if args.is_empty() {
             for (cmd, msg) in COMMAND_HELP.iter() {
@@ -166,35 +205,10 @@ impl Repl {
         };
 
         if results.is_empty() {
-            println!("No results found.")
-        }
-        
-        let mut output:String = String::new();
-        match results {
-            QueryResults::Scalar(Some(val)) => { 
-                output.push_str(&self.typed_value_as_string(val) ); 
-            },
-            QueryResults::Tuple(Some(vals)) => { 
-                for val in vals {
-                    output.push_str(&format!("{}\t", self.typed_value_as_string(val)));
-                }
-            },
-            QueryResults::Coll(vv) => { 
-                for val in vv {
-                    output.push_str(&format!("{}\n", self.typed_value_as_string(val)));
-                }
-            },
-            QueryResults::Rel(vvv) => { 
-                for vv in vvv {
-                    for v in vv {
-                        output.push_str(&format!("{}\t", self.typed_value_as_string(v)));
-                    }
-                    output.push_str("\n");
-                }
-            },
-            _ => output.push_str(&format!("No results found."))
+            return println!("No results found.");
         }
-        println!("\n{}", output);
+
+        println!("\n{}", results.dump(&*self.store.schema()));
     }
 
     pub fn execute_transact(&mut self, transaction: String) {
@@ -203,19 +217,6 @@ impl Repl {
             Result::Err(err) => println!("{:?}.", err),
         }
     }
-
-    fn typed_value_as_string(&self, value: TypedValue) -> String {
-        match value {
-            TypedValue::Boolean(b) => if b { "true".to_string() } else { "false".to_string() },
-            TypedValue::Double(d) => format!("{}", d),
-            TypedValue::Instant(i) => format!("{}", i),
-            TypedValue::Keyword(k) => format!("{}", k),
-            TypedValue::Long(l) => format!("{}", l),
-            TypedValue::Ref(r) => format!("{}", r),
-            TypedValue::String(s) => format!("{:?}", s.to_string()),
-            TypedValue::Uuid(u) => format!("{}", u),
-        }
-    }
 }
 
 #[cfg(test)]
@@ -8,6 +8,12 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::io::{
+    Read,
+    Write,
+};
+use std::sync::Arc;
+
 use rusqlite;
 
 use edn;
@@ -18,9 +24,18 @@ use mentat::{
     new_connection,
 };
 
-use mentat::query::QueryResults;
+use mentat::query::QueryOutput;
+use mentat::{
+    ConnStats,
+    GcReport,
+    PartitionInfo,
+    VacuumMode,
+    VerificationReport,
+};
 
 use mentat::conn::Conn;
+use mentat_core::Schema;
+use mentat_db::debug::dump_sql_query;
 use mentat_db::types::TxReport;
 
 pub struct Store {
@@ -29,6 +44,15 @@ pub struct Store {
     pub db_name: String,
 }
 
+/// What `.stats` reports about a store.
+pub struct StoreStats {
+    pub datoms: i64,
+    pub transactions: i64,
+    pub partitions: Vec<PartitionInfo>,
+    /// The counters of the `Conn`, which cover only the current session.
+    pub session: ConnStats,
+}
+
 pub fn db_output_name(db_name: &String) -> String {
     if db_name.is_empty() { "in-memory db".to_string() } else { db_name.clone() }
 }
@@ -54,7 +78,7 @@ impl Store {
         self.open(None)
     }
 
-    pub fn query(&self, query: String) -> Result<QueryResults, cli::Error> {
+    pub fn query(&self, query: String) -> Result<QueryOutput, cli::Error> {
         Ok(self.conn.q_once(&self.handle, &query, None)?)
     }
 
@@ -62,7 +86,44 @@ impl Store {
         Ok(self.conn.transact(&mut self.handle, &transaction)?)
     }
 
+    pub fn verify(&self) -> Result<VerificationReport, cli::Error> {
+        Ok(self.conn.verify(&self.handle)?)
+    }
+
+    pub fn gc(&mut self, vacuum: VacuumMode) -> Result<GcReport, cli::Error> {
+        Ok(self.conn.gc(&mut self.handle, vacuum)?)
+    }
+
+    pub fn export_edn(&mut self, w: &mut Write) -> Result<(), cli::Error> {
+        Ok(self.conn.export_edn(&mut self.handle, w)?)
+    }
+
+    pub fn import_edn(&mut self, r: &mut Read) -> Result<(), cli::Error> {
+        Ok(self.conn.import_edn(&mut self.handle, r)?)
+    }
+
     pub fn fetch_schema(&self) -> edn::Value {
         self.conn.current_schema().to_edn_value()
     }
+
+    pub fn schema(&self) -> Arc<Schema> {
+        self.conn.current_schema()
+    }
+
+    pub fn stats(&self) -> Result<StoreStats, cli::Error> {
+        let datoms = self.handle.query_row("SELECT count(*) FROM datoms", &[], |row| row.get(0))?;
+        let transactions = self.handle.query_row("SELECT count(DISTINCT tx) FROM transactions", &[], |row| row.get(0))?;
+        Ok(StoreStats {
+            datoms: datoms,
+            transactions: transactions,
+            partitions: self.conn.partitions(),
+            session: self.conn.stats(),
+        })
+    }
+
+    /// Run `sql` directly against the store's SQLite connection, formatting the results with
+    /// `mentat_db::debug::dump_sql_query`.
+    pub fn sql(&self, sql: &str) -> Result<String, cli::Error> {
+        Ok(dump_sql_query(&self.handle, sql, &[], None)?)
+    }
 }
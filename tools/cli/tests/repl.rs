@@ -0,0 +1,141 @@
+// Copyright 2017 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate mentat;
+extern crate tempfile;
+
+use std::env;
+use std::fs::File;
+use std::io::{
+    Read,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process::{
+    Command,
+    Stdio,
+};
+
+use tempfile::NamedTempFile;
+
+use mentat::Conn;
+
+/// The path to the `mentat_cli` binary.  Cargo builds it next to the directory holding the
+/// integration tests.
+fn cli_path() -> PathBuf {
+    let mut path = env::current_exe().expect("current executable");
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.push(format!("mentat_cli{}", env::consts::EXE_SUFFIX));
+    path
+}
+
+/// Open the store at `db` in the REPL, feed it `input` on stdin, and return what it prints.
+fn run_repl(db: &Path, input: &str) -> String {
+    let mut child = Command::new(cli_path())
+        .arg(db)
+        .env_remove("TERM")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("to spawn the REPL");
+    child.stdin.as_mut().unwrap().write_all(input.as_bytes()).expect("to write input");
+
+    let output = child.wait_with_output().expect("to run the REPL");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).expect("UTF-8 output")
+}
+
+/// A store holding two people.
+fn seeded_store() -> NamedTempFile {
+    let file = NamedTempFile::new().expect("temporary file");
+    let (mut conn, mut sqlite) = Conn::open(file.path()).expect("opened");
+    conn.transact(&mut sqlite, "[{:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]")
+        .expect("transacted schema");
+    conn.transact(&mut sqlite, r#"[{:person/name "Alice"} {:person/name "Bob"}]"#)
+        .expect("transacted data");
+    file
+}
+
+/// The lines of `output`, without the trailing whitespace the table padding leaves.
+fn lines(output: &str) -> Vec<&str> {
+    output.lines().map(|line| line.trim_right()).collect()
+}
+
+#[test]
+fn test_query() {
+    let db = seeded_store();
+    let output = run_repl(db.path(), "[:find [?name ...] :where [_ :person/name ?name] :order ?name]\n");
+
+    let lines = lines(&output);
+    let header = lines.iter().position(|line| *line == "?name").expect("a header");
+    assert_eq!(&lines[header + 1..header + 3], &["\"Alice\"", "\"Bob\""]);
+}
+
+#[test]
+fn test_multi_line_input() {
+    let db = seeded_store();
+    let output = run_repl(db.path(),
+                          ".t [{:person/name\n\
+                                \"Carol\"}]\n\
+                           [:find ?name\n\
+                            :where [_ :person/name ?name]\n\
+                            ; ]]] comments don't close brackets\n\
+                            :order ?name]\n");
+
+    let lines = lines(&output);
+    let header = lines.iter().position(|line| *line == "?name").expect("a header");
+    assert_eq!(&lines[header + 1..header + 4], &["\"Alice\"", "\"Bob\"", "\"Carol\""]);
+}
+
+#[test]
+fn test_no_results() {
+    let db = seeded_store();
+    let output = run_repl(db.path(), "[:find ?e :where [?e :person/name \"Dave\"]]\n");
+    assert!(lines(&output).contains(&"No results found."));
+}
+
+#[test]
+fn test_sql() {
+    let db = seeded_store();
+    let output = run_repl(db.path(), ".sql SELECT upper(v) AS name FROM datoms WHERE v = 'Bob'\n");
+
+    let lines = lines(&output);
+    let header = lines.iter().position(|line| *line == "name").expect("a header");
+    assert_eq!(lines[header + 1], "BOB");
+}
+
+#[test]
+fn test_stats() {
+    let db = seeded_store();
+    let output = run_repl(db.path(), ".stats\n");
+
+    let lines = lines(&output);
+    assert!(lines.iter().any(|line| line.starts_with("datoms: ")));
+    assert!(lines.iter().any(|line| line.starts_with("  :db.part/user: ")));
+}
+
+#[test]
+fn test_export() {
+    let db = seeded_store();
+    let export = NamedTempFile::new().expect("temporary file");
+    let output = run_repl(db.path(), &format!(".export {}\n", export.path().display()));
+    assert!(output.contains("Exported to"));
+
+    let mut exported = String::new();
+    File::open(export.path()).expect("opened export").read_to_string(&mut exported).expect("read export");
+    assert!(exported.contains("\"Alice\""));
+    assert!(exported.contains("\"Bob\""));
+}
@@ -23,6 +23,18 @@ error_chain! {
             display("error parsing edn values:\n{}", parse_error)
         }
 
+        /// A top-level entity of a transaction couldn't be parsed.  The entity is identified by its
+        /// index in the transaction, counting from 0, and by the 1-based line and column at which
+        /// it starts, if the source text is known.  The underlying `ParseError` follows in the
+        /// chain.
+        EntityParseError(index: usize, line_and_column: Option<(usize, usize)>, message: String) {
+            description("error parsing entity")
+            display("entity {}{}: {}",
+                    index,
+                    line_and_column.map_or_else(String::new, |(line, column)| format!(" (line {}, column {})", line, column)),
+                    message)
+        }
+
         DbIdError {
             description("bad :db/id in map notation")
             display("bad :db/id in map notation: must either be not present or be an entid, an ident, or a tempid")
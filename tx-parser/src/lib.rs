@@ -41,7 +41,7 @@ use mentat_tx::entities::{
     OpType,
     TempId,
 };
-use mentat_parser_utils::{ResultParser};
+use mentat_parser_utils::{ResultParser, ValueParseError};
 use mentat_parser_utils::value_and_span::{
     Item,
     OfExactlyParsing,
@@ -157,13 +157,74 @@ def_parser!(Tx, entities, Vec<Entity>, {
     vector().of_exactly(many(Tx::entity()))
 });
 
+/// Say, in terms a user can act on, what's wrong with `entity`, a top-level entity that failed to
+/// parse.
+fn describe_invalid_entity(entity: &edn::ValueAndSpan) -> String {
+    match entity.inner {
+        edn::SpannedValue::Vector(ref vs) => {
+            let op = match vs.first().map(|v| &v.inner) {
+                Some(&edn::SpannedValue::NamespacedKeyword(ref kw)) if kw.namespace == "db" && (kw.name == "add" || kw.name == "retract") => kw,
+                Some(v) => return format!("expected :db/add or :db/retract, found {}", v.type_name()),
+                None => return "expected :db/add or :db/retract, found an empty vector".to_string(),
+            };
+            if vs.len() != 4 {
+                return format!("expected [{} e a v], found {} values", op, vs.len());
+            }
+            match vs[2].inner {
+                edn::SpannedValue::NamespacedKeyword(_) |
+                edn::SpannedValue::Integer(_) => format!("expected an entity and a value in [{} e a v]", op),
+                ref a => format!("expected an attribute in [{} e a v], found {}", op, a.type_name()),
+            }
+        },
+        edn::SpannedValue::Map(_) => "expected map notation, with attributes as keys".to_string(),
+        ref v => format!("expected :db/add, :db/retract, or a map, found {}", v.type_name()),
+    }
+}
+
 impl<'a> Tx<'a> {
+    /// Parse `input`, a transaction vector, into entities.
+    ///
+    /// If an entity can't be parsed, this fails with `ErrorKind::EntityParseError`, saying which
+    /// entity and why.
     pub fn parse(input: &'a edn::ValueAndSpan) -> std::result::Result<Vec<Entity>, errors::Error> {
-        Tx::entities()
-            .skip(eof())
-            .parse(input.atom_stream())
-            .map(|x| x.0)
-            .map_err(|e| Error::from_kind(ErrorKind::ParseError(e.into())))
+        Tx::parse_located(input, None)
+    }
+
+    /// Like `parse`, but a failure to parse reports its location as a line and column in `source`,
+    /// the EDN text from which `input` was parsed.
+    pub fn parse_with_source(input: &'a edn::ValueAndSpan, source: &str) -> std::result::Result<Vec<Entity>, errors::Error> {
+        Tx::parse_located(input, Some(source))
+    }
+
+    fn parse_located(input: &'a edn::ValueAndSpan, source: Option<&str>) -> std::result::Result<Vec<Entity>, errors::Error> {
+        let parse_error: ValueParseError = match Tx::entities().skip(eof()).parse(input.atom_stream()) {
+            Ok((entities, _)) => return Ok(entities),
+            Err(e) => e.into(),
+        };
+        let parse_error = match source {
+            Some(source) => parse_error.with_source(source),
+            None => parse_error,
+        };
+
+        // Find the top-level entity in which parsing failed.
+        let position = parse_error.position;
+        let invalid = match input.inner {
+            edn::SpannedValue::Vector(ref entities) => {
+                entities.iter()
+                        .enumerate()
+                        .find(|&(_, entity)| entity.span.0 <= position.0 && position.0 < entity.span.1)
+            },
+            _ => None,
+        };
+
+        let error = Error::from_kind(ErrorKind::ParseError(parse_error));
+        match invalid {
+            Some((index, entity)) => {
+                let line_and_column = source.map(|source| entity.span.start_line_and_column(source));
+                Err(Error::with_chain(error, ErrorKind::EntityParseError(index, line_and_column, describe_invalid_entity(entity))))
+            },
+            None => Err(error),
+        }
     }
 
     fn parse_entid_or_lookup_ref_or_temp_id(input: edn::ValueAndSpan) -> std::result::Result<EntidOrLookupRefOrTempId, errors::Error> {
@@ -190,6 +251,7 @@ pub fn remove_db_id(map: &mut MapNotation) -> std::result::Result<Option<EntidOr
                     .chain_err(|| Error::from(ErrorKind::DbIdError))?;
                 Some(db_id)
             },
+            AtomOrLookupRefOrVectorOrMapNotation::TempId(tempid) => Some(EntidOrLookupRefOrTempId::TempId(tempid)),
             AtomOrLookupRefOrVectorOrMapNotation::LookupRef(_) |
             AtomOrLookupRefOrVectorOrMapNotation::Vector(_) |
             AtomOrLookupRefOrVectorOrMapNotation::MapNotation(_) => {
@@ -19,6 +19,10 @@ use self::edn::symbols::NamespacedKeyword;
 
 /// A tempid, either an external tempid given in a transaction (usually as an `edn::Value::Text`),
 /// or an internal tempid allocated by Mentat itself.
+///
+/// The transactor allocates positive internal tempids.  Negative ones are reserved for callers
+/// that build entities directly and need tempids that can't collide with any string, like
+/// `mentat::TermBuilder::tempid`; they are reported in `TxReport::minted_tempids`.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
 pub enum TempId {
     External(String),
@@ -26,6 +30,12 @@ pub enum TempId {
 }
 
 impl TempId {
+    /// An external tempid with the given name, as if given in a transaction as a string literal.
+    /// Keep it to look up the entid it resolved to with `TxReport::resolved`.
+    pub fn external<S: Into<String>>(name: S) -> TempId {
+        TempId::External(name.into())
+    }
+
     pub fn into_external(self) -> Option<String> {
         match self {
             TempId::External(s) => Some(s),
@@ -95,6 +105,9 @@ pub enum AtomOrLookupRefOrVectorOrMapNotation {
     LookupRef(LookupRef),
     Vector(Vec<AtomOrLookupRefOrVectorOrMapNotation>),
     MapNotation(MapNotation),
+    // The value of a ref attribute given as a tempid by code that builds entities directly.  The
+    // parser never produces this: a string literal tempid is an `Atom`.
+    TempId(TempId),
 }
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
@@ -122,3 +135,81 @@ pub enum Entity {
     // Like {:db/id "tempid" a1 v1 a2 v2}.
     MapNotation(MapNotation),
 }
+
+// The `Display` implementations below render entities as transaction EDN, so that an entity that
+// couldn't be transacted can be shown to the user.
+
+impl fmt::Display for Entid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &Entid::Entid(e) => write!(f, "{}", e),
+            &Entid::Ident(ref a) => write!(f, "{}", a),
+        }
+    }
+}
+
+impl fmt::Display for LookupRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "(lookup-ref {} {})", self.a, self.v)
+    }
+}
+
+impl fmt::Display for EntidOrLookupRefOrTempId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &EntidOrLookupRefOrTempId::Entid(ref e) => write!(f, "{}", e),
+            &EntidOrLookupRefOrTempId::LookupRef(ref lookup_ref) => write!(f, "{}", lookup_ref),
+            &EntidOrLookupRefOrTempId::TempId(TempId::External(ref s)) => write!(f, "{}", edn::Value::Text(s.clone())),
+            &EntidOrLookupRefOrTempId::TempId(ref tempid) => write!(f, "{}", tempid),
+        }
+    }
+}
+
+fn fmt_map_notation(map_notation: &MapNotation, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "{{")?;
+    for (i, (a, v)) in map_notation.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{} {}", a, v)?;
+    }
+    write!(f, "}}")
+}
+
+impl fmt::Display for AtomOrLookupRefOrVectorOrMapNotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &AtomOrLookupRefOrVectorOrMapNotation::Atom(ref v) => write!(f, "{}", v),
+            &AtomOrLookupRefOrVectorOrMapNotation::LookupRef(ref lookup_ref) => write!(f, "{}", lookup_ref),
+            &AtomOrLookupRefOrVectorOrMapNotation::Vector(ref vs) => {
+                write!(f, "[")?;
+                for (i, v) in vs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            },
+            &AtomOrLookupRefOrVectorOrMapNotation::MapNotation(ref map_notation) => fmt_map_notation(map_notation, f),
+            &AtomOrLookupRefOrVectorOrMapNotation::TempId(TempId::External(ref s)) => write!(f, "{}", edn::Value::Text(s.clone())),
+            &AtomOrLookupRefOrVectorOrMapNotation::TempId(ref tempid) => write!(f, "{}", tempid),
+        }
+    }
+}
+
+impl fmt::Display for Entity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &Entity::AddOrRetract { ref op, ref e, ref a, ref v } => {
+                let op = match op {
+                    &OpType::Add => ":db/add",
+                    &OpType::Retract => ":db/retract",
+                };
+                write!(f, "[{} {} {} {}]", op, e, a, v)
+            },
+            &Entity::MapNotation(ref map_notation) => fmt_map_notation(map_notation, f),
+        }
+    }
+}
+